@@ -0,0 +1,49 @@
+#!/bin/sh
+# Create and delete long names on a FAT32 image made by mkfs.vfat,
+# mounted on /mnt from the xv6 shell, and have fsck.vfat check the
+# image afterwards. fs.img needs the mount and umount programs.
+#   make fat-test, or sh scripts/fat-test.sh ../fat.img
+set -e
+
+FAT_IMG=${1:-../fat.img}
+LOG=target/fat-test.log
+IN=target/fat-test.in
+# seconds until the shell is up
+BOOT=${BOOT:-8}
+KEPT=a-rather-long-file-name.text
+GONE=another-long-name-to-delete.text
+
+mkdir -p target
+rm -f $IN
+mkfifo $IN
+# a session of its own, so that make and qemu go down together
+setsid make -s qemu DISK2_IMG=$FAT_IMG < $IN > $LOG 2>&1 &
+QEMU_PID=$!
+{
+    sleep $BOOT
+    for cmd in "mkdir /mnt" "mount 2 /mnt vfat" \
+        "echo kept > /mnt/$KEPT" "echo gone > /mnt/$GONE" "rm /mnt/$GONE" \
+        "ls /mnt" "umount /mnt"; do
+        echo "$cmd"
+        sleep 1
+    done
+    sleep 2
+} > $IN
+kill -TERM -$QEMU_PID 2>/dev/null || true
+wait $QEMU_PID 2>/dev/null || true
+rm -f $IN
+
+fail() {
+    echo "fat-test: $1, see $LOG"
+    exit 1
+}
+grep -q "fat32: dev 2" $LOG || fail "the volume was not mounted"
+# lines of ls start with the name, the commands echoed with the prompt
+grep -Eq "^$KEPT +[0-9]" $LOG || fail "$KEPT is not listed"
+grep -Eq "^$GONE +[0-9]" $LOG && fail "$GONE is still listed"
+
+# -n checks without repairing, -l lists the files
+fsck.vfat -n -l $FAT_IMG > target/fat-test.fsck 2>&1 || fail "fsck.vfat found errors: $(cat target/fat-test.fsck)"
+grep -q "$KEPT" target/fat-test.fsck || fail "fsck.vfat does not see $KEPT"
+grep -q "$GONE" target/fat-test.fsck && fail "fsck.vfat still sees $GONE"
+echo "fat-test: ok"
@@ -0,0 +1,23 @@
+//! Root for testing src/fs/fat_format.rs on the host, standing in for
+//! the parts of the kernel it uses, see `make fat-format-test`.
+
+#![allow(dead_code)]
+
+extern crate alloc;
+
+mod arch {
+    pub mod riscv {
+        pub mod qemu {
+            pub mod fs {
+                pub const NAME_MAX: usize = 255;
+                pub const DIRSIZ: usize = NAME_MAX + 1;
+            }
+        }
+    }
+}
+
+#[path = "../src/syscall/errno.rs"]
+mod syscall;
+
+#[path = "../src/fs/fat_format.rs"]
+mod fat_format;
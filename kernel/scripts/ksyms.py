@@ -0,0 +1,73 @@
+#!/usr/bin/env python3
+# Build the kernel symbol table used by backtrace.rs.
+#
+# usage: rust-nm -C --defined-only -n kernel | ksyms.py kernel ksyms.bin
+#
+# The table replaces the contents of the `.ksyms` section with
+# `objcopy --update-section`, so it is padded to the size of that
+# section and the kernel layout does not change.
+#
+# layout (little endian):
+#   u32 magic "KSYM", u32 count
+#   count * (u64 address, u32 name offset, u32 name length), sorted by address
+#   names
+
+import re
+import struct
+import sys
+
+MAGIC = 0x4d59534b
+MAX_NAME = 128
+
+
+def section_size(elf, name):
+    with open(elf, 'rb') as f:
+        data = f.read()
+    if data[:4] != b'\x7fELF' or data[4] != 2:
+        sys.exit('ksyms: %s is not an ELF64 file' % elf)
+    shoff, = struct.unpack_from('<Q', data, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from('<HHH', data, 0x3a)
+    strtab = struct.unpack_from('<QQ', data, shoff + shstrndx * shentsize + 0x18)
+    for i in range(shnum):
+        header = shoff + i * shentsize
+        name_off, = struct.unpack_from('<I', data, header)
+        start = strtab[0] + name_off
+        if data[start:data.index(b'\0', start)].decode() == name:
+            size, = struct.unpack_from('<Q', data, header + 0x20)
+            return size
+    sys.exit('ksyms: no %s section in %s' % (name, elf))
+
+
+def main():
+    elf, out = sys.argv[1], sys.argv[2]
+    size = section_size(elf, '.ksyms')
+
+    symbols = {}
+    for line in sys.stdin:
+        parts = line.rstrip('\n').split(' ', 2)
+        if len(parts) != 3 or parts[1] not in 'TtWw':
+            continue
+        addr = int(parts[0], 16)
+        # drop the hash rustc appends to every symbol
+        name = re.sub(r'::h[0-9a-f]{16}$', '', parts[2])
+        symbols.setdefault(addr, name[:MAX_NAME])
+    symbols = sorted(symbols.items())
+
+    entries = b''
+    names = b''
+    names_base = 8 + 16 * len(symbols)
+    for addr, name in symbols:
+        encoded = name.encode()
+        entries += struct.pack('<QII', addr, names_base + len(names), len(encoded))
+        names += encoded
+    table = struct.pack('<II', MAGIC, len(symbols)) + entries + names
+
+    if len(table) > size:
+        sys.exit('ksyms: table needs %d bytes but .ksyms has %d, '
+                 'raise KSYMS_SIZE in backtrace.rs' % (len(table), size))
+    with open(out, 'wb') as f:
+        f.write(table + b'\0' * (size - len(table)))
+
+
+if __name__ == '__main__':
+    main()
@@ -1 +1,4 @@
-pub const CONSOLE: usize = 1;
\ No newline at end of file
+pub const CONSOLE: usize = 1;
+pub const IRQSTAT: usize = 2; // per-irq interrupt counters
+pub const PROF: usize = 3; // sampling profiler
+pub const LOCKSTAT: usize = 4; // spinlock contention counters
\ No newline at end of file
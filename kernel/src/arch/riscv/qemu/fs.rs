@@ -4,10 +4,28 @@ use crate::fs::SuperBlock;
 
 /// magic number indentifying this specific file system
 pub const FSMAGIC: u32 = 0x10203040;
+/// superblock feature: directories hold variable-length records
+/// with names up to NAME_MAX bytes instead of 16-byte entries
+pub const FEATURE_LONG_NAMES: u32 = 1 << 0;
+/// superblock feature: 128-byte inodes with mode, owner and timestamps
+pub const FEATURE_INODE_ATTRS: u32 = 1 << 1;
+/// features the kernel needs in an image
+pub const FEATURES_REQUIRED: u32 = FEATURE_LONG_NAMES | FEATURE_INODE_ATTRS;
+
+/// Permission bits of DiskInode::mode
+pub const S_ISUID: u16 = 0o4000;
+pub const S_ISGID: u16 = 0o2000;
+/// in a directory, only the owners of an entry or of the directory may remove it
+pub const S_ISVTX: u16 = 0o1000;
+pub const S_IRWXU: u16 = 0o700;
+pub const S_IRWXG: u16 = 0o070;
+pub const S_IRWXO: u16 = 0o007;
+pub const S_IALL: u16 = 0o7777;
 /// size of disk block
 pub const BSIZE: usize = 1024;
-/// Maxinum of blocks an FS op can write
-pub const MAXOPBLOCKS: usize = 10;
+/// Maxinum of blocks an FS op can write, enough for a write
+/// of a few blocks through three levels of indirect blocks
+pub const MAXOPBLOCKS: usize = 14;
 /// size of buffer cache for block
 pub const NBUF: usize = MAXOPBLOCKS * 3;
 /// size of log space in disk
@@ -23,17 +41,27 @@ pub const NINODE: usize = 50;
 pub const ROOTDEV: u32 = 1;
 /// root inode path name
 pub const ROOTIPATH: [u8; 2] = [b'/', 0];
-/// size of file system in blocks
-pub const FSSIZE: usize = 1000; 
+/// size of file system in blocks, as made by mkfs; large enough
+/// for files to reach the doubly indirect blocks
+pub const FSSIZE: usize = 20000;
 
 pub const ROOTINUM: u32 = 1;
 
-pub const NDIRECT: usize = 12;
+/// Block numbers kept in the inode itself, followed by one singly,
+/// one doubly and one triply indirect block. 
+pub const NDIRECT: usize = 10;
 pub const NINDIRECT: usize =  BSIZE / 8;
-pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+pub const NDINDIRECT: usize = NINDIRECT * NINDIRECT;
+pub const NTINDIRECT: usize = NDINDIRECT * NINDIRECT;
+pub const MAXFILE: usize = NDIRECT + NINDIRECT + NDINDIRECT + NTINDIRECT;
 
-/// Directory is a file containing a sequence of dirent structures
-pub const DIRSIZ: usize = 14;
+/// Directory is a file containing a sequence of dirent structures. 
+/// Longest file name, in bytes
+pub const NAME_MAX: usize = 255;
+/// Size of a buffer holding a file name and its trailing 0
+pub const DIRSIZ: usize = NAME_MAX + 1;
+/// Symbolic links followed within one lookup before giving up with ELOOP
+pub const MAXSYMLINKS: usize = 8;
 
 /// Inodes per block. 
 pub const IPB: usize = BSIZE / size_of::<DiskInode>();
@@ -64,6 +92,7 @@ pub enum OpenMode {
    RDWR = 0x002,
    CREATE = 0x200,
    TRUNC = 0x400,
+   NOFOLLOW = 0x1000,
    INVALID
 }
 
@@ -75,6 +104,7 @@ impl OpenMode {
             0x002 => { Self::RDWR },
             0x200 => { Self::CREATE },
             0x400 => { Self::TRUNC },
+            0x1000 => { Self::NOFOLLOW },
             _ => {Self::INVALID}
         }
     }
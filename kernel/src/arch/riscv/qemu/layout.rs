@@ -4,16 +4,20 @@
 // based on qemu's hw/riscv/virt.c:
 //
 // 00001000 -- boot ROM, provided by qemu
+// 00101000 -- goldfish RTC
 // 02000000 -- CLINT
 // 0C000000 -- PLIC
 // 10000000 -- uart0 
 // 10001000 -- virtio disk 
-// 80000000 -- boot ROM jumps here in machine mode
+// 80000000 -- boot ROM jumps here in machine mode,
+//             -bios loads OpenSBI here
+// 80200000 -- OpenSBI jumps here in supervisor mode,
 //             -kernel loads the kernel here
 // unused RAM after 80000000.
 
 // the kernel uses physical memory thus:
-// 0x80000000 -- entry.S, then kernel text and data
+// 0x80000000 -- OpenSBI, kept from supervisor mode by PMP
+// 0x80200000 -- entry.S, then kernel text and data
 // end -- start of kernel page allocation area
 // PHYSTOP -- end RAM used by the kernel
 
@@ -23,9 +27,15 @@ use super::*;
 pub const UART0:usize = 0x10000000;
 pub const UART0_IRQ: u32 = 10;
 
+/// goldfish real-time clock
+pub const RTC0: usize = 0x101000;
+
 /// virtio mmio interface
 pub const VIRTIO0:usize = 0x10001000;
 pub const VIRTIO0_IRQ: u32 = 1;
+/// a second virtio disk, if qemu was given one
+pub const VIRTIO1:usize = 0x10002000;
+pub const VIRTIO1_IRQ: u32 = 2;
 
 /// core local interruptor (CLINT), which contains the timer.
 pub const CLINT: usize = 0x2000000;
@@ -70,16 +80,51 @@ pub const PGMASKLEN: usize = 9;
 pub const PGMASK: usize = 0x1FF;
 
 
+/// Number of page-table levels in use: 3 for Sv39, 4 for Sv48.
+/// Decided once by kvm_init() before any page table is built.
+static mut PAGE_LEVELS: usize = 3;
+
+#[inline]
+pub fn page_levels() -> usize {
+    unsafe{ PAGE_LEVELS }
+}
+
+/// Only used in boot, before paging is enabled.
+pub unsafe fn set_page_levels(levels: usize) {
+    PAGE_LEVELS = levels;
+}
+
 /// One beyond the highest possible virtual address.
-/// MAXVA is actually one bit less than the max allowed by
-/// Sv39, to avoid having to sign-extend virtual addresses
+/// max_va is actually one bit less than the max allowed by
+/// Sv39 (Sv48), to avoid having to sign-extend virtual addresses
 /// that have the high bit set.
-pub const MAXVA: usize =  1 << (9 + 9 + 9 + 12 - 1); 
+#[inline]
+pub fn max_va() -> usize {
+    1 << (PGMASKLEN * page_levels() + PGSHIFT - 1)
+}
 
 // map the trampoline page to the highest address,
 // in both user and kernel space.
-pub const TRAMPOLINE: usize = MAXVA - PGSIZE;
-pub const TRAPFRAME: usize = TRAMPOLINE - PGSIZE;
+#[inline]
+pub fn trampoline_va() -> usize {
+    max_va() - PGSIZE
+}
+
+#[inline]
+pub fn trapframe_va() -> usize {
+    trampoline_va() - PGSIZE
+}
+
+/// Pages of each kernel stack, the lowest one left unmapped as a guard.
+/// The stacks lie below the trampoline, in both user and kernel space.
+pub const KSTACK_PAGES: usize = 5;
+
+/// One beyond the highest address of user memory: below the kernel
+/// stacks, so it grows with the page-table mode like max_va.
+#[inline]
+pub fn user_top() -> usize {
+    trampoline_va() - param::NPROC * KSTACK_PAGES * PGSIZE
+}
 
 
 
@@ -1,6 +1,10 @@
 pub const NPROC:usize = 64; // maximum number of processes
 pub const NCPU:usize = 8; // maximum number of CPUs
 pub const NDEV:usize = 10;  // maximum major device number
+pub const NDISK:usize = 2; // maximum virtio disks, disk i is device i+1
+pub const NMOUNT:usize = 8; // maximum mounted file systems
+pub const TMPFS_PAGES:usize = 4096; // maximum size of a tmpfs, in pages
+pub const TMPFS_NINODE:usize = 1024; // maximum nodes of a tmpfs
 pub const MAXARG:usize  = 32;  // max exec arguments
 pub const MAXPATH:usize = 128;   // maximum file path name
 
@@ -9,3 +13,4 @@ pub const LEAF_SIZE:usize = 16;
 
 // max memory size for buddy system
 pub const MAX_ALIGNMENT:usize = 4096;
+pub const NGROUPS:usize = 16; // max supplementary groups of a process
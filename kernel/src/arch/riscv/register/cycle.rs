@@ -0,0 +1,8 @@
+// cycle counter, readable in supervisor mode
+// once mcounteren.CY is set.
+#[inline]
+pub unsafe fn read() -> usize {
+    let ret:usize;
+    core::arch::asm!("csrr {}, cycle", out(reg)ret);
+    ret
+}
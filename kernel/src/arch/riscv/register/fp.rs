@@ -0,0 +1,8 @@
+// read fp (s0), the frame pointer of the current function.
+// Only meaningful when the kernel is built with frame pointers.
+#[inline(always)]
+pub unsafe fn read() -> usize {
+    let ret:usize;
+    core::arch::asm!("mv {}, s0",out(reg)ret);
+    ret
+}
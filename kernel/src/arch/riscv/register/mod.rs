@@ -18,15 +18,15 @@ pub mod scause;
 pub mod stval;
 pub mod mcounteren;
 pub mod time;
+pub mod cycle;
 pub mod sp;
 pub mod ra;
+pub mod fp;
 pub mod clint;
 pub mod pmp;
 
 #[inline]
 // flush the TLB.
 pub unsafe fn sfence_vma(){
-    println!("flush the TLB");
     core::arch::asm!("sfence.vma zero, zero");
-    println!("finish sfence vma");
 }
\ No newline at end of file
@@ -1,5 +1,21 @@
 // use riscv's sv39 page table scheme.
 pub const SATP_SV39:usize =  8 << 60;
+// or sv48 when the hart supports it.
+pub const SATP_SV48:usize =  9 << 60;
+
+// the ASID field, bits 44..59.
+pub const ASID_SHIFT:usize = 44;
+pub const ASID_BITS:usize = 16;
+pub const ASID_MASK:usize = ((1 << ASID_BITS) - 1) << ASID_SHIFT;
+
+/// The MODE field for a page table with the given number of levels. 
+pub fn mode(levels: usize) -> usize {
+    match levels {
+        3 => SATP_SV39,
+        4 => SATP_SV48,
+        _ => panic!("satp: unsupported paging levels {}", levels)
+    }
+}
 
 // supervisor address translation and protection;
 // holds the address of the page table.
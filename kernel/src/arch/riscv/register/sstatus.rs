@@ -1,5 +1,7 @@
 /// Supervisor Status Register, sstatus
 pub enum SSTATUS {
+    /// permit Supervisor User Memory access
+    SUM = 1 << 18,
     /// Previous mode, 1=Supervisor, 0=User
     SPP = 1 << 8,
     /// Supervisor Previous Interrupt Enable
@@ -47,6 +49,17 @@ pub unsafe fn intr_off() {
 }
 
 
+/// let supervisor mode access user pages
+#[inline]
+pub unsafe fn sum_on() {
+    write(read() | SSTATUS::SUM as usize);
+}
+
+#[inline]
+pub unsafe fn sum_off() {
+    write(read() & !(SSTATUS::SUM as usize));
+}
+
 /// are device interrupts enabled?
 #[inline]
 pub unsafe fn intr_get() -> bool {
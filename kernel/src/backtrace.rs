@@ -0,0 +1,189 @@
+//! Frame pointer backtraces.
+//!
+//! The kernel is built with frame pointers, so every frame keeps the return
+//! address at fp - 8 and the caller's frame pointer at fp - 16. Return
+//! addresses are turned into names with the symbol table that
+//! scripts/ksyms.py writes into the `.ksyms` section after linking.
+
+use core::ptr::read_volatile;
+use core::str::from_utf8;
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use crate::arch::riscv::register::fp;
+use crate::arch::riscv::qemu::layout::KERNEL_BASE;
+use crate::process::ProcData;
+
+/// Size of the `.ksyms` section, ksyms.py pads the table to this size.
+const KSYMS_SIZE: usize = 0x80000;
+
+/// "KSYM"
+const KSYMS_MAGIC: u32 = 0x4d59534b;
+
+/// Length of the table header: magic and number of symbols.
+const KSYMS_HEADER: usize = 8;
+
+/// Length of a symbol entry: address, name offset and name length.
+const KSYMS_ENTRY: usize = 16;
+
+/// Deepest call chain we print.
+const MAX_DEPTH: usize = 32;
+
+/// Largest distance between two frames we are willing to follow.
+const MAX_FRAME_SIZE: usize = 0x4000;
+
+/// Filled in after linking, all zero (no symbols) until then.
+/// `static mut` so that the compiler can not assume it stays zero.
+#[used]
+#[no_mangle]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// Set while printing a backtrace, a fault in the middle of
+/// one must not start another.
+static BACKTRACING: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn etext();
+}
+
+fn ksyms_u32(offset: usize) -> u32 {
+    unsafe{ read_volatile(KSYMS.as_ptr().add(offset) as *const u32) }
+}
+
+fn ksyms_u64(offset: usize) -> u64 {
+    unsafe{ read_volatile(KSYMS.as_ptr().add(offset) as *const u64) }
+}
+
+fn ksyms_count() -> usize {
+    if ksyms_u32(0) != KSYMS_MAGIC {
+        return 0
+    }
+    ksyms_u32(4) as usize
+}
+
+fn ksyms_addr(index: usize) -> usize {
+    ksyms_u64(KSYMS_HEADER + index * KSYMS_ENTRY) as usize
+}
+
+fn ksyms_name(index: usize) -> &'static str {
+    let entry = KSYMS_HEADER + index * KSYMS_ENTRY;
+    let offset = ksyms_u32(entry + 8) as usize;
+    let len = ksyms_u32(entry + 12) as usize;
+    if offset + len > KSYMS_SIZE {
+        return "?"
+    }
+    let name = unsafe{ core::slice::from_raw_parts(KSYMS.as_ptr().add(offset), len) };
+    from_utf8(name).unwrap_or("?")
+}
+
+/// Find the kernel function containing pc,
+/// return its name and the offset of pc into it.
+pub fn lookup(pc: usize) -> Option<(&'static str, usize)> {
+    if pc < KERNEL_BASE || pc >= etext as usize {
+        return None
+    }
+    let count = ksyms_count();
+    if count == 0 || ksyms_addr(0) > pc {
+        return None
+    }
+    // last symbol whose address is <= pc
+    let (mut low, mut high) = (0, count);
+    while high - low > 1 {
+        let mid = (low + high) / 2;
+        if ksyms_addr(mid) <= pc {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((ksyms_name(low), pc - ksyms_addr(low)))
+}
+
+/// Print one line of a kernel backtrace.
+pub fn print_pc(depth: usize, pc: usize) {
+    match lookup(pc) {
+        Some((name, offset)) => println!("  #{:<2} {:#x} {}+{:#x}", depth, pc, name, offset),
+        None => println!("  #{:<2} {:#x} ?", depth, pc)
+    }
+}
+
+/// Follow a frame pointer chain, calling f with each return address.
+/// read gives back the word at an address, or None if it can not be read.
+fn walk(mut frame: usize, mut read: impl FnMut(usize) -> Option<usize>, mut f: impl FnMut(usize, usize)) {
+    for depth in 0..MAX_DEPTH {
+        if frame == 0 || frame % 8 != 0 {
+            break;
+        }
+        let (ra, prev) = match (read(frame - 8), read(frame - 16)) {
+            (Some(ra), Some(prev)) => (ra, prev),
+            _ => break
+        };
+        if ra == 0 {
+            break;
+        }
+        f(depth, ra);
+        if prev <= frame || prev - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = prev;
+    }
+}
+
+/// Collect the return addresses of the kernel call chain starting
+/// from frame pointer frame into callers, innermost first.
+/// Return how many were found.
+pub fn kernel_callers(frame: usize, callers: &mut [usize]) -> usize {
+    let mut count = 0;
+    walk(
+        frame,
+        |addr| if addr >= KERNEL_BASE { Some(unsafe{ read_volatile(addr as *const usize) }) } else { None },
+        |depth, ra| if depth < callers.len() {
+            callers[depth] = ra;
+            count = depth + 1;
+        }
+    );
+    count
+}
+
+/// Print the kernel call chain starting from frame pointer frame.
+pub fn print_backtrace_from(frame: usize) {
+    if BACKTRACING.swap(true, Ordering::SeqCst) {
+        return
+    }
+    println!("backtrace:");
+    walk(
+        frame,
+        |addr| if addr >= KERNEL_BASE { Some(unsafe{ read_volatile(addr as *const usize) }) } else { None },
+        |depth, ra| print_pc(depth, ra)
+    );
+    BACKTRACING.store(false, Ordering::SeqCst);
+}
+
+/// Print the call chain of our caller.
+#[inline(always)]
+pub fn print_backtrace() {
+    print_backtrace_from(unsafe{ fp::read() });
+}
+
+/// Print the call chain of a process which trapped from user space.
+/// User programs are built with frame pointers too, but there is
+/// no symbol table for them, so only addresses are printed.
+pub fn print_user_backtrace(pdata: &mut ProcData) {
+    let tf = unsafe{ &*pdata.trapframe };
+    let (epc, frame) = (tf.epc, tf.s0);
+    let page_table = match pdata.pagetable.as_mut() {
+        Some(page_table) => page_table,
+        None => return
+    };
+    println!("user backtrace:");
+    println!("  #0  {:#x}", epc);
+    walk(
+        frame,
+        |addr| {
+            let mut word: usize = 0;
+            page_table.copy_in(&mut word as *mut usize as *mut u8, addr, 8).ok()?;
+            Some(word)
+        },
+        |depth, ra| println!("  #{:<2} {:#x}", depth + 1, ra)
+    );
+}
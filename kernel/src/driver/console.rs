@@ -1,9 +1,11 @@
 use core::num::Wrapping;
 
-use crate::{lock::spinlock::Spinlock, memory::{copy_to_kernel, copy_from_kernel}, process::{CPU_MANAGER, PROC_MANAGER}};
+use crate::{lock::{spinlock::Spinlock, waitqueue::Condvar}, memory::{copy_to_kernel, copy_from_kernel}, process::{CPU_MANAGER, PROC_MANAGER}};
 use super::uart::{UART, putc_sync, uart_get, uart_put};
 
 static CONSOLE: Spinlock<Console> = Spinlock::new(Console::new(), "console");
+/// Signalled when a whole line is in CONSOLE.
+static CONSOLE_LINE: Condvar = Condvar::new_interruptible("console_line");
 const INPUT_BUF: usize = 128;
 
 /// end of transmit/file.line
@@ -24,6 +26,10 @@ pub const CTRL_DEL: u8 = 0x7f;
 /// for debug, print process list
 pub const CTRL_PRINT_PROCESS: u8 = 0x10;
 
+/// for debug, print live kernel heap allocations (Ctrl-K),
+/// only with the `kmem_debug` feature
+pub const CTRL_LEAK_REPORT: u8 = 0x0B;
+
 /// backspace the whole line
 // TODO
 pub const CTRL_BS_LINE: u8 = 0x15;
@@ -65,7 +71,8 @@ pub(crate) fn putc(c: u8) {
 pub(super) fn console_read(
     is_user: bool, 
     mut dst: usize, 
-    size: usize
+    size: usize,
+    _offset: usize
 ) -> Option<usize> {
     let mut console = CONSOLE.acquire();
 
@@ -81,8 +88,7 @@ pub(super) fn console_read(
                 return None
             }
             // 当用户仍在输入的时候，调用 sleep 进行休眠
-            p.sleep(&console.read_index as *const _ as usize, console);
-            console = CONSOLE.acquire();
+            console = CONSOLE_LINE.wait(console);
         }
 
         // read
@@ -151,6 +157,11 @@ pub(super) fn console_intr(c: u8) {
             }
         },
 
+        #[cfg(feature = "kmem_debug")]
+        CTRL_LEAK_REPORT => {
+            crate::memory::KERNEL_HEAP.leak_report();
+        },
+
         CTRL_BS_LINE => {
             while console.edit_index != console.write_index &&
             console.buf[(console.edit_index - Wrapping(1)).0 % INPUT_BUF] != CTRL_LF {
@@ -177,9 +188,7 @@ pub(super) fn console_intr(c: u8) {
                 if c == CTRL_LF || c == CTRL_EOT || (console.edit_index - console.read_index).0 == INPUT_BUF {
                     console.write_index = console.edit_index;
                     // 当检测到用户换行的时候，唤醒 `console_read` 进行读取
-                    unsafe{
-                        PROC_MANAGER.wake_up(&console.read_index as *const _ as usize)
-                    };
+                    CONSOLE_LINE.notify_all();
                 }
             }
         }
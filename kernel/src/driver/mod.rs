@@ -3,4 +3,5 @@ pub mod pci;
 pub mod plic;
 pub mod uart;
 pub mod console;
+pub mod rtc;
 
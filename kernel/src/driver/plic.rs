@@ -1,6 +1,16 @@
 use core::ptr;
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use alloc::string::String;
+use array_macro::array;
 
-use crate::{arch::riscv::qemu::layout::{PLIC_BASE, UART0_IRQ, VIRTIO0_IRQ}, process::{cpu, cpuid}};
+use crate::arch::riscv::qemu::{ layout::PLIC_BASE, param::NCPU, devices::IRQSTAT };
+use crate::fs::{ DEVICE_LIST, read_text };
+use crate::lock::spinlock::Spinlock;
+use crate::process::cpuid;
+
+/// Number of interrupt sources we manage, 0 means "no interrupt".
+pub const NIRQ: usize = 64;
 
 const PLIC_PRIORITY: usize = PLIC_BASE;
 const PLIC_PENDING: usize = PLIC_BASE + 0x1000;
@@ -29,22 +39,164 @@ fn PLIC_SCLAIM(hart_id: usize) -> usize {
     PLIC_BASE + 0x201004 + hart_id * 0x2000
 }
 
+/// Called from the trap handlers with interrupts off.
+pub type IrqHandler = fn();
+
+#[derive(Clone, Copy)]
+struct IrqDesc {
+    name: &'static str,
+    handler: Option<IrqHandler>,
+    priority: u32,
+    enabled: bool
+}
+
+impl IrqDesc {
+    const fn new() -> Self {
+        Self {
+            name: "",
+            handler: None,
+            priority: 0,
+            enabled: false
+        }
+    }
+}
+
+struct IrqTable {
+    irqs: [IrqDesc; NIRQ],
+    /// Harts which have called plic_init_hart().
+    online: usize
+}
+
+static IRQ_TABLE: Spinlock<IrqTable> = Spinlock::new(
+    IrqTable { irqs: [IrqDesc::new(); NIRQ], online: 0 }, 
+    "irq"
+);
+
+/// How many times each irq was served by each hart.
+static IRQ_COUNT: [[AtomicUsize; NCPU]; NIRQ] = array![_ => array![_ => AtomicUsize::new(0); NCPU]; NIRQ];
+
+/// Install handler for irq and enable it on every hart with
+/// the given priority (1 is the lowest, 7 the highest).
+pub fn register_irq(
+    irq: u32, 
+    name: &'static str, 
+    handler: IrqHandler, 
+    priority: u32
+) -> Result<(), &'static str> {
+    let irq = irq as usize;
+    if irq == 0 || irq >= NIRQ {
+        return Err("register_irq: invalid irq")
+    }
+    let mut table = IRQ_TABLE.acquire();
+    if table.irqs[irq].handler.is_some() {
+        return Err("register_irq: irq already registered")
+    }
+    table.irqs[irq] = IrqDesc {
+        name,
+        handler: Some(handler),
+        priority: 0,
+        enabled: false
+    };
+    table.set_priority(irq, priority);
+    table.set_enable(irq, true);
+    Ok(())
+}
+
+/// Disable irq and remove its handler.
+pub fn unregister_irq(irq: u32) {
+    let mut table = IRQ_TABLE.acquire();
+    table.set_enable(irq as usize, false);
+    table.set_priority(irq as usize, 0);
+    table.irqs[irq as usize] = IrqDesc::new();
+}
+
+/// Priority 0 means never interrupt.
+pub fn set_irq_priority(irq: u32, priority: u32) {
+    IRQ_TABLE.acquire().set_priority(irq as usize, priority);
+}
+
+pub fn enable_irq(irq: u32) {
+    IRQ_TABLE.acquire().set_enable(irq as usize, true);
+}
+
+pub fn disable_irq(irq: u32) {
+    IRQ_TABLE.acquire().set_enable(irq as usize, false);
+}
+
+impl IrqTable {
+    fn set_priority(&mut self, irq: usize, priority: u32) {
+        self.irqs[irq].priority = priority;
+        write(PLIC_PRIORITY + irq * 4, priority);
+    }
+
+    fn set_enable(&mut self, irq: usize, enabled: bool) {
+        self.irqs[irq].enabled = enabled;
+        for hart_id in (0..NCPU).filter(|hart| self.online & (1 << hart) != 0) {
+            self.write_enable(hart_id, irq / 32);
+        }
+    }
+
+    /// Write one 32-irq word of the S-mode enable bits of a hart.
+    fn write_enable(&self, hart_id: usize, word: usize) {
+        let mut bits = 0;
+        for i in 0..32 {
+            let irq = word * 32 + i;
+            if irq < NIRQ && self.irqs[irq].enabled {
+                bits |= 1 << i;
+            }
+        }
+        write(PLIC_SENABLE(hart_id) + word * 4, bits);
+    }
+}
+
 pub fn plic_init() {
     // set desired IRQ priorities non-zero (otherwise disable)
-    write(PLIC_BASE + (UART0_IRQ * 4) as usize, 1);
-    write(PLIC_BASE + (VIRTIO0_IRQ * 4) as usize, 1);
+    let table = IRQ_TABLE.acquire();
+    for (irq, desc) in table.irqs.iter().enumerate().skip(1) {
+        write(PLIC_PRIORITY + irq * 4, desc.priority);
+    }
+    drop(table);
+
+    unsafe {
+        DEVICE_LIST.table[IRQSTAT].read = irq_stat_read as *const u8;
+    }
 }
 
 pub fn plic_init_hart() {
     let hart_id = unsafe{ cpuid() };
 
-    // Set UART's enable bit for this hart's S-mode. 
-    write(PLIC_SENABLE(hart_id), (1 << UART0_IRQ) | (1 << VIRTIO0_IRQ));
+    // Set the enable bits of registered irqs for this hart's S-mode. 
+    let mut table = IRQ_TABLE.acquire();
+    table.online |= 1 << hart_id;
+    for word in 0..(NIRQ + 31) / 32 {
+        table.write_enable(hart_id, word);
+    }
+    drop(table);
 
     // Set this hart's S-mode pirority threshold to 0. 
     write(PLIC_SPRIORITY(hart_id), 0);
 }
 
+/// Serve one pending device interrupt, called from the trap handlers. 
+pub fn handle_irq() {
+    let interrupt = match plic_claim() {
+        Some(interrupt) => interrupt,
+        None => return
+    };
+    let irq = interrupt as usize;
+    let handler = if irq < NIRQ {
+        IRQ_COUNT[irq][unsafe{ cpuid() }].fetch_add(1, Ordering::Relaxed);
+        IRQ_TABLE.acquire().irqs[irq].handler
+    } else {
+        None
+    };
+    match handler {
+        Some(handler) => handler(),
+        None => println!("plic: unexpected interrupt {}", irq)
+    }
+    plic_complete(interrupt);
+}
+
 /// Ask the PLIC what interrupt we should serve. 
 pub fn plic_claim() -> Option<u32> {
     let hart_id = unsafe {
@@ -66,15 +218,52 @@ pub fn plic_complete(interrupt: u32) {
     write(PLIC_SCLAIM(hart_id), interrupt);
 }
 
+/// Harts which serve interrupts, one bit per hart. 
+pub fn online_harts() -> usize {
+    IRQ_TABLE.acquire().online
+}
+
+/// Read of /dev/irqstat, see `irq_stat`. 
+fn irq_stat_read(is_user: bool, dst: usize, len: usize, offset: usize) -> Option<usize> {
+    let mut text = String::new();
+    irq_stat(&mut text);
+    read_text(is_user, dst, len, offset, &text)
+}
+
+/// Write one line per irq that has a handler or has
+/// been seen, with its priority and per-hart counts. 
+pub fn irq_stat(text: &mut String) {
+    let table = IRQ_TABLE.acquire();
+    let harts: usize = (0..NCPU).filter(|hart| table.online & (1 << hart) != 0).count();
+    let _ = write!(text, "irq name         prio enabled");
+    for hart in 0..harts {
+        let _ = write!(text, " hart{:<6}", hart);
+    }
+    text.push('\n');
+    for (irq, desc) in table.irqs.iter().enumerate() {
+        let seen = IRQ_COUNT[irq].iter().any(|count| count.load(Ordering::Relaxed) != 0);
+        if desc.handler.is_none() && !seen {
+            continue;
+        }
+        let name = if desc.handler.is_some() { desc.name } else { "-" };
+        let _ = write!(text, "{:>3} {:<12} {:>4} {:>7}", irq, name, desc.priority, desc.enabled);
+        for hart in 0..harts {
+            let _ = write!(text, " {:>10}", IRQ_COUNT[irq][hart].load(Ordering::Relaxed));
+        }
+        text.push('\n');
+    }
+    drop(table);
+}
+
 
 fn write(addr: usize, val: u32) {
     unsafe {
-        ptr::write(addr as *mut u32, val);
+        ptr::write_volatile(addr as *mut u32, val);
     }
 }
 
 fn read(addr: usize) -> u32 {
     unsafe {
-        ptr::read(addr as *const u32)
+        ptr::read_volatile(addr as *const u32)
     }
-}
\ No newline at end of file
+}
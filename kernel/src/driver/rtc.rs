@@ -0,0 +1,23 @@
+//! Goldfish real-time clock of qemu virt, the wall-clock time.
+
+use core::ptr;
+
+use crate::arch::riscv::qemu::layout::RTC0;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+/// Nanoseconds since the Unix epoch.
+pub fn now_ns() -> u64 {
+    unsafe {
+        // reading TIME_LOW latches TIME_HIGH
+        let low = ptr::read_volatile((RTC0 + TIME_LOW) as *const u32) as u64;
+        let high = ptr::read_volatile((RTC0 + TIME_HIGH) as *const u32) as u64;
+        (high << 32) | low
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    now_ns() / 1_000_000_000
+}
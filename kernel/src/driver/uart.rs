@@ -4,9 +4,11 @@ use core::convert::{ Into, TryInto };
 use core::fmt::{self, Write, Error};
 use core::sync::atomic::Ordering;
 
-use crate::process::{CPU_MANAGER, PROC_MANAGER, pop_off, push_off};
-use crate::{arch::riscv::qemu::layout::UART0, println};
+use crate::process::{pop_off, push_off};
+use crate::{arch::riscv::qemu::layout::{UART0, UART0_IRQ}, println};
+use crate::driver::plic::register_irq;
 use crate::lock::spinlock::*;
+use crate::lock::waitqueue::Condvar;
 
 use super::console::console_intr;
 use super::console::PANICKED;
@@ -39,12 +41,19 @@ const UART_BASE_ADDR: usize = UART0;
 
 const UART_BUF_SIZE:usize = 32;
 pub static UART: Spinlock<Uart> = Spinlock::new(Uart::new(), "uart");
+/// Signalled when the transmit buffer has room again.
+static UART_SPACE: Condvar = Condvar::new("uart_space");
 
 /// init uart
 pub unsafe fn uart_init() {
     let mut uart = UART.acquire();
     uart.init();
     drop(uart);
+    register_irq(UART0_IRQ, "uart", uart_intr, 1).expect("uart_init");
+}
+
+fn uart_intr() {
+    UART.intr();
 }
 
 /// UART DRIVER
@@ -132,9 +141,7 @@ impl Uart {
             let read_index = self.read_index.0 % UART_BUF_SIZE;
             let c = self.buf[read_index];
             self.read_index += Wrapping(1);
-            unsafe{
-                PROC_MANAGER.wake_up(&self.read_index as *const Wrapping<_> as usize);
-            }
+            UART_SPACE.notify_all();
             write_reg(UART_BASE_ADDR + THR, c);
         }
     }
@@ -182,12 +189,7 @@ impl Spinlock<Uart> {
 
         loop {
             if uart.write_index == uart.read_index + Wrapping(UART_BUF_SIZE) {
-                let p = unsafe {
-                    CPU_MANAGER.myproc().expect("Fail to get my process.")
-                };
-
-                p.sleep(&uart.read_index as *const _ as usize, uart);
-                uart = self.acquire();
+                uart = UART_SPACE.wait(uart);
             } else {
                 let write_index = uart.write_index.0 % UART_BUF_SIZE;
                 uart.buf[write_index] = c;
@@ -1,5 +1,8 @@
 //! driver for virtio device, only used for disk now
 //!
+//! There may be up to NDISK disks, the buffer cache device number
+//! of disk i is i+1, so the root disk ROOTDEV is the first one.
+//!
 //! from sec 2.6 in https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf :
 //!     * Descriptor Table - occupies the Descriptor Area
 //!     * Available Ring - occupies the Driver Area
@@ -15,14 +18,48 @@ use core::sync::atomic::{fence, Ordering};
 use core::ptr;
 use core::convert::TryInto;
 
-use crate::arch::riscv::qemu::layout::{PGSHIFT, PGSIZE, VIRTIO0};
+use crate::arch::riscv::qemu::layout::{PGSHIFT, PGSIZE, VIRTIO0, VIRTIO0_IRQ, VIRTIO1, VIRTIO1_IRQ};
+use crate::arch::riscv::qemu::param::NDISK;
+use crate::driver::plic::register_irq;
 use crate::arch::riscv::qemu::fs::BSIZE;
 use crate::arch::riscv::qemu::virtio::*;
 use crate::fs::Buf;
 use crate::lock::spinlock::Spinlock;
-use crate::process::{PROC_MANAGER, CPU_MANAGER};
+use crate::lock::waitqueue::Condvar;
+
+pub static DISKS: [Spinlock<Disk>; NDISK] = [
+    Spinlock::new(Disk::new(0, VIRTIO0), "virtio_disk0"),
+    Spinlock::new(Disk::new(1, VIRTIO1), "virtio_disk1"),
+];
+/// Signalled when descriptors of disk i are freed.
+static DESC_FREE: [Condvar; NDISK] = array![_ => Condvar::new("virtio_desc"); NDISK];
+/// Signalled when the request of disk i whose first descriptor is j completes.
+static REQ_DONE: [[Condvar; NUM]; NDISK] = array![_ => array![_ => Condvar::new("virtio_req"); NUM]; NDISK];
+
+/// Probe and set up the disks, the first one must be there.
+/// Only called once when the kernel boots.
+pub unsafe fn init() {
+    for (i, disk) in DISKS.iter().enumerate() {
+        if !disk.acquire().init() {
+            if i == 0 {
+                panic!("could not find virtio disk");
+            }
+            println!("virtio disk {}: not found", i);
+        }
+    }
+}
 
-pub static DISK: Spinlock<Disk> = Spinlock::new(Disk::new(), "virtio_disk");
+/// Is there a disk for device dev? 
+pub fn present(dev: u32) -> bool {
+    let i = (dev as usize).wrapping_sub(1);
+    i < NDISK && DISKS[i].acquire().present
+}
+
+/// Read or write buf on the disk of its device. 
+pub fn rw(buf: &mut Buf<'_>, writing: bool) {
+    let i = buf.read_dev() as usize - 1;
+    DISKS[i].rw(buf, writing);
+}
 
 #[repr(C, align(4096))]
 pub struct Disk {
@@ -39,10 +76,14 @@ pub struct Disk {
     used_idx: u16,
     info: [Info; NUM],
     ops: [VirtIOBlkReq; NUM],
+    id: usize,
+    /// base address of its mmio registers
+    base: usize,
+    present: bool,
 }
 
 impl Disk {
-    const fn new() -> Self {
+    const fn new(id: usize, base: usize) -> Self {
         Self {
             pad1: Pad::new(),
             desc: array![_ => VQDesc::new(); NUM],
@@ -54,33 +95,36 @@ impl Disk {
             used_idx: 0,
             info: array![_ => Info::new(); NUM],
             ops: array![_ => VirtIOBlkReq::new(); NUM],
+            id,
+            base,
+            present: false,
         }
     }
 
-    /// Init the Disk.
+    /// Init the Disk, return false if there is none.
     /// Only called once when the kernel boots.
-    pub unsafe fn init(&mut self) {
+    unsafe fn init(&mut self) -> bool {
         debug_assert_eq!((&self.desc as *const _ as usize) % PGSIZE, 0);
         debug_assert_eq!((&self.used as *const _ as usize) % PGSIZE, 0);
         debug_assert_eq!((&self.free as *const _ as usize) % PGSIZE, 0);
     
-        if read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
-            || read(VIRTIO_MMIO_VERSION) != 1
-            || read(VIRTIO_MMIO_DEVICE_ID) != 2
-            || read(VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
+        if read(self.base, VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+            || read(self.base, VIRTIO_MMIO_VERSION) != 1
+            || read(self.base, VIRTIO_MMIO_DEVICE_ID) != 2
+            || read(self.base, VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
         {
-            panic!("could not find virtio disk");
+            return false
         }
     
         // step 1,2,3 - reset and set these two status bit
         let mut status: u32 = 0;
         status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
-        write(VIRTIO_MMIO_STATUS, status);
+        write(self.base, VIRTIO_MMIO_STATUS, status);
         status |= VIRTIO_CONFIG_S_DRIVER;
-        write(VIRTIO_MMIO_STATUS, status);
+        write(self.base, VIRTIO_MMIO_STATUS, status);
     
         // step 4 - read feature bits and negotiate
-        let mut features: u32 = read(VIRTIO_MMIO_DEVICE_FEATURES);
+        let mut features: u32 = read(self.base, VIRTIO_MMIO_DEVICE_FEATURES);
         features &= !(1u32 << VIRTIO_BLK_F_RO);
         features &= !(1u32 << VIRTIO_BLK_F_SCSI);
         features &= !(1u32 << VIRTIO_BLK_F_CONFIG_WCE);
@@ -88,36 +132,43 @@ impl Disk {
         features &= !(1u32 << VIRTIO_F_ANY_LAYOUT);
         features &= !(1u32 << VIRTIO_RING_F_EVENT_IDX);
         features &= !(1u32 << VIRTIO_RING_F_INDIRECT_DESC);
-        write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+        write(self.base, VIRTIO_MMIO_DRIVER_FEATURES, features);
     
         // step 5
         // set FEATURES_OK bit to tell the device feature negotiation is complete
         status |= VIRTIO_CONFIG_S_FEATURES_OK;
-        write(VIRTIO_MMIO_STATUS, status);
+        write(self.base, VIRTIO_MMIO_STATUS, status);
     
         // step 8
         // set DRIVER_OK bit to tell device that driver is ready
         // at this point device is "live"
         status |= VIRTIO_CONFIG_S_DRIVER_OK;
-        write(VIRTIO_MMIO_STATUS, status);
+        write(self.base, VIRTIO_MMIO_STATUS, status);
     
-        write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
+        write(self.base, VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
     
         // initialize queue 0
-        write(VIRTIO_MMIO_QUEUE_SEL, 0);
-        let max = read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+        write(self.base, VIRTIO_MMIO_QUEUE_SEL, 0);
+        let max = read(self.base, VIRTIO_MMIO_QUEUE_NUM_MAX);
         if max == 0 {
             panic!("virtio disk has no queue 0");
         }
         if max < NUM as u32 {
             panic!("virtio disk max queue short than NUM={}", NUM);
         }
-        write(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+        write(self.base, VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
         let pfn: usize = (self as *const Disk as usize) >> PGSHIFT;
-        write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(pfn).unwrap());
+        write(self.base, VIRTIO_MMIO_QUEUE_PFN, u32::try_from(pfn).unwrap());
 
         // set the descriptors free
         self.free.iter_mut().for_each(|f| *f = true);
+
+        match self.id {
+            0 => register_irq(VIRTIO0_IRQ, "virtio_disk0", disk0_intr, 1),
+            _ => register_irq(VIRTIO1_IRQ, "virtio_disk1", disk1_intr, 1),
+        }.expect("virtio disk");
+        self.present = true;
+        true
     }
 
     /// Allocate three descriptors.
@@ -158,9 +209,7 @@ impl Disk {
         self.desc[i].flags = 0;
         self.desc[i].next = 0;
         self.free[i] = true;
-        unsafe {
-            PROC_MANAGER.wake_up(&self.free[0] as *const bool as usize);
-        }
+        DESC_FREE[self.id].notify_all();
     }
 
     /// Free a chain of descriptors.
@@ -181,8 +230,8 @@ impl Disk {
     /// when the disk sends an interrupt.
     pub fn intr(&mut self) {
         unsafe {
-            let intr_stat = read(VIRTIO_MMIO_INTERRUPT_STATUS);
-            write(VIRTIO_MMIO_INTERRUPT_ACK, intr_stat & 0x3);
+            let intr_stat = read(self.base, VIRTIO_MMIO_INTERRUPT_STATUS);
+            write(self.base, VIRTIO_MMIO_INTERRUPT_ACK, intr_stat & 0x3);
         }
 
         fence(Ordering::SeqCst);
@@ -197,10 +246,11 @@ impl Disk {
                 panic!("interrupt status");
             }
 
-            let buf_raw_data = self.info[id].buf_channel.clone()
-                .expect("virtio disk intr handler not found pre-stored buf channel to wakeup");
+            if self.info[id].buf_channel.is_none() {
+                panic!("virtio disk intr: no buf recorded for the finished request");
+            }
             self.info[id].disk = false;
-            unsafe { PROC_MANAGER.wake_up(buf_raw_data); }
+            REQ_DONE[self.id][id].notify_one();
 
             self.used_idx += 1;
         }
@@ -218,10 +268,8 @@ impl Spinlock<Disk> {
             if guard.alloc3_desc(&mut idx) {
                 break;
             } else {
-                unsafe {
-                    CPU_MANAGER.myproc().unwrap().sleep(&guard.free[0] as *const bool as usize, guard);
-                }
-                guard = self.acquire();
+                let id = guard.id;
+                guard = DESC_FREE[id].wait(guard);
             }
         }
 
@@ -265,13 +313,12 @@ impl Spinlock<Disk> {
 
         fence(Ordering::SeqCst);
 
-        unsafe { write(VIRTIO_MMIO_QUEUE_NOTIFY, 0); }
+        unsafe { write(guard.base, VIRTIO_MMIO_QUEUE_NOTIFY, 0); }
 
         // wait for the disk to handle the buf data
         while guard.info[idx[0]].disk {
-            // choose the raw buf data as channel
-            unsafe { CPU_MANAGER.myproc().unwrap().sleep(buf_raw_data as usize, guard); }
-            guard = self.acquire();
+            let id = guard.id;
+            guard = REQ_DONE[id][idx[0]].wait(guard);
         }
 
         let buf_channel = guard.info[idx[0]].buf_channel.take();
@@ -363,8 +410,8 @@ impl VQUsedElem {
 
 #[repr(C)]
 struct Info {
-    /// Disk rw op stores the sleep channel in it.
-    /// Disk intr op retrieves it to wake up proc.
+    /// Disk rw op stores the raw buf data in it,
+    /// checked when the request completes.
     buf_channel: Option<usize>,
     status: u8,
     /// Is the relevant buf owned by disk?
@@ -447,13 +494,21 @@ const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
 const NUM: usize = 8;
 
 #[inline]
-unsafe fn read(offset: usize) -> u32 {
-    let src = (Into::<usize>::into(VIRTIO0) + offset) as *const u32;
+unsafe fn read(base: usize, offset: usize) -> u32 {
+    let src = (base + offset) as *const u32;
     ptr::read_volatile(src)
 }
 
 #[inline]
-unsafe fn write(offset: usize, data: u32) {
-    let dst = (Into::<usize>::into(VIRTIO0) + offset) as *mut u32;
+unsafe fn write(base: usize, offset: usize, data: u32) {
+    let dst = (base + offset) as *mut u32;
     ptr::write_volatile(dst, data);
 }
+
+fn disk0_intr() {
+    DISKS[0].acquire().intr();
+}
+
+fn disk1_intr() {
+    DISKS[1].acquire().intr();
+}
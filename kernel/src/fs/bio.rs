@@ -8,7 +8,7 @@ use core::sync::atomic::{Ordering, AtomicBool};
 
 use crate::lock::sleeplock::{SleepLock, SleepLockGuard};
 use crate::lock::spinlock::Spinlock;
-use crate::driver::virtio_disk::DISK;
+use crate::driver::virtio_disk;
 use crate::arch::riscv::qemu::fs::{NBUF, BSIZE};
 
 pub static BCACHE: Bcache = Bcache::new();
@@ -93,7 +93,7 @@ impl Bcache {
     pub fn bread<'a>(&'a self, dev: u32, blockno: u32) -> Buf<'a> {
         let mut b = self.bget(dev, blockno);
         if !self.bufs[b.index].valid.load(Ordering::Relaxed) {
-            DISK.rw(&mut b, false);
+            virtio_disk::rw(&mut b, false);
             self.bufs[b.index].valid.store(true, Ordering::Relaxed);
         }
         b
@@ -121,8 +121,12 @@ impl<'a> Buf<'a> {
         self.blockno
     }
 
+    pub fn read_dev(&self) -> u32 {
+        self.dev
+    }
+
     pub fn bwrite(&mut self) {
-        DISK.rw(self, true);
+        virtio_disk::rw(self, true);
     }
 
     /// Gives out a raw const pointer at the buf data. 
@@ -1,7 +1,7 @@
 use bit_field::BitField;
 
-use super::SUPER_BLOCK;
-use super::LOG;
+use super::superblock::super_block;
+use super::log::log_write;
 use super::BCACHE;
 use super::{ InodeType, DiskInode };
 
@@ -14,7 +14,7 @@ use core::ptr;
 // pub fn bzero(dev: u32, bno: u32) {
 //     let mut buf = BCACHE.bread(dev, bno);
 //     unsafe{ (&mut *buf.raw_data_mut()).zero() };
-//     LOG.write(buf);
+//     log_write(buf);
 // }
 
 /// Given an inode number. 
@@ -26,7 +26,7 @@ fn locate_inode_offset(inum: u32) -> usize {
 
 /// Free a block in the disk by setting the relevant bit in bitmap to 0.
 pub fn bfree(dev: u32, blockno: u32) {
-    let bm_blockno = unsafe { SUPER_BLOCK.bitmap_blockno(blockno) };
+    let bm_blockno = super_block(dev).bitmap_blockno(blockno);
     let bm_offset = blockno % BPB;
     let index = (bm_offset / 8) as isize;
     let bit = (bm_offset % 8) as usize;
@@ -37,16 +37,16 @@ pub fn bfree(dev: u32, blockno: u32) {
         panic!("bitmap: double freeing a block");
     }
     byte.set_bit(bit, false);
-    LOG.write(buf);
+    log_write(buf);
 }
 
 
 /// Allocate a zeroed disk block 
 pub fn balloc(dev: u32) -> u32 {
     let mut b = 0;
-    let sb_size = unsafe{ SUPER_BLOCK.size() };
+    let sb_size = super_block(dev).size();
     while b < sb_size {
-        let bm_blockno = unsafe{ SUPER_BLOCK.bitmap_blockno(b) };
+        let bm_blockno = super_block(dev).bitmap_blockno(b);
         let mut buf = BCACHE.bread(dev, bm_blockno);
         let mut bi = 0;
         while bi < BPB && b + bi < sb_size {
@@ -56,7 +56,7 @@ pub fn balloc(dev: u32) -> u32 {
             let buf_val = unsafe{ ptr::read(buf_ptr) };
             if buf_val == 0 { // Is block free?
                 unsafe{ ptr::write(buf_ptr, m) };
-                LOG.write(buf);
+                log_write(buf);
                 // drop(buf);
                 // bzero(dev, b + bi);
                 return b + bi
@@ -69,15 +69,15 @@ pub fn balloc(dev: u32) -> u32 {
 }
 
 pub fn inode_alloc(dev: u32, itype: InodeType) -> u32 {
-    let size = unsafe { SUPER_BLOCK.ninodes() };
+    let size = super_block(dev).ninodes();
     for inum in 1..size {
-        let blockno = unsafe { SUPER_BLOCK.locate_inode(inum) };
+        let blockno = super_block(dev).locate_inode(inum);
         let offset = locate_inode_offset(inum) as isize;
         let mut buf = BCACHE.bread(dev, blockno);
         let dinode = unsafe { (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
         let dinode = unsafe { &mut *dinode };
         if dinode.try_alloc(itype).is_ok() {
-            LOG.write(buf);
+            log_write(buf);
             return inum
         }
     }
@@ -1,8 +1,10 @@
 use crate::arch::riscv::qemu::param::NDEV;
+use crate::memory::copy_from_kernel;
 
 use core::mem::transmute;
 
-type ReadFn = fn(bool, usize, usize) -> Option<usize>;
+/// (is_user, dst, len, offset), offset is the file offset of the read.
+type ReadFn = fn(bool, usize, usize, usize) -> Option<usize>;
 type WriteFn = fn(bool, usize, usize) -> Option<usize>;
 
 pub static mut DEVICE_LIST: DeviceList = DeviceList::uninit();
@@ -47,4 +49,21 @@ impl Device {
         };
         func
     }
+}
+
+/// For devices whose content is a text report built on each read:
+/// copy what is left of text after offset, at most len bytes. 
+pub fn read_text(
+    is_user: bool, 
+    dst: usize, 
+    len: usize, 
+    offset: usize, 
+    text: &str
+) -> Option<usize> {
+    if offset >= text.len() {
+        return Some(0)
+    }
+    let count = core::cmp::min(len, text.len() - offset);
+    copy_from_kernel(is_user, dst, text[offset..].as_ptr(), count).ok()?;
+    Some(count)
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use core::ptr;
+use core::mem::size_of;
 
-use crate::arch::riscv::qemu::fs::{ NDIRECT, DIRSIZ };
+use crate::arch::riscv::qemu::fs::NDIRECT;
 
 #[repr(u16)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -8,10 +9,11 @@ pub enum InodeType {
     Empty = 0,
     Directory = 1,
     File = 2,
-    Device = 3
+    Device = 3,
+    Symlink = 4 // Data is the target path
 }
 
-/// On-disk inode structure
+/// On-disk inode structure, 128 bytes
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct DiskInode {
@@ -20,13 +22,29 @@ pub struct DiskInode {
     pub minor: i16, // Minor device number (T_DEVICE only)
     pub nlink: i16, // Number of links to inode in file system
     pub size: u32, // Size of file (bytes)
-    pub addrs: [u32; NDIRECT+1] // Data block addresses
+    pub mode: u16, // Permission bits, S_ISUID and below
+    pub pad: u16,
+    pub uid: u32, // Owner
+    pub gid: u32, // Group
+    pub atime: u64, // Last access, seconds since the epoch
+    pub mtime: u64, // Last change of the data
+    pub ctime: u64, // Last change of the inode
+    pub addrs: [u32; NDIRECT+3], // Data block addresses
+    pub reserved: [u32; 7]
 }
 
+/// On-disk directory record: this header followed by name_len bytes
+/// of name, without a trailing 0. rec_len covers the header, the name
+/// and the free space up to the next record; records never cross a
+/// block, so a directory is a whole number of blocks. A record with
+/// inum 0 is free. 
 #[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct DirEntry {
-    pub inum: u16,
-    pub name:[u8;DIRSIZ]
+    pub inum: u32,
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub file_type: u8 // InodeType of the entry
 }
 
 impl DiskInode {
@@ -37,7 +55,15 @@ impl DiskInode {
             minor: 0,
             nlink: 0,
             size: 0,
-            addrs: [0; NDIRECT+1]
+            mode: 0,
+            pad: 0,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            addrs: [0; NDIRECT+3],
+            reserved: [0; 7]
         }
     }
 
@@ -56,7 +82,15 @@ impl DirEntry {
     pub const fn new() -> Self {
         Self {
             inum: 0,
-            name: [0;DIRSIZ]
+            rec_len: 0,
+            name_len: 0,
+            file_type: 0
         }
     }
+
+    /// Bytes a record with a name of name_len bytes takes at least,
+    /// records start 4-byte aligned. 
+    pub const fn rec_size(name_len: usize) -> usize {
+        (size_of::<DirEntry>() + name_len + 3) & !3
+    }
 }
\ No newline at end of file
@@ -0,0 +1,1083 @@
+//! FAT32 file system with VFAT long names, for disks shared with
+//! other systems.
+//!
+//! FAT has no inodes: a file is its 32 byte entry in the directory
+//! holding it, which has its size, times and first cluster, the rest of
+//! its clusters are chained in the file allocation table. The nodes of
+//! a file share a `FatFile`, which keeps nothing but where its entry is,
+//! and read the entry anew in every operation, so they never disagree.
+//! Inode numbers are the positions of the entries, in 32 byte slots
+//! from the start of the disk.
+//!
+//! Every operation of a volume holds its lock. Blocks go through the
+//! buffer cache and are written back at once, there is no log.
+//! Unlinking a file with nodes frees its slots, which new entries may
+//! take, but not its clusters: the file keeps a copy of its entry in
+//! memory and goes on with it until its last node is dropped.
+
+use alloc::sync::{ Arc, Weak };
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, DIRSIZ };
+use crate::driver::{ rtc, virtio_disk };
+use crate::lock::sleeplock::SleepLock;
+use crate::lock::spinlock::Spinlock;
+use crate::memory::{ copy_from_kernel, copy_to_kernel };
+use crate::misc::min;
+use crate::syscall::Errno;
+
+use super::{ BCACHE, InodeType };
+use super::fat_format::{
+    decode_long, encode_long, fat_time, fits_short, lfn_checksum, short_basis, unix_time, write_decimal
+};
+use super::inode::name_len;
+use super::stat::Stat;
+use super::vfs::{ read_dir_records, Attr, FileSystem, VNode };
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID marks a long name slot
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Bytes of a directory slot
+const SLOT_SIZE: usize = 32;
+/// First byte of a free slot, 0 marks the end of the directory
+const SLOT_FREE: u8 = 0xe5;
+/// Set in the order of the last long name slot, which comes first
+const LFN_LAST: u8 = 0x40;
+/// UCS-2 characters per long name slot, at these offsets
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Long names are at most 255 characters
+const LFN_MAX_SLOTS: usize = 20;
+/// ntres bits telling that the base and the extension of a short name are lower case
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+const FAT_MASK: u32 = 0x0fff_ffff;
+/// Entries at least this end a chain
+const FAT_EOC: u32 = 0x0fff_fff8;
+/// Inode number of the root directory, which has no entry
+const ROOT_INUM: u32 = 1;
+
+/// Directory entry of a file, a short 8.3 name with the metadata.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ShortEntry {
+    name: [u8; 11],
+    attr: u8,
+    ntres: u8,
+    crt_time_tenth: u8,
+    crt_time: u16,
+    crt_date: u16,
+    acc_date: u16,
+    cluster_hi: u16,
+    wrt_time: u16,
+    wrt_date: u16,
+    cluster_lo: u16,
+    size: u32
+}
+
+impl ShortEntry {
+    fn cluster(&self) -> u32 {
+        (self.cluster_hi as u32) << 16 | self.cluster_lo as u32
+    }
+
+    fn set_cluster(&mut self, cluster: u32) {
+        self.cluster_hi = (cluster >> 16) as u16;
+        self.cluster_lo = cluster as u16;
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// "." and "..", which every directory but the root has.
+    fn is_dot(&self) -> bool {
+        self.name[0] == b'.'
+    }
+
+    /// Set the modification time, and the access date, to t.
+    fn touch(&mut self, t: u64) {
+        let (date, time) = fat_time(t);
+        self.wrt_date = date;
+        self.wrt_time = time;
+        self.acc_date = date;
+    }
+
+    /// The name as "BASE.EXT", lower case where ntres says so.
+    fn display_name(&self, name: &mut [u8; DIRSIZ]) -> usize {
+        let mut len = 0;
+        let base = trim_spaces(&self.name[..8]);
+        let ext = trim_spaces(&self.name[8..]);
+        for (i, &c) in base.iter().enumerate() {
+            // 0x05 stands for a first byte of 0xe5
+            let c = if i == 0 && c == 0x05 { SLOT_FREE } else { c };
+            name[len] = if self.ntres & NTRES_LOWER_BASE != 0 { c.to_ascii_lowercase() } else { c };
+            len += 1;
+        }
+        if !ext.is_empty() {
+            name[len] = b'.';
+            len += 1;
+            for &c in ext {
+                name[len] = if self.ntres & NTRES_LOWER_EXT != 0 { c.to_ascii_lowercase() } else { c };
+                len += 1;
+            }
+        }
+        name[len] = 0;
+        len
+    }
+}
+
+fn trim_spaces(s: &[u8]) -> &[u8] {
+    let len = s.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    &s[..len]
+}
+
+/// An entry found in a directory.
+#[derive(Clone, Copy)]
+struct Found {
+    short: ShortEntry,
+    /// position of the short entry on the disk
+    pos: u64,
+    /// positions of the long name slots before it
+    lfn: [u64; LFN_MAX_SLOTS],
+    nlfn: usize,
+    /// the long name if there is one, the short name otherwise
+    name: [u8; DIRSIZ],
+    name_len: usize
+}
+
+impl Found {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    /// Does the entry go by name? Names are compared
+    /// ignoring case, the short one too.
+    fn matches(&self, name: &[u8]) -> bool {
+        if self.name().eq_ignore_ascii_case(name) {
+            return true
+        }
+        let mut short = [0u8; DIRSIZ];
+        let len = self.short.display_name(&mut short);
+        short[..len].eq_ignore_ascii_case(name)
+    }
+}
+
+struct VolumeState {
+    /// where to look for a free cluster next
+    next_free: u32,
+    /// inode number for the next file unlinked with nodes, counting
+    /// down from the top, far from those of the entry positions
+    next_orphan: u32,
+    /// files with nodes by the position of their entries,
+    /// but for the root and those unlinked
+    files: Vec<(u64, Weak<FatFile>)>
+}
+
+/// A mounted FAT32 volume. Positions are bytes from the start of the disk.
+struct Volume {
+    dev: u32,
+    cluster_size: usize,
+    fat_start: u64,
+    fat_size: u64,
+    nfats: u32,
+    /// the only FAT in use when mirroring is off
+    active_fat: Option<u32>,
+    data_start: u64,
+    /// data clusters, numbered from 2
+    clusters: u32,
+    root_cluster: u32,
+    state: SleepLock<VolumeState>
+}
+
+impl Volume {
+    /// Copy len bytes at pos on the disk to dst, or from src when writing.
+    fn io(&self, pos: u64, is_user: bool, addr: usize, len: usize, writing: bool) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < len {
+            let at = pos + done as u64;
+            let off = (at % BSIZE as u64) as usize;
+            let count = min(len - done, BSIZE - off);
+            let mut buf = BCACHE.bread(self.dev, (at / BSIZE as u64) as u32);
+            let data = unsafe{ (buf.raw_data_mut() as *mut u8).add(off) };
+            if writing {
+                copy_to_kernel(data, is_user, addr + done, count)?;
+                buf.bwrite();
+            } else {
+                copy_from_kernel(is_user, addr + done, data, count)?;
+            }
+            drop(buf);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_u32(&self, pos: u64) -> u32 {
+        let mut v = 0u32;
+        self.io(pos, false, &mut v as *mut u32 as usize, 4, false).expect("fat: read");
+        u32::from_le(v)
+    }
+
+    fn write_u32(&self, pos: u64, v: u32) {
+        let v = v.to_le();
+        self.io(pos, false, &v as *const u32 as usize, 4, true).expect("fat: write");
+    }
+
+    fn read_slot(&self, pos: u64) -> [u8; SLOT_SIZE] {
+        let mut slot = [0u8; SLOT_SIZE];
+        self.io(pos, false, slot.as_mut_ptr() as usize, SLOT_SIZE, false).expect("fat: read slot");
+        slot
+    }
+
+    fn write_slot(&self, pos: u64, slot: &[u8; SLOT_SIZE]) {
+        self.io(pos, false, slot.as_ptr() as usize, SLOT_SIZE, true).expect("fat: write slot");
+    }
+
+    fn read_entry(&self, pos: u64) -> ShortEntry {
+        let slot = self.read_slot(pos);
+        unsafe{ ptr::read_unaligned(slot.as_ptr() as *const ShortEntry) }
+    }
+
+    fn write_entry(&self, pos: u64, entry: &ShortEntry) {
+        let mut slot = [0u8; SLOT_SIZE];
+        unsafe{ ptr::write_unaligned(slot.as_mut_ptr() as *mut ShortEntry, *entry); }
+        self.write_slot(pos, &slot);
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    fn fat_get(&self, cluster: u32) -> u32 {
+        let fat = self.active_fat.unwrap_or(0) as u64;
+        self.read_u32(self.fat_start + fat * self.fat_size + cluster as u64 * 4) & FAT_MASK
+    }
+
+    /// Set the entry of cluster in every FAT in use,
+    /// keeping the 4 reserved bits.
+    fn fat_set(&self, cluster: u32, value: u32) {
+        for fat in 0..self.nfats {
+            if self.active_fat.map_or(false, |active| active != fat) {
+                continue;
+            }
+            let pos = self.fat_start + fat as u64 * self.fat_size + cluster as u64 * 4;
+            let old = self.read_u32(pos);
+            self.write_u32(pos, (old & !FAT_MASK) | (value & FAT_MASK));
+        }
+    }
+
+    /// The cluster after cluster in its chain.
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let next = self.fat_get(cluster);
+        if next < FAT_EOC && self.valid_cluster(next) { Some(next) } else { None }
+    }
+
+    fn zero_cluster(&self, cluster: u32) {
+        static ZEROS: [u8; BSIZE] = [0; BSIZE];
+        let pos = self.cluster_pos(cluster);
+        let mut done = 0;
+        while done < self.cluster_size {
+            let count = min(self.cluster_size - done, BSIZE);
+            self.io(pos + done as u64, false, ZEROS.as_ptr() as usize, count, true).expect("fat: zero cluster");
+            done += count;
+        }
+    }
+
+    /// Allocate a cluster ending a chain, zeroed if zero is set.
+    fn alloc_cluster(&self, state: &mut VolumeState, zero: bool) -> Result<u32, Errno> {
+        for i in 0..self.clusters {
+            let cluster = 2 + (state.next_free - 2 + i) % self.clusters;
+            if self.fat_get(cluster) == 0 {
+                self.fat_set(cluster, FAT_MASK);
+                state.next_free = 2 + (cluster - 2 + 1) % self.clusters;
+                if zero {
+                    self.zero_cluster(cluster);
+                }
+                return Ok(cluster)
+            }
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    /// Free the chain starting at cluster.
+    fn free_chain(&self, mut cluster: u32) {
+        let mut count = 0;
+        while self.valid_cluster(cluster) && count < self.clusters {
+            let next = self.fat_get(cluster);
+            self.fat_set(cluster, 0);
+            cluster = next;
+            count += 1;
+        }
+    }
+
+    /// The cluster holding byte offset of the chain starting at first.
+    fn seek(&self, first: u32, offset: usize) -> Result<u32, &'static str> {
+        let mut cluster = first;
+        for _ in 0..offset / self.cluster_size {
+            cluster = self.next_cluster(cluster).ok_or("fat: chain shorter than the file")?;
+        }
+        Ok(cluster)
+    }
+
+    /// Call f with the position and content of each slot of the
+    /// directory whose chain starts at cluster, until f returns false.
+    /// Return the cluster it stopped in, the last one of the chain
+    /// if f never returned false.
+    fn slots(&self, cluster: u32, f: &mut dyn FnMut(u64, &[u8; SLOT_SIZE]) -> bool) -> u32 {
+        let mut cluster = cluster;
+        for _ in 0..self.clusters {
+            let base = self.cluster_pos(cluster);
+            for off in (0..self.cluster_size).step_by(SLOT_SIZE) {
+                let pos = base + off as u64;
+                if !f(pos, &self.read_slot(pos)) {
+                    return cluster
+                }
+            }
+            match self.next_cluster(cluster) {
+                Some(next) => cluster = next,
+                None => break
+            }
+        }
+        cluster
+    }
+
+    /// Call f with each entry of the directory starting at cluster,
+    /// joined with its long name, until f returns false.
+    fn scan(&self, cluster: u32, f: &mut dyn FnMut(&Found) -> bool) {
+        let mut long = [0u16; LFN_MAX_SLOTS * LFN_CHARS];
+        let mut lfn = [0u64; LFN_MAX_SLOTS];
+        let mut nlfn = 0;
+        // order of the long name slot expected next, Some(0) once complete
+        let mut next_ord: Option<u8> = None;
+        let mut checksum = 0;
+        self.slots(cluster, &mut |pos, slot| {
+            if slot[0] == 0 {
+                return false
+            }
+            if slot[0] == SLOT_FREE {
+                next_ord = None;
+                return true
+            }
+            if slot[11] & 0x3f == ATTR_LONG_NAME {
+                let ord = slot[0] & !LFN_LAST;
+                if slot[0] & LFN_LAST != 0 {
+                    if ord == 0 || ord as usize > LFN_MAX_SLOTS {
+                        next_ord = None;
+                        return true
+                    }
+                    long.iter_mut().for_each(|c| *c = 0);
+                    nlfn = 0;
+                    checksum = slot[13];
+                } else if next_ord != Some(ord) || ord == 0 || slot[13] != checksum {
+                    next_ord = None;
+                    return true
+                }
+                let start = (ord as usize - 1) * LFN_CHARS;
+                for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+                    long[start + i] = u16::from_le_bytes([slot[off], slot[off + 1]]);
+                }
+                lfn[nlfn] = pos;
+                nlfn += 1;
+                next_ord = Some(ord - 1);
+                return true
+            }
+            let short = unsafe{ ptr::read_unaligned(slot.as_ptr() as *const ShortEntry) };
+            let has_long = next_ord == Some(0) && checksum == lfn_checksum(&short.name);
+            next_ord = None;
+            if short.attr & ATTR_VOLUME_ID != 0 {
+                return true
+            }
+            let mut name = [0u8; DIRSIZ];
+            let name_len = match has_long {
+                true => decode_long(&long, &mut name),
+                false => None
+            };
+            let name_len = name_len.unwrap_or_else(|| short.display_name(&mut name));
+            f(&Found {
+                short,
+                pos,
+                lfn,
+                nlfn: if has_long { nlfn } else { 0 },
+                name,
+                name_len
+            })
+        });
+    }
+
+    /// Look for the entry called name in the directory starting at cluster.
+    fn find(&self, cluster: u32, name: &[u8]) -> Option<Found> {
+        let mut ret = None;
+        self.scan(cluster, &mut |found| {
+            if !found.short.is_dot() && found.matches(name) {
+                ret = Some(*found);
+                return false
+            }
+            true
+        });
+        ret
+    }
+
+    /// Find n free slots in a row in the directory starting
+    /// at cluster, growing it if there are none.
+    fn free_slots(&self, state: &mut VolumeState, cluster: u32, n: usize) -> Result<Vec<u64>, Errno> {
+        let mut run: Vec<u64> = Vec::new();
+        let mut last = self.slots(cluster, &mut |pos, slot| {
+            if slot[0] == 0 || slot[0] == SLOT_FREE {
+                run.push(pos);
+            } else {
+                run.clear();
+            }
+            run.len() < n
+        });
+        while run.len() < n {
+            let next = self.alloc_cluster(state, true)?;
+            self.fat_set(last, next);
+            last = next;
+            let base = self.cluster_pos(next);
+            for off in (0..self.cluster_size).step_by(SLOT_SIZE) {
+                if run.len() == n {
+                    break;
+                }
+                run.push(base + off as u64);
+            }
+        }
+        Ok(run)
+    }
+
+    /// Is there a short name in the directory starting at cluster?
+    fn short_name_used(&self, cluster: u32, name: &[u8; 11]) -> bool {
+        let mut used = false;
+        self.scan(cluster, &mut |found| {
+            used = &found.short.name == name;
+            !used
+        });
+        used
+    }
+
+    /// Does the directory starting at cluster only hold "." and ".."?
+    fn is_empty_dir(&self, cluster: u32) -> bool {
+        let mut empty = true;
+        self.scan(cluster, &mut |found| {
+            empty = found.short.is_dot();
+            empty
+        });
+        empty
+    }
+}
+
+pub struct Fat32Fs {
+    vol: Arc<Volume>
+}
+
+impl Fat32Fs {
+    /// Read the boot sector of disk dev, which must hold a FAT32 volume.
+    pub fn mount(dev: u32) -> Result<Self, Errno> {
+        if !virtio_disk::present(dev) {
+            return Err(Errno::ENODEV)
+        }
+        let mut boot = [0u8; 512];
+        let probe = Volume {
+            dev,
+            cluster_size: 0,
+            fat_start: 0,
+            fat_size: 0,
+            nfats: 0,
+            active_fat: None,
+            data_start: 0,
+            clusters: 0,
+            root_cluster: 0,
+            state: SleepLock::new(VolumeState{ next_free: 2, next_orphan: u32::MAX, files: Vec::new() }, "fat32")
+        };
+        probe.io(0, false, boot.as_mut_ptr() as usize, boot.len(), false).map_err(|_| Errno::EIO)?;
+
+        let u16_at = |off: usize| u16::from_le_bytes([boot[off], boot[off + 1]]) as u64;
+        let u32_at = |off: usize| u32::from_le_bytes([boot[off], boot[off + 1], boot[off + 2], boot[off + 3]]) as u64;
+        let sector_size = u16_at(11);
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(14);
+        let nfats = boot[16] as u64;
+        let root_entries = u16_at(17);
+        let fat_size16 = u16_at(22);
+        let total = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_sectors = u32_at(36);
+        let ext_flags = u16_at(40);
+        let root_cluster = u32_at(44) as u32;
+        let fs_info = u16_at(48);
+        // FAT32 is told apart by its FAT size being
+        // in the FAT32 field, like Linux does
+        if boot[510] != 0x55 || boot[511] != 0xaa ||
+            !sector_size.is_power_of_two() || sector_size < 512 || sector_size > 4096 ||
+            !sectors_per_cluster.is_power_of_two() ||
+            nfats == 0 || root_entries != 0 || fat_size16 != 0 || fat_sectors == 0 {
+            println!("fat32: dev {}: no FAT32 volume", dev);
+            return Err(Errno::EINVAL)
+        }
+        let data_sector = reserved + nfats * fat_sectors;
+        if total <= data_sector {
+            return Err(Errno::EINVAL)
+        }
+        // the FAT may not be able to hold all the clusters there is room for
+        let clusters = core::cmp::min((total - data_sector) / sectors_per_cluster, fat_sectors * sector_size / 4 - 2);
+        let vol = Volume {
+            cluster_size: (sectors_per_cluster * sector_size) as usize,
+            fat_start: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            nfats: nfats as u32,
+            active_fat: if ext_flags & 0x80 != 0 { Some((ext_flags & 0xf) as u32) } else { None },
+            data_start: data_sector * sector_size,
+            clusters: clusters as u32,
+            root_cluster,
+            ..probe
+        };
+        if !vol.valid_cluster(root_cluster) {
+            return Err(Errno::EINVAL)
+        }
+
+        // The free count of the FSInfo sector would go stale,
+        // mark it unknown so that others count again.
+        if fs_info > 0 && fs_info < reserved {
+            let pos = fs_info * sector_size;
+            if vol.read_u32(pos) == 0x4161_5252 && vol.read_u32(pos + 484) == 0x6141_7272 {
+                vol.write_u32(pos + 488, 0xffff_ffff);
+            }
+        }
+        println!("fat32: dev {}: {} clusters of {} bytes", dev, vol.clusters, vol.cluster_size);
+        Ok(Self { vol: Arc::new(vol) })
+    }
+}
+
+impl FileSystem for Fat32Fs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn dev(&self) -> u32 {
+        self.vol.dev
+    }
+
+    fn root(&self) -> Arc<dyn VNode> {
+        let file = Arc::new(FatFile::new(self.vol.clone(), 0));
+        Arc::new(FatNode { vol: self.vol.clone(), file, parent: None })
+    }
+}
+
+/// A file or directory with nodes, shared by them.
+struct FatFile {
+    vol: Arc<Volume>,
+    /// position of its entry, 0 for the root
+    pos: u64,
+    /// by its position, one of its own once it is unlinked, as
+    /// another file may get an entry at the position
+    inum: AtomicU32,
+    /// its entry as of when it was unlinked, the one to go on with
+    orphan: Spinlock<Option<ShortEntry>>
+}
+
+impl FatFile {
+    fn new(vol: Arc<Volume>, pos: u64) -> Self {
+        let inum = match pos {
+            0 => ROOT_INUM,
+            pos => (pos / SLOT_SIZE as u64) as u32
+        };
+        Self { vol, pos, inum: AtomicU32::new(inum), orphan: Spinlock::new(None, "fat file") }
+    }
+
+    fn orphan(&self) -> Option<ShortEntry> {
+        *self.orphan.acquire()
+    }
+}
+
+impl Drop for FatFile {
+    /// The clusters of an unlinked file are freed with its last node.
+    /// Takes the volume lock, so a file must not be dropped under it.
+    fn drop(&mut self) {
+        let mut state = self.vol.state.lock();
+        state.files.retain(|(_, file)| file.strong_count() > 0);
+        let orphan = self.orphan.acquire().take();
+        if let Some(entry) = orphan {
+            self.vol.free_chain(entry.cluster());
+        }
+        drop(state);
+    }
+}
+
+/// A file or directory of a FAT32 volume.
+#[derive(Clone)]
+struct FatNode {
+    vol: Arc<Volume>,
+    file: Arc<FatFile>,
+    /// the directory holding it, for ".."; None for the root
+    parent: Option<Arc<FatNode>>
+}
+
+impl FatNode {
+    /// Its entry, ENOENT if it is gone from the disk.
+    /// The root gets one made up, an unlinked file its copy.
+    fn entry(&self) -> Result<ShortEntry, Errno> {
+        if let Some(entry) = self.file.orphan() {
+            return Ok(entry)
+        }
+        if self.file.pos == 0 {
+            let mut root = ShortEntry {
+                name: [b' '; 11],
+                attr: ATTR_DIRECTORY,
+                ntres: 0,
+                crt_time_tenth: 0,
+                crt_time: 0,
+                crt_date: 0,
+                acc_date: 0,
+                cluster_hi: 0,
+                wrt_time: 0,
+                wrt_date: 0,
+                cluster_lo: 0,
+                size: 0
+            };
+            root.set_cluster(self.vol.root_cluster);
+            return Ok(root)
+        }
+        let entry = self.vol.read_entry(self.file.pos);
+        if entry.name[0] == 0 || entry.name[0] == SLOT_FREE {
+            return Err(Errno::ENOENT)
+        }
+        Ok(entry)
+    }
+
+    /// Write back its entry, into the copy once it is unlinked.
+    fn put_entry(&self, entry: &ShortEntry) {
+        let mut orphan = self.file.orphan.acquire();
+        if orphan.is_some() {
+            *orphan = Some(*entry);
+            return
+        }
+        drop(orphan);
+        self.vol.write_entry(self.file.pos, entry);
+    }
+
+    fn inum(&self) -> u32 {
+        self.file.inum.load(Ordering::Relaxed)
+    }
+
+    /// First cluster of a directory, the entry of this node.
+    /// Nothing goes into an unlinked directory.
+    fn dir_cluster(&self) -> Result<u32, Errno> {
+        if self.file.orphan().is_some() {
+            return Err(Errno::ENOENT)
+        }
+        let entry = self.entry()?;
+        if !entry.is_dir() {
+            return Err(Errno::ENOTDIR)
+        }
+        Ok(entry.cluster())
+    }
+
+    /// A node for the entry at pos in this directory,
+    /// sharing the file of the nodes it already has.
+    fn child(&self, state: &mut VolumeState, pos: u64) -> Arc<FatNode> {
+        // one going away is left for its drop to remove
+        let file = state.files.iter()
+            .filter(|(file_pos, _)| *file_pos == pos)
+            .find_map(|(_, file)| file.upgrade());
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let file = Arc::new(FatFile::new(self.vol.clone(), pos));
+                state.files.push((pos, Arc::downgrade(&file)));
+                file
+            }
+        };
+        Arc::new(FatNode { vol: self.vol.clone(), file, parent: Some(Arc::new(self.clone())) })
+    }
+
+    /// Pick a short name for name in the directory starting at cluster,
+    /// with a ~n tail unless name maps to one of its own.
+    fn short_name(&self, cluster: u32, name: &[u8]) -> Result<[u8; 11], Errno> {
+        let (basis, lossy) = short_basis(name);
+        if !lossy && basis[0] != b' ' && !self.vol.short_name_used(cluster, &basis) {
+            return Ok(basis)
+        }
+        let base_len = basis[..8].iter().position(|&c| c == b' ').unwrap_or(8);
+        let mut digits = [0u8; 10];
+        for n in 1..1_000_000 {
+            let len = write_decimal(n, &mut digits);
+            let keep = core::cmp::min(base_len, 8 - 1 - len);
+            let mut short = basis;
+            if keep == 0 {
+                short[0] = b'_';
+            }
+            let at = core::cmp::max(keep, 1);
+            short[at] = b'~';
+            short[at + 1..at + 1 + len].copy_from_slice(&digits[..len]);
+            for c in short[at + 1 + len..8].iter_mut() {
+                *c = b' ';
+            }
+            if !self.vol.short_name_used(cluster, &short) {
+                return Ok(short)
+            }
+        }
+        Err(Errno::EEXIST)
+    }
+
+    /// Enter name into this directory, with attr and first cluster.
+    /// Return the position of its short entry.
+    fn add_entry(&self, state: &mut VolumeState, dir_cluster: u32, name: &[u8], attr: u8, cluster: u32) -> Result<u64, Errno> {
+        let long = encode_long(name)?;
+        // a valid 8.3 name needs no long name, unless its
+        // short name is taken by the alias of another one
+        let basis = short_basis(name).0;
+        let plain = fits_short(name) && !self.vol.short_name_used(dir_cluster, &basis);
+        let short_name = if plain { basis } else { self.short_name(dir_cluster, name)? };
+        let nlfn = if plain { 0 } else { (long.len() + LFN_CHARS - 1) / LFN_CHARS };
+        let slots = self.vol.free_slots(state, dir_cluster, nlfn + 1)?;
+
+        // long name slots go before the short entry, last part first
+        let checksum = lfn_checksum(&short_name);
+        for (i, &pos) in slots[..nlfn].iter().enumerate() {
+            let ord = nlfn - i;
+            let mut slot = [0u8; SLOT_SIZE];
+            slot[0] = ord as u8 | if i == 0 { LFN_LAST } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (j, &off) in LFN_OFFSETS.iter().enumerate() {
+                let k = (ord - 1) * LFN_CHARS + j;
+                // a 0 ends the name, the rest is filled with 0xffff
+                let c = if k < long.len() { long[k] } else if k == long.len() { 0 } else { 0xffff };
+                slot[off..off + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            self.vol.write_slot(pos, &slot);
+        }
+        let now = rtc::now();
+        let (date, time) = fat_time(now);
+        let mut entry = ShortEntry {
+            name: short_name,
+            attr,
+            ntres: 0,
+            crt_time_tenth: 0,
+            crt_time: time,
+            crt_date: date,
+            acc_date: date,
+            cluster_hi: 0,
+            wrt_time: time,
+            wrt_date: date,
+            cluster_lo: 0,
+            size: 0
+        };
+        entry.set_cluster(cluster);
+        let pos = slots[nlfn];
+        self.vol.write_entry(pos, &entry);
+        Ok(pos)
+    }
+
+    /// Make the directory entries "." and ".." in cluster.
+    fn make_dots(&self, cluster: u32, parent_cluster: u32) {
+        let base = self.vol.cluster_pos(cluster);
+        let now = rtc::now();
+        for (i, &(name, target)) in [(&b".          "[..], cluster), (&b"..         "[..], parent_cluster)].iter().enumerate() {
+            let mut entry = ShortEntry {
+                name: [0; 11],
+                attr: ATTR_DIRECTORY,
+                ntres: 0,
+                crt_time_tenth: 0,
+                crt_time: 0,
+                crt_date: 0,
+                acc_date: 0,
+                cluster_hi: 0,
+                wrt_time: 0,
+                wrt_date: 0,
+                cluster_lo: 0,
+                size: 0
+            };
+            entry.name.copy_from_slice(name);
+            entry.set_cluster(target);
+            entry.touch(now);
+            self.vol.write_entry(base + (i * SLOT_SIZE) as u64, &entry);
+        }
+    }
+}
+
+impl VNode for FatNode {
+    fn id(&self) -> (u32, u32) {
+        (self.vol.dev, self.inum())
+    }
+
+    fn itype(&self) -> InodeType {
+        let _state = self.vol.state.lock();
+        match self.entry() {
+            Ok(entry) if entry.is_dir() => InodeType::Directory,
+            Ok(_) => InodeType::File,
+            Err(_) => InodeType::Empty
+        }
+    }
+
+    fn stat(&self, stat: &mut Stat) {
+        let _state = self.vol.state.lock();
+        stat.dev = self.vol.dev;
+        stat.inum = self.inum();
+        let entry = match self.entry() {
+            Ok(entry) => entry,
+            Err(_) => return
+        };
+        stat.itype = if entry.is_dir() { InodeType::Directory } else { InodeType::File };
+        stat.nlink = if self.file.orphan().is_some() { 0 } else { 1 };
+        stat.mode = match (entry.is_dir(), entry.attr & ATTR_READ_ONLY != 0) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644
+        };
+        stat.uid = 0;
+        stat.gid = 0;
+        stat.size = entry.size as usize;
+        stat.atime = unix_time(entry.acc_date, 0);
+        stat.mtime = unix_time(entry.wrt_date, entry.wrt_time);
+        stat.ctime = stat.mtime;
+    }
+
+    fn read(&self, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        let state = self.vol.state.lock();
+        let entry = self.entry().map_err(|_| "fat read: file is gone")?;
+        if entry.is_dir() {
+            drop(state);
+            return read_dir_records(self, is_user, dst, offset, len)
+        }
+        let size = entry.size as usize;
+        if offset >= size {
+            return Ok(0)
+        }
+        let count = min(len, size - offset);
+        let mut cluster = self.vol.seek(entry.cluster(), offset)?;
+        let mut done = 0;
+        while done < count {
+            let at = offset + done;
+            let off = at % self.vol.cluster_size;
+            let n = min(count - done, self.vol.cluster_size - off);
+            self.vol.io(self.vol.cluster_pos(cluster) + off as u64, is_user, dst + done, n, false)?;
+            done += n;
+            if done < count {
+                cluster = self.vol.next_cluster(cluster).ok_or("fat read: chain shorter than the file")?;
+            }
+        }
+        drop(state);
+        Ok(done)
+    }
+
+    fn write(&self, is_user: bool, src: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        let mut state = self.vol.state.lock();
+        let mut entry = self.entry().map_err(|_| "fat write: file is gone")?;
+        if entry.is_dir() {
+            return Err("fat write: is a directory")
+        }
+        let size = entry.size as usize;
+        if offset > size {
+            return Err("fat write: offset past the end")
+        }
+        if offset + len > u32::MAX as usize {
+            return Err("fat write: file too large")
+        }
+        if len == 0 {
+            return Ok(0)
+        }
+        if entry.cluster() == 0 {
+            let first = self.vol.alloc_cluster(&mut state, false).map_err(|_| "fat write: disk full")?;
+            entry.set_cluster(first);
+        }
+        // the cluster holding the byte before the one written next, a
+        // write right at the end of the last cluster has to add one
+        let mut cluster = match offset {
+            0 => entry.cluster(),
+            _ => self.vol.seek(entry.cluster(), offset - 1)?
+        };
+        let mut done = 0;
+        let mut res = Ok(());
+        while done < len {
+            let at = offset + done;
+            let off = at % self.vol.cluster_size;
+            if off == 0 && at > 0 {
+                // move on to the next cluster, adding one at the end of the chain
+                cluster = match self.vol.next_cluster(cluster) {
+                    Some(next) => next,
+                    None => match self.vol.alloc_cluster(&mut state, false) {
+                        Ok(next) => {
+                            self.vol.fat_set(cluster, next);
+                            next
+                        },
+                        Err(_) => {
+                            res = Err("fat write: disk full");
+                            break;
+                        }
+                    }
+                };
+            }
+            let n = min(len - done, self.vol.cluster_size - off);
+            if let Err(err) = self.vol.io(self.vol.cluster_pos(cluster) + off as u64, is_user, src + done, n, true) {
+                res = Err(err);
+                break;
+            }
+            done += n;
+        }
+        entry.size = core::cmp::max(size, offset + done) as u32;
+        entry.touch(rtc::now());
+        entry.attr |= ATTR_ARCHIVE;
+        self.put_entry(&entry);
+        drop(state);
+        if done == 0 {
+            res?;
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self) -> Result<(), Errno> {
+        let _state = self.vol.state.lock();
+        let mut entry = self.entry()?;
+        if entry.is_dir() {
+            return Ok(())
+        }
+        self.vol.free_chain(entry.cluster());
+        entry.set_cluster(0);
+        entry.size = 0;
+        entry.touch(rtc::now());
+        self.put_entry(&entry);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<dyn VNode>, Errno> {
+        let name = &name[..name_len(name)];
+        let mut state = self.vol.state.lock();
+        let cluster = self.dir_cluster()?;
+        match name {
+            b"." => Ok(Arc::new(self.clone())),
+            b".." => Ok(match &self.parent {
+                Some(parent) => parent.clone(),
+                None => Arc::new(self.clone())
+            }),
+            _ => {
+                let found = self.vol.find(cluster, name).ok_or(Errno::ENOENT)?;
+                Ok(self.child(&mut state, found.pos))
+            }
+        }
+    }
+
+    fn create(&self, name: &[u8], itype: InodeType, _major: i16, _minor: i16) -> Result<Arc<dyn VNode>, Errno> {
+        let name = &name[..name_len(name)];
+        let mut state = self.vol.state.lock();
+        let dir_cluster = self.dir_cluster()?;
+        if self.vol.find(dir_cluster, name).is_some() {
+            return Err(Errno::EEXIST)
+        }
+        let pos = match itype {
+            InodeType::File => self.add_entry(&mut state, dir_cluster, name, ATTR_ARCHIVE, 0)?,
+            InodeType::Directory => {
+                let cluster = self.vol.alloc_cluster(&mut state, true)?;
+                // ".." of a directory in the root points at cluster 0
+                let parent_cluster = if self.file.pos == 0 { 0 } else { dir_cluster };
+                self.make_dots(cluster, parent_cluster);
+                match self.add_entry(&mut state, dir_cluster, name, ATTR_DIRECTORY, cluster) {
+                    Ok(pos) => pos,
+                    Err(err) => {
+                        self.vol.free_chain(cluster);
+                        return Err(err)
+                    }
+                }
+            },
+            // FAT has nowhere to keep device numbers or link targets
+            _ => return Err(Errno::EPERM)
+        };
+        let node = self.child(&mut state, pos);
+        drop(state);
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &[u8]) -> Result<(), Errno> {
+        let name = &name[..name_len(name)];
+        let mut state = self.vol.state.lock();
+        let dir_cluster = self.dir_cluster()?;
+        let found = self.vol.find(dir_cluster, name).ok_or(Errno::ENOENT)?;
+        if found.short.is_dir() && !self.vol.is_empty_dir(found.short.cluster()) {
+            return Err(Errno::ENOTEMPTY)
+        }
+        for &pos in found.lfn[..found.nlfn].iter().chain(core::iter::once(&found.pos)) {
+            let mut slot = self.vol.read_slot(pos);
+            slot[0] = SLOT_FREE;
+            self.vol.write_slot(pos, &slot);
+        }
+        // a file with nodes is no longer found by its position
+        // and keeps its clusters until they are gone
+        let mut file = None;
+        state.files.retain(|(pos, weak)| {
+            if *pos != found.pos {
+                return true
+            }
+            if file.is_none() {
+                file = weak.upgrade();
+            }
+            false
+        });
+        match &file {
+            Some(file) => {
+                file.inum.store(state.next_orphan, Ordering::Relaxed);
+                state.next_orphan -= 1;
+                *file.orphan.acquire() = Some(found.short);
+            }
+            None => self.vol.free_chain(found.short.cluster())
+        }
+        // the last node may go with file, not under the lock
+        drop(state);
+        drop(file);
+        Ok(())
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(u32, InodeType, &[u8]) -> bool) -> Result<(), Errno> {
+        let _state = self.vol.state.lock();
+        let cluster = self.dir_cluster()?;
+        let parent = self.parent.as_ref().map_or(ROOT_INUM, |parent| parent.inum());
+        if !f(self.inum(), InodeType::Directory, b".") || !f(parent, InodeType::Directory, b"..") {
+            return Ok(())
+        }
+        self.vol.scan(cluster, &mut |found| {
+            if found.short.is_dot() {
+                return true
+            }
+            let itype = if found.short.is_dir() { InodeType::Directory } else { InodeType::File };
+            f((found.pos / SLOT_SIZE as u64) as u32, itype, found.name())
+        });
+        Ok(())
+    }
+
+    fn set_attr(&self, attr: &Attr) -> Result<(), Errno> {
+        // there are no owners on FAT
+        if attr.uid.is_some() || attr.gid.is_some() {
+            return Err(Errno::EPERM)
+        }
+        let _state = self.vol.state.lock();
+        if self.file.pos == 0 {
+            return Ok(())
+        }
+        let mut entry = self.entry()?;
+        if let Some(mode) = attr.mode {
+            // only read-only is kept
+            if mode & 0o222 == 0 {
+                entry.attr |= ATTR_READ_ONLY;
+            } else {
+                entry.attr &= !ATTR_READ_ONLY;
+            }
+        }
+        if let Some(atime) = attr.atime {
+            entry.acc_date = fat_time(atime).0;
+        }
+        if let Some(mtime) = attr.mtime {
+            let (date, time) = fat_time(mtime);
+            entry.wrt_date = date;
+            entry.wrt_time = time;
+        }
+        self.put_entry(&entry);
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! How FAT32 keeps names and times on disk: short 8.3 names, the
+//! UTF-16 long names of VFAT and the 2 second time stamps. Kept apart
+//! from the driver as it needs nothing of the kernel, and is tested on
+//! the host, see scripts/fat_format_test.rs.
+
+use alloc::vec::Vec;
+use core::char::decode_utf16;
+
+use crate::arch::riscv::qemu::fs::{ DIRSIZ, NAME_MAX };
+use crate::syscall::Errno;
+
+/// Checksum of a short name kept in the long name slots before it.
+pub fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// Decode a long name ending at a 0 or at the end, None if
+/// it is no valid UTF-16 or too long.
+pub fn decode_long(long: &[u16], name: &mut [u8; DIRSIZ]) -> Option<usize> {
+    let end = long.iter().position(|&c| c == 0).unwrap_or(long.len());
+    let mut len = 0;
+    for c in decode_utf16(long[..end].iter().cloned()) {
+        let c = c.ok()?;
+        if len + c.len_utf8() > NAME_MAX {
+            return None
+        }
+        c.encode_utf8(&mut name[len..]);
+        len += c.len_utf8();
+    }
+    name[len] = 0;
+    Some(len)
+}
+
+/// Characters which may appear in a short name,
+/// besides upper case letters and digits.
+const SHORT_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+fn is_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_SPECIAL.contains(&c)
+}
+
+/// Make the short name of name, as "BASE    EXT", and tell whether
+/// it is lossy, so that it needs a ~n tail to be told apart.
+pub fn short_basis(name: &[u8]) -> ([u8; 11], bool) {
+    let mut short = [b' '; 11];
+    let mut lossy = false;
+    let name = {
+        // leading dots and spaces are dropped
+        let start = name.iter().position(|&c| c != b'.' && c != b' ').unwrap_or(name.len());
+        lossy |= start > 0;
+        &name[start..]
+    };
+    let dot = name.iter().rposition(|&c| c == b'.');
+    let (base, ext) = match dot {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..])
+    };
+    let mut fill = |part: &[u8], out: &mut [u8]| {
+        let mut len = 0;
+        for &c in part {
+            if c == b' ' || c == b'.' {
+                lossy = true;
+                continue;
+            }
+            if len == out.len() {
+                lossy = true;
+                break;
+            }
+            let upper = c.to_ascii_uppercase();
+            out[len] = if is_short_char(upper) { upper } else { lossy = true; b'_' };
+            len += 1;
+        }
+    };
+    let (short_base, short_ext) = short.split_at_mut(8);
+    fill(base, short_base);
+    fill(ext, short_ext);
+    (short, lossy)
+}
+
+/// Can name be kept as a short name alone, without long name slots?
+pub fn fits_short(name: &[u8]) -> bool {
+    let dot = name.iter().position(|&c| c == b'.');
+    let (base, ext) = match dot {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..])
+    };
+    !base.is_empty() && base.len() <= 8 && ext.len() <= 3 && (dot.is_none() || !ext.is_empty()) &&
+        base.iter().chain(ext.iter()).all(|&c| is_short_char(c))
+}
+
+/// Characters Windows does not allow in names
+const BAD_CHARS: &[u8] = b"\"*/:<>?\\|";
+
+/// Check name and encode it as UTF-16 for the long name slots.
+pub fn encode_long(name: &[u8]) -> Result<Vec<u16>, Errno> {
+    if name.is_empty() || name == b"." || name == b".." {
+        return Err(Errno::EINVAL)
+    }
+    if name.iter().any(|&c| c < 0x20 || BAD_CHARS.contains(&c)) {
+        return Err(Errno::EINVAL)
+    }
+    let name = core::str::from_utf8(name).map_err(|_| Errno::EINVAL)?;
+    let long: Vec<u16> = name.encode_utf16().collect();
+    if long.len() > 255 {
+        return Err(Errno::ENAMETOOLONG)
+    }
+    Ok(long)
+}
+
+/// Write "n" in decimal into out, return its length.
+pub fn write_decimal(mut n: u32, out: &mut [u8; 10]) -> usize {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 { break; }
+    }
+    for i in 0..len {
+        out[i] = digits[len - 1 - i];
+    }
+    len
+}
+
+/// Days since the epoch of a civil date, and back.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+/// FAT date and time of t, seconds since the epoch. FAT counts
+/// from 1980 in 2 second steps and has no time zone, we take UTC.
+pub fn fat_time(t: u64) -> (u16, u16) {
+    let t = core::cmp::max(t as i64, days_from_civil(1980, 1, 1) * 86400);
+    let (y, m, d) = civil_from_days(t / 86400);
+    let secs = t % 86400;
+    let date = ((core::cmp::min(y, 2107) - 1980) << 9 | m << 5 | d) as u16;
+    let time = ((secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60) / 2) as u16;
+    (date, time)
+}
+
+/// Seconds since the epoch of a FAT date and time, 0 if there is no date.
+pub fn unix_time(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0
+    }
+    let y = 1980 + (date >> 9) as i64;
+    let m = core::cmp::max((date >> 5 & 0xf) as i64, 1);
+    let d = core::cmp::max((date & 0x1f) as i64, 1);
+    let secs = (time >> 11) as i64 * 3600 + (time >> 5 & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    (days_from_civil(y, m, d) * 86400 + secs) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(lfn_checksum(b"README  TXT"), 115);
+        assert_ne!(lfn_checksum(b"README  TXT"), lfn_checksum(b"README~1TXT"));
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_basis(b"readme.txt"), (*b"README  TXT", false));
+        assert_eq!(short_basis(b"a long name.text"), (*b"ALONGNAMTEX", true));
+        assert_eq!(short_basis(b".profile"), (*b"PROFILE    ", true));
+        assert_eq!(short_basis(b"a+b.c"), (*b"A_B     C  ", true));
+        assert_eq!(short_basis(b"archive.tar.gz"), (*b"ARCHIVETGZ ", true));
+
+        assert!(fits_short(b"README.TXT"));
+        assert!(fits_short(b"A~1"));
+        assert!(!fits_short(b"readme.txt"));
+        assert!(!fits_short(b"FOO."));
+        assert!(!fits_short(b".FOO"));
+        assert!(!fits_short(b"TOOLONGNAME"));
+        assert!(!fits_short(b"A.B.C"));
+    }
+
+    #[test]
+    fn long_names() {
+        for name in [&b""[..], b".", b"..", b"a/b", b"a:b", b"a\x01b", b"\xff"].iter() {
+            assert_eq!(encode_long(name), Err(Errno::EINVAL));
+        }
+        assert_eq!(encode_long(&[b'a'; 256]), Err(Errno::ENAMETOOLONG));
+
+        let mut name = [0u8; DIRSIZ];
+        for &long in [&b"a rather long file name.text"[..], "h\u{e9}llo \u{1f600}".as_bytes(), &[b'a'; 255]].iter() {
+            let mut utf16 = encode_long(long).unwrap();
+            // slots are padded with a 0 and then 0xffff
+            utf16.push(0);
+            utf16.push(0xffff);
+            let len = decode_long(&utf16, &mut name).unwrap();
+            assert_eq!(&name[..len], long);
+            assert_eq!(name[len], 0);
+        }
+        // a lone surrogate
+        assert_eq!(decode_long(&[0x61, 0xd800, 0x62], &mut name), None);
+        // 128 three byte characters are more than NAME_MAX bytes
+        assert_eq!(decode_long(&[0x20ac; 128], &mut name), None);
+    }
+
+    #[test]
+    fn decimal() {
+        let mut out = [0u8; 10];
+        let len = write_decimal(0, &mut out);
+        assert_eq!(&out[..len], b"0");
+        let len = write_decimal(u32::MAX, &mut out);
+        assert_eq!(&out[..len], b"4294967295");
+    }
+
+    #[test]
+    fn times() {
+        // 2000-01-01 00:00:00
+        assert_eq!(fat_time(946_684_800), (20 << 9 | 1 << 5 | 1, 0));
+        assert_eq!(unix_time(20 << 9 | 1 << 5 | 1, 0), 946_684_800);
+        // 2001-09-09 01:46:40, seconds are kept in 2 second steps
+        for &t in [1_000_000_000u64, 1_000_000_001, 1_700_000_000].iter() {
+            let (date, time) = fat_time(t);
+            assert_eq!(unix_time(date, time), t & !1);
+        }
+        // before 1980 is 1980, no date is the epoch
+        assert_eq!(fat_time(0), (1 << 5 | 1, 0));
+        assert_eq!(unix_time(0, 0x1234), 0);
+        // 2107 is the last year there is
+        assert_eq!(fat_time(u32::MAX as u64 * 2).0 >> 9, 2107 - 1980);
+    }
+}
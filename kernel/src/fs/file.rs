@@ -1,250 +1,147 @@
-use crate::arch::riscv::qemu::fs::{ BSIZE, MAXOPBLOCKS };
+use alloc::sync::Arc;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
 use crate::arch::riscv::qemu::param::NDEV;
-use crate::lock::spinlock::Spinlock;
 use crate::lock::sleeplock::SleepLock;
-use crate::process::CPU_MANAGER;
-use super::pipe::Pipe;
-use super::inode::Inode;
 use super::devices::DEVICE_LIST;
+use super::dinode::InodeType;
+use super::mount::{ MountPin, PinnedNode };
 use super::stat::Stat;
-// use super::{ FILE_TABLE, LOG };
-use super::LOG;
-
-use core::mem::size_of;
-use core::ptr::NonNull;
-
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-#[repr(u16)]
-pub enum FileType {
-    None = 0,
-    Pipe = 1,
-    Inode = 2,
-    Device = 3,
-    Socket = 4,
-}
-
-#[derive(Clone)]
-pub struct Device {
-
-}
-
-#[derive(Clone)]
-pub struct File {
+use super::vfs::{ remove_suid, FileOps, VNode };
 
-}
-
-#[derive(Clone)]
-pub enum FileInner {
-    Device(Device),
-    File(File)
-    // Pipe(Pipe)
-}
-
-/// Virtual File, which can abstract struct to dispatch 
-/// syscall to specific file.
-#[derive(Clone, Debug)]
+/// Virtual File, an open file descriptor. It dispatches
+/// syscalls to the file behind it through its `FileOps`.
 pub struct VFile {
-    pub(crate) ftype: FileType,
     pub(crate) readable: bool,
     pub(crate) writeable: bool,
-    pub(crate) pipe: Option<*mut Pipe>,
-    pub(crate) inode: Option<Inode>,
-    pub(crate) offset: u32,
-    pub(crate) major: i16
-    // inner: FileInner
+    offset: AtomicUsize,
+    /// Held from loading the offset to storing it back for files of
+    /// nodes, so that processes sharing one after fork neither read the
+    /// same bytes nor write over each other. Devices and pipes go
+    /// without, their reads may wait for input for ever.
+    offset_lock: Option<SleepLock<()>>,
+    ops: Arc<dyn FileOps>,
+    /// Keeps the mount of an open node busy, dropped after ops.
+    pin: Option<MountPin>
 }
 
 impl VFile {
-    pub(crate) const fn init() -> Self {
-        Self{
-            ftype: FileType::None,
-            readable: false,
-            writeable: false,
-            pipe: None,
-            inode: None,
-            offset: 0,
-            major: 0
+    pub fn new(ops: Arc<dyn FileOps>, readable: bool, writeable: bool) -> Self {
+        Self {
+            readable,
+            writeable,
+            offset: AtomicUsize::new(0),
+            offset_lock: None,
+            ops,
+            pin: None
         }
     }
 
-    pub fn read(
-        &self, 
-        addr: usize, 
-        len: usize
-    ) -> Result<usize, &'static str> {
-        let ret;
-        if !self.readable() {
-            panic!("File can't be read!")
-        }
+    /// Open node for reading and/or writing. 
+    pub fn open(node: PinnedNode, readable: bool, writeable: bool) -> Self {
+        let (node, pin) = node.into_parts();
+        let (ops, offset_lock): (Arc<dyn FileOps>, _) = match node.itype() {
+            InodeType::Device => (Arc::new(DeviceFile{ major: node.major(), node }), None),
+            _ => (Arc::new(NodeFile{ node }), Some(SleepLock::new((), "file offset")))
+        };
+        Self { pin: Some(pin), offset_lock, ..Self::new(ops, readable, writeable) }
+    }
 
-        match self.ftype {
-            FileType::Pipe => {
-                let pipe = unsafe{ &*self.pipe.unwrap() };
-                ret = pipe.read(addr, len)?;
-                return Ok(ret)
-            },
-
-            FileType::Device => {
-                if self.major < 0 || 
-                self.major as usize >= NDEV || 
-                unsafe{ DEVICE_LIST.table[self.major as usize].read as usize == 0 }{
-                    return Err("[Error] vfs: Fail to read device")
-                }
-                let read = unsafe { 
-                    DEVICE_LIST.table[self.major as usize].read()
-                };               
-                ret = read(true, addr, len).ok_or("Fail to read device")?;
-                return Ok(ret)
-            },
-
-            FileType::Inode => {
-                let inode = self.inode.as_ref().unwrap();
-                let mut inode_guard = inode.lock();
-                match inode_guard.read(true, addr, self.offset, len as u32) {
-                    Ok(size) => {
-                        ret = size;
-                        let offset = unsafe { &mut *(&self.offset as *const _ as *mut u32)};
-                        *offset += ret as u32;
-                        drop(inode_guard);
-                        Ok(ret)
-                    },
-                    Err(err) => {
-                        Err(err)
-                    }
-                }
-            },
-
-            _ => {
-                panic!("Invalid file!")
-            },
+    /// Read from file.
+    /// addr is a user virtual address.
+    pub fn read(&self, addr: usize, len: usize) -> Result<usize, &'static str> {
+        if !self.readable {
+            return Err("File can't be read!")
         }
+        self.at_offset(|offset| self.ops.read(addr, len, offset))
     }
 
-    /// Write to file f. 
+    /// Write to file.
     /// addr is a user virtual address.
-    pub fn write(
-        &self, 
-        addr: usize, 
-        len: usize
-    ) -> Result<usize, &'static str> {
-        let ret; 
-        if !self.writeable() {
-            panic!("file can't be written")
-        }
-        
-        match self.ftype {
-            FileType::Pipe => {
-                let pipe = unsafe{ &*self.pipe.unwrap() };
-                ret = pipe.write(addr, len)?;
-                Ok(ret)
-            },
-
-            FileType::Device => {
-                if self.major < 0 || 
-                self.major as usize >= NDEV || 
-                unsafe{ DEVICE_LIST.table[self.major as usize].write as usize == 0 } {
-                    return Err("Fail to write to device")
-                }
-
-                let write = unsafe{ 
-                    DEVICE_LIST.table[self.major as usize].write()
-                };
-                ret = write(true, addr, len).ok_or("Fail to write device")?;
-                Ok(ret)
-            },
-
-            FileType::Inode => {
-                // write a few blocks at a time to avoid exceeding 
-                // the maxinum log transaction size, including
-                // inode, indirect block, allocation blocks, 
-                // and 2 blocks of slop for non-aligned writes. 
-                // this really belongs lower down, since inode write
-                // might be writing a device like console. 
-                let max = ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE;
-                let mut count  = 0;
-                while count < len {
-                    let mut write_bytes = len - count;
-                    if write_bytes > max { write_bytes = max; }
-
-                    // start log
-                    LOG.begin_op();
-                    let inode = self.inode.as_ref().unwrap();
-                    let mut inode_guard = inode.lock();
-
-                    // return err when failt to write
-                    inode_guard.write(
-                        true, 
-                        addr + count, 
-                        self.offset, 
-                        write_bytes as u32
-                    )?;
-
-                    // release sleeplock
-                    drop(inode_guard);
-                    // end log
-                    LOG.end_op();
-
-                    // update loop data
-                    // self.offset += write_bytes as u32;
-                    let offset = unsafe{ &mut *(&self.offset as *const _ as *mut u32) };
-                    *offset += write_bytes as u32;
-                    count += write_bytes;
-                    
-                }
-                ret = count;
-                Ok(ret)
-            },
-
-            _ => {
-                panic!("Invalid File Type!")
-            }
+    pub fn write(&self, addr: usize, len: usize) -> Result<usize, &'static str> {
+        if !self.writeable {
+            return Err("file can't be written")
         }
+        self.at_offset(|offset| self.ops.write(addr, len, offset))
+    }
 
+    /// Do io at the offset, then move the offset past the bytes it did.
+    fn at_offset<F: FnOnce(usize) -> Result<usize, &'static str>>(&self, io: F) -> Result<usize, &'static str> {
+        let _guard = self.offset_lock.as_ref().map(|lock| lock.lock());
+        let offset = self.offset.load(Ordering::Relaxed);
+        let ret = io(offset)?;
+        self.offset.store(offset + ret, Ordering::Relaxed);
+        Ok(ret)
     }
 
-    fn readable(&self) -> bool {
-        self.readable
+    /// Get metadata about the file.
+    pub fn stat(&self, stat: &mut Stat) -> Result<(), &'static str> {
+        self.ops.stat(stat)
     }
 
-    fn writeable(&self) -> bool {
-        self.writeable
+    /// Where the next read or write goes.
+    pub fn offset(&self) -> usize {
+        self.offset.load(Ordering::Relaxed)
     }
+}
 
-    /// Get metadata about file f. 
-    /// addr is a user virtual address, pointing to a struct stat. 
-    pub fn stat(&self, addr: usize) -> Result<(), &'static str> {
-        let p = unsafe{ CPU_MANAGER.myproc().unwrap() };
-        let mut stat: Stat = Stat::new();
-        match self.ftype {
-            FileType::Device | FileType::Inode => {
-                let inode = self.inode.as_ref().unwrap();
-                
-                #[cfg(feature = "debug")]
-                println!("[Kernel] stat: inode index: {}, dev: {}, inum: {}", inode.index, inode.dev, inode.inum);
-
-                let inode_guard = inode.lock();
-                inode_guard.stat(&mut stat);
-                drop(inode_guard);
-                
-                // println!(
-                //     "[Kernel] stat: dev: {}, inum: {}, nlink: {}, size: {}, type: {:?}", 
-                //     stat.dev, stat.inum, stat.nlink, stat.size, stat.itype
-                // );
-                let pdata = p.data.get_mut();
-                let page_table = pdata.pagetable.as_mut().unwrap();
-                page_table.copy_out(addr, (&stat) as *const Stat as *const u8, size_of::<Stat>())?;
-                Ok(())
-            },  
-
-            _ => {
-                Err("")
-            }
-        }
+/// A regular file or directory, read and written through its node.
+struct NodeFile {
+    node: Arc<dyn VNode>
+}
+
+impl FileOps for NodeFile {
+    fn read(&self, addr: usize, len: usize, offset: usize) -> Result<usize, &'static str> {
+        self.node.read(true, addr, offset, len)
+    }
+
+    fn write(&self, addr: usize, len: usize, offset: usize) -> Result<usize, &'static str> {
+        remove_suid(&*self.node);
+        self.node.write(true, addr, offset, len)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<(), &'static str> {
+        self.node.stat(stat);
+        Ok(())
     }
 }
 
+/// A device node, read and written by the driver of its major number.
+struct DeviceFile {
+    major: i16,
+    node: Arc<dyn VNode>
+}
 
+impl DeviceFile {
+    fn major(&self) -> Option<usize> {
+        if self.major < 0 || self.major as usize >= NDEV {
+            return None
+        }
+        Some(self.major as usize)
+    }
+}
 
+impl FileOps for DeviceFile {
+    fn read(&self, addr: usize, len: usize, offset: usize) -> Result<usize, &'static str> {
+        let major = self.major().ok_or("[Error] vfs: Fail to read device")?;
+        if unsafe{ DEVICE_LIST.table[major].read.is_null() } {
+            return Err("[Error] vfs: Fail to read device")
+        }
+        let read = unsafe{ DEVICE_LIST.table[major].read() };
+        read(true, addr, len, offset).ok_or("Fail to read device")
+    }
 
+    fn write(&self, addr: usize, len: usize, _offset: usize) -> Result<usize, &'static str> {
+        let major = self.major().ok_or("Fail to write to device")?;
+        if unsafe{ DEVICE_LIST.table[major].write.is_null() } {
+            return Err("Fail to write to device")
+        }
+        let write = unsafe{ DEVICE_LIST.table[major].write() };
+        write(true, addr, len).ok_or("Fail to write device")
+    }
 
+    fn stat(&self, stat: &mut Stat) -> Result<(), &'static str> {
+        self.node.stat(stat);
+        Ok(())
+    }
+}
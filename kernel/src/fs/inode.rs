@@ -1,11 +1,11 @@
-use crate::arch::riscv::qemu::fs::{BSIZE, DIRSIZ, IPB, MAXFILE, NDIRECT, NINDIRECT, NINODE, ROOTDEV, ROOTINUM};
-use crate::fs::LOG;
-use crate::fs::bitmap::inode_alloc;
-use crate::lock::sleeplock::{SleepLock, SleepLockGuard};
+use crate::arch::riscv::qemu::fs::{BSIZE, DIRSIZ, NAME_MAX, IPB, MAXFILE, NDIRECT, NINDIRECT, NDINDIRECT, NTINDIRECT, NINODE};
+use crate::fs::log::log_write;
+use crate::lock::rwlock::{ RwSleepLock, RwSleepReadGuard, RwSleepWriteGuard };
 use crate::lock::spinlock::Spinlock;
 use crate::memory::{copy_from_kernel, copy_to_kernel};
 use crate::misc::{ min, mem_set };
-use crate::process::CPU_MANAGER;
+use crate::driver::rtc;
+use crate::syscall::Errno;
 
 use alloc::boxed::Box;
 use alloc::string::String;
@@ -17,26 +17,95 @@ use array_macro::array;
 
 use super::Buf;
 use super::BCACHE;
-use super::SUPER_BLOCK;
+use super::superblock::super_block;
 use super::stat::Stat;
 use super::{ InodeType, DiskInode, DirEntry };
 use super::bitmap::{balloc, bfree};
 
+/// How long an atime stays in memory only, see accessed.
+const RELATIME_SECS: u64 = 24 * 60 * 60;
+
 pub static ICACHE: InodeCache = InodeCache::new();
 
 type BlockNo = u32;
 
 
+/// Permission bits new inodes get, there is no umask. 
+pub(super) fn default_mode(itype: InodeType) -> u16 {
+    match itype {
+        InodeType::Directory => 0o755,
+        InodeType::Symlink => 0o777,
+        InodeType::Device => 0o666,
+        _ => 0o644
+    }
+}
+
+/// Where block bn of a file is found: the slot of dinode.addrs, how many
+/// indirect blocks lie below it, and the entry to take in each of them,
+/// outermost first. None past MAXFILE. 
+fn block_path(bn: usize) -> Option<(usize, usize, [usize; 3])> {
+    if bn < NDIRECT {
+        return Some((bn, 0, [0; 3]))
+    }
+    let bn = bn - NDIRECT;
+    if bn < NINDIRECT {
+        return Some((NDIRECT, 1, [bn, 0, 0]))
+    }
+    let bn = bn - NINDIRECT;
+    if bn < NDINDIRECT {
+        return Some((NDIRECT + 1, 2, [bn / NINDIRECT, bn % NINDIRECT, 0]))
+    }
+    let bn = bn - NDINDIRECT;
+    if bn < NTINDIRECT {
+        return Some((NDIRECT + 2, 3, [bn / NDINDIRECT, bn / NINDIRECT % NINDIRECT, bn % NINDIRECT]))
+    }
+    None
+}
+
+/// Entry index of the indirect block addr, allocating
+/// the block it points to if alloc is set and it is 0. 
+fn indirect_entry(dev: u32, addr: BlockNo, index: usize, alloc: bool) -> BlockNo {
+    let buf = BCACHE.bread(dev, addr);
+    let entries = buf.raw_data() as *mut BlockNo;
+    let mut bn = unsafe{ read(entries.add(index)) };
+    if bn == 0 && alloc {
+        bn = balloc(dev);
+        unsafe{ write(entries.add(index), bn); }
+        log_write(buf);
+    }
+    bn
+}
+
+/// Free the indirect block addr and the blocks below it, 
+/// depth levels down to the data blocks. 
+fn free_indirect(dev: u32, addr: BlockNo, depth: usize) {
+    let buf = BCACHE.bread(dev, addr);
+    let entries = buf.raw_data() as *const BlockNo;
+    for i in 0..NINDIRECT {
+        let bn = unsafe{ read(entries.add(i)) };
+        if bn > 0 {
+            if depth > 1 {
+                free_indirect(dev, bn, depth - 1);
+            } else {
+                bfree(dev, bn);
+            }
+        }
+    }
+    drop(buf);
+    bfree(dev, addr);
+}
+
+
 pub struct InodeCache {
     meta: Spinlock<[InodeMeta; NINODE]>,
-    data: [SleepLock<InodeData>; NINODE]
+    data: [RwSleepLock<InodeData>; NINODE]
 }
 
 impl InodeCache {
     const fn new() -> Self {
         Self {
             meta: Spinlock::new(array![_ => InodeMeta::new(); NINODE], "InodeMeta"),
-            data: array![_ => SleepLock::new(InodeData::new(), "InodeData"); NINODE],
+            data: array![_ => RwSleepLock::new(InodeData::new(), "InodeData"); NINODE],
         }
     }
 
@@ -63,7 +132,7 @@ impl InodeCache {
 
         if imeta.refs == 1 {
             // SAFETY: reference count is 1, so this lock will not block. 
-            let mut idata = self.data[i].lock();
+            let mut idata = self.data[i].write();
             if !idata.valid || idata.dinode.nlink > 0 {
                 idata.valid = false;
                 drop(idata);
@@ -95,14 +164,10 @@ impl InodeCache {
     /// Mark it as allocated by giving it type type. 
     /// Returns an unlocked but allocated and reference inode 
     pub fn alloc(&self, dev: u32, itype: InodeType) -> Option<Inode> {
-        let ninodes = unsafe {
-            SUPER_BLOCK.ninodes()
-        };
+        let ninodes = super_block(dev).ninodes();
         for inum in 1 ..= ninodes {
             // get block id
-            let block_id = unsafe {
-                SUPER_BLOCK.locate_inode(inum)
-            };
+            let block_id = super_block(dev).locate_inode(inum);
             // read block into buffer by device and block_id
             let mut block = BCACHE.bread(dev, block_id);
         
@@ -112,7 +177,7 @@ impl InodeCache {
             let dinode = unsafe{ &mut *dinode };
             // Find a empty inode
             if dinode.try_alloc(itype).is_ok() {
-                LOG.write(block);
+                log_write(block);
                 return Some(self.get(dev, inum))
             }
             // drop(block);
@@ -124,7 +189,7 @@ impl InodeCache {
     /// If found, return an handle. 
     /// If not found, alloc an in-memory location in the cache, 
     /// but not fetch it from the disk yet. 
-    fn get(&self, dev: u32, inum: u32) -> Inode {
+    pub(crate) fn get(&self, dev: u32, inum: u32) -> Inode {
         let mut guard = self.meta.acquire();
 
         // lookup in the cache 
@@ -161,174 +226,6 @@ impl InodeCache {
             index: empty_i
         }
     }
-
-    /// Helper function for 'namei' and 'namei_parent'
-    fn namex(
-        &self, 
-        path: &[u8], 
-        name: &mut [u8;DIRSIZ], 
-        is_parent: bool
-    ) -> Option<Inode> {
-        let mut inode: Inode;
-        if path[0] == b'/' {
-            inode = self.get(ROOTDEV, ROOTINUM);
-        } else {
-            let p = unsafe { CPU_MANAGER.myproc().unwrap() };
-            inode = self.dup(p.data.get_mut().cwd.as_ref().unwrap());
-        }
-        let mut cur: usize = 0;
-        loop {
-            cur = skip_path(path, cur, name);
-            if cur == 0 { break; }
-
-            let mut data_guard = inode.lock();
-            if data_guard.dinode.itype != InodeType::Directory {
-                drop(data_guard);
-                return None
-            }
-            if is_parent && path[cur] == 0 {
-                drop(data_guard);
-                return Some(inode)
-            }
-
-            match data_guard.dir_lookup(name) {
-                None => {
-                    drop(data_guard);
-                    // println!("[Kernel] name: {}", String::from_utf8(name.to_vec()).unwrap());
-                    return None
-                },
-                Some(last_inode) => {
-                    drop(data_guard);
-                    inode = last_inode;
-                }
-            }
-        }
-        if is_parent {
-            // only when querying root inode's parent 
-            println!("[Kernel] Warning: namex querying root inode's parent");
-            None 
-        } else {
-            Some(inode)
-        }
-    }
-
-    /// namei interprets the path argument as an pathname to Unix file. 
-    /// It will return an [`inode`] if succeed, Err(()) if fail. 
-    /// It must be called inside a transaction(i.e.,'begin_op' and `end_op`) since it calls `put`.
-    /// Note: the path should end with 0u8, otherwise it might panic due to out-of-bound. 
-    pub fn namei(&self, path: &[u8]) -> Option<Inode> {
-        let mut name: [u8;DIRSIZ] = [0;DIRSIZ];
-        self.namex(path, &mut name, false)
-    }
-
-    /// Same behavior as `namei`, but return the parent of the inode, 
-    /// and copy the end path into name. 
-    pub fn namei_parent(&self, path: &[u8], name: &mut [u8;DIRSIZ]) -> Option<Inode> {
-        self.namex(path, name, true)
-    }
-
-    pub fn create(
-        &self,
-        path: &[u8],
-        itype: InodeType,
-        major: i16,
-        minor: i16
-    ) -> Result<Inode, &'static str> {
-        // println!("[Kernel] create: path: {}", String::from_utf8(path.to_vec()).unwrap());
-        let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
-        let dirinode = self.namei_parent(path, &mut name).unwrap();
-        let mut dirinode_guard = dirinode.lock();
-        
-        match dirinode_guard.dir_lookup(&name) {
-            Some(inode) => {
-                drop(dirinode_guard);
-                let inode_guard = inode.lock();
-                match inode_guard.dinode.itype {
-                    InodeType::Device | InodeType::File => {
-                        if itype == InodeType::File {
-                            drop(inode_guard);
-                            return Ok(inode)
-                        }
-                        return Err("create: unmatched type.");
-                    },
-    
-                    _ => {
-                        return Err("create: unmatched type.")
-                    }
-                }
-            },
-    
-            None => {}
-        }
-        // Allocate a new inode to create file
-        let dev = dirinode_guard.dev;
-        let inum = inode_alloc(dev, itype);
-        let inode = self.get(dev, inum);
-        
-        let mut inode_guard = inode.lock();
-        // initialize new allocated inode
-        inode_guard.dinode.major = major;
-        inode_guard.dinode.minor = minor;
-        inode_guard.dinode.nlink = 1;
-        // Write back to disk
-        inode_guard.update();
-        debug_assert_eq!(inode_guard.dinode.itype, itype);
-    
-        // Directory, create .. 
-        if itype == InodeType::Directory {
-            // Create . and .. entries. 
-            inode_guard.dinode.nlink += 1;
-            inode_guard.update();
-            // No nlink++ for . to avoid recycle ref count. 
-            inode_guard.dir_link(".".as_bytes(), inode.inum)?;
-            inode_guard.dir_link("..".as_bytes(), dirinode_guard.inum)?;
-        }
-        dirinode_guard
-            .dir_link(&name, inode_guard.inum)
-            .expect("Parent inode fail to link");
-
-        drop(inode_guard);
-        drop(dirinode_guard);
-        Ok(inode)
-    }
-}
-
-/// Skip the path starting at cur by b'/'s. 
-/// It will copy the skipped content to name. 
-/// Return the current offset after skiping. 
-fn skip_path(
-    path: &[u8], 
-    mut cur: usize, 
-    name: &mut [u8; DIRSIZ]
-) -> usize {
-    // skip preceding b'/'
-    while path[cur] == b'/' {
-        cur += 1;
-    }
-    if path[cur] == 0 {
-        return 0
-    }
-
-    let start = cur;
-    while path[cur] != b'/' && path[cur] != 0 {
-        cur += 1;
-    }
-
-    let mut count = cur - start; 
-    if count >= name.len() {
-        debug_assert!(false);
-        count = name.len() - 1;
-    }
-    unsafe{
-        ptr::copy(path.as_ptr().offset(start as isize), name.as_mut_ptr(), count);
-    }
-    name[count] = 0;
-
-    // skip succeeding b'/'
-    while path[cur] == b'/' {
-        cur += 1;
-    }
-    cur
 }
 
 
@@ -379,7 +276,25 @@ impl InodeData {
         stat.inum = self.inum;
         stat.itype = self.dinode.itype;
         stat.nlink = self.dinode.nlink;
+        stat.mode = self.dinode.mode;
+        stat.uid = self.dinode.uid;
+        stat.gid = self.dinode.gid;
         stat.size = self.dinode.size as usize;
+        stat.atime = self.dinode.atime;
+        stat.mtime = self.dinode.mtime;
+        stat.ctime = self.dinode.ctime;
+    }
+
+    /// Record an access to the data, relatime style: return whether
+    /// the new atime is due on disk, i.e. the last one was earlier than
+    /// the last change or is a day old. Otherwise it stays
+    /// in memory until the next update.
+    pub fn accessed(&mut self) -> bool {
+        let now = rtc::now();
+        let atime = self.dinode.atime;
+        self.dinode.atime = now;
+        atime < self.dinode.mtime || atime < self.dinode.ctime
+            || now.saturating_sub(atime) >= RELATIME_SECS
     }
 
     /// Discard the inode data/content. 
@@ -392,81 +307,84 @@ impl InodeData {
             }
         }
 
-        // indirect block
-        if self.dinode.addrs[NDIRECT] > 0 {
-            let buf = BCACHE.bread(inode.dev, self.dinode.addrs[NDIRECT]);
-            let buf_ptr = buf.raw_data() as *const BlockNo;
-            for i in 0..NINDIRECT {
-                let bn = unsafe{ read(buf_ptr.offset(i as isize)) };
-                if bn > 0 {
-                    bfree(inode.dev, bn);
-                }
+        // singly, doubly and triply indirect blocks
+        for depth in 1..=3 {
+            let slot = NDIRECT + depth - 1;
+            if self.dinode.addrs[slot] > 0 {
+                free_indirect(inode.dev, self.dinode.addrs[slot], depth);
+                self.dinode.addrs[slot] = 0;
             }
-            drop(buf);
-            bfree(inode.dev, self.dinode.addrs[NDIRECT]);
-            self.dinode.addrs[NDIRECT] = 0;
         }
 
         self.dinode.size = 0;
+        self.dinode.mtime = rtc::now();
         self.update();
     }
 
     /// Update a modified in-memory inode to disk. 
     /// Typically called after changing the content of inode info. 
+    /// Every update changes the inode, so ctime moves along. 
     pub fn update(&mut self) {
+        self.dinode.ctime = rtc::now();
+        self.write_back();
+    }
+
+    /// Write the in-memory inode to disk as it is, leaving ctime alone
+    /// for an atime. Must be called inside a log op.
+    pub fn write_back(&mut self) {
         let mut buf = BCACHE.bread(
             self.dev, 
-            unsafe { SUPER_BLOCK.locate_inode(self.inum)}
+            super_block(self.dev).locate_inode(self.inum)
         );
         let offset = locate_inode_offset(self.inum) as isize;
         let dinode = unsafe{ (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
         unsafe{ write(dinode, self.dinode) };
         // println!("self.dindoe: {:?}", self.dinode);
-        LOG.write(buf);
+        log_write(buf);
     }
 
     /// The content (data) associated with each inode is stored
     /// in blocks on the disk. The first NDIRECT block numbers
-    /// are listed in self.dinode.addrs, The next NINDIRECT blocks are 
-    /// listed in block self.dinode.addrs[NDIRECT]. 
+    /// are listed in self.dinode.addrs, the next NINDIRECT blocks are 
+    /// listed in block self.dinode.addrs[NDIRECT], the next NDINDIRECT
+    /// blocks below the doubly indirect block self.dinode.addrs[NDIRECT+1]
+    /// and the last NTINDIRECT blocks below the triply indirect block
+    /// self.dinode.addrs[NDIRECT+2]. 
     /// 
     /// Return the disk block address of the nth block in inode. 
     /// If there is no such block, bmap allocates one. 
     pub fn bmap(&mut self, offset_bn: u32) -> Result<u32, &'static str> {
-        let mut addr;
-        let offset_bn = offset_bn as usize;
-        if offset_bn < NDIRECT {
-            if self.dinode.addrs[offset_bn] == 0 {
-                addr = balloc(self.dev);
-                self.dinode.addrs[offset_bn] = addr;
-                return Ok(addr)
-            } else {
-                return Ok(self.dinode.addrs[offset_bn])
-            }
+        let (slot, depth, indexes) = block_path(offset_bn as usize)
+            .ok_or("inode bmap: file too large")?;
+        let mut addr = self.dinode.addrs[slot];
+        if addr == 0 {
+            addr = balloc(self.dev);
+            self.dinode.addrs[slot] = addr;
         }
-        if offset_bn < NINDIRECT + NDIRECT {
-            // Load indirect block, allocating if necessary. 
-            let count = offset_bn - NDIRECT;
-            if self.dinode.addrs[NDIRECT] == 0 {
-                addr = balloc(self.dev);
-                self.dinode.addrs[NDIRECT] = addr;
-            } else {
-                addr = self.dinode.addrs[NDIRECT]
-            }
-            let buf = BCACHE.bread(self.dev, addr);
-            let buf_data = buf.raw_data() as *mut u32;
-            addr = unsafe{ read(buf_data.offset(count as isize)) };
+        // Walk down the indirect blocks, allocating if necessary. 
+        for &index in indexes[..depth].iter() {
+            addr = indirect_entry(self.dev, addr, index, true);
+        }
+        Ok(addr)
+    }
+
+    /// Return the disk block address of the nth block in inode
+    /// without allocating, for readers. Files have no holes,
+    /// so every block below the size is there. 
+    pub fn bmap_lookup(&self, offset_bn: u32) -> Result<u32, &'static str> {
+        let (slot, depth, indexes) = block_path(offset_bn as usize)
+            .ok_or("inode bmap_lookup: out of range")?;
+        let mut addr = self.dinode.addrs[slot];
+        for &index in indexes[..depth].iter() {
             if addr == 0 {
-                unsafe{
-                    addr = balloc(self.dev);
-                    write(buf_data.offset(count as isize), addr);
-                }
-                LOG.write(buf);
+                break;
             }
-            // drop(buf);
-            return Ok(addr)
+            addr = indirect_entry(self.dev, addr, index, false);
+        }
+        if addr == 0 {
+            return Err("inode bmap_lookup: block not allocated")
         }
-        panic!("inode bmap: out of range.");
+        Ok(addr)
     }
 
     /// Read data from inode. 
@@ -475,7 +393,7 @@ impl InodeData {
     /// otherwise, dst is a kernel address. 
     /// is_user 为 true 表示 dst 为用户虚拟地址，否则表示内核虚拟地址
     pub fn read(
-        &mut self, 
+        &self, 
         is_user: bool, 
         mut dst: usize, 
         offset: u32, 
@@ -495,7 +413,7 @@ impl InodeData {
         let mut block_offset = offset % BSIZE;
         while total < count as usize {
             let surplus_len = count - total;
-            let block_no = self.bmap(block_basic as u32)?;
+            let block_no = self.bmap_lookup(block_basic as u32)?;
             let buf = BCACHE.bread(self.dev, block_no);
             let write_len = min(surplus_len, BSIZE - block_offset);
             if copy_from_kernel(
@@ -539,6 +457,10 @@ impl InodeData {
         //     return Err("inode write: end is more than diskinode's size.")
         // }
 
+        if offset as usize + count as usize > MAXFILE * BSIZE {
+            return Err("inode write: file too large")
+        }
+
         let mut offset = offset as usize;
         let count = count as usize;
         let mut total = 0;
@@ -565,7 +487,7 @@ impl InodeData {
             block_basic = offset / BSIZE;
             block_offset = offset % BSIZE;
 
-            LOG.write(buf);
+            log_write(buf);
             // println!("[Kernel] Write Once");
             // println!("[Kernel] total: {}, count: {}", total, count);
         }
@@ -573,6 +495,7 @@ impl InodeData {
         if self.dinode.size < offset as u32 {
             self.dinode.size = offset as u32;
         }
+        self.dinode.mtime = rtc::now();
 
         self.update();
         
@@ -580,99 +503,190 @@ impl InodeData {
         Ok(total)
     }
 
+    /// Copy the target of a symbolic link into dst, followed by a 0. 
+    /// Return the length of the target. 
+    pub fn read_link(&self, dst: &mut [u8]) -> Result<usize, Errno> {
+        if self.dinode.itype != InodeType::Symlink {
+            return Err(Errno::EINVAL)
+        }
+        let len = self.dinode.size as usize;
+        if len >= dst.len() {
+            return Err(Errno::ENAMETOOLONG)
+        }
+        self.read(false, dst.as_mut_ptr() as usize, 0, len as u32).map_err(|_| Errno::EIO)?;
+        dst[len] = 0;
+        Ok(len)
+    }
+
+    /// Read the directory record at offset, and its name into name
+    /// if the record is in use. 
+    fn read_dirent(&self, offset: u32, name: &mut [u8; DIRSIZ]) -> DirEntry {
+        let mut dir_entry = DirEntry::new();
+        self.read(
+            false, 
+            &mut dir_entry as *mut DirEntry as usize, 
+            offset, 
+            size_of::<DirEntry>() as u32
+        ).expect("Cannot read entry in this dir");
+        if (dir_entry.rec_len as usize) < size_of::<DirEntry>() ||
+            offset as usize % BSIZE + dir_entry.rec_len as usize > BSIZE {
+            panic!("dir: corrupted record at offset {} of inode {}", offset, self.inum);
+        }
+        if dir_entry.inum != 0 {
+            self.read(
+                false, 
+                name.as_mut_ptr() as usize, 
+                offset + size_of::<DirEntry>() as u32, 
+                dir_entry.name_len as u32
+            ).expect("Cannot read entry name in this dir");
+        }
+        dir_entry
+    }
+
+    /// Write the record dir_entry with name at offset. 
+    fn write_dirent(&mut self, offset: u32, dir_entry: &DirEntry, name: &[u8]) -> Result<(), &'static str> {
+        self.write(
+            false, 
+            dir_entry as *const DirEntry as usize, 
+            offset, 
+            size_of::<DirEntry>() as u32
+        )?;
+        self.write(
+            false, 
+            name.as_ptr() as usize, 
+            offset + size_of::<DirEntry>() as u32, 
+            name.len() as u32
+        )?;
+        Ok(())
+    }
+
     /// Look for an inode entry in this directory according the name. 
+    /// name ends at its first 0, if any. 
     /// Panics if this is not a directory. 
-    pub fn dir_lookup(&mut self, name: &[u8]) -> Option<Inode> {
-        // assert!(name.len() == DIRSIZ);
+    pub fn dir_lookup(&self, name: &[u8]) -> Option<Inode> {
         if self.dinode.itype != InodeType::Directory {
             panic!("inode type is not directory");
         }
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..self.dinode.size).step_by(de_size) {
-            self.read(
-                false, 
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
-            if dir_entry.inum == 0 {
-                continue;
-            }
-            // println!("dir_entry_name: {}, name: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap());
-            for i in 0..DIRSIZ {
-                if dir_entry.name[i] != name[i] {
-                    break;
-                }
-                if dir_entry.name[i] == 0 {
-                    return Some(ICACHE.get(self.dev, dir_entry.inum as u32))
-                }
+        let name = &name[..name_len(name)];
+        let mut entry_name = [0u8; DIRSIZ];
+        let mut offset = 0;
+        while offset < self.dinode.size {
+            let dir_entry = self.read_dirent(offset, &mut entry_name);
+            if dir_entry.inum != 0 && &entry_name[..dir_entry.name_len as usize] == name {
+                return Some(ICACHE.get(self.dev, dir_entry.inum))
             }
+            offset += dir_entry.rec_len as u32;
         }
         None
     }
 
-    /// Write s new directory entry (name, inum) into the directory
-    pub fn dir_link(&mut self, name: &[u8], inum: u32) -> Result<(), &'static str>{
+    /// Write s new directory entry (name, inum) into the directory, 
+    /// itype is the type of inode inum. 
+    pub fn dir_link(&mut self, name: &[u8], inum: u32, itype: InodeType) -> Result<(), &'static str>{
+        let name = &name[..name_len(name)];
+        if name.len() > NAME_MAX {
+            return Err("dir_link: name too long")
+        }
         if self.dir_lookup(name).is_some() {
             return Err("It's incorrect to find entry in disk")
         }
-        let mut dir_entry = DirEntry::new();
-        // look for an empty dir_entry
-        let mut entry_offset = 0;
-        for offset in (0..self.dinode.size).step_by(size_of::<DirEntry>()) {
-            self.read(
-                false, 
-                (&mut dir_entry) as *mut DirEntry as usize, 
-                offset, 
-                size_of::<DirEntry>() as u32
-            )?;
-            entry_offset += size_of::<DirEntry>() as u32;
-            if dir_entry.inum == 0 {
-                break;
+        let need = DirEntry::rec_size(name.len());
+        let mut new_entry = DirEntry {
+            inum,
+            rec_len: 0,
+            name_len: name.len() as u8,
+            file_type: itype as u8
+        };
+        // look for a free record, or a record with room after its name
+        let mut entry_name = [0u8; DIRSIZ];
+        let mut offset = 0;
+        while offset < self.dinode.size {
+            let mut dir_entry = self.read_dirent(offset, &mut entry_name);
+            let rec_len = dir_entry.rec_len as usize;
+            if dir_entry.inum == 0 && rec_len >= need {
+                // take the free record whole
+                new_entry.rec_len = dir_entry.rec_len;
+                return self.write_dirent(offset, &new_entry, name)
             }
+            let used = DirEntry::rec_size(dir_entry.name_len as usize);
+            if dir_entry.inum != 0 && rec_len >= used + need {
+                // split it, the new record gets the space after the name
+                dir_entry.rec_len = used as u16;
+                self.write_dirent(offset, &dir_entry, &entry_name[..dir_entry.name_len as usize])?;
+                new_entry.rec_len = (rec_len - used) as u16;
+                return self.write_dirent(offset + used as u32, &new_entry, name)
+            }
+            offset += rec_len as u32;
         }
-        unsafe {
-            ptr::copy(name.as_ptr(), dir_entry.name.as_mut_ptr(), name.len());
-        }
-        dir_entry.inum = inum as u16;
-        self.write(
-            false, 
-            (&dir_entry) as *const _ as usize, 
-            entry_offset, 
-            size_of::<DirEntry>() as u32
-        )?;
-        
+        // no room, add a block holding one record
+        new_entry.rec_len = BSIZE as u16;
+        self.write_dirent(offset, &new_entry, name)?;
+        self.dinode.size = offset + BSIZE as u32;
+        self.update();
         Ok(())
     }
 
-    /// Is the directory empty execpt for "." and ".." ?
-    pub fn is_dir_empty(&mut self) -> bool {
-        let mut dir_entry = DirEntry::new();
-        // "." and ".." size
-        let init_size = 2 * size_of::<DirEntry>() as u32;
-        let final_size = self.dinode.size;
-        for offset in (init_size..final_size).step_by(size_of::<DirEntry>()) {
-            // Check each direntry, foreach step by size of DirEntry. 
-            if self.read(
-                false, 
-                &mut dir_entry as *mut DirEntry as usize, 
-                offset, 
-                size_of::<DirEntry>() as u32
-            ).is_err() {
-                panic!("is_dir_empty(): Fail to read dir content");
+    /// Remove the entry called name from the directory, 
+    /// its space goes to the record before it in the block. 
+    pub fn dir_unlink(&mut self, name: &[u8]) -> Result<(), &'static str> {
+        let name = &name[..name_len(name)];
+        let mut entry_name = [0u8; DIRSIZ];
+        let mut prev: Option<(u32, DirEntry)> = None;
+        let mut offset = 0;
+        while offset < self.dinode.size {
+            if offset as usize % BSIZE == 0 {
+                prev = None;
             }
-
-            if dir_entry.inum != 0 {
-                return true
+            let mut dir_entry = self.read_dirent(offset, &mut entry_name);
+            if dir_entry.inum != 0 && &entry_name[..dir_entry.name_len as usize] == name {
+                return match prev {
+                    Some((prev_offset, mut prev_entry)) => {
+                        prev_entry.rec_len += dir_entry.rec_len;
+                        self.write(
+                            false, 
+                            &prev_entry as *const DirEntry as usize, 
+                            prev_offset, 
+                            size_of::<DirEntry>() as u32
+                        ).map(|_| ())
+                    },
+                    None => {
+                        dir_entry.inum = 0;
+                        self.write(
+                            false, 
+                            &dir_entry as *const DirEntry as usize, 
+                            offset, 
+                            size_of::<DirEntry>() as u32
+                        ).map(|_| ())
+                    }
+                }
             }
+            prev = Some((offset, dir_entry));
+            offset += dir_entry.rec_len as u32;
         }
-        false
+        Err("dir_unlink: no such entry")
+    }
 
+    /// Is the directory empty execpt for "." and ".." ?
+    pub fn is_dir_empty(&self) -> bool {
+        let mut entry_name = [0u8; DIRSIZ];
+        let mut offset = 0;
+        while offset < self.dinode.size {
+            let dir_entry = self.read_dirent(offset, &mut entry_name);
+            let name = &entry_name[..dir_entry.name_len as usize];
+            if dir_entry.inum != 0 && name != b"." && name != b".." {
+                return false
+            }
+            offset += dir_entry.rec_len as u32;
+        }
+        true
     }
 }
 
+/// Length of the file name in name, which ends at its first 0 if any. 
+pub fn name_len(name: &[u8]) -> usize {
+    name.iter().position(|&c| c == 0).unwrap_or(name.len())
+}
+
 /// Inode handed out by inode cache. 
 /// It is actually a handle pointing to the cache. 
 #[derive(Debug)]
@@ -691,13 +705,13 @@ impl Clone for Inode {
 impl Inode {
     /// Lock the inode. 
     /// Load it from the disk if its content not cached yet. 
-    pub fn lock<'a>(&'a self) -> SleepLockGuard<'a, InodeData> {
+    pub fn lock<'a>(&'a self) -> RwSleepWriteGuard<'a, InodeData> {
         assert!(self.index < NINODE, "index must less than NINODE");
         // println!("[Kernel] inode.lock(): inode index: {}, dev: {}, inum: {}", self.index, self.dev, self.inum);
-        let mut guard = ICACHE.data[self.index].lock();
+        let mut guard = ICACHE.data[self.index].write();
         
         if !guard.valid {
-            let blockno = unsafe{ SUPER_BLOCK.locate_inode(self.inum) };
+            let blockno = super_block(self.dev).locate_inode(self.inum);
             let buf = BCACHE.bread(self.dev, blockno);
             let offset = locate_inode_offset(self.inum) as isize;
             let dinode = unsafe{ (buf.raw_data() as *const DiskInode).offset(offset) };
@@ -712,6 +726,20 @@ impl Inode {
         }
         guard
     }
+
+    /// Lock the inode for reading only, other readers may hold it too. 
+    pub fn lock_shared<'a>(&'a self) -> RwSleepReadGuard<'a, InodeData> {
+        assert!(self.index < NINODE, "index must less than NINODE");
+        loop {
+            let guard = ICACHE.data[self.index].read();
+            if guard.valid {
+                return guard
+            }
+            drop(guard);
+            // load it from disk with the lock held exclusively. 
+            drop(self.lock());
+        }
+    }
 }
 
 impl Drop for Inode {
@@ -3,12 +3,46 @@
 use core::{ops::{Deref, DerefMut}, panic, ptr};
 use core::mem;
 
+use array_macro::array;
+
 use crate::arch::riscv::qemu::fs::{MAXOPBLOCKS, LOGSIZE, BSIZE};
-use crate::process::{CPU_MANAGER, PROC_MANAGER};
+use crate::arch::riscv::qemu::param::NDISK;
 use crate::lock::spinlock::Spinlock;
-use super::{BCACHE, Buf, SUPER_BLOCK, BufData};
+use crate::lock::waitqueue::Condvar;
+use super::{BCACHE, Buf, BufData};
+use super::superblock::super_block;
+
+/// The log of each disk, an fs op only writes blocks of one disk.
+static LOGS: [Spinlock<Log>; NDISK] = array![_ => Spinlock::new(Log::uninit(), "log"); NDISK];
+/// Signalled when an op ends or a commit is done, so that
+/// begin_op may find room in the log of disk i.
+static LOG_ROOM: [Condvar; NDISK] = array![_ => Condvar::new("log_room"); NDISK];
 
-pub static LOG: Spinlock<Log> = Spinlock::new(Log::uninit(), "log");
+/// The log of device dev.
+pub fn log(dev: u32) -> &'static Spinlock<Log> {
+    &LOGS[dev as usize - 1]
+}
+
+/// Set up the log of device dev from its super block,
+/// recovering the file system if necessary.
+/// SAFETY: no fs op may run on dev meanwhile.
+pub unsafe fn init(dev: u32) {
+    let log_ptr = log(dev).acquire().deref_mut() as *mut Log;
+    log_ptr.as_mut().unwrap().init(dev);
+}
+
+/// Forget the log of device dev, `init` sets it up again.
+/// SAFETY: the file system on dev must be unmounted.
+pub unsafe fn reset(dev: u32) {
+    let mut guard = log(dev).acquire();
+    debug_assert!(guard.outstanding == 0 && !guard.committing);
+    *guard = Log::uninit();
+}
+
+/// Write buf into the log of its device, see `write`.
+pub fn log_write(buf: Buf<'_>) {
+    log(buf.read_dev()).write(buf);
+}
 
 /// Log info about the file system.
 pub struct Log {
@@ -42,7 +76,7 @@ impl Log {
     pub unsafe fn init(&mut self, dev: u32) {
         debug_assert!(mem::size_of::<LogHeader>() < BSIZE);
         debug_assert_eq!(mem::align_of::<BufData>() % mem::align_of::<LogHeader>(), 0);
-        let (start, size) = SUPER_BLOCK.read_log();
+        let (start, size) = super_block(dev).read_log();
         self.start = start;
         self.size = size;
         self.dev = dev;
@@ -165,9 +199,8 @@ impl Spinlock<Log> {
                 1 + guard.lh.len as usize +
                 (guard.outstanding+1) as usize * MAXOPBLOCKS > LOGSIZE
             {
-                let channel = guard.deref() as *const Log as usize;
-                unsafe { CPU_MANAGER.myproc().unwrap().sleep(channel, guard); }
-                guard = self.acquire();
+                let room = &LOG_ROOM[guard.dev as usize - 1];
+                guard = room.wait(guard);
             } else {
                 guard.outstanding += 1;
                 drop(guard);
@@ -222,8 +255,7 @@ impl Spinlock<Log> {
             guard.committing = true;
             log_ptr = guard.deref_mut() as *mut Log;
         } else {
-            let channel = guard.deref() as *const Log as usize;
-            unsafe { PROC_MANAGER.wake_up(channel); }
+            LOG_ROOM[guard.dev as usize - 1].notify_all();
         }
         drop(guard);
 
@@ -233,8 +265,7 @@ impl Spinlock<Log> {
             unsafe { log_ptr.as_mut().unwrap().commit(); }
             let mut guard = self.acquire();
             guard.committing = false;
-            let channel = guard.deref() as *const Log as usize;
-            unsafe { PROC_MANAGER.wake_up(channel); }
+            LOG_ROOM[guard.dev as usize - 1].notify_all();
             drop(guard);
         }
     }
@@ -13,34 +13,80 @@ mod devices;
 // mod file_table;
 mod stat;
 mod bitmap;
+mod vfs;
+mod xv6fs;
+mod fat32;
+mod fat_format;
+mod tmpfs;
+mod procfs;
+mod mount;
+pub mod namei;
 
 pub use bio::Buf;
 pub use bio::BCACHE;
-pub use log::LOG;
-pub use file::{ VFile, FileType };
-pub use inode::{ Inode, InodeData, ICACHE };
+pub use log::log;
+pub use file::VFile;
+pub use vfs::{ FileSystem, VNode, FileOps, Attr, permission, owned, MAY_READ, MAY_WRITE, MAY_EXEC, NO_SPACE };
+pub use xv6fs::{ Xv6Fs, Xv6Node };
+pub use fat32::Fat32Fs;
+pub use tmpfs::TmpFs;
+pub use procfs::ProcFs;
+pub use mount::{ PinnedNode, mount, umount, unlink, is_mount_point };
+pub use inode::{ Inode, InodeData, ICACHE, name_len };
 pub use dinode::{ DiskInode, DirEntry, InodeType };
-pub use superblock::{ SUPER_BLOCK, SuperBlock };
-pub use devices::DEVICE_LIST;
+pub use superblock::{ super_block, SuperBlock };
+pub use devices::{ DEVICE_LIST, read_text };
 pub use pipe::Pipe;
+pub use stat::Stat;
 
 use log::Log;
 use bio::BufData;
 
 
 use crate::arch::riscv::qemu::fs::DIRSIZ;
+use crate::arch::riscv::qemu::devices::LOCKSTAT;
 use crate::lock::sleeplock::SleepLockGuard;
+use crate::syscall::Errno;
 
 /// Init fs.
-/// Read super block info.
-/// Init log info and recover if necessary.
+/// Mount the file system on disk dev as the root,
+/// which reads its super block and recovers its log if necessary,
+/// a tmpfs at /tmp and the proc file system at /proc, and makes
+/// the device files the kernel has but the image may not.
 pub unsafe fn init(dev: u32) {
-    SUPER_BLOCK.init(dev);
-    let log_ptr = LOG.acquire().deref_mut() as *mut Log;
-    log_ptr.as_mut().unwrap().init(dev);
+    if let Err(err) = mount::mount_root(dev) {
+        panic!("file system: cannot mount root: {:?}", err);
+    }
+    mount_at(b"/tmp\0", b"tmpfs");
+    mount_at(b"/proc\0", b"proc");
+    make_dev(b"/dev/lockstat\0", LOCKSTAT);
     println!("file system: setup done");
 }
 
+/// Make the device file at path for major unless it is there,
+/// and /dev if there is none.
+fn make_dev(path: &[u8], major: usize) {
+    let made = match namei::create(b"/dev\0", InodeType::Directory, 0, 0) {
+        Ok(_) | Err(Errno::EEXIST) => namei::create(path, InodeType::Device, major as i16, 0),
+        Err(err) => Err(err)
+    };
+    match made {
+        Ok(_) | Err(Errno::EEXIST) => {},
+        Err(err) => println!("file system: cannot make {}: {:?}", core::str::from_utf8(&path[..name_len(path)]).unwrap_or("?"), err)
+    }
+}
+
+/// Mount a file system without disk at path, making the directory if there is none.
+fn mount_at(path: &[u8], fstype: &[u8]) {
+    let dir = match namei::lookup(path, true) {
+        Err(Errno::ENOENT) => namei::create(path, InodeType::Directory, 0, 0),
+        res => res
+    };
+    if let Err(err) = dir.and_then(|dir| mount::mount(fstype, 0, dir)) {
+        println!("file system: cannot mount {}: {:?}", core::str::from_utf8(&path[..name_len(path)]).unwrap_or("?"), err);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::bio::Bcache;
@@ -0,0 +1,237 @@
+//! Mount table.
+//!
+//! Every mounted file system has a slot in `MOUNTS`, slot 0 holds the
+//! root file system. A mount covers a directory of the file system it
+//! is mounted on, path lookups step from that directory into the root
+//! of the mount, and from the root back out for "..".
+//!
+//! Nodes handed out by lookups are `PinnedNode`s, which count as users
+//! of their mount for as long as they live. Open files, working
+//! directories and the mounts on top of a mount all keep it busy, so
+//! umount fails instead of pulling the file system away under them.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::{ NDISK, NMOUNT };
+use crate::lock::rwlock::RwSpinlock;
+use crate::lock::sleeplock::SleepLock;
+use crate::syscall::Errno;
+
+use super::InodeType;
+use super::namei;
+use super::vfs::{ may_delete, FileSystem, VNode };
+use super::xv6fs::Xv6Fs;
+use super::fat32::Fat32Fs;
+use super::tmpfs::TmpFs;
+use super::procfs::ProcFs;
+
+/// The root goes before the file system when a mount is dropped.
+struct Mount {
+    root: Arc<dyn VNode>,
+    fs: Arc<dyn FileSystem>,
+    root_id: (u32, u32),
+    /// The directory the file system is mounted on, None for the root.
+    covered: Option<PinnedNode>,
+    covered_id: (u32, u32),
+    /// Path of the covered directory when mounting.
+    path: Vec<u8>
+}
+
+static MOUNTS: RwSpinlock<[Option<Mount>; NMOUNT]> = RwSpinlock::new(array![_ => None; NMOUNT], "mounts");
+/// Number of pins on the mount in each slot.
+static USERS: [AtomicUsize; NMOUNT] = array![_ => AtomicUsize::new(0); NMOUNT];
+/// Serializes mount and umount, which sleep while setting up
+/// or tearing down a file system.
+static MOUNT_LOCK: SleepLock<()> = SleepLock::new((), "mount");
+/// Next device number for a file system without a disk,
+/// they come after those of the disks and are never reused.
+static NEXT_ANON_DEV: AtomicU32 = AtomicU32::new(NDISK as u32 + 1);
+
+/// A device number of its own for a file system without a disk.
+pub fn anon_dev() -> u32 {
+    NEXT_ANON_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Keeps the mount in slot busy.
+pub struct MountPin {
+    slot: usize
+}
+
+impl MountPin {
+    fn new(slot: usize) -> Self {
+        USERS[slot].fetch_add(1, Ordering::Relaxed);
+        Self { slot }
+    }
+}
+
+impl Clone for MountPin {
+    fn clone(&self) -> Self {
+        Self::new(self.slot)
+    }
+}
+
+impl Drop for MountPin {
+    fn drop(&mut self) {
+        USERS[self.slot].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// A node together with a pin on the mount it belongs to.
+/// The node goes before the pin when it is dropped.
+#[derive(Clone)]
+pub struct PinnedNode {
+    pub node: Arc<dyn VNode>,
+    pin: MountPin
+}
+
+impl PinnedNode {
+    /// A node of the same mount as self.
+    pub fn sibling(&self, node: Arc<dyn VNode>) -> PinnedNode {
+        PinnedNode { node, pin: self.pin.clone() }
+    }
+
+    pub(super) fn into_parts(self) -> (Arc<dyn VNode>, MountPin) {
+        (self.node, self.pin)
+    }
+}
+
+/// Set up a file system of type fstype on device dev,
+/// which file systems in memory ignore.
+unsafe fn make_fs(fstype: &[u8], dev: u32) -> Result<Arc<dyn FileSystem>, Errno> {
+    match fstype {
+        b"xv6fs" => Ok(Arc::new(Xv6Fs::mount(dev)?)),
+        b"vfat" | b"fat32" => Ok(Arc::new(Fat32Fs::mount(dev)?)),
+        b"tmpfs" => Ok(Arc::new(TmpFs::new(anon_dev())?)),
+        b"proc" | b"procfs" => Ok(Arc::new(ProcFs::new(anon_dev()))),
+        _ => Err(Errno::ENODEV)
+    }
+}
+
+/// Does a file system of type fstype live on the disk it is mounted
+/// from? The others get a device number of their own and ignore dev.
+fn on_disk(fstype: &[u8]) -> bool {
+    matches!(fstype, b"xv6fs" | b"vfat" | b"fat32")
+}
+
+/// Mount the xv6 file system on disk dev as the root.
+/// SAFETY: called once, by the first process.
+pub unsafe fn mount_root(dev: u32) -> Result<(), Errno> {
+    let fs = make_fs(b"xv6fs", dev)?;
+    let root = fs.root();
+    let root_id = root.id();
+    MOUNTS.write()[0] = Some(Mount { fs, root, root_id, covered: None, covered_id: (0, 0), path: b"/".to_vec() });
+    Ok(())
+}
+
+/// The root directory.
+pub fn root() -> PinnedNode {
+    let mounts = MOUNTS.read();
+    let mount = mounts[0].as_ref().expect("mount: no root file system");
+    PinnedNode { node: mount.root.clone(), pin: MountPin::new(0) }
+}
+
+/// The root of the file system mounted on the node with id, if any.
+pub fn mounted_on(id: (u32, u32)) -> Option<PinnedNode> {
+    let mounts = MOUNTS.read();
+    mounts.iter().enumerate().find_map(|(slot, mount)| match mount {
+        Some(mount) if mount.covered.is_some() && mount.covered_id == id =>
+            Some(PinnedNode { node: mount.root.clone(), pin: MountPin::new(slot) }),
+        _ => None
+    })
+}
+
+/// The directory covered by dir, if dir is the root of a mount.
+pub fn covered_by(dir: &PinnedNode) -> Option<PinnedNode> {
+    let mounts = MOUNTS.read();
+    match &mounts[dir.pin.slot] {
+        Some(mount) if mount.root_id == dir.node.id() => mount.covered.clone(),
+        _ => None
+    }
+}
+
+/// Is a file system mounted on the node with id?
+pub fn is_mount_point(id: (u32, u32)) -> bool {
+    let mounts = MOUNTS.read();
+    mounts.iter().flatten().any(|mount| mount.covered.is_some() && mount.covered_id == id)
+}
+
+/// Remove the entry name from dir, EBUSY if a file system is mounted
+/// on it, EPERM if dir is sticky and not ours nor the entry is.
+/// Under the mount lock, so that no mount comes in between.
+pub fn unlink(dir: &PinnedNode, name: &[u8]) -> Result<(), Errno> {
+    let mount_guard = MOUNT_LOCK.lock();
+    let node = dir.node.lookup(name)?;
+    if is_mount_point(node.id()) {
+        return Err(Errno::EBUSY)
+    }
+    may_delete(&*dir.node, &*node)?;
+    drop(node);
+    let ret = dir.node.unlink(name);
+    drop(mount_guard);
+    ret
+}
+
+/// Device, path and type of each mounted file system.
+pub fn mounts() -> Vec<(u32, Vec<u8>, &'static str)> {
+    let mounts = MOUNTS.read();
+    mounts.iter().flatten().map(|mount| (mount.fs.dev(), mount.path.clone(), mount.fs.name())).collect()
+}
+
+/// Mount a file system of type fstype on device dev at
+/// the directory target.
+pub fn mount(fstype: &[u8], dev: u32, target: PinnedNode) -> Result<(), Errno> {
+    let mount_guard = MOUNT_LOCK.lock();
+    if target.node.itype() != InodeType::Directory {
+        return Err(Errno::ENOTDIR)
+    }
+    let target_id = target.node.id();
+    let disk = on_disk(fstype);
+    let mounts = MOUNTS.read();
+    for mount in mounts.iter().flatten() {
+        // target is the root of a mount when it is a mount point
+        if (disk && mount.fs.dev() == dev) || mount.root_id == target_id {
+            return Err(Errno::EBUSY)
+        }
+    }
+    let slot = mounts.iter().position(|mount| mount.is_none()).ok_or(Errno::ENOMEM)?;
+    drop(mounts);
+    let path = namei::path_of(&target)?;
+
+    let fs = unsafe{ make_fs(fstype, dev)? };
+    let root = fs.root();
+    let root_id = root.id();
+    MOUNTS.write()[slot] = Some(Mount { fs, root, root_id, covered: Some(target), covered_id: target_id, path });
+    drop(mount_guard);
+    Ok(())
+}
+
+/// Unmount the file system whose root is target,
+/// EBUSY while anything else still uses it.
+pub fn umount(target: PinnedNode) -> Result<(), Errno> {
+    let mount_guard = MOUNT_LOCK.lock();
+    let PinnedNode { node, pin } = target;
+    let slot = pin.slot;
+    let mut mounts = MOUNTS.write();
+    match &mounts[slot] {
+        Some(mount) if mount.root_id == node.id() => {
+            // ours is the only pin left
+            if mount.covered.is_none() || USERS[slot].load(Ordering::Acquire) > 1 {
+                return Err(Errno::EBUSY)
+            }
+        },
+        _ => return Err(Errno::EINVAL)
+    }
+    let mount = mounts[slot].take();
+    drop(pin);
+    drop(mounts);
+
+    // dropping nodes may sleep, not under the spinlock
+    drop(node);
+    drop(mount);
+    drop(mount_guard);
+    Ok(())
+}
@@ -0,0 +1,246 @@
+//! Path name lookup through the VFS.
+//!
+//! A path is looked up one component at a time from the root or the
+//! working directory of the current process. Lookups cross into a
+//! file system mounted on a directory, and ".." at the root of a mount
+//! goes on from the directory it covers.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, DIRSIZ, NAME_MAX, MAXSYMLINKS };
+use crate::arch::riscv::qemu::param::MAXPATH;
+use crate::process::CPU_MANAGER;
+use crate::syscall::Errno;
+
+use super::{ DirEntry, InodeType };
+use super::inode::name_len;
+use super::stat::Stat;
+use super::mount::{ self, PinnedNode };
+use super::vfs::{ permission, MAY_EXEC, MAY_WRITE };
+
+/// Look up path, following a symbolic link in the
+/// last component only if follow is set.
+/// Note: the path should end with 0u8, otherwise it might panic due to out-of-bound.
+pub fn lookup(path: &[u8], follow: bool) -> Result<PinnedNode, Errno> {
+    let mut name = [0u8; DIRSIZ];
+    walk(start(path), path, &mut name, false, follow, 0)
+}
+
+/// Look up the directory holding the last component
+/// of path, and copy that component into name.
+pub fn lookup_parent(path: &[u8], name: &mut [u8; DIRSIZ]) -> Result<PinnedNode, Errno> {
+    walk(start(path), path, name, true, true, 0)
+}
+
+/// Create path as a node of type itype. An existing regular file or
+/// device is fine when asking for a regular file, and returned as it is.
+pub fn create(path: &[u8], itype: InodeType, major: i16, minor: i16) -> Result<PinnedNode, Errno> {
+    let mut name = [0u8; DIRSIZ];
+    let dir = lookup_parent(path, &mut name)?;
+    loop {
+        match lookup_in(&dir, &name) {
+            Ok(node) => {
+                return match node.node.itype() {
+                    InodeType::File | InodeType::Device if itype == InodeType::File => Ok(node),
+                    _ => Err(Errno::EEXIST)
+                }
+            },
+            Err(Errno::ENOENT) => {},
+            Err(err) => return Err(err)
+        }
+        permission(&*dir.node, MAY_WRITE | MAY_EXEC)?;
+        match dir.node.create(&name, itype, major, minor) {
+            Ok(node) => return Ok(dir.sibling(node)),
+            // somebody else was faster, look again
+            Err(Errno::EEXIST) => continue,
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+/// The path of the directory dir from the root, found by going up
+/// through ".." and looking for each directory in the one above.
+/// ENOENT once a directory on the way has been unlinked.
+pub fn path_of(dir: &PinnedNode) -> Result<Vec<u8>, Errno> {
+    let root_id = mount::root().node.id();
+    let mut names: Vec<Vec<u8>> = Vec::new();
+    let mut dir = dir.clone();
+    loop {
+        // the root of a mount has its name in the file system below
+        while let Some(covered) = mount::covered_by(&dir) {
+            dir = covered;
+        }
+        if dir.node.id() == root_id {
+            break;
+        }
+        if names.len() >= MAXPATH / 2 {
+            return Err(Errno::ENAMETOOLONG)
+        }
+        let parent = lookup_in(&dir, b"..\0")?;
+        names.push(name_in(&parent, dir.node.id().1)?);
+        dir = parent;
+    }
+    let mut path = Vec::new();
+    for name in names.iter().rev() {
+        path.push(b'/');
+        path.extend_from_slice(name);
+    }
+    if path.is_empty() {
+        path.push(b'/');
+    }
+    Ok(path)
+}
+
+/// The name of the entry for inode inum in the directory dir,
+/// read as directory records like any user program would.
+fn name_in(dir: &PinnedNode, inum: u32) -> Result<Vec<u8>, Errno> {
+    let mut stat = Stat::new();
+    dir.node.stat(&mut stat);
+    let mut block = vec![0u8; BSIZE];
+    let mut offset = 0;
+    loop {
+        // xv6 directories have a size, the others are read until
+        // nothing comes
+        let len = match stat.size {
+            0 => BSIZE,
+            size if offset < size => core::cmp::min(BSIZE, size - offset),
+            _ => return Err(Errno::ENOENT)
+        };
+        let count = match dir.node.read(false, block.as_mut_ptr() as usize, offset, len) {
+            Ok(0) | Err(_) => return Err(Errno::ENOENT),
+            Ok(count) => count
+        };
+        let mut off = 0;
+        while off + size_of::<DirEntry>() <= count {
+            let entry = unsafe{ ptr::read_unaligned(block[off..].as_ptr() as *const DirEntry) };
+            if entry.rec_len == 0 {
+                break;
+            }
+            let name_start = off + size_of::<DirEntry>();
+            let name = &block[name_start..core::cmp::min(name_start + entry.name_len as usize, count)];
+            if entry.inum == inum && name != b"." && name != b".." {
+                return Ok(name.to_vec())
+            }
+            off += entry.rec_len as usize;
+        }
+        offset += count;
+    }
+}
+
+/// Look up name in the directory dir, stepping across mounts.
+pub fn lookup_in(dir: &PinnedNode, name: &[u8]) -> Result<PinnedNode, Errno> {
+    let mut dir = dir.clone();
+    if &name[..name_len(name)] == b".." {
+        while let Some(covered) = mount::covered_by(&dir) {
+            dir = covered;
+        }
+    }
+    let mut node = dir.sibling(dir.node.lookup(name)?);
+    while let Some(root) = mount::mounted_on(node.node.id()) {
+        node = root;
+    }
+    Ok(node)
+}
+
+/// Where a lookup of path starts, a None
+/// working directory is the root.
+fn start(path: &[u8]) -> PinnedNode {
+    if path[0] == b'/' {
+        return mount::root()
+    }
+    let p = unsafe { CPU_MANAGER.myproc().unwrap() };
+    match &p.data.get_mut().cwd {
+        Some(cwd) => cwd.clone(),
+        None => mount::root()
+    }
+}
+
+/// Look up path starting from the directory dir, depth is
+/// the number of symbolic links we are in.
+/// Symbolic links are followed, except in the last component
+/// when follow is false.
+fn walk(
+    mut dir: PinnedNode,
+    path: &[u8],
+    name: &mut [u8; DIRSIZ],
+    is_parent: bool,
+    follow: bool,
+    depth: usize
+) -> Result<PinnedNode, Errno> {
+    let mut cur: usize = 0;
+    loop {
+        cur = skip_path(path, cur, name).ok_or(Errno::ENAMETOOLONG)?;
+        if cur == 0 { break; }
+
+        if dir.node.itype() != InodeType::Directory {
+            return Err(Errno::ENOTDIR)
+        }
+        permission(&*dir.node, MAY_EXEC)?;
+        if is_parent && path[cur] == 0 {
+            return Ok(dir)
+        }
+
+        let next = lookup_in(&dir, name)?;
+        if next.node.itype() != InodeType::Symlink || (path[cur] == 0 && !follow) {
+            dir = next;
+            continue;
+        }
+        if depth >= MAXSYMLINKS {
+            return Err(Errno::ELOOP)
+        }
+        // resolve the link from the directory holding it
+        let mut target = [0u8; MAXPATH];
+        next.node.read_link(&mut target)?;
+        drop(next);
+        let start = if target[0] == b'/' { mount::root() } else { dir };
+        let mut link_name = [0u8; DIRSIZ];
+        dir = walk(start, &target, &mut link_name, false, true, depth + 1)?;
+    }
+    if is_parent {
+        // only when querying root inode's parent
+        Err(Errno::ENOENT)
+    } else {
+        Ok(dir)
+    }
+}
+
+/// Skip the path starting at cur by b'/'s.
+/// It will copy the skipped content to name.
+/// Return the current offset after skiping,
+/// None if the name is longer than NAME_MAX.
+fn skip_path(
+    path: &[u8],
+    mut cur: usize,
+    name: &mut [u8; DIRSIZ]
+) -> Option<usize> {
+    // skip preceding b'/'
+    while path[cur] == b'/' {
+        cur += 1;
+    }
+    if path[cur] == 0 {
+        return Some(0)
+    }
+
+    let start = cur;
+    while path[cur] != b'/' && path[cur] != 0 {
+        cur += 1;
+    }
+
+    let count = cur - start;
+    if count > NAME_MAX {
+        return None
+    }
+    unsafe{
+        ptr::copy(path.as_ptr().offset(start as isize), name.as_mut_ptr(), count);
+    }
+    name[count] = 0;
+
+    // skip succeeding b'/'
+    while path[cur] == b'/' {
+        cur += 1;
+    }
+    Some(cur)
+}
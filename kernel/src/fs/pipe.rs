@@ -1,18 +1,18 @@
-use core::ptr::drop_in_place;
-use crate::{lock::spinlock::Spinlock, memory::{ RawPage, PageAllocator }, process::{CPU, CPU_MANAGER, PROC_MANAGER}};
+use alloc::sync::Arc;
+use crate::{lock::{spinlock::Spinlock, waitqueue::Condvar}, process::{CPU, CPU_MANAGER}};
 
-use super::{FileType, VFile};
-
-// use super::File;
+use super::VFile;
+use super::vfs::FileOps;
 
 const PIPE_SIZE: usize = 512;
-#[repr(C)]
 pub struct Pipe {
-    guard: Spinlock<PipeGuard>
+    guard: Spinlock<PipeGuard>,
+    /// signalled when data is written or the write end closes
+    readable: Condvar,
+    /// signalled when data is read or the read end closes
+    writable: Condvar
 }
 
-#[repr(C)]
-#[derive(Clone, Copy)]
 struct PipeGuard {
     data: [u8; PIPE_SIZE],
     /// number of bytes read
@@ -25,24 +25,23 @@ struct PipeGuard {
     write_open: bool
 }
 
+/// One end of a pipe, closing it when the last file using it goes away. 
+struct PipeEnd {
+    pipe: Arc<Pipe>,
+    writeable: bool
+}
+
 impl Pipe {
-    pub fn alloc(rf: &mut &mut VFile, wf: &mut &mut VFile) -> Self {
-        let pipe_guard = unsafe{ *PipeGuard::alloc() }; 
-        let mut pipe = Self {
-            guard: Spinlock::new(pipe_guard, "pipe")
-        };
-        **rf = VFile::init();
-        **wf = VFile::init();
-        rf.ftype = FileType::Pipe;
-        rf.readable = true;
-        rf.writeable = false;
-        rf.pipe = Some(&mut pipe as *mut Pipe);
-        wf.ftype = FileType::Pipe;
-        wf.readable = false;
-        wf.writeable = true;
-        wf.pipe = Some(&mut pipe as *mut Pipe);
-
-        pipe
+    /// Make a pipe, return the files of its read and write end. 
+    pub fn alloc() -> (VFile, VFile) {
+        let pipe = Arc::new(Self {
+            guard: Spinlock::new(PipeGuard::new(), "pipe"),
+            readable: Condvar::new_interruptible("pipe_read"),
+            writable: Condvar::new_interruptible("pipe_write")
+        });
+        let rf = VFile::new(Arc::new(PipeEnd{ pipe: Arc::clone(&pipe), writeable: false }), true, false);
+        let wf = VFile::new(Arc::new(PipeEnd{ pipe, writeable: true }), false, true);
+        (rf, wf)
     }
 
     pub fn read(&self, addr: usize, len: usize) -> Result<usize, &'static str> {
@@ -58,11 +57,7 @@ impl Pipe {
                 return Err("pipe read: current process has been killed")
             }
             // pipe read sleep
-            my_proc.sleep(
-                &pipe_guard.read_number as *const _ as usize, 
-                pipe_guard
-            );
-            pipe_guard = self.guard.acquire();
+            pipe_guard = self.readable.wait(pipe_guard);
         }
 
         let mut i = 0;
@@ -75,10 +70,10 @@ impl Pipe {
             if pgt.copy_out(addr + index, &ch as *const u8, 1).is_err() {
                 break;
             }
-            i = index;
+            i = index + 1;
         }
 
-        unsafe{ PROC_MANAGER.wake_up(&pipe_guard.write_number as *const _ as usize) };
+        self.writable.notify_all();
         drop(pipe_guard);
         Ok(i)
     }
@@ -97,11 +92,8 @@ impl Pipe {
             }
 
             if pipe_guard.write_number == pipe_guard.read_number + PIPE_SIZE {
-                unsafe {
-                    PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
-                }
-                my_proc.sleep(&pipe_guard.write_number as *const _ as usize, pipe_guard);
-                pipe_guard = self.guard.acquire();
+                self.readable.notify_all();
+                pipe_guard = self.writable.wait(pipe_guard);
             } else {
                 let mut char: u8 = 0;
                 let pgt = my_proc.page_table();
@@ -110,13 +102,12 @@ impl Pipe {
                 }
                 let write_cursor = pipe_guard.write_number % PIPE_SIZE;
                 pipe_guard.data[write_cursor % PIPE_SIZE] = char;
+                pipe_guard.write_number += 1;
                 i += 1;
             }
         }
 
-        unsafe {
-            PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
-        }
+        self.readable.notify_all();
         drop(pipe_guard);
 
         Ok(i)
@@ -126,39 +117,39 @@ impl Pipe {
         let mut pipe_guard = self.guard.acquire();
         if writeable {
             pipe_guard.write_open = false;
-            unsafe {
-                PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
-            }
+            self.readable.notify_all();
         } else {
             pipe_guard.read_open = false;
-            unsafe {
-                PROC_MANAGER.wake_up(&pipe_guard.write_number as *const _ as usize);
-            }
-        }
-        
-        if !pipe_guard.read_open && !pipe_guard.write_open {
-            pipe_guard.free();
-            drop(pipe_guard);
-        } else {
-            drop(pipe_guard);
+            self.writable.notify_all();
         }
+        drop(pipe_guard);
     }
 }
 
 impl PipeGuard {
-    fn alloc() -> *mut Self {
-        let pipe = unsafe{ RawPage::new_zeroed() as *mut PipeGuard };
-        let pipe = unsafe{ &mut *pipe };
-        pipe.read_number = 0;
-        pipe.write_number = 0;
-        pipe.read_open = true;
-        pipe.write_open = true;
-        pipe as *mut Self 
+    const fn new() -> Self {
+        Self {
+            data: [0; PIPE_SIZE],
+            read_number: 0,
+            write_number: 0,
+            read_open: true,
+            write_open: true
+        }
     }
+}
 
-    pub fn free(&mut self) {
-        unsafe {
-            drop_in_place(self as *const _ as *mut RawPage)
-        }
+impl FileOps for PipeEnd {
+    fn read(&self, addr: usize, len: usize, _offset: usize) -> Result<usize, &'static str> {
+        self.pipe.read(addr, len)
+    }
+
+    fn write(&self, addr: usize, len: usize, _offset: usize) -> Result<usize, &'static str> {
+        self.pipe.write(addr, len)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        self.pipe.close(self.writeable);
     }
 }
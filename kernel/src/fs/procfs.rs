@@ -0,0 +1,423 @@
+//! Process and kernel state as files, mounted at /proc when booting.
+//!
+//! /proc holds a directory for each process, named by its pid, with
+//!     status   name, state, parent and ids
+//!     cmdline  arguments of its last exec, each ending with 0
+//!     maps     its mapped user pages, by permissions
+//!     fds      its open files
+//!     cwd      a link to its working directory
+//! and the files meminfo, cpuinfo, uptime, mounts and interrupts.
+//! "self" is a link to the directory of the process looking.
+//!
+//! Nodes only know what they stand for, files are made up anew on
+//! every read. Reading a file of a process running on another cpu may
+//! fail with EAGAIN, see `ProcManager::proc_info`.
+
+use alloc::string::{ String, ToString };
+use alloc::sync::Arc;
+use core::fmt::Write;
+use core::str::from_utf8;
+
+use crate::arch::riscv::qemu::layout::{ PGSIZE, trampoline_va, trapframe_va, page_levels };
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::driver::{ plic, rtc };
+use crate::memory::{ KERNEL_HEAP, PteFlags };
+use crate::memory::asid::asid_enabled;
+use crate::process::{ current_cred, CPU_MANAGER, PROC_MANAGER, ProcInfo, ProcState };
+use crate::syscall::Errno;
+use crate::watchdog;
+
+use super::{ InodeType, namei };
+use super::devices::read_text;
+use super::inode::name_len;
+use super::mount;
+use super::stat::Stat;
+use super::vfs::{ read_dir_records, FileSystem, VNode };
+
+/// What a node stands for.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Root,
+    Meminfo,
+    Cpuinfo,
+    Uptime,
+    Mounts,
+    Interrupts,
+    SelfLink,
+    Pid(usize),
+    Status(usize),
+    Cmdline(usize),
+    Maps(usize),
+    Fds(usize),
+    Cwd(usize)
+}
+
+const GLOBAL_FILES: [(&[u8], Kind); 6] = [
+    (b"meminfo", Kind::Meminfo),
+    (b"cpuinfo", Kind::Cpuinfo),
+    (b"uptime", Kind::Uptime),
+    (b"mounts", Kind::Mounts),
+    (b"interrupts", Kind::Interrupts),
+    (b"self", Kind::SelfLink)
+];
+
+const PID_FILES: [(&[u8], fn(usize) -> Kind); 5] = [
+    (b"status", Kind::Status),
+    (b"cmdline", Kind::Cmdline),
+    (b"maps", Kind::Maps),
+    (b"fds", Kind::Fds),
+    (b"cwd", Kind::Cwd)
+];
+
+impl Kind {
+    /// Global nodes have small numbers, those of process
+    /// pid are pid * 8 for its directory and on.
+    fn inum(&self) -> u32 {
+        let inum = match *self {
+            Kind::Root => 1,
+            Kind::Meminfo => 2,
+            Kind::Cpuinfo => 3,
+            Kind::Uptime => 4,
+            Kind::Mounts => 5,
+            Kind::Interrupts => 6,
+            Kind::SelfLink => 7,
+            Kind::Pid(pid) => pid << 3,
+            Kind::Status(pid) => pid << 3 | 1,
+            Kind::Cmdline(pid) => pid << 3 | 2,
+            Kind::Maps(pid) => pid << 3 | 3,
+            Kind::Fds(pid) => pid << 3 | 4,
+            Kind::Cwd(pid) => pid << 3 | 5
+        };
+        inum as u32
+    }
+
+    /// The process the node belongs to.
+    fn pid(&self) -> Option<usize> {
+        match *self {
+            Kind::Pid(pid) | Kind::Status(pid) | Kind::Cmdline(pid) |
+            Kind::Maps(pid) | Kind::Fds(pid) | Kind::Cwd(pid) => Some(pid),
+            _ => None
+        }
+    }
+
+    fn itype(&self) -> InodeType {
+        match *self {
+            Kind::Root | Kind::Pid(_) => InodeType::Directory,
+            Kind::SelfLink | Kind::Cwd(_) => InodeType::Symlink,
+            _ => InodeType::File
+        }
+    }
+
+    /// Files telling about the memory and the files
+    /// of a process are for its owner only.
+    fn mode(&self) -> u16 {
+        match *self {
+            Kind::Root | Kind::Pid(_) => 0o555,
+            Kind::SelfLink | Kind::Cwd(_) => 0o777,
+            Kind::Maps(_) | Kind::Fds(_) => 0o400,
+            _ => 0o444
+        }
+    }
+}
+
+fn proc_info(pid: usize, detail: bool) -> Result<ProcInfo, Errno> {
+    unsafe{ PROC_MANAGER.proc_info(pid, detail) }
+}
+
+/// The pid named by name, which must be written as it is printed.
+fn parse_pid(name: &[u8]) -> Option<usize> {
+    if name.is_empty() || name[0] == b'0' || !name.iter().all(u8::is_ascii_digit) {
+        return None
+    }
+    from_utf8(name).ok()?.parse().ok()
+}
+
+pub struct ProcFs {
+    dev: u32
+}
+
+impl ProcFs {
+    pub const fn new(dev: u32) -> Self {
+        Self { dev }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Arc<dyn VNode> {
+        Arc::new(ProcNode { dev: self.dev, kind: Kind::Root })
+    }
+}
+
+struct ProcNode {
+    dev: u32,
+    kind: Kind
+}
+
+impl ProcNode {
+    fn node(&self, kind: Kind) -> Arc<dyn VNode> {
+        Arc::new(ProcNode { dev: self.dev, kind })
+    }
+
+    /// Make up the content of a file.
+    fn text(&self, text: &mut String) -> Result<(), Errno> {
+        match self.kind {
+            Kind::Meminfo => meminfo(text),
+            Kind::Cpuinfo => cpuinfo(text),
+            Kind::Uptime => {
+                let centisecs = watchdog::centisecs(watchdog::now());
+                let _ = writeln!(text, "{}.{:02}", centisecs / 100, centisecs % 100);
+            },
+            Kind::Mounts => {
+                for (dev, path, fstype) in mount::mounts() {
+                    let _ = writeln!(text, "{} {} {}", dev, String::from_utf8_lossy(&path), fstype);
+                }
+            },
+            Kind::Interrupts => plic::irq_stat(text),
+            Kind::Status(pid) => status(text, &proc_info(pid, false)?),
+            Kind::Cmdline(pid) => {
+                let info = proc_info(pid, true)?;
+                text.push_str(&String::from_utf8_lossy(&info.detail.unwrap().cmdline));
+            },
+            Kind::Maps(pid) => maps(text, &proc_info(pid, true)?),
+            Kind::Fds(pid) => fds(text, &proc_info(pid, true)?),
+            _ => return Err(Errno::EINVAL)
+        }
+        Ok(())
+    }
+}
+
+impl VNode for ProcNode {
+    fn id(&self) -> (u32, u32) {
+        (self.dev, self.kind.inum())
+    }
+
+    fn itype(&self) -> InodeType {
+        self.kind.itype()
+    }
+
+    fn stat(&self, stat: &mut Stat) {
+        stat.dev = self.dev;
+        stat.inum = self.kind.inum();
+        stat.itype = self.kind.itype();
+        stat.nlink = if self.kind.itype() == InodeType::Directory { 2 } else { 1 };
+        stat.mode = self.kind.mode();
+        // the files of a process belong to its owner
+        let cred = self.kind.pid().and_then(|pid| proc_info(pid, false).ok()).map(|info| info.cred);
+        stat.uid = cred.map_or(0, |cred| cred.euid);
+        stat.gid = cred.map_or(0, |cred| cred.egid);
+        stat.size = 0;
+        let now = rtc::now();
+        stat.atime = now;
+        stat.mtime = now;
+        stat.ctime = now;
+    }
+
+    fn read(&self, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        match self.kind.itype() {
+            InodeType::Directory => read_dir_records(self, is_user, dst, offset, len),
+            InodeType::File => {
+                let mut text = String::new();
+                self.text(&mut text).map_err(|err| match err {
+                    Errno::EAGAIN => "procfs: process is running, try again",
+                    _ => "procfs: no such process"
+                })?;
+                read_text(is_user, dst, len, offset, &text).ok_or("procfs: bad address")
+            },
+            _ => Err("procfs: read of a link")
+        }
+    }
+
+    fn write(&self, _is_user: bool, _src: usize, _offset: usize, _len: usize) -> Result<usize, &'static str> {
+        Err("procfs: read only")
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<dyn VNode>, Errno> {
+        let name = &name[..name_len(name)];
+        match self.kind {
+            Kind::Root => match name {
+                b"." | b".." => Ok(self.node(Kind::Root)),
+                _ => {
+                    if let Some(&(_, kind)) = GLOBAL_FILES.iter().find(|(file, _)| *file == name) {
+                        return Ok(self.node(kind))
+                    }
+                    let pid = parse_pid(name).ok_or(Errno::ENOENT)?;
+                    proc_info(pid, false).map_err(|_| Errno::ENOENT)?;
+                    Ok(self.node(Kind::Pid(pid)))
+                }
+            },
+            Kind::Pid(pid) => {
+                proc_info(pid, false).map_err(|_| Errno::ENOENT)?;
+                match name {
+                    b"." => Ok(self.node(self.kind)),
+                    b".." => Ok(self.node(Kind::Root)),
+                    _ => PID_FILES.iter().find(|(file, _)| *file == name)
+                        .map(|&(_, kind)| self.node(kind(pid)))
+                        .ok_or(Errno::ENOENT)
+                }
+            },
+            _ => Err(Errno::ENOTDIR)
+        }
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(u32, InodeType, &[u8]) -> bool) -> Result<(), Errno> {
+        match self.kind {
+            Kind::Root => {
+                let root = Kind::Root.inum();
+                if !f(root, InodeType::Directory, b".") || !f(root, InodeType::Directory, b"..") {
+                    return Ok(())
+                }
+                for &(name, kind) in GLOBAL_FILES.iter() {
+                    if !f(kind.inum(), kind.itype(), name) {
+                        return Ok(())
+                    }
+                }
+                for pid in unsafe{ PROC_MANAGER.pids() } {
+                    if !f(Kind::Pid(pid).inum(), InodeType::Directory, pid.to_string().as_bytes()) {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+            Kind::Pid(pid) => {
+                proc_info(pid, false).map_err(|_| Errno::ENOENT)?;
+                if !f(self.kind.inum(), InodeType::Directory, b".") ||
+                    !f(Kind::Root.inum(), InodeType::Directory, b"..") {
+                    return Ok(())
+                }
+                for &(name, kind) in PID_FILES.iter() {
+                    if !f(kind(pid).inum(), kind(pid).itype(), name) {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+            _ => Err(Errno::ENOTDIR)
+        }
+    }
+
+    fn read_link(&self, dst: &mut [u8]) -> Result<usize, Errno> {
+        let target = match self.kind {
+            Kind::SelfLink => {
+                let pid = unsafe{ CPU_MANAGER.myproc().unwrap().pid() };
+                pid.to_string().into_bytes()
+            },
+            Kind::Cwd(pid) => {
+                let info = proc_info(pid, true)?;
+                let cred = current_cred();
+                if !cred.is_root() && cred.euid != info.cred.euid {
+                    return Err(Errno::EACCES)
+                }
+                // no working directory is the root, unless it exited
+                match info.detail.unwrap().cwd {
+                    Some(cwd) => namei::path_of(&cwd)?,
+                    None if info.state != ProcState::ZOMBIE => b"/".to_vec(),
+                    None => return Err(Errno::ENOENT)
+                }
+            },
+            _ => return Err(Errno::EINVAL)
+        };
+        if target.len() >= dst.len() {
+            return Err(Errno::ENAMETOOLONG)
+        }
+        dst[..target.len()].copy_from_slice(&target);
+        dst[target.len()] = 0;
+        Ok(target.len())
+    }
+}
+
+fn meminfo(text: &mut String) {
+    let stats = KERNEL_HEAP.stats();
+    let _ = writeln!(text, "HeapTotal: {:>10} kB", stats.total / 1024);
+    let _ = writeln!(text, "HeapUsed:  {:>10} kB", stats.in_use / 1024);
+    let _ = writeln!(text, "HeapFree:  {:>10} kB", stats.total.saturating_sub(stats.in_use) / 1024);
+    let _ = writeln!(text, "HeapPeak:  {:>10} kB", stats.peak / 1024);
+    let _ = writeln!(text, "Allocs:    {:>10}", stats.allocs);
+    let _ = writeln!(text, "Frees:     {:>10}", stats.frees);
+    let _ = writeln!(text, "Failed:    {:>10}", stats.failures);
+}
+
+fn cpuinfo(text: &mut String) {
+    let online = plic::online_harts();
+    for hart in (0..NCPU).filter(|hart| online & (1 << hart) != 0) {
+        let _ = writeln!(text, "hart\t: {}", hart);
+        let _ = writeln!(text, "isa\t: rv64imafdc");
+        let _ = writeln!(text, "mmu\t: sv{}", 12 + 9 * page_levels());
+        let _ = writeln!(text, "asid\t: {}", if asid_enabled() { "yes" } else { "no" });
+        text.push('\n');
+    }
+}
+
+fn status(text: &mut String, info: &ProcInfo) {
+    let name = from_utf8(&info.name[..name_len(&info.name)]).unwrap_or("?");
+    let cred = &info.cred;
+    let _ = writeln!(text, "Name:\t{}", name);
+    let _ = writeln!(text, "State:\t{:?}{}", info.state, if info.killed { " (killed)" } else { "" });
+    let _ = writeln!(text, "Pid:\t{}", info.pid);
+    let _ = writeln!(text, "PPid:\t{}", info.ppid);
+    let _ = writeln!(text, "Uid:\t{}\t{}\t{}", cred.uid, cred.euid, cred.suid);
+    let _ = writeln!(text, "Gid:\t{}\t{}\t{}", cred.gid, cred.egid, cred.sgid);
+    let _ = write!(text, "Groups:\t");
+    for gid in cred.groups() {
+        let _ = write!(text, "{} ", gid);
+    }
+    text.push('\n');
+    let _ = writeln!(text, "VmSize:\t{} kB", info.size / 1024);
+}
+
+fn maps(text: &mut String, info: &ProcInfo) {
+    let flag = |flags: PteFlags, bit: PteFlags, c: char| if flags.contains(bit) { c } else { '-' };
+    for &(start, end, flags) in info.detail.as_ref().unwrap().regions.iter() {
+        let _ = writeln!(
+            text, "{:016x}-{:016x} {}{}{}{}",
+            start, end,
+            flag(flags, PteFlags::R, 'r'),
+            flag(flags, PteFlags::W, 'w'),
+            flag(flags, PteFlags::X, 'x'),
+            flag(flags, PteFlags::U, 'u')
+        );
+    }
+    // mapped into every process, for traps
+    let _ = writeln!(text, "{:016x}-{:016x} rw-- [trapframe]", trapframe_va(), trapframe_va() + PGSIZE);
+    let _ = writeln!(text, "{:016x}-{:016x} r-x- [trampoline]", trampoline_va(), trampoline_va() + PGSIZE);
+}
+
+fn fds(text: &mut String, info: &ProcInfo) {
+    let _ = writeln!(text, "{:>3} {:<4} {:>10} {:<9} {:>5} {:>10}", "fd", "mode", "offset", "type", "dev", "inum");
+    for (fd, file) in info.detail.as_ref().unwrap().files.iter() {
+        let mode = match (file.readable, file.writeable) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-"
+        };
+        let _ = write!(text, "{:>3} {:<4} {:>10} ", fd, mode, file.offset());
+        let mut stat = Stat::new();
+        match file.stat(&mut stat) {
+            Ok(()) => {
+                let _ = writeln!(text, "{:<9} {:>5} {:>10}", itype_name(stat.itype), stat.dev, stat.inum);
+            },
+            // only pipes have no node
+            Err(_) => {
+                let _ = writeln!(text, "{:<9} {:>5} {:>10}", "pipe", "-", "-");
+            }
+        }
+    }
+}
+
+fn itype_name(itype: InodeType) -> &'static str {
+    match itype {
+        InodeType::Directory => "directory",
+        InodeType::File => "file",
+        InodeType::Device => "device",
+        InodeType::Symlink => "symlink",
+        InodeType::Empty => "-"
+    }
+}
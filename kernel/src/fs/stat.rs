@@ -7,7 +7,13 @@ pub struct Stat {
     pub inum: u32, // Inode number
     pub itype: InodeType, // Type of file
     pub nlink: i16, // Number of links to link
+    pub mode: u16, // Permission bits
+    pub uid: u32, // Owner
+    pub gid: u32, // Group
     pub size: usize, // Size of file bytes 
+    pub atime: u64, // Last access, seconds since the epoch
+    pub mtime: u64, // Last change of the data
+    pub ctime: u64, // Last change of the inode
 }
 
 impl Stat {
@@ -17,7 +23,13 @@ impl Stat {
             inum: 0,
             itype: InodeType::Empty,
             nlink: 0,
-            size: 0
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0
         }
     }
 }
\ No newline at end of file
@@ -4,10 +4,32 @@ use core::ptr;
 use core::mem::{self, MaybeUninit};
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::arch::riscv::qemu::fs::{ FSMAGIC, IPB, BPB };
+use array_macro::array;
+
+use crate::arch::riscv::qemu::fs::{ FSMAGIC, FEATURES_REQUIRED, IPB, BPB };
+use crate::arch::riscv::qemu::param::NDISK;
 use super::{ BCACHE, BufData };
 
-pub static mut SUPER_BLOCK: SuperBlock = SuperBlock::uninit();
+/// Super block of each disk.
+static mut SUPER_BLOCKS: [SuperBlock; NDISK] = array![_ => SuperBlock::uninit(); NDISK];
+
+/// The super block of device dev, which must have been read by `init`.
+pub fn super_block(dev: u32) -> &'static SuperBlock {
+    unsafe{ &SUPER_BLOCKS[dev as usize - 1] }
+}
+
+/// Read the super block of device dev.
+/// SAFETY: not to be called for a device in use, or twice at once.
+pub unsafe fn init(dev: u32) -> Result<(), &'static str> {
+    SUPER_BLOCKS[dev as usize - 1].init(dev)
+}
+
+/// Forget the super block of device dev, so that the next
+/// `init` reads it from disk again.
+/// SAFETY: the file system on dev must be unmounted.
+pub unsafe fn reset(dev: u32) {
+    SUPER_BLOCKS[dev as usize - 1].initialized.store(false, Ordering::SeqCst);
+}
 
 /// In-memory copy of superblock
 #[derive(Debug)]
@@ -27,11 +49,11 @@ impl SuperBlock {
     }
 
     /// Read and init the super block from disk into memory.
-    /// SAFETY: it should only be called by the first regular process alone.
-    pub unsafe fn init(&mut self, dev: u32) {
+    /// Fail if the disk does not hold a file system we can use.
+    unsafe fn init(&mut self, dev: u32) -> Result<(), &'static str> {
         debug_assert_eq!(mem::align_of::<BufData>() % mem::align_of::<RawSuperBlock>(), 0);
         if self.initialized.load(Ordering::Relaxed) {
-            return
+            return Ok(())
         }
         let buf = BCACHE.bread(dev, 1);
         ptr::copy_nonoverlapping(
@@ -40,14 +62,20 @@ impl SuperBlock {
             1,
         );
         println!("check magic number");
+        drop(buf);
         if self.data.as_ptr().as_ref().unwrap().magic != FSMAGIC {
-            panic!("invalid file system magic num");
+            return Err("invalid file system magic num")
+        }
+        let features = self.data.as_ptr().as_ref().unwrap().features;
+        if features & FEATURES_REQUIRED != FEATURES_REQUIRED {
+            println!("file system image lacks features {:#x}, rebuild it with mkfs", FEATURES_REQUIRED & !features);
+            return Err("file system image lacks required features")
         }
         self.initialized.store(true, Ordering::SeqCst);
-        drop(buf);
 
         #[cfg(feature = "verbose_init_info")]
         println!("super block data: {:?}", self.data.as_ptr().as_ref().unwrap());
+        Ok(())
     }
 
     /// Read the info of super block.
@@ -88,6 +116,11 @@ impl SuperBlock {
         self.read().ninodes
     }
 
+    /// FEATURE_* flags of the image
+    pub fn features(&self) -> u32 {
+        self.read().features
+    }
+
     /// Given an inode number. 
     /// Return the blockno of the block this inode resides. 
     /// Panic if the queryed inode out of range. 
@@ -124,4 +157,5 @@ struct RawSuperBlock {
     logstart: u32,   // Block number of first log block
     inodestart: u32, // Block number of first inode block
     bmapstart: u32,  // Block number of first free map block
+    features: u32,   // FEATURE_* flags, 0 in images from before them
 }
@@ -0,0 +1,477 @@
+//! In-memory file system, for scratch files that need not survive
+//! a reboot. It is mounted at /tmp when booting.
+//!
+//! Nodes live on the kernel heap: a directory holds its entries, open
+//! files and working directories hold the nodes they use, and a node
+//! is freed with its last holder. File contents are kept in pages.
+//! A tmpfs holds at most TMPFS_PAGES pages and TMPFS_NINODE nodes,
+//! beyond that writes and creates fail with no space left.
+
+use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
+use alloc::sync::{ Arc, Weak };
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+
+use crate::arch::riscv::qemu::fs::S_ISVTX;
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::arch::riscv::qemu::param::{ TMPFS_NINODE, TMPFS_PAGES };
+use crate::driver::rtc;
+use crate::lock::sleeplock::{ SleepLock, SleepLockGuard };
+use crate::lock::spinlock::Spinlock;
+use crate::memory::{ copy_from_kernel, copy_to_kernel };
+use crate::misc::min;
+use crate::process::current_cred;
+use crate::syscall::Errno;
+
+use super::InodeType;
+use super::inode::{ default_mode, name_len };
+use super::stat::Stat;
+use super::vfs::{ read_dir_records, Attr, FileSystem, VNode, NO_SPACE };
+
+/// What the nodes of one tmpfs share.
+struct TmpInfo {
+    dev: u32,
+    next_inum: AtomicU32,
+    /// pages held by the files
+    pages: AtomicUsize,
+    /// every live node by inode number, for link
+    nodes: Spinlock<BTreeMap<u32, Weak<TmpNode>>>
+}
+
+impl TmpInfo {
+    /// Take a page for a file, false if the file system is full.
+    fn charge_page(&self) -> bool {
+        self.pages.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            if used < TMPFS_PAGES { Some(used + 1) } else { None }
+        }).is_ok()
+    }
+
+    fn uncharge_pages(&self, count: usize) {
+        self.pages.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+pub struct TmpFs {
+    info: Arc<TmpInfo>,
+    root: Arc<TmpNode>
+}
+
+impl TmpFs {
+    /// An empty tmpfs with device number dev, whose root anybody may
+    /// write, but only remove what they own from.
+    pub fn new(dev: u32) -> Result<Self, Errno> {
+        let info = Arc::new(TmpInfo {
+            dev,
+            next_inum: AtomicU32::new(1),
+            pages: AtomicUsize::new(0),
+            nodes: Spinlock::new(BTreeMap::new(), "tmpfs")
+        });
+        let root = TmpNode::new(&info, InodeType::Directory, 0, 0, None)?;
+        let mut root_guard = root.data.lock();
+        root_guard.mode = S_ISVTX | 0o777;
+        root_guard.nlink = 2;
+        drop(root_guard);
+        Ok(Self { info, root })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn dev(&self) -> u32 {
+        self.info.dev
+    }
+
+    fn root(&self) -> Arc<dyn VNode> {
+        self.root.clone()
+    }
+}
+
+struct NodeData {
+    major: i16,
+    minor: i16,
+    nlink: i16,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    size: usize,
+    /// content of a file, or the target of a symbolic link
+    pages: Vec<Box<[u8]>>,
+    /// entries of a directory, without "." and ".."
+    entries: Vec<(Box<[u8]>, Arc<TmpNode>)>,
+    /// the directory holding a directory, itself for the root
+    parent: Weak<TmpNode>
+}
+
+impl NodeData {
+    /// The entry called name, a directory's.
+    fn entry(&self, name: &[u8]) -> Option<usize> {
+        self.entries.iter().position(|(entry, _)| &**entry == name)
+    }
+
+    /// The data or the attributes changed.
+    fn changed(&mut self) {
+        let now = rtc::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+pub struct TmpNode {
+    info: Arc<TmpInfo>,
+    inum: u32,
+    itype: InodeType,
+    /// the node itself, for "."
+    this: Weak<TmpNode>,
+    data: SleepLock<NodeData>
+}
+
+impl TmpNode {
+    /// Make a node of type itype owned by the current process, in
+    /// the directory parent. ENOSPC past TMPFS_NINODE nodes.
+    fn new(
+        info: &Arc<TmpInfo>,
+        itype: InodeType,
+        major: i16,
+        minor: i16,
+        parent: Option<Weak<TmpNode>>
+    ) -> Result<Arc<Self>, Errno> {
+        let cred = current_cred();
+        let now = rtc::now();
+        let mut nodes = info.nodes.acquire();
+        if nodes.len() >= TMPFS_NINODE {
+            return Err(Errno::ENOSPC)
+        }
+        let inum = info.next_inum.fetch_add(1, Ordering::Relaxed);
+        let node = Arc::new_cyclic(|this: &Weak<TmpNode>| TmpNode {
+            info: info.clone(),
+            inum,
+            itype,
+            this: this.clone(),
+            data: SleepLock::new(NodeData {
+                major,
+                minor,
+                nlink: 1,
+                mode: default_mode(itype),
+                uid: cred.euid,
+                gid: cred.egid,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                size: 0,
+                pages: Vec::new(),
+                entries: Vec::new(),
+                parent: parent.unwrap_or_else(|| this.clone())
+            }, "tmpnode")
+        });
+        nodes.insert(inum, Arc::downgrade(&node));
+        drop(nodes);
+        Ok(node)
+    }
+
+    fn this(&self) -> Arc<TmpNode> {
+        self.this.upgrade().expect("tmpfs: node used while dropped")
+    }
+
+    /// Lock a directory, ENOTDIR if it is none.
+    fn lock_dir(&self) -> Result<SleepLockGuard<NodeData>, Errno> {
+        if self.itype != InodeType::Directory {
+            return Err(Errno::ENOTDIR)
+        }
+        Ok(self.data.lock())
+    }
+
+    /// Add node as name to the locked directory dir.
+    fn add_entry(dir: &mut NodeData, name: &[u8], node: Arc<TmpNode>) {
+        dir.entries.push((name.to_vec().into_boxed_slice(), node));
+        dir.changed();
+    }
+}
+
+/// A zeroed page for a file, with room for it in pages,
+/// None if the kernel heap is out of memory.
+fn new_page(pages: &mut Vec<Box<[u8]>>) -> Option<Box<[u8]>> {
+    let mut page = Vec::new();
+    page.try_reserve_exact(PGSIZE).ok()?;
+    page.resize(PGSIZE, 0u8);
+    pages.try_reserve(1).ok()?;
+    Some(page.into_boxed_slice())
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        self.info.uncharge_pages(self.data.lock().pages.len());
+        self.info.nodes.acquire().remove(&self.inum);
+    }
+}
+
+impl VNode for TmpNode {
+    fn id(&self) -> (u32, u32) {
+        (self.info.dev, self.inum)
+    }
+
+    fn itype(&self) -> InodeType {
+        self.itype
+    }
+
+    fn major(&self) -> i16 {
+        self.data.lock().major
+    }
+
+    fn stat(&self, stat: &mut Stat) {
+        let data = self.data.lock();
+        stat.dev = self.info.dev;
+        stat.inum = self.inum;
+        stat.itype = self.itype;
+        stat.nlink = data.nlink;
+        stat.mode = data.mode;
+        stat.uid = data.uid;
+        stat.gid = data.gid;
+        stat.size = data.size;
+        stat.atime = data.atime;
+        stat.mtime = data.mtime;
+        stat.ctime = data.ctime;
+    }
+
+    fn read(&self, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        if self.itype == InodeType::Directory {
+            return read_dir_records(self, is_user, dst, offset, len)
+        }
+        let mut data = self.data.lock();
+        if offset >= data.size {
+            return Ok(0)
+        }
+        let count = min(len, data.size - offset);
+        let mut done = 0;
+        while done < count {
+            let at = offset + done;
+            let page = &data.pages[at / PGSIZE];
+            let n = min(count - done, PGSIZE - at % PGSIZE);
+            copy_from_kernel(is_user, dst + done, page[at % PGSIZE..].as_ptr(), n)?;
+            done += n;
+        }
+        data.atime = rtc::now();
+        Ok(done)
+    }
+
+    fn write(&self, is_user: bool, src: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        if self.itype == InodeType::Directory {
+            return Err("tmpfs write: is a directory")
+        }
+        let mut data = self.data.lock();
+        if offset > data.size {
+            return Err("tmpfs write: offset past the end")
+        }
+        let mut done = 0;
+        let mut res = Ok(());
+        while done < len {
+            let at = offset + done;
+            let index = at / PGSIZE;
+            if index == data.pages.len() {
+                if !self.info.charge_page() {
+                    res = Err(NO_SPACE);
+                    break;
+                }
+                // the heap is shared with user memory, running
+                // out of it must not panic the kernel
+                match new_page(&mut data.pages) {
+                    Some(page) => data.pages.push(page),
+                    None => {
+                        self.info.uncharge_pages(1);
+                        res = Err(NO_SPACE);
+                        break;
+                    }
+                }
+            }
+            let n = min(len - done, PGSIZE - at % PGSIZE);
+            let page = &mut data.pages[index];
+            if let Err(err) = copy_to_kernel(page[at % PGSIZE..].as_mut_ptr(), is_user, src + done, n) {
+                res = Err(err);
+                break;
+            }
+            done += n;
+        }
+        if offset + done > data.size {
+            data.size = offset + done;
+        }
+        if done > 0 {
+            data.changed();
+        } else {
+            res?;
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self) -> Result<(), Errno> {
+        let mut data = self.data.lock();
+        if self.itype == InodeType::File {
+            self.info.uncharge_pages(data.pages.len());
+            data.pages = Vec::new();
+            data.size = 0;
+            data.changed();
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<dyn VNode>, Errno> {
+        let name = &name[..name_len(name)];
+        let dir = self.lock_dir()?;
+        match name {
+            b"." => Ok(self.this()),
+            // the parent of an unlinked directory may be gone
+            b".." => Ok(dir.parent.upgrade().ok_or(Errno::ENOENT)?),
+            _ => {
+                let index = dir.entry(name).ok_or(Errno::ENOENT)?;
+                Ok(dir.entries[index].1.clone())
+            }
+        }
+    }
+
+    fn create(&self, name: &[u8], itype: InodeType, major: i16, minor: i16) -> Result<Arc<dyn VNode>, Errno> {
+        let name = &name[..name_len(name)];
+        let mut dir = self.lock_dir()?;
+        // an unlinked directory takes no new entries
+        if dir.nlink == 0 {
+            return Err(Errno::ENOENT)
+        }
+        if name == b"." || name == b".." || dir.entry(name).is_some() {
+            return Err(Errno::EEXIST)
+        }
+        let node = TmpNode::new(&self.info, itype, major, minor, Some(self.this.clone()))?;
+        if itype == InodeType::Directory {
+            // its "." and the ".." in it
+            node.data.lock().nlink = 2;
+            dir.nlink += 1;
+        }
+        TmpNode::add_entry(&mut dir, name, node.clone());
+        drop(dir);
+        Ok(node)
+    }
+
+    fn symlink(&self, name: &[u8], target: &[u8]) -> Result<(), Errno> {
+        let name = &name[..name_len(name)];
+        let mut dir = self.lock_dir()?;
+        if dir.nlink == 0 {
+            return Err(Errno::ENOENT)
+        }
+        if name == b"." || name == b".." || dir.entry(name).is_some() {
+            return Err(Errno::EEXIST)
+        }
+        // the target is written before the link shows up
+        let node = TmpNode::new(&self.info, InodeType::Symlink, 0, 0, None)?;
+        node.write(false, target.as_ptr() as usize, 0, name_len(target)).map_err(|_| Errno::ENOSPC)?;
+        TmpNode::add_entry(&mut dir, name, node);
+        Ok(())
+    }
+
+    fn link(&self, name: &[u8], node: &dyn VNode) -> Result<(), Errno> {
+        let (dev, inum) = node.id();
+        if dev != self.info.dev {
+            return Err(Errno::EXDEV)
+        }
+        let name = &name[..name_len(name)];
+        let node = self.info.nodes.acquire().get(&inum).and_then(Weak::upgrade).ok_or(Errno::ENOENT)?;
+        if node.itype == InodeType::Directory {
+            return Err(Errno::EPERM)
+        }
+        let mut node_guard = node.data.lock();
+        // unlinked already
+        if node_guard.nlink == 0 {
+            return Err(Errno::ENOENT)
+        }
+        node_guard.nlink += 1;
+        node_guard.ctime = rtc::now();
+        drop(node_guard);
+
+        let res = match self.lock_dir() {
+            Ok(mut dir) => {
+                if dir.nlink == 0 {
+                    Err(Errno::ENOENT)
+                } else if name == b"." || name == b".." || dir.entry(name).is_some() {
+                    Err(Errno::EEXIST)
+                } else {
+                    TmpNode::add_entry(&mut dir, name, node.clone());
+                    Ok(())
+                }
+            },
+            Err(err) => Err(err)
+        };
+        if res.is_err() {
+            node.data.lock().nlink -= 1;
+        }
+        res
+    }
+
+    fn unlink(&self, name: &[u8]) -> Result<(), Errno> {
+        let name = &name[..name_len(name)];
+        let mut dir = self.lock_dir()?;
+        let index = dir.entry(name).ok_or(Errno::ENOENT)?;
+        let node = dir.entries[index].1.clone();
+        let mut node_guard = node.data.lock();
+        if node.itype == InodeType::Directory {
+            if !node_guard.entries.is_empty() {
+                return Err(Errno::ENOTEMPTY)
+            }
+            // its "." and the ".." in it go as well
+            node_guard.nlink = 0;
+            dir.nlink -= 1;
+        } else {
+            node_guard.nlink -= 1;
+        }
+        node_guard.ctime = rtc::now();
+        drop(node_guard);
+        dir.entries.remove(index);
+        dir.changed();
+        drop(dir);
+        // the node is freed here unless it is still open
+        drop(node);
+        Ok(())
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(u32, InodeType, &[u8]) -> bool) -> Result<(), Errno> {
+        let dir = self.lock_dir()?;
+        let parent = dir.parent.upgrade().map_or(self.inum, |parent| parent.inum);
+        if !f(self.inum, InodeType::Directory, b".") || !f(parent, InodeType::Directory, b"..") {
+            return Ok(())
+        }
+        for (name, node) in dir.entries.iter() {
+            if !f(node.inum, node.itype, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, dst: &mut [u8]) -> Result<usize, Errno> {
+        if self.itype != InodeType::Symlink {
+            return Err(Errno::EINVAL)
+        }
+        let data = self.data.lock();
+        let len = data.size;
+        if len >= dst.len() {
+            return Err(Errno::ENAMETOOLONG)
+        }
+        if len > 0 {
+            dst[..len].copy_from_slice(&data.pages[0][..len]);
+        }
+        dst[len] = 0;
+        Ok(len)
+    }
+
+    fn set_attr(&self, attr: &Attr) -> Result<(), Errno> {
+        let mut data = self.data.lock();
+        if let Some(mode) = attr.mode { data.mode = mode; }
+        if let Some(uid) = attr.uid { data.uid = uid; }
+        if let Some(gid) = attr.gid { data.gid = gid; }
+        if let Some(atime) = attr.atime { data.atime = atime; }
+        if let Some(mtime) = attr.mtime { data.mtime = mtime; }
+        data.ctime = rtc::now();
+        Ok(())
+    }
+}
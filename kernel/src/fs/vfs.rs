@@ -0,0 +1,257 @@
+//! Virtual file system.
+//!
+//! A `FileSystem` hands out `VNode`s, its files, directories and device
+//! nodes. An open file is a `VFile` whose reads and writes go through
+//! `FileOps`, so the syscalls don't need to know what kind of file
+//! they are working on: a node of some file system, a device or a pipe.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, NAME_MAX, S_ISUID, S_ISGID, S_ISVTX };
+use crate::memory::copy_from_kernel;
+use crate::process::current_cred;
+use crate::syscall::Errno;
+
+use super::{ DirEntry, InodeType };
+use super::stat::Stat;
+
+/// Access wanted from `permission`
+pub const MAY_EXEC: u16 = 0o1;
+pub const MAY_WRITE: u16 = 0o2;
+pub const MAY_READ: u16 = 0o4;
+
+/// Error of a write that found no room left, which sys_write
+/// returns as ENOSPC rather than EIO.
+pub const NO_SPACE: &str = "no space left on device";
+
+/// A mounted file system.
+pub trait FileSystem: Send + Sync {
+    /// Name of the file system type, such as "xv6fs".
+    fn name(&self) -> &'static str;
+
+    /// Device number the file system lives on.
+    fn dev(&self) -> u32;
+
+    /// The root directory.
+    fn root(&self) -> Arc<dyn VNode>;
+}
+
+/// A file, directory, device node or symbolic link in a file system.
+/// Operations a node does not support fail, with EPERM unless said otherwise.
+pub trait VNode: Send + Sync {
+    /// Device and inode number, which tell nodes apart.
+    fn id(&self) -> (u32, u32);
+
+    fn itype(&self) -> InodeType;
+
+    /// Major device number, for device nodes.
+    fn major(&self) -> i16 {
+        0
+    }
+
+    fn stat(&self, stat: &mut Stat);
+
+    /// Read len bytes at offset into dst, a user virtual address
+    /// if is_user, a kernel one otherwise. Returns the number of bytes read.
+    fn read(&self, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str>;
+
+    /// Write len bytes from src at offset. Returns the number of bytes written.
+    fn write(&self, is_user: bool, src: usize, offset: usize, len: usize) -> Result<usize, &'static str>;
+
+    /// Throw away the content of a regular file.
+    fn truncate(&self) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    /// Look up name, which ends at its first 0 if any, in this
+    /// directory. Mount points are not crossed here, see namei.rs.
+    fn lookup(&self, _name: &[u8]) -> Result<Arc<dyn VNode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Make a new node called name in this directory,
+    /// EEXIST if there is one already.
+    fn create(&self, _name: &[u8], _itype: InodeType, _major: i16, _minor: i16) -> Result<Arc<dyn VNode>, Errno> {
+        Err(Errno::EPERM)
+    }
+
+    /// Make a symbolic link called name to target in this directory.
+    fn symlink(&self, _name: &[u8], _target: &[u8]) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    /// Add an entry called name for node, which is not a directory.
+    /// Nodes of another file system get EXDEV.
+    fn link(&self, _name: &[u8], _node: &dyn VNode) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    /// Remove the entry called name, ENOTEMPTY for a directory with entries.
+    fn unlink(&self, _name: &[u8]) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    /// Call f with the inode number, type and name of each entry of
+    /// this directory, "." and ".." first, until f returns false.
+    /// Nodes that have it can be read as a directory with `read_dir_records`.
+    fn read_dir(&self, _f: &mut dyn FnMut(u32, InodeType, &[u8]) -> bool) -> Result<(), Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Copy the target of a symbolic link into dst, followed by a 0.
+    /// Return the length of the target, EINVAL if this is no link.
+    fn read_link(&self, _dst: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Change the attributes which are set in attr.
+    fn set_attr(&self, _attr: &Attr) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+}
+
+/// What can be done with an open file. addr is a user virtual address,
+/// offset the position of the file, which `VFile` moves on by what was
+/// read or written.
+pub trait FileOps: Send + Sync {
+    fn read(&self, addr: usize, len: usize, offset: usize) -> Result<usize, &'static str>;
+
+    fn write(&self, addr: usize, len: usize, offset: usize) -> Result<usize, &'static str>;
+
+    fn stat(&self, _stat: &mut Stat) -> Result<(), &'static str> {
+        Err("stat: not supported by this file")
+    }
+}
+
+/// Attributes for `VNode::set_attr`, None leaves one alone.
+#[derive(Default)]
+pub struct Attr {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<u64>,
+    pub mtime: Option<u64>
+}
+
+/// Check that the current process may access node as mask,
+/// MAY_READ, MAY_WRITE and MAY_EXEC or'ed. Root may do anything
+/// but execute a file without any execute bit.
+pub fn permission(node: &dyn VNode, mask: u16) -> Result<(), Errno> {
+    let mut stat = Stat::new();
+    node.stat(&mut stat);
+    let cred = current_cred();
+    let mode = stat.mode;
+    if cred.is_root() {
+        if mask & MAY_EXEC != 0 && stat.itype != InodeType::Directory && mode & 0o111 == 0 {
+            return Err(Errno::EACCES)
+        }
+        return Ok(())
+    }
+    let bits = if cred.euid == stat.uid {
+        mode >> 6
+    } else if cred.in_group(stat.gid) {
+        mode >> 3
+    } else {
+        mode
+    } & 0o7;
+    if bits & mask == mask {
+        Ok(())
+    } else {
+        Err(Errno::EACCES)
+    }
+}
+
+/// Is the current process the owner of node, or root?
+pub fn owned(node: &dyn VNode) -> bool {
+    let mut stat = Stat::new();
+    node.stat(&mut stat);
+    let cred = current_cred();
+    cred.is_root() || cred.euid == stat.uid
+}
+
+/// Check that the current process may remove node from dir: in a
+/// sticky directory only the owners of either, or root, may.
+pub fn may_delete(dir: &dyn VNode, node: &dyn VNode) -> Result<(), Errno> {
+    let mut stat = Stat::new();
+    dir.stat(&mut stat);
+    if stat.mode & S_ISVTX == 0 || owned(dir) || owned(node) {
+        Ok(())
+    } else {
+        Err(Errno::EPERM)
+    }
+}
+
+/// Clear the setuid and setgid bits of a file written by anyone but
+/// root, whose content is then no longer the program they were set for.
+pub fn remove_suid(node: &dyn VNode) {
+    if current_cred().is_root() {
+        return
+    }
+    let mut stat = Stat::new();
+    node.stat(&mut stat);
+    if stat.mode & (S_ISUID | S_ISGID) != 0 {
+        let attr = Attr { mode: Some(stat.mode & !(S_ISUID | S_ISGID)), ..Attr::default() };
+        let _ = node.set_attr(&attr);
+    }
+}
+
+/// Read a directory whose node has `read_dir` as if it held xv6
+/// directory records, so that programs reading directories need not
+/// know the file system. Records are laid out in blocks of BSIZE, like
+/// on disk, the last one of a block taking up the rest of it.
+pub fn read_dir_records(node: &dyn VNode, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+    let end = offset.saturating_add(len);
+    let mut records: Vec<u8> = Vec::new();
+    // where the last record of the current block starts
+    let mut last = 0;
+    node.read_dir(&mut |inum, itype, name| {
+        // name_len has no room for it, leave it out
+        if name.len() > NAME_MAX {
+            return true
+        }
+        let size = DirEntry::rec_size(name.len());
+        let used = records.len() % BSIZE;
+        if used > 0 && used + size > BSIZE {
+            extend_record(&mut records, last, BSIZE - used);
+        }
+        // only the blocks up to end are needed
+        if records.len() >= end {
+            return false
+        }
+        last = records.len();
+        let entry = DirEntry {
+            inum,
+            rec_len: size as u16,
+            name_len: name.len() as u8,
+            file_type: itype as u8
+        };
+        let header = unsafe {
+            core::slice::from_raw_parts(&entry as *const DirEntry as *const u8, size_of::<DirEntry>())
+        };
+        records.extend_from_slice(header);
+        records.extend_from_slice(name);
+        records.resize(last + size, 0);
+        true
+    }).map_err(|_| "read_dir_records: not a directory")?;
+    let used = records.len() % BSIZE;
+    if used > 0 {
+        extend_record(&mut records, last, BSIZE - used);
+    }
+
+    if offset >= records.len() {
+        return Ok(0)
+    }
+    let count = core::cmp::min(len, records.len() - offset);
+    copy_from_kernel(is_user, dst, records[offset..].as_ptr(), count)?;
+    Ok(count)
+}
+
+/// Give the record at last the room left at the end of its block.
+fn extend_record(records: &mut Vec<u8>, last: usize, room: usize) {
+    // rec_len follows the u32 inum
+    let rec_len = u16::from_ne_bytes([records[last + 4], records[last + 5]]) + room as u16;
+    records[last + 4..last + 6].copy_from_slice(&rec_len.to_ne_bytes());
+    records.resize(records.len() + room, 0);
+}
@@ -0,0 +1,337 @@
+//! The xv6 disk file system seen through the VFS.
+
+use alloc::sync::Arc;
+use core::mem::ManuallyDrop;
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, MAXOPBLOCKS, ROOTINUM };
+use crate::driver::{ rtc, virtio_disk };
+use crate::process::current_cred;
+use crate::syscall::Errno;
+
+use super::{ ICACHE, Inode, InodeData, InodeType };
+use super::bitmap::inode_alloc;
+use super::inode::{ default_mode, name_len };
+use super::log::{ self, log };
+use super::stat::Stat;
+use super::superblock;
+use super::vfs::{ Attr, FileSystem, VNode };
+
+pub struct Xv6Fs {
+    dev: u32
+}
+
+impl Xv6Fs {
+    pub const fn new(dev: u32) -> Self {
+        Self { dev }
+    }
+
+    /// Read the super block of disk dev and recover its log.
+    /// SAFETY: dev must not be mounted already.
+    pub unsafe fn mount(dev: u32) -> Result<Self, Errno> {
+        if !virtio_disk::present(dev) {
+            return Err(Errno::ENODEV)
+        }
+        if let Err(err) = superblock::init(dev) {
+            println!("xv6fs: dev {}: {}", dev, err);
+            return Err(Errno::EINVAL)
+        }
+        log::init(dev);
+        Ok(Self::new(dev))
+    }
+}
+
+impl Drop for Xv6Fs {
+    /// Unmounted, dev may be mounted again with another image.
+    fn drop(&mut self) {
+        unsafe {
+            log::reset(self.dev);
+            superblock::reset(self.dev);
+        }
+    }
+}
+
+impl FileSystem for Xv6Fs {
+    fn name(&self) -> &'static str {
+        "xv6fs"
+    }
+
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Arc<dyn VNode> {
+        Xv6Node::new(ICACHE.get(self.dev, ROOTINUM))
+    }
+}
+
+/// An inode held by the VFS. Dropping the last reference to an
+/// unlinked inode frees it on disk, so a node is dropped in a log op
+/// of its own and must not be dropped inside one.
+pub struct Xv6Node {
+    inode: ManuallyDrop<Inode>
+}
+
+impl Xv6Node {
+    pub fn new(inode: Inode) -> Arc<Self> {
+        Arc::new(Self { inode: ManuallyDrop::new(inode) })
+    }
+
+    /// Run f within a log op on the disk of this node.
+    fn op<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let log = log(self.inode.dev);
+        log.begin_op();
+        let ret = f();
+        log.end_op();
+        ret
+    }
+
+    /// Allocate an inode of type itype and enter it as name into
+    /// the locked directory dir. Must be called inside a log op.
+    fn alloc_entry(
+        &self,
+        dir: &mut InodeData,
+        name: &[u8],
+        itype: InodeType,
+        major: i16,
+        minor: i16
+    ) -> Result<Inode, Errno> {
+        if dir.dinode.itype != InodeType::Directory {
+            return Err(Errno::ENOTDIR)
+        }
+        if dir.dir_lookup(name).is_some() {
+            return Err(Errno::EEXIST)
+        }
+        let dev = dir.dev;
+        let inode = ICACHE.get(dev, inode_alloc(dev, itype));
+        let mut inode_guard = inode.lock();
+        // initialize new allocated inode
+        let cred = current_cred();
+        let now = rtc::now();
+        inode_guard.dinode.major = major;
+        inode_guard.dinode.minor = minor;
+        inode_guard.dinode.nlink = 1;
+        inode_guard.dinode.mode = default_mode(itype);
+        inode_guard.dinode.uid = cred.euid;
+        inode_guard.dinode.gid = cred.egid;
+        inode_guard.dinode.atime = now;
+        inode_guard.dinode.mtime = now;
+        // Write back to disk
+        inode_guard.update();
+        debug_assert_eq!(inode_guard.dinode.itype, itype);
+
+        // Directory, create ..
+        let linked = if itype == InodeType::Directory {
+            // Create . and .. entries.
+            // No nlink++ for . to avoid recycle ref count.
+            inode_guard.dir_link(".".as_bytes(), inode.inum, InodeType::Directory)
+                .and_then(|_| inode_guard.dir_link("..".as_bytes(), dir.inum, InodeType::Directory))
+                .map_err(|_| Errno::EIO)
+        } else {
+            Ok(())
+        };
+        let linked = linked.and_then(|_|
+            dir.dir_link(name, inode.inum, itype).map_err(|_| Errno::ENAMETOOLONG));
+        if let Err(err) = linked {
+            // not entered anywhere, let dropping inode free it
+            inode_guard.dinode.nlink = 0;
+            inode_guard.update();
+            return Err(err)
+        }
+        if itype == InodeType::Directory {
+            // the .. of the new directory links its parent,
+            // unlink takes it off again
+            dir.dinode.nlink += 1;
+            dir.update();
+        }
+        drop(inode_guard);
+        Ok(inode)
+    }
+}
+
+impl Drop for Xv6Node {
+    fn drop(&mut self) {
+        let log = log(self.inode.dev);
+        log.begin_op();
+        unsafe{ ManuallyDrop::drop(&mut self.inode); }
+        log.end_op();
+    }
+}
+
+impl VNode for Xv6Node {
+    fn id(&self) -> (u32, u32) {
+        (self.inode.dev, self.inode.inum)
+    }
+
+    fn itype(&self) -> InodeType {
+        self.inode.lock_shared().dinode.itype
+    }
+
+    fn major(&self) -> i16 {
+        self.inode.lock_shared().dinode.major
+    }
+
+    fn stat(&self, stat: &mut Stat) {
+        self.inode.lock_shared().stat(stat);
+    }
+
+    fn read(&self, is_user: bool, dst: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        let mut inode_guard = self.inode.lock();
+        let size = inode_guard.read(is_user, dst, offset as u32, len as u32)?;
+        let due = inode_guard.accessed();
+        // the log op has to begin before the inode is locked
+        drop(inode_guard);
+        if due {
+            self.op(|| self.inode.lock().write_back());
+        }
+        Ok(size)
+    }
+
+    fn write(&self, is_user: bool, src: usize, offset: usize, len: usize) -> Result<usize, &'static str> {
+        // write a few blocks at a time to avoid exceeding the maximum
+        // log transaction size: the inode, up to 5 indirect blocks when
+        // the write crosses from one triply indirect block to the next,
+        // allocation blocks, and 2 blocks of slop for non-aligned writes.
+        let max = ((MAXOPBLOCKS -1 -5 -2) / 2) * BSIZE;
+        let mut count = 0;
+        while count < len {
+            let mut write_bytes = len - count;
+            if write_bytes > max { write_bytes = max; }
+
+            // end the log op before returning an error
+            self.op(|| {
+                self.inode.lock().write(
+                    is_user,
+                    src + count,
+                    (offset + count) as u32,
+                    write_bytes as u32
+                )
+            })?;
+
+            count += write_bytes;
+        }
+        Ok(count)
+    }
+
+    fn truncate(&self) -> Result<(), Errno> {
+        self.op(|| {
+            let mut inode_guard = self.inode.lock();
+            if inode_guard.dinode.itype == InodeType::File {
+                inode_guard.truncate(&self.inode);
+            }
+        });
+        Ok(())
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<dyn VNode>, Errno> {
+        let inode_guard = self.inode.lock_shared();
+        if inode_guard.dinode.itype != InodeType::Directory {
+            return Err(Errno::ENOTDIR)
+        }
+        let inode = inode_guard.dir_lookup(name).ok_or(Errno::ENOENT)?;
+        drop(inode_guard);
+        Ok(Xv6Node::new(inode))
+    }
+
+    fn create(&self, name: &[u8], itype: InodeType, major: i16, minor: i16) -> Result<Arc<dyn VNode>, Errno> {
+        let inode = self.op(|| {
+            let mut dir_guard = self.inode.lock();
+            self.alloc_entry(&mut dir_guard, name, itype, major, minor)
+        })?;
+        Ok(Xv6Node::new(inode))
+    }
+
+    fn symlink(&self, name: &[u8], target: &[u8]) -> Result<(), Errno> {
+        let len = name_len(target);
+        // the link and its target are written in one op,
+        // nobody gets to see a link without target
+        self.op(|| {
+            let mut dir_guard = self.inode.lock();
+            let inode = self.alloc_entry(&mut dir_guard, name, InodeType::Symlink, 0, 0)?;
+            drop(dir_guard);
+            let mut inode_guard = inode.lock();
+            let res = inode_guard.write(false, target.as_ptr() as usize, 0, len as u32);
+            drop(inode_guard);
+            res.map(|_| ()).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn link(&self, name: &[u8], node: &dyn VNode) -> Result<(), Errno> {
+        let (dev, inum) = node.id();
+        if dev != self.inode.dev {
+            return Err(Errno::EXDEV)
+        }
+        self.op(|| {
+            let inode = ICACHE.get(dev, inum);
+            let mut inode_guard = inode.lock();
+            if inode_guard.dinode.itype == InodeType::Directory {
+                return Err(Errno::EPERM)
+            }
+            inode_guard.dinode.nlink += 1;
+            inode_guard.update();
+            drop(inode_guard);
+
+            let mut dir_guard = self.inode.lock();
+            let res = if dir_guard.dinode.itype != InodeType::Directory {
+                Err(Errno::ENOTDIR)
+            } else {
+                dir_guard.dir_link(name, inum, node.itype()).map_err(|_| Errno::EEXIST)
+            };
+            drop(dir_guard);
+            if res.is_err() {
+                let mut inode_guard = inode.lock();
+                inode_guard.dinode.nlink -= 1;
+                inode_guard.update();
+            }
+            res
+        })
+    }
+
+    fn unlink(&self, name: &[u8]) -> Result<(), Errno> {
+        self.op(|| {
+            let mut dir_guard = self.inode.lock();
+            if dir_guard.dinode.itype != InodeType::Directory {
+                return Err(Errno::ENOTDIR)
+            }
+            let inode = dir_guard.dir_lookup(name).ok_or(Errno::ENOENT)?;
+            let mut inode_guard = inode.lock();
+            if inode_guard.dinode.nlink < 1 {
+                panic!("unlink: inods's nlink must be larger than 1.");
+            }
+            if inode_guard.dinode.itype == InodeType::Directory &&
+                !inode_guard.is_dir_empty() {
+                    return Err(Errno::ENOTEMPTY)
+                }
+
+            if dir_guard.dir_unlink(name).is_err() {
+                panic!("unlink: entry vanished from its locked parent");
+            }
+            if inode_guard.dinode.itype == InodeType::Directory {
+                dir_guard.dinode.nlink -= 1;
+                dir_guard.update();
+            }
+            drop(dir_guard);
+
+            inode_guard.dinode.nlink -= 1;
+            inode_guard.update();
+            Ok(())
+        })
+    }
+
+    fn read_link(&self, dst: &mut [u8]) -> Result<usize, Errno> {
+        self.inode.lock_shared().read_link(dst)
+    }
+
+    fn set_attr(&self, attr: &Attr) -> Result<(), Errno> {
+        self.op(|| {
+            let mut inode_guard = self.inode.lock();
+            let dinode = &mut inode_guard.dinode;
+            if let Some(mode) = attr.mode { dinode.mode = mode; }
+            if let Some(uid) = attr.uid { dinode.uid = uid; }
+            if let Some(gid) = attr.gid { dinode.gid = gid; }
+            if let Some(atime) = attr.atime { dinode.atime = atime; }
+            if let Some(mtime) = attr.mtime { dinode.mtime = mtime; }
+            inode_guard.update();
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Inter-processor interrupts.
+//!
+//! Each hart owns a mailbox of work items. A sender puts an item into the
+//! mailbox of the target and asks the SBI to raise a supervisor software
+//! interrupt on it; the trap handler runs everything queued for the hart.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use array_macro::array;
+
+use crate::sbi;
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::lock::spinlock::Spinlock;
+use crate::memory::asid::{ Asid, asid_flush_range };
+use crate::process::{ cpuid, push_off, pop_off };
+
+const MAILBOX_SIZE: usize = 16;
+
+static MAILBOXES: [Spinlock<Mailbox>; NCPU] = array![_ => Spinlock::new(Mailbox::new(), "ipi"); NCPU];
+
+/// Acknowledgements each hart is still waiting for.
+static PENDING_ACKS: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+
+#[derive(Clone, Copy)]
+pub enum IpiWork {
+    /// Drop the translations of [start, end) tagged with asid,
+    /// then acknowledge to hart `from`.
+    TlbFlush { asid: Asid, start: usize, end: usize, from: usize }
+}
+
+impl IpiWork {
+    fn run(self) {
+        match self {
+            IpiWork::TlbFlush{ asid, start, end, from } => {
+                asid_flush_range(asid, start, end);
+                PENDING_ACKS[from].fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+struct Mailbox {
+    works: [Option<IpiWork>; MAILBOX_SIZE],
+    head: usize,
+    len: usize
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self {
+            works: [None; MAILBOX_SIZE],
+            head: 0,
+            len: 0
+        }
+    }
+
+    fn push(&mut self, work: IpiWork) -> bool {
+        if self.len == MAILBOX_SIZE {
+            return false
+        }
+        self.works[(self.head + self.len) % MAILBOX_SIZE] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<IpiWork> {
+        if self.len == 0 {
+            return None
+        }
+        let work = self.works[self.head].take();
+        self.head = (self.head + 1) % MAILBOX_SIZE;
+        self.len -= 1;
+        work
+    }
+}
+
+/// Queue work for hart and interrupt it. While the mailbox of the
+/// target is full we keep serving our own, so that two harts sending
+/// to each other can not deadlock.
+pub fn send_ipi(hart: usize, work: IpiWork) {
+    loop {
+        if MAILBOXES[hart].acquire().push(work) {
+            break;
+        }
+        handle_ipi();
+        spin_loop();
+    }
+    sbi::send_ipi(1 << hart, 0).expect("send_ipi");
+}
+
+/// Run all the work queued for this hart.
+/// Called from the supervisor software interrupt handler.
+pub fn handle_ipi() {
+    let hart = unsafe{ cpuid() };
+    loop {
+        let work = MAILBOXES[hart].acquire().pop();
+        match work {
+            Some(work) => work.run(),
+            None => break
+        }
+    }
+}
+
+/// Flush [start, end) of asid on every hart in the mask harts and wait
+/// until all of them have acknowledged. Once this returns no hart can
+/// use the old translations, so the frames they mapped may be reused.
+pub fn tlb_shootdown(harts: usize, asid: Asid, start: usize, end: usize) {
+    // stay on this hart until the acknowledgements are in.
+    push_off();
+    let me = unsafe{ cpuid() };
+    if harts & (1 << me) != 0 {
+        asid_flush_range(asid, start, end);
+    }
+
+    let others = harts & !(1 << me);
+    if others != 0 {
+        PENDING_ACKS[me].store(others.count_ones() as usize, Ordering::SeqCst);
+        for hart in (0..NCPU).filter(|hart| others & (1 << hart) != 0) {
+            send_ipi(hart, IpiWork::TlbFlush{ asid, start, end, from: me });
+        }
+        // interrupts are off, keep serving requests from other harts
+        // which may be shooting at us at the same time.
+        while PENDING_ACKS[me].load(Ordering::Acquire) != 0 {
+            handle_ipi();
+            spin_loop();
+        }
+    }
+    pop_off();
+}
@@ -0,0 +1,201 @@
+//! Lock dependency validator, built with `--features lockdep`.
+//!
+//! Every `Spinlock` and `SleepLock` belongs to a lock class named after its
+//! `name`. Whenever a lock is acquired while others are held, the order
+//! "held before acquired" is recorded between their classes; spinlocks are
+//! held per hart, sleep locks per process. The first time a new order closes
+//! a cycle, or a sleep lock is taken while holding a spinlock, we print what
+//! happened and turn the validator off, like Linux does.
+//!
+//! Locks of one class nested in each other (two buffers, two processes) are
+//! not told apart, so such nesting is not checked.
+
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use core::hint::spin_loop;
+
+use crate::backtrace::print_backtrace;
+use crate::process::{ CPU_MANAGER, push_off, pop_off, cpuid };
+
+/// Number of lock classes we can tell apart.
+const NCLASS: usize = 64;
+
+/// Cleared after the first report or when we run out of classes.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Protects the class table and the graph. It can not be a `Spinlock`,
+/// which would validate itself.
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+
+static mut CLASSES: [&'static str; NCLASS] = [""; NCLASS];
+static NCLASSES: AtomicUsize = AtomicUsize::new(0);
+
+/// AFTER[a] has bit b set when class b was acquired while holding class a.
+static mut AFTER: [u64; NCLASS] = [0; NCLASS];
+
+struct GraphGuard;
+
+impl GraphGuard {
+    fn lock() -> Self {
+        while GRAPH_LOCK.swap(true, Ordering::Acquire) {
+            spin_loop();
+        }
+        GraphGuard
+    }
+}
+
+impl Drop for GraphGuard {
+    fn drop(&mut self) {
+        GRAPH_LOCK.store(false, Ordering::Release);
+    }
+}
+
+/// Class of locks called name, registering it the first time.
+fn class_of(_graph: &GraphGuard, name: &'static str) -> Option<usize> {
+    let count = NCLASSES.load(Ordering::Relaxed);
+    let classes = unsafe{ &mut CLASSES };
+    if let Some(class) = classes[..count].iter().position(|&c| c == name) {
+        return Some(class)
+    }
+    if count == NCLASS {
+        return None
+    }
+    classes[count] = name;
+    NCLASSES.store(count + 1, Ordering::Relaxed);
+    Some(count)
+}
+
+/// Path of classes from `from` to `to` following recorded orders,
+/// written into path; return its length.
+fn find_path(_graph: &GraphGuard, from: usize, to: usize, path: &mut [usize; NCLASS]) -> Option<usize> {
+    let after = unsafe{ &AFTER };
+    let mut seen: u64 = 1 << from;
+    let mut prev = [usize::MAX; NCLASS];
+    let mut queue = [0usize; NCLASS];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from;
+    while head < tail {
+        let class = queue[head];
+        head += 1;
+        if class == to {
+            let mut len = 0;
+            let mut c = to;
+            while c != usize::MAX {
+                path[len] = c;
+                len += 1;
+                c = prev[c];
+            }
+            path[..len].reverse();
+            return Some(len)
+        }
+        for next in 0..NCLASS {
+            if after[class] & (1 << next) != 0 && seen & (1 << next) == 0 {
+                seen |= 1 << next;
+                prev[next] = class;
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+    }
+    None
+}
+
+fn class_name(class: usize) -> &'static str {
+    unsafe{ CLASSES[class] }
+}
+
+/// Turn the validator off and tell whether we are the one who did,
+/// only the first problem is reported.
+fn start_report() -> bool {
+    ENABLED.swap(false, Ordering::SeqCst)
+}
+
+/// Record that name is being acquired after held, report if this
+/// order closes a cycle.
+fn add_order(held: &'static str, name: &'static str) {
+    let graph = GraphGuard::lock();
+    let (from, to) = match (class_of(&graph, held), class_of(&graph, name)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            drop(graph);
+            if start_report() {
+                println!("lockdep: out of lock classes, turning off the validator");
+            }
+            return
+        }
+    };
+    let after = unsafe{ &mut AFTER };
+    if from == to || after[from] & (1 << to) != 0 {
+        return
+    }
+    after[from] |= 1 << to;
+    let mut path = [0usize; NCLASS];
+    let cycle = find_path(&graph, to, from, &mut path);
+    drop(graph);
+
+    if let Some(len) = cycle {
+        if !start_report() {
+            return
+        }
+        println!("lockdep: possible deadlock on hart {}", unsafe{ cpuid() });
+        println!("acquiring {} while holding {}, but earlier:", name, held);
+        for pair in path[..len].windows(2) {
+            println!("  {} was acquired while holding {}", class_name(pair[1]), class_name(pair[0]));
+        }
+        print_backtrace();
+    }
+}
+
+/// Called by `Spinlock::acquire` before spinning, interrupts are off.
+pub fn spin_acquire(name: &'static str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return
+    }
+    let my_cpu = unsafe{ CPU_MANAGER.mycpu() };
+    for &(_, held) in my_cpu.held_locks.iter() {
+        add_order(held, name);
+    }
+    if let Some(process) = my_cpu.process {
+        let pdata = unsafe{ &*process.as_ref().data.get() };
+        for &(_, held) in pdata.held_sleeplocks.iter() {
+            add_order(held, name);
+        }
+    }
+}
+
+/// Called by `SleepLock::lock` before it may sleep.
+pub fn sleep_acquire(name: &'static str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return
+    }
+    push_off();
+    let my_cpu = unsafe{ CPU_MANAGER.mycpu() };
+    if let Some(&(_, spin)) = my_cpu.held_locks.iter().next() {
+        if start_report() {
+            println!("lockdep: hart {} acquires sleep lock {} while holding spinlock {}",
+                unsafe{ cpuid() }, name, spin);
+            print_backtrace();
+        }
+    }
+    if let Some(process) = my_cpu.process {
+        let pdata = unsafe{ &*process.as_ref().data.get() };
+        for &(_, held) in pdata.held_sleeplocks.iter() {
+            add_order(held, name);
+        }
+    }
+    pop_off();
+}
+
+/// Called by `SleepLock` once it is locked or unlocked,
+/// sleep locks are held by the process.
+pub fn sleep_locked(lock: usize, name: &'static str, locked: bool) {
+    push_off();
+    if let Some(process) = unsafe{ CPU_MANAGER.mycpu() }.process {
+        let pdata = unsafe{ &mut *process.as_ref().data.get() };
+        if locked {
+            pdata.held_sleeplocks.push(lock, name);
+        } else {
+            pdata.held_sleeplocks.pop(lock);
+        }
+    }
+    pop_off();
+}
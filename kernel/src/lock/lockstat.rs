@@ -0,0 +1,137 @@
+//! Contention statistics of spinlocks, by lock name.
+//!
+//! Locks sharing a name (every process lock, every pipe) share one
+//! slot. /dev/lockstat reads a table of the counters, writing
+//! "reset" to it clears them.
+
+use core::fmt::Write;
+use core::hint::spin_loop;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use alloc::string::String;
+use array_macro::array;
+
+use crate::arch::riscv::qemu::devices::LOCKSTAT;
+use crate::fs::{ DEVICE_LIST, read_text };
+use crate::memory::copy_to_kernel;
+
+/// Number of lock names we keep apart, the last slot collects the rest.
+const NCLASS: usize = 64;
+
+/// Class of a lock which was never acquired.
+pub const NO_CLASS: usize = usize::MAX;
+
+struct LockStat {
+    acquisitions: AtomicUsize,
+    contended: AtomicUsize, // Acquisitions which had to wait
+    spins: AtomicUsize, // Rounds spent waiting
+    hold_cycles: AtomicUsize,
+    max_hold: AtomicUsize // Longest hold, in cycles
+}
+
+impl LockStat {
+    const fn new() -> Self {
+        Self {
+            acquisitions: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            spins: AtomicUsize::new(0),
+            hold_cycles: AtomicUsize::new(0),
+            max_hold: AtomicUsize::new(0)
+        }
+    }
+}
+
+static STATS: [LockStat; NCLASS] = array![_ => LockStat::new(); NCLASS];
+
+/// Protects NAMES while a class is added. It can not be
+/// a `Spinlock`, which would count itself.
+static NAMES_LOCK: AtomicBool = AtomicBool::new(false);
+static mut NAMES: [&'static str; NCLASS] = [""; NCLASS];
+static NNAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Slot of the locks called name, adding it the first time.
+pub fn class_of(name: &'static str) -> usize {
+    while NAMES_LOCK.swap(true, Ordering::Acquire) {
+        spin_loop();
+    }
+    let names = unsafe{ &mut NAMES };
+    let count = NNAMES.load(Ordering::Relaxed);
+    let class = match names[..count].iter().position(|&n| n == name) {
+        Some(class) => class,
+        None if count < NCLASS - 1 => {
+            names[count] = name;
+            NNAMES.store(count + 1, Ordering::Release);
+            count
+        },
+        None => {
+            names[NCLASS - 1] = "(other)";
+            NCLASS - 1
+        }
+    };
+    NAMES_LOCK.store(false, Ordering::Release);
+    class
+}
+
+pub fn record_acquire(class: usize, spins: usize) {
+    let stat = &STATS[class];
+    stat.acquisitions.fetch_add(1, Ordering::Relaxed);
+    if spins != 0 {
+        stat.contended.fetch_add(1, Ordering::Relaxed);
+        stat.spins.fetch_add(spins, Ordering::Relaxed);
+    }
+}
+
+pub fn record_release(class: usize, hold: usize) {
+    let stat = &STATS[class];
+    stat.hold_cycles.fetch_add(hold, Ordering::Relaxed);
+    stat.max_hold.fetch_max(hold, Ordering::Relaxed);
+}
+
+pub fn lockstat_init() {
+    unsafe {
+        DEVICE_LIST.table[LOCKSTAT].read = lockstat_read as *const u8;
+        DEVICE_LIST.table[LOCKSTAT].write = lockstat_write as *const u8;
+    }
+}
+
+/// Read of /dev/lockstat: one line per lock name, hold times in cycles.
+fn lockstat_read(is_user: bool, dst: usize, len: usize, offset: usize) -> Option<usize> {
+    let mut text = String::new();
+    let _ = writeln!(
+        text, "{:<16} {:>10} {:>10} {:>12} {:>10} {:>10}",
+        "name", "acquired", "contended", "spins", "avg hold", "max hold"
+    );
+    let names = unsafe{ &NAMES };
+    for (class, stat) in STATS.iter().enumerate() {
+        let acquisitions = stat.acquisitions.load(Ordering::Relaxed);
+        if acquisitions == 0 {
+            continue;
+        }
+        let _ = writeln!(
+            text, "{:<16} {:>10} {:>10} {:>12} {:>10} {:>10}",
+            names[class], acquisitions,
+            stat.contended.load(Ordering::Relaxed),
+            stat.spins.load(Ordering::Relaxed),
+            stat.hold_cycles.load(Ordering::Relaxed) / acquisitions,
+            stat.max_hold.load(Ordering::Relaxed)
+        );
+    }
+    read_text(is_user, dst, len, offset, &text)
+}
+
+/// Write of /dev/lockstat: "reset" clears the counters.
+fn lockstat_write(is_user: bool, src: usize, len: usize) -> Option<usize> {
+    let mut buf = [0u8; 8];
+    let count = core::cmp::min(len, buf.len());
+    copy_to_kernel(buf.as_mut_ptr(), is_user, src, count).ok()?;
+    if core::str::from_utf8(&buf[..count]).ok()?.trim() != "reset" {
+        return None
+    }
+    for stat in STATS.iter() {
+        stat.acquisitions.store(0, Ordering::Relaxed);
+        stat.contended.store(0, Ordering::Relaxed);
+        stat.spins.store(0, Ordering::Relaxed);
+        stat.hold_cycles.store(0, Ordering::Relaxed);
+        stat.max_hold.store(0, Ordering::Relaxed);
+    }
+    Some(len)
+}
@@ -1,2 +1,7 @@
 pub mod spinlock;
-pub mod sleeplock;
\ No newline at end of file
+pub mod sleeplock;
+pub mod rwlock;
+pub mod waitqueue;
+pub mod lockstat;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
\ No newline at end of file
@@ -0,0 +1,249 @@
+//! Reader-writer locks.
+//!
+//! Any number of readers or one writer hold the lock. A waiting writer
+//! keeps new readers out, so that a stream of readers can not starve it.
+//! `RwSpinlock` spins with interrupts off like `Spinlock`, `RwSleepLock`
+//! sleeps on a wait queue like `SleepLock`.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{ Deref, DerefMut };
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::process::{ push_off, pop_off };
+use crate::watchdog::SpinWatch;
+
+use super::spinlock::Spinlock;
+use super::waitqueue::WaitQueue;
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+
+/// Set in the state of a `RwSpinlock` while a writer holds it,
+/// the other bits count the readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+pub struct RwSpinlock<T: ?Sized> {
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    name: &'static str,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            name,
+            data: UnsafeCell::new(data)
+        }
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    /// Shared access. A hart must not take it again while
+    /// holding it, a writer waiting in between would deadlock.
+    pub fn read(&self) -> RwSpinReadGuard<'_, T> {
+        push_off();
+        let mut watch = SpinWatch::new();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 && self.writers_waiting.load(Ordering::Relaxed) == 0 &&
+                self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                break;
+            }
+            spin_loop();
+            watch.check(self.name, -1);
+        }
+        RwSpinReadGuard{ lock: self }
+    }
+
+    /// Exclusive access.
+    pub fn write(&self) -> RwSpinWriteGuard<'_, T> {
+        push_off();
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        let mut watch = SpinWatch::new();
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop();
+            watch.check(self.name, -1);
+        }
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        RwSpinWriteGuard{ lock: self }
+    }
+}
+
+pub struct RwSpinReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinlock<T>
+}
+
+impl<T: ?Sized> Deref for RwSpinReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        pop_off();
+    }
+}
+
+pub struct RwSpinWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinlock<T>
+}
+
+impl<T: ?Sized> Deref for RwSpinWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        pop_off();
+    }
+}
+
+struct RwState {
+    readers: usize,
+    writer: bool,
+    writers_waiting: usize
+}
+
+pub struct RwSleepLock<T: ?Sized> {
+    lock: Spinlock<RwState>,
+    queue: WaitQueue,
+    name: &'static str,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSleepLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSleepLock<T> {}
+
+impl<T> RwSleepLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Self {
+            lock: Spinlock::new(RwState{ readers: 0, writer: false, writers_waiting: 0 }, "rwsleeplock"),
+            queue: WaitQueue::new("rwsleeplock_queue"),
+            name,
+            data: UnsafeCell::new(data)
+        }
+    }
+}
+
+impl<T: ?Sized> RwSleepLock<T> {
+    fn addr(&self) -> usize {
+        &self.lock as *const _ as *const u8 as usize
+    }
+
+    /// Shared access, might sleep.
+    pub fn read(&self) -> RwSleepReadGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_acquire(self.name);
+        let mut guard = self.lock.acquire();
+        while guard.writer || guard.writers_waiting > 0 {
+            self.queue.wait(guard);
+            guard = self.lock.acquire();
+        }
+        guard.readers += 1;
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self.addr(), self.name, true);
+        drop(guard);
+        RwSleepReadGuard{ lock: self }
+    }
+
+    /// Exclusive access, might sleep.
+    pub fn write(&self) -> RwSleepWriteGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_acquire(self.name);
+        let mut guard = self.lock.acquire();
+        guard.writers_waiting += 1;
+        while guard.writer || guard.readers > 0 {
+            self.queue.wait(guard);
+            guard = self.lock.acquire();
+        }
+        guard.writers_waiting -= 1;
+        guard.writer = true;
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self.addr(), self.name, true);
+        drop(guard);
+        RwSleepWriteGuard{ lock: self }
+    }
+
+    fn read_unlock(&self) {
+        let mut guard = self.lock.acquire();
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self.addr(), self.name, false);
+        guard.readers -= 1;
+        if guard.readers == 0 {
+            self.queue.wake_all();
+        }
+        drop(guard);
+    }
+
+    fn write_unlock(&self) {
+        let mut guard = self.lock.acquire();
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self.addr(), self.name, false);
+        guard.writer = false;
+        self.queue.wake_all();
+        drop(guard);
+    }
+}
+
+pub struct RwSleepReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSleepLock<T>
+}
+
+impl<T: ?Sized> Deref for RwSleepReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSleepReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+pub struct RwSleepWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSleepLock<T>
+}
+
+impl<T: ?Sized> Deref for RwSleepWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSleepWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSleepWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
@@ -6,16 +6,16 @@ use core::cell::{Cell, UnsafeCell};
 use core::hint::spin_loop;
 
 use crate::process::{push_off, pop_off};
-use crate::process::{ PROC_MANAGER, CPU_MANAGER };
 
 use super::spinlock::Spinlock;
-
-pub struct SleepChannel(u8);
+use super::waitqueue::WaitQueue;
+#[cfg(feature = "lockdep")]
+use super::lockdep;
 
 pub struct SleepLock<T: ?Sized> {
     lock: Spinlock<()>,
     locked: Cell<bool>,
-    chan: SleepChannel,
+    queue: WaitQueue,
     name: &'static str,
     data: UnsafeCell<T>,
 }
@@ -29,7 +29,7 @@ impl<T> SleepLock<T> {
         Self {
             lock: Spinlock::new((), "sleeplock"),
             locked: Cell::new(false),
-            chan: SleepChannel(0),
+            queue: WaitQueue::new("sleeplock_queue"),
             name,
             data: UnsafeCell::new(data),
         }
@@ -39,14 +39,16 @@ impl<T> SleepLock<T> {
 impl<T: ?Sized> SleepLock<T> {
     /// non-blocking, but might sleep if other p lock this sleeplock
     pub fn lock(&self) -> SleepLockGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_acquire(self.name);
         let mut guard = self.lock.acquire();
         while self.locked.get() {
-            unsafe {
-                CPU_MANAGER.myproc().unwrap().sleep(self.locked.as_ptr() as usize, guard);
-            }
+            self.queue.wait(guard);
             guard = self.lock.acquire();
         }
         self.locked.set(true);
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self as *const _ as *const u8 as usize, self.name, true);
         drop(guard);
         SleepLockGuard {
             lock: &self,
@@ -57,16 +59,12 @@ impl<T: ?Sized> SleepLock<T> {
     /// Called by its guard when dropped
     pub fn unlock(&self) {
         let guard = self.lock.acquire();
+        #[cfg(feature = "lockdep")]
+        lockdep::sleep_locked(self as *const _ as *const u8 as usize, self.name, false);
         self.locked.set(false);
-        self.wake_up();
+        self.queue.wake_one();
         drop(guard);
     }
-
-    fn wake_up(&self) {
-        unsafe{ 
-            PROC_MANAGER.wake_up(self.locked.as_ptr() as usize);
-        }
-    }
 }
 
 
@@ -75,6 +73,13 @@ pub struct SleepLockGuard<'a, T: ?Sized + 'a> {
     data: &'a mut T,
 }
 
+impl<'a, T: ?Sized> SleepLockGuard<'a, T> {
+    /// The lock this guard holds.
+    pub fn sleeplock(&self) -> &'a SleepLock<T> {
+        self.lock
+    }
+}
+
 impl<'a, T: ?Sized> Deref for SleepLockGuard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -1,15 +1,25 @@
-use core::sync::atomic::{AtomicBool, Ordering, fence};
+use core::sync::atomic::{AtomicUsize, Ordering, fence};
 use core::hint::spin_loop;
 use core::cell::{Cell, UnsafeCell};
 use core::ops::{Deref, DerefMut};
 
+use crate::arch::riscv::cycle;
 use crate::process::{ CPU_MANAGER, push_off, pop_off, cpuid };
-
-#[derive(Debug,Default)]
+use crate::watchdog::SpinWatch;
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+use super::lockstat::{ self, NO_CLASS };
+
+/// A ticket lock: harts take a ticket and get the lock in the
+/// order they took them, so no hart can starve.
+#[derive(Debug)]
 pub struct Spinlock<T: ?Sized>{
-    locked:AtomicBool,
+    next: AtomicUsize, // Next ticket to hand out
+    serving: AtomicUsize, // Ticket of the holder
     name: &'static str,
     cpu_id: Cell<isize>,
+    class: AtomicUsize, // Index of name in the lock statistics
+    acquired_at: Cell<usize>, // Cycle counter when the holder got the lock
     data:UnsafeCell<T>,
 }
 
@@ -21,9 +31,12 @@ impl<T> Spinlock<T>{
 
     pub const fn new(data: T, name: &'static str) -> Self {
         let lock = Spinlock {
-            locked: AtomicBool::new(false),
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
             name: name,
             cpu_id: Cell::new(-1),
+            class: AtomicUsize::new(NO_CLASS),
+            acquired_at: Cell::new(0),
             data: UnsafeCell::new(data)
         };
         lock
@@ -35,34 +48,83 @@ impl<T> Spinlock<T>{
         if self.holding() {
             panic!("spinlock {} acquire", self.name);
         }
+        #[cfg(feature = "lockdep")]
+        lockdep::spin_acquire(self.name);
         
-        while self.locked.swap(true, Ordering::Acquire){
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut watch = SpinWatch::new();
+        let mut spins = 0;
+        while self.serving.load(Ordering::Acquire) != ticket {
             // Now we signals the processor that it is inside a busy-wait spin-loop 
             spin_loop();
+            spins += 1;
+            watch.check(self.name, self.cpu_id.get());
         }
+        self.locked_by_me(spins);
+
+        SpinlockGuard{spinlock: &self}
+    }
+
+    /// Acquire the lock only if nobody holds it or waits for it.
+    pub fn try_acquire(&self) -> Option<SpinlockGuard<'_, T>> {
+        push_off();
+        if self.holding() {
+            panic!("spinlock {} acquire", self.name);
+        }
+        let ticket = self.serving.load(Ordering::Acquire);
+        if self.next.compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            pop_off();
+            return None
+        }
+        self.locked_by_me(0);
+        Some(SpinlockGuard{spinlock: &self})
+    }
+
+    fn locked_by_me(&self, spins: usize) {
         fence(Ordering::SeqCst);
         unsafe {
             self.cpu_id.set(cpuid() as isize);
+            CPU_MANAGER.mycpu().held_locks.push(self as *const _ as *const u8 as usize, self.name);
+            lockstat::record_acquire(self.class(), spins);
+            self.acquired_at.set(cycle::read());
         }
+    }
 
-        SpinlockGuard{spinlock: &self}
+    /// The statistics slot of this lock, found by name the first time.
+    fn class(&self) -> usize {
+        let class = self.class.load(Ordering::Relaxed);
+        if class != NO_CLASS {
+            return class
+        }
+        let class = lockstat::class_of(self.name);
+        self.class.store(class, Ordering::Relaxed);
+        class
     }
 
     pub fn release(&self) {
         if !self.holding() {
             panic!("spinlock {} release", self.name);
         }
+        lockstat::record_release(self.class(), unsafe{ cycle::read() } - self.acquired_at.get());
         self.cpu_id.set(-1);
+        unsafe{ CPU_MANAGER.mycpu().held_locks.pop(self as *const _ as *const u8 as usize); }
         fence(Ordering::SeqCst);
-        self.locked.store(false, Ordering::Release);
+        // only the holder changes serving
+        let ticket = self.serving.load(Ordering::Relaxed);
+        self.serving.store(ticket + 1, Ordering::Release);
         pop_off();
     }
 
+    /// Whether some cpu holds the lock.
+    fn locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
     // Check whether this cpu is holding the lock.
     // Interrupts must be off.
     pub fn holding(&self) -> bool{
         // self.locked.load(Ordering::Relaxed) && (self.cpu_id.get() == unsafe{ cpuid() } as isize)
-        if self.locked.load(Ordering::Relaxed) && self.cpu_id.get() == unsafe{ cpuid() } as isize {
+        if self.locked() && self.cpu_id.get() == unsafe{ cpuid() } as isize {
             return true
         }
         false
@@ -71,10 +133,22 @@ impl<T> Spinlock<T>{
 
 }
 
+/// Through new, a derived one would put the lock in statistics slot 0.
+impl<T: Default> Default for Spinlock<T> {
+    fn default() -> Self {
+        Self::new(T::default(), "")
+    }
+}
+
 impl<'a, T> SpinlockGuard<'a, T>{
     pub unsafe fn holding(&self) -> bool{
         self.spinlock.holding()
     }
+
+    /// The lock this guard holds.
+    pub fn spinlock(&self) -> &'a Spinlock<T> {
+        self.spinlock
+    }
 }
 
 impl<T> Deref for SpinlockGuard<'_, T>{
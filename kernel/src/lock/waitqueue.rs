@@ -0,0 +1,335 @@
+//! Wait queues and condition variables.
+//!
+//! A `WaitQueue` keeps the processes sleeping on it in a list linked
+//! through their `ProcData::wait_next`, so waking them up only touches the
+//! waiters. Like xv6's sleep, `wait` gives up the lock guarding the
+//! condition only once the process is on the queue, so a waker which
+//! changes the condition under that lock can not be missed.
+//!
+//! Timed waits are also kept in `TIMERS`, which the clock interrupt
+//! checks every tick.
+
+use core::ptr::null_mut;
+
+use crate::arch::riscv::qemu::param::NPROC;
+use crate::process::{ CPU_MANAGER, Process, ProcState };
+use crate::trap::ticks;
+use crate::watchdog;
+
+use super::spinlock::{ Spinlock, SpinlockGuard };
+use super::sleeplock::{ SleepLock, SleepLockGuard };
+
+struct WaitList {
+    head: *mut Process,
+    tail: *mut Process
+}
+
+unsafe impl Send for WaitList {}
+
+impl WaitList {
+    const fn new() -> Self {
+        Self { head: null_mut(), tail: null_mut() }
+    }
+
+    fn push(&mut self, proc: *mut Process) {
+        unsafe {
+            (*(*proc).data.get()).wait_next = null_mut();
+            if self.tail.is_null() {
+                self.head = proc;
+            } else {
+                (*(*self.tail).data.get()).wait_next = proc;
+            }
+        }
+        self.tail = proc;
+    }
+
+    fn pop(&mut self) -> Option<*mut Process> {
+        let proc = self.head;
+        if proc.is_null() {
+            return None
+        }
+        unsafe {
+            self.head = (*(*proc).data.get()).wait_next;
+            (*(*proc).data.get()).wait_next = null_mut();
+        }
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+        Some(proc)
+    }
+
+    fn contains(&self, proc: *mut Process) -> bool {
+        let mut cur = self.head;
+        while !cur.is_null() {
+            if cur == proc {
+                return true
+            }
+            cur = unsafe{ (*(*cur).data.get()).wait_next };
+        }
+        false
+    }
+
+    /// Take proc off the list, return whether it was there.
+    fn remove(&mut self, proc: *mut Process) -> bool {
+        let mut prev: *mut Process = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let next = unsafe{ (*(*cur).data.get()).wait_next };
+            if cur == proc {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe{ (*(*prev).data.get()).wait_next = next; }
+                }
+                if self.tail == proc {
+                    self.tail = prev;
+                }
+                unsafe{ (*(*proc).data.get()).wait_next = null_mut(); }
+                return true
+            }
+            prev = cur;
+            cur = next;
+        }
+        false
+    }
+}
+
+pub struct WaitQueue {
+    list: Spinlock<WaitList>,
+    interruptible: bool
+}
+
+impl WaitQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            list: Spinlock::new(WaitList::new(), name),
+            interruptible: false
+        }
+    }
+
+    /// A queue for events which may never come, such as input,
+    /// the watchdog leaves its waiters alone.
+    pub const fn new_interruptible(name: &'static str) -> Self {
+        Self {
+            list: Spinlock::new(WaitList::new(), name),
+            interruptible: true
+        }
+    }
+
+    /// Put the current process on the queue, then drop guard and sleep
+    /// until woken up or killed. Returns with guard not held.
+    pub fn wait<G>(&self, guard: G) {
+        self.sleep(guard, None);
+    }
+
+    /// Like `wait`, but give up after ticks clock ticks.
+    /// Return false if we timed out.
+    pub fn wait_timeout<G>(&self, guard: G, ticks: usize) -> bool {
+        self.sleep(guard, Some(ticks))
+    }
+
+    fn sleep<G>(&self, guard: G, timeout: Option<usize>) -> bool {
+        let proc = unsafe{ CPU_MANAGER.myproc().unwrap() };
+        let me = proc as *mut Process;
+        self.list.acquire().push(me);
+        if let Some(timeout) = timeout {
+            add_timer(me, self, ticks() + timeout);
+        }
+        drop(guard);
+
+        // Must acquire p->lock in order to change p->state and then
+        // call sched. A waker takes us off the list before it takes
+        // p->lock, so if we are still on it we won't miss the wakeup.
+        let mut pmeta = proc.meta.acquire();
+        let list = self.list.acquire();
+        if list.contains(me) {
+            drop(list);
+            pmeta.channel = self as *const _ as usize;
+            pmeta.sleep_since = watchdog::now();
+            pmeta.interruptible = self.interruptible;
+            pmeta.hung_reported = false;
+            pmeta.set_state(ProcState::SLEEPING);
+            unsafe {
+                let my_cpu = CPU_MANAGER.mycpu();
+                let ctx = (&mut *proc.data.get()).get_context_mut();
+                pmeta = my_cpu.sched(pmeta, ctx);
+            }
+            pmeta.channel = 0;
+        } else {
+            drop(list);
+        }
+        drop(pmeta);
+
+        // kill() wakes a sleeper without taking it off the list.
+        // Whoever took us off decides: a waker and the timer may both
+        // come at about the same time, but only one finds us there.
+        let mut list = self.list.acquire();
+        list.remove(me);
+        let expired = unsafe{ core::mem::replace(&mut (*proc.data.get()).wait_expired, false) };
+        drop(list);
+        if timeout.is_some() {
+            cancel_timer(me);
+        }
+        !expired
+    }
+
+    /// Wake up the process waiting longest, return whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let proc = self.list.acquire().pop();
+        match proc {
+            Some(proc) => {
+                wake(proc);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Wake up every process waiting, return how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+        while self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Make a process taken off a wait queue runnable again.
+/// Must be called without any p->lock.
+fn wake(proc: *mut Process) {
+    let mut pmeta = unsafe{ (*proc).meta.acquire() };
+    if pmeta.state == ProcState::SLEEPING {
+        pmeta.state = ProcState::RUNNABLE;
+    }
+    drop(pmeta);
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    proc: *mut Process,
+    queue: *const WaitQueue,
+    deadline: usize
+}
+
+struct Timers {
+    timers: [Option<Timer>; NPROC]
+}
+
+unsafe impl Send for Timers {}
+
+/// Timed waits, at most one per process. The queue of a timer stays
+/// alive while it is here: its waiter cancels the timer before
+/// returning from the wait.
+static TIMERS: Spinlock<Timers> = Spinlock::new(Timers{ timers: [None; NPROC] }, "timers");
+
+fn add_timer(proc: *mut Process, queue: &WaitQueue, deadline: usize) {
+    let mut timers = TIMERS.acquire();
+    let slot = timers.timers.iter_mut().find(|timer| timer.is_none()).expect("add_timer: no slot");
+    *slot = Some(Timer{ proc, queue, deadline });
+}
+
+/// Forget the timer of proc, if it did not fire yet.
+fn cancel_timer(proc: *mut Process) {
+    let mut timers = TIMERS.acquire();
+    for slot in timers.timers.iter_mut() {
+        if let Some(timer) = slot {
+            if timer.proc == proc {
+                *slot = None;
+                return
+            }
+        }
+    }
+}
+
+/// Wake up the waiters whose deadline passed, called by the clock interrupt.
+pub fn expire_timers(now: usize) {
+    let mut timers = TIMERS.acquire();
+    for slot in timers.timers.iter_mut() {
+        let timer = match slot {
+            Some(timer) if timer.deadline <= now => *timer,
+            _ => continue
+        };
+        *slot = None;
+        let queue = unsafe{ &*timer.queue };
+        let mut list = queue.list.acquire();
+        if list.remove(timer.proc) {
+            // the waiter was not woken up, tell it so.
+            unsafe{ (*(*timer.proc).data.get()).wait_expired = true; }
+            drop(list);
+            wake(timer.proc);
+        }
+    }
+    drop(timers);
+}
+
+/// Guards which `Condvar` can give up and take again.
+pub trait Guard<'a>: Sized {
+    type Lock: 'a;
+    fn lock_of(&self) -> &'a Self::Lock;
+    fn relock(lock: &'a Self::Lock) -> Self;
+}
+
+impl<'a, T> Guard<'a> for SpinlockGuard<'a, T> {
+    type Lock = Spinlock<T>;
+
+    fn lock_of(&self) -> &'a Spinlock<T> {
+        self.spinlock()
+    }
+
+    fn relock(lock: &'a Spinlock<T>) -> Self {
+        lock.acquire()
+    }
+}
+
+impl<'a, T: ?Sized> Guard<'a> for SleepLockGuard<'a, T> {
+    type Lock = SleepLock<T>;
+
+    fn lock_of(&self) -> &'a SleepLock<T> {
+        self.sleeplock()
+    }
+
+    fn relock(lock: &'a SleepLock<T>) -> Self {
+        lock.lock()
+    }
+}
+
+/// A condition variable: wait for a condition guarded by a lock,
+/// getting the lock back once woken up.
+pub struct Condvar {
+    queue: WaitQueue
+}
+
+impl Condvar {
+    pub const fn new(name: &'static str) -> Self {
+        Self { queue: WaitQueue::new(name) }
+    }
+
+    pub const fn new_interruptible(name: &'static str) -> Self {
+        Self { queue: WaitQueue::new_interruptible(name) }
+    }
+
+    /// Give up guard, sleep until notified, then lock again. Wakeups
+    /// may be spurious (kill, notify_all), so check the condition in a loop.
+    pub fn wait<'a, G: Guard<'a>>(&self, guard: G) -> G {
+        let lock = guard.lock_of();
+        self.queue.wait(guard);
+        G::relock(lock)
+    }
+
+    /// Like `wait`, but give up after ticks clock ticks,
+    /// the flag is false if we timed out.
+    pub fn wait_timeout<'a, G: Guard<'a>>(&self, guard: G, ticks: usize) -> (G, bool) {
+        let lock = guard.lock_of();
+        let woken = self.queue.wait_timeout(guard, ticks);
+        (G::relock(lock), woken)
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.queue.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.queue.wake_all()
+    }
+}
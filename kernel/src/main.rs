@@ -30,11 +30,13 @@ core::arch::global_asm!(include_str!("asm/entry.S"));
 core::arch::global_asm!(include_str!("asm/kernelvec.S"));
 core::arch::global_asm!(include_str!("asm/trampoline.S"));
 core::arch::global_asm!(include_str!("asm/switch.S"));
+core::arch::global_asm!(include_str!("asm/usercopy.S"));
 
 
 #[macro_use]
 mod printf;
 mod shutdown;
+mod sbi;
 
 mod logo;
 mod arch;
@@ -47,8 +49,12 @@ mod driver;
 mod net;
 mod misc;
 mod trap;
+mod ipi;
+mod backtrace;
+mod watchdog;
+mod prof;
 
-use core::sync::atomic::{ AtomicBool, Ordering };
+use core::sync::atomic::{ AtomicUsize, Ordering };
 
 use crate::driver::plic::{plic_init, plic_init_hart};
 use crate::process::cpu::cpuid;
@@ -56,117 +62,86 @@ use crate::logo::LOGO;
 use crate::driver::console::console_init;
 use crate::driver::uart::UART;
 use crate::trap::trap_init_hart;
+use crate::lock::lockstat::lockstat_init;
 use crate::memory::{
     RawPage,
     kalloc::*,
-    mapping::kernel_map::{ kvm_init, kvm_init_hart }
+    mapping::kernel_map::{ kvm_init, kvm_init_hart },
+    asid::asid_init
 };
 use crate::process::*;
 use crate::fs::*;
-use crate::driver::virtio_disk::DISK;
-use crate::arch::riscv::{
-    mstatus, mepc, satp, medeleg, mideleg, sie, mhartid, tp, clint, 
-    mscratch, mtvec, mie, sstatus, pmp,
-};
+use crate::driver::virtio_disk;
+use crate::arch::riscv::{ satp, sie, tp, sstatus };
+use crate::trap::set_next_timer;
 use crate::arch::riscv::qemu::param::NCPU;
 
-static mut TIMER_SCRATCH:[[u64; 5]; NCPU] = [[0u64; 5]; NCPU];
-static STARTED:AtomicBool = AtomicBool::new(false);
+static BOOT_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
 
-/// 引导启动程序,进行寄存器的初始化操作
+/// 引导启动程序, 每个 hart 都在 supervisor mode 下从 _entry 进入
 #[no_mangle]
-pub unsafe fn start() -> !{
-    // Set M Previlege mode to Supervisor, for mret
-    mstatus::set_mpp();
-
-    // set M Exception Program Counter to main, for mret.
-    // requires gcc -mcmodel=medany
-    mepc::write(rust_main as usize);
+pub unsafe extern "C" fn start(hartid: usize) -> ! {
+    // keep each CPU's hartid in its tp register, for cpuid().
+    tp::write(hartid);
 
     // disable paging for now.
     satp::write(0);
 
-    // delegate all interrupts and exceptions to supervisor mode.
-    medeleg::write(0xffff);
-    mideleg::write(0xffff);
+    // enable external, timer and software interrupts,
+    // still need to set SIE bit in sstatus.
     sie::intr_on();
 
-    // configure Physical Memory Protection to give supervisor mode access to all of physical memory.
-    pmp::pmpaddr0::write(0x3fffffffffffff);
-    pmp::pmpcfg0::set_pmp(0, pmp::pmpcfg0::Range::TOR, pmp::pmpcfg0::Permission::RWX, false);
-
     // ask for clock interrupts.
-    timer_init();
-
-    // keep each CPU's hartid in its tp register, for cpuid().
-    let id:usize = mhartid::read(); 
-    tp::write(id);
+    set_next_timer();
 
-    // switch to supervisor mode and jump to main().
-    core::arch::asm!("mret");
+    rust_main();
 
     loop{}
-    
 }
 
-/// set up to receive timer interrupts in machine mode,
-/// which arrive at timervec in kernelvec.S,
-/// which turns them into software interrupts for
-/// devintr() in trap.rs.
-/// 启动时钟中断
-unsafe fn timer_init(){
-    // each CPU has a separate source of timer interrupts.
-    let id = mhartid::read();
-
-    // ask the CLINT for a timer interrupt.
-    let interval = 1000000;// cycles; about 1/10th second in qemu.
-    clint::add_mtimecmp(id, interval);
-
-
-    // prepare information in scratch[] for timervec.
-    // scratch[0..2] : space for timervec to save registers.
-    // scratch[3] : address of CLINT MTIMECMP register.
-    // scratch[4] : desired interval (in cycles) between timer interrupts.
-    TIMER_SCRATCH[id][3] = clint::count_mtiecmp(id) as u64;
-    TIMER_SCRATCH[id][4] = interval;
-    mscratch::write(TIMER_SCRATCH[id].as_ptr() as usize);
-
-    // set the machine-mode trap handler.
+/// The boot hart is started by the SBI, it starts the others
+/// once the kernel is initialized. Harts which do not exist
+/// are reported as invalid parameters.
+unsafe fn start_harts() {
     extern "C" {
-        fn timervec();
+        fn _entry();
+    }
+    let me = cpu::cpuid();
+    for hart in (0..NCPU).filter(|&hart| hart != me) {
+        match sbi::hart_start(hart, _entry as usize, 0) {
+            Ok(_) | Err(sbi::SBI_ERR_ALREADY_AVAILABLE) | Err(sbi::SBI_ERR_INVALID_PARAM) => {},
+            Err(err) => println!("hart {}: fail to start, sbi error {}", hart, err)
+        }
     }
-
-    mtvec::write(timervec as usize);
-
-    // enable machine-mode interrupts.
-    mstatus::enable_interrupt();
-
-    // enable machine-mode timer interrupts.
-    mie::write(mie::read() | mie::MIE::MTIE as usize);
-
 }
 
 /// 进入内核初始化
 #[no_mangle]
 pub unsafe extern "C" fn rust_main() {
-    if cpu::cpuid() == 0 {
+    let boot = BOOT_HART.compare_exchange(
+        usize::MAX, cpu::cpuid(), Ordering::SeqCst, Ordering::SeqCst
+    ).is_ok();
+    if boot {
         console_init();
         println!("{}",LOGO); 
         println!("xv6-rust kernel is booting!");
+        sbi::sbi_init(); // check the SBI extensions we use
         KERNEL_HEAP.kinit(); // physical page allocator
         kvm_init(); // create kernel page table
         kvm_init_hart(); // turn on paging
+        asid_init(); // address space identifiers
         PROC_MANAGER.init(); // process table
         trap_init_hart(); // trap vectors
         plic_init(); // set up interrupt controller
         plic_init_hart(); // ask PLIC for device interrupts
+        prof::prof_init(); // sampling profiler
+        lockstat_init(); // spinlock statistics
         BCACHE.binit(); // buffer cache
-        DISK.acquire().init(); // emulated hard disk
+        virtio_disk::init(); // emulated hard disks
         PROC_MANAGER.user_init(); // first user process
-        STARTED.store(true, Ordering::SeqCst);
+        start_harts(); // bring up the other harts
         sstatus::intr_on();
     } else {
-        while !STARTED.load(Ordering::SeqCst){}
         println!("hart {} starting\n", cpu::cpuid());
         kvm_init_hart(); // turn on paging
         trap_init_hart(); // install kernel trap vector
@@ -0,0 +1,143 @@
+//! Address space identifiers.
+//!
+//! Each user page table is tagged with an ASID in satp, so that switching
+//! between address spaces does not have to throw away the whole TLB.
+//! ASIDs are handed out from a counter; when it runs out a new generation
+//! starts and every hart flushes its TLB once before it uses an ASID of
+//! the new generation. A process keeps its ASID until the generation
+//! changes or its page table is replaced; stale entries on other harts
+//! are shot down through IPIs (see ipi.rs).
+//! The kernel page table always uses ASID 0.
+
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+use crate::lock::spinlock::Spinlock;
+use crate::arch::riscv::{ satp, sfence_vma };
+use crate::arch::riscv::qemu::layout::PGSIZE;
+
+/// Flushing more pages than this one by one costs more
+/// than dropping every entry of the ASID.
+const MAX_FLUSH_PAGES: usize = 64;
+
+/// Current generation, starts from 1 so that `Asid::NONE` is always stale.
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+static ASID_ALLOCATOR: Spinlock<AsidAllocator> = Spinlock::new(AsidAllocator::new(), "asid");
+
+static ASID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// An ASID together with the generation it was allocated in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Asid(usize);
+
+impl Asid {
+    /// No ASID yet, the owner gets a fresh one on its next return to user space.
+    pub const NONE: Asid = Asid(0);
+
+    const fn new(generation: usize, asid: usize) -> Self {
+        Self(generation << satp::ASID_BITS | asid)
+    }
+
+    pub fn generation(&self) -> usize {
+        self.0 >> satp::ASID_BITS
+    }
+
+    /// Whether some hart may still switch to this ASID.
+    pub fn is_current(&self) -> bool {
+        self.generation() == GENERATION.load(Ordering::SeqCst)
+    }
+
+    /// The value put into the ASID field of satp.
+    pub fn as_usize(&self) -> usize {
+        self.0 & ((1 << satp::ASID_BITS) - 1)
+    }
+}
+
+struct AsidAllocator {
+    /// Next ASID to hand out in the current generation.
+    next: usize,
+    /// Largest ASID the harts implement, 0 when there is no ASID support.
+    max: usize
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            next: 1,
+            max: 0
+        }
+    }
+
+    fn alloc(&mut self) -> Asid {
+        if self.next > self.max {
+            // Out of ASIDs: start a new generation, every hart
+            // flushes before using one of the new ASIDs.
+            GENERATION.fetch_add(1, Ordering::SeqCst);
+            self.next = 1;
+        }
+        let asid = Asid::new(GENERATION.load(Ordering::SeqCst), self.next);
+        self.next += 1;
+        asid
+    }
+}
+
+/// Find out how many ASID bits the hart implements:
+/// unimplemented bits of the ASID field are read-only zero.
+/// Must be called after paging is enabled, by hart 0 only.
+pub unsafe fn asid_init() {
+    let old = satp::read();
+    satp::write(old | satp::ASID_MASK);
+    let bits = ((satp::read() & satp::ASID_MASK) >> satp::ASID_SHIFT).count_ones() as usize;
+    satp::write(old);
+    sfence_vma();
+
+    // 关闭 ASID 以便与打开时比较上下文切换的开销
+    #[cfg(feature = "no_asid")]
+    let bits = 0;
+
+    ASID_ALLOCATOR.acquire().max = (1 << bits) - 1;
+    ASID_ENABLED.store(bits != 0, Ordering::SeqCst);
+    println!("asid: {} bits", bits);
+}
+
+/// Whether address spaces are tagged at all.
+pub fn asid_enabled() -> bool {
+    ASID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Make sure `asid` belongs to the current generation, allocating
+/// a new one if not, before this hart switches to it.
+/// `hart_generation` is the last generation this hart flushed for.
+/// Return true if the whole TLB of this hart must be flushed first.
+pub fn asid_activate(asid: &mut Asid, hart_generation: &mut usize) -> bool {
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if asid.generation() != generation || *hart_generation != generation {
+        let mut allocator = ASID_ALLOCATOR.acquire();
+        if allocator.max == 0 {
+            return true
+        }
+        if asid.generation() != GENERATION.load(Ordering::SeqCst) {
+            *asid = allocator.alloc();
+        }
+        drop(allocator);
+        if *hart_generation != asid.generation() {
+            *hart_generation = asid.generation();
+            return true
+        }
+    }
+    false
+}
+
+/// Drop this hart's translations of [start, end) tagged with `asid`.
+pub fn asid_flush_range(asid: Asid, start: usize, end: usize) {
+    let pages = (end - start + PGSIZE - 1) / PGSIZE;
+    unsafe {
+        if pages > MAX_FLUSH_PAGES {
+            core::arch::asm!("sfence.vma zero, {}", in(reg)asid.as_usize());
+        } else {
+            for i in 0..pages {
+                core::arch::asm!("sfence.vma {}, {}", in(reg)start + i * PGSIZE, in(reg)asid.as_usize());
+            }
+        }
+    }
+}
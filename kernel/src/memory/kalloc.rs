@@ -2,7 +2,10 @@ use crate::lock::spinlock::Spinlock;
 use crate::arch::riscv::qemu::param::{ LEAF_SIZE, MAX_ALIGNMENT };
 use crate::arch::riscv::qemu::layout::{PGSIZE, PHYSTOP};
 use super::address::{PhysicalAddress, Addr};
+#[cfg(feature = "kmem_debug")]
+use super::kdebug;
 use core::alloc::{ GlobalAlloc, Layout };
+use core::sync::atomic::{ AtomicUsize, Ordering };
 
 use allocator::*;
 
@@ -13,33 +16,112 @@ use core::ptr::{write_volatile, write, NonNull};
 #[global_allocator]
 pub static KERNEL_HEAP: KernelHeap = KernelHeap::uninit();
 
+/// Only reached by infallible allocations (Box::new, Vec, ...) inside the kernel. 
+/// User memory and page tables go through `PageAllocator::try_new_zeroed`,
+/// whose failures are reported as ENOMEM and handled by the OOM killer. 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
-    panic!("alloc error: {:?}", layout);
+    panic!("kernel heap exhausted: {:?}", layout);
 }
 
 // kernel heap
-pub struct KernelHeap(Spinlock<BuddySystem>);
+pub struct KernelHeap {
+    heap: Spinlock<BuddySystem>,
+    stats: HeapCounters
+}
+
+/// Usage of the kernel heap, in bytes asked for, see `KernelHeap::stats`.
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    pub total: usize,
+    pub in_use: usize,
+    pub peak: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub failures: usize
+}
+
+struct HeapCounters {
+    total: AtomicUsize,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+    failures: AtomicUsize
+}
+
+impl HeapCounters {
+    const fn new() -> Self {
+        Self {
+            total: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0)
+        }
+    }
+
+    fn alloc(&self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        let in_use = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    fn dealloc(&self, size: usize) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.in_use.fetch_sub(size, Ordering::Relaxed);
+    }
+}
 
+#[cfg(not(feature = "kmem_debug"))]
 unsafe impl GlobalAlloc for KernelHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0.acquire().alloc(layout)
+        let ptr = self.heap.acquire().alloc(layout);
+        self.stats.alloc(ptr, layout.size());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0.acquire().dealloc(ptr, layout)
+        self.heap.acquire().dealloc(ptr, layout);
+        self.stats.dealloc(layout.size());
+    }
+}
+
+// With kmem_debug every block is wrapped with redzones and tracked,
+// see memory/kdebug.rs.
+#[cfg(feature = "kmem_debug")]
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let raw = self.heap.acquire().alloc(kdebug::outer_layout(layout));
+        self.stats.alloc(raw, layout.size());
+        kdebug::track_alloc(raw, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let raw = kdebug::track_dealloc(ptr, layout);
+        self.heap.acquire().dealloc(raw, kdebug::outer_layout(layout));
+        self.stats.dealloc(layout.size());
     }
 }
 
 impl KernelHeap {
     const fn uninit() -> Self {
-        Self(Spinlock::new(BuddySystem::uninit(), "kernel heap"))
+        Self {
+            heap: Spinlock::new(BuddySystem::uninit(), "kernel heap"),
+            stats: HeapCounters::new()
+        }
     }
 
     unsafe fn init(&self, start: usize, end: usize) {
-        let res = self.0.acquire().init(start, end, LEAF_SIZE, MAX_ALIGNMENT);
+        let res = self.heap.acquire().init(start, end, LEAF_SIZE, MAX_ALIGNMENT);
         match res {
             Ok(()) => {
+                self.stats.total.store(end - start, Ordering::Relaxed);
                 println!("KernelHeap: success to init.");
             },
 
@@ -57,4 +139,24 @@ impl KernelHeap {
         println!("KernelHeap: available memory: [{:#x}, {:#x})", end, PHYSTOP);
         self.init(end, PHYSTOP);
     }
+
+    /// How much of the heap is in use. Bytes are counted as asked for,
+    /// the buddy system rounds blocks up, so more may be taken.
+    pub fn stats(&self) -> HeapStats {
+        let stats = &self.stats;
+        HeapStats {
+            total: stats.total.load(Ordering::Relaxed),
+            in_use: stats.in_use.load(Ordering::Relaxed),
+            peak: stats.peak.load(Ordering::Relaxed),
+            allocs: stats.allocs.load(Ordering::Relaxed),
+            frees: stats.frees.load(Ordering::Relaxed),
+            failures: stats.failures.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Print all live allocations together with their allocation sites.
+    #[cfg(feature = "kmem_debug")]
+    pub fn leak_report(&self) {
+        kdebug::leak_report();
+    }
 }
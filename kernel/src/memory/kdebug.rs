@@ -0,0 +1,337 @@
+//! Kernel heap debugging, built with `--features kmem_debug`.
+//!
+//! Every block handed out by `KERNEL_HEAP` is surrounded by redzones that are
+//! checked when the block is freed, freed memory is poisoned, and each live
+//! allocation is recorded together with the return addresses of its callers,
+//! so that leaks can be listed from the console with Ctrl-K.
+
+use core::alloc::Layout;
+use core::ptr;
+
+use crate::lock::spinlock::Spinlock;
+use crate::arch::riscv::register::fp;
+
+/// Size of the guard area placed around each allocation.
+pub const REDZONE: usize = 16;
+
+/// Pattern written into redzones.
+const REDZONE_BYTE: u8 = 0xFD;
+
+/// Pattern written over freed memory.
+const POISON_BYTE: u8 = 0x6B;
+
+/// Number of allocations that can be tracked at the same time.
+const MAX_RECORDS: usize = 4096;
+
+/// Number of freed records kept to catch double frees.
+const MAX_TOMBSTONES: usize = MAX_RECORDS / 4;
+
+/// Number of return addresses kept for each allocation.
+const CALLER_DEPTH: usize = 6;
+
+/// Largest distance between two frames we are willing to follow.
+const MAX_FRAME_SIZE: usize = 0x4000;
+
+static ALLOC_TABLE: Spinlock<AllocTable> = Spinlock::new(AllocTable::new(), "kmem debug");
+
+#[derive(Clone, Copy, PartialEq)]
+enum RecordState {
+    Empty,
+    Live,
+    Freed
+}
+
+#[derive(Clone, Copy)]
+struct AllocRecord {
+    state: RecordState,
+    ptr: usize,
+    size: usize,
+    /// Number of the free which made it a tombstone
+    serial: usize,
+    callers: [usize; CALLER_DEPTH]
+}
+
+impl AllocRecord {
+    const fn new() -> Self {
+        Self {
+            state: RecordState::Empty,
+            ptr: 0,
+            size: 0,
+            serial: 0,
+            callers: [0; CALLER_DEPTH]
+        }
+    }
+
+    fn print(&self) {
+        print!("  {:#x} size {:<6} from", self.ptr, self.size);
+        for &ra in self.callers.iter().take_while(|&&ra| ra != 0) {
+            print!(" {:#x}", ra);
+        }
+        print!("\n");
+    }
+}
+
+enum FreeError {
+    DoubleFree(AllocRecord),
+    Invalid,
+    Untracked
+}
+
+/// Open addressing table of allocations keyed by the returned pointer,
+/// probed linearly. Freed records are kept as tombstones so that a second
+/// free of the same pointer can be told apart from a free of a wild
+/// pointer. Only the last MAX_TOMBSTONES are, so that empty slots remain
+/// to end probes; a double free older than those looks like a wild one.
+struct AllocTable {
+    records: [AllocRecord; MAX_RECORDS],
+    live: usize,
+    live_bytes: usize,
+    /// Allocations which could not be recorded because the table was full.
+    dropped: usize,
+    /// Pointers and serials of the tombstones in the order they were
+    /// freed, the oldest at `oldest`. An allocation may have taken one
+    /// over since, the pointer may be a tombstone of a later free.
+    freed: [(usize, usize); MAX_TOMBSTONES],
+    oldest: usize,
+    nfreed: usize,
+    frees: usize
+}
+
+impl AllocTable {
+    const fn new() -> Self {
+        Self {
+            records: [AllocRecord::new(); MAX_RECORDS],
+            live: 0,
+            live_bytes: 0,
+            dropped: 0,
+            freed: [(0, 0); MAX_TOMBSTONES],
+            oldest: 0,
+            nfreed: 0,
+            frees: 0
+        }
+    }
+
+    fn slot(ptr: usize) -> usize {
+        (ptr >> 4) % MAX_RECORDS
+    }
+
+    fn lookup(&self, ptr: usize) -> Option<usize> {
+        let start = Self::slot(ptr);
+        for i in 0..MAX_RECORDS {
+            let index = (start + i) % MAX_RECORDS;
+            let record = &self.records[index];
+            if record.state == RecordState::Empty {
+                return None
+            }
+            if record.ptr == ptr {
+                return Some(index)
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, ptr: usize, size: usize, callers: [usize; CALLER_DEPTH]) {
+        let start = Self::slot(ptr);
+        let mut candidate = None;
+        for i in 0..MAX_RECORDS {
+            let index = (start + i) % MAX_RECORDS;
+            let record = &self.records[index];
+            match record.state {
+                RecordState::Empty => {
+                    candidate.get_or_insert(index);
+                    break;
+                },
+                RecordState::Freed => {
+                    if record.ptr == ptr {
+                        candidate = Some(index);
+                        break;
+                    }
+                    candidate.get_or_insert(index);
+                },
+                RecordState::Live => {}
+            }
+        }
+
+        match candidate {
+            Some(index) => {
+                self.records[index] = AllocRecord {
+                    state: RecordState::Live,
+                    ptr,
+                    size,
+                    serial: 0,
+                    callers
+                };
+                self.live += 1;
+                self.live_bytes += size;
+            },
+            None => self.dropped += 1
+        }
+    }
+
+    fn remove(&mut self, ptr: usize) -> Result<AllocRecord, FreeError> {
+        match self.lookup(ptr) {
+            Some(index) => {
+                let record = &mut self.records[index];
+                if record.state == RecordState::Freed {
+                    return Err(FreeError::DoubleFree(*record))
+                }
+                self.frees += 1;
+                record.state = RecordState::Freed;
+                record.serial = self.frees;
+                let record = *record;
+                self.live -= 1;
+                self.live_bytes -= record.size;
+                if self.nfreed == MAX_TOMBSTONES {
+                    self.forget_oldest();
+                }
+                self.freed[(self.oldest + self.nfreed) % MAX_TOMBSTONES] = (ptr, record.serial);
+                self.nfreed += 1;
+                Ok(record)
+            },
+            None if self.dropped > 0 => Err(FreeError::Untracked),
+            None => Err(FreeError::Invalid)
+        }
+    }
+
+    /// Empty the slot of the oldest tombstone still there.
+    fn forget_oldest(&mut self) {
+        while self.nfreed > 0 {
+            let (ptr, serial) = self.freed[self.oldest];
+            self.oldest = (self.oldest + 1) % MAX_TOMBSTONES;
+            self.nfreed -= 1;
+            match self.lookup(ptr) {
+                Some(index) if self.records[index].state == RecordState::Freed
+                    && self.records[index].serial == serial => {
+                    self.clear(index);
+                    return
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Empty the slot at hole, moving back the records after it which
+    /// would no longer be found past the empty slot.
+    fn clear(&mut self, mut hole: usize) {
+        self.records[hole] = AllocRecord::new();
+        let mut index = hole;
+        loop {
+            index = (index + 1) % MAX_RECORDS;
+            if self.records[index].state == RecordState::Empty {
+                return
+            }
+            // a record stays if its probe starts after the hole
+            let home = Self::slot(self.records[index].ptr);
+            let stays = if hole < index {
+                hole < home && home <= index
+            } else {
+                hole < home || home <= index
+            };
+            if !stays {
+                self.records[hole] = self.records[index];
+                self.records[index] = AllocRecord::new();
+                hole = index;
+            }
+        }
+    }
+}
+
+/// Bytes placed in front of the block handed out to the caller.
+/// Blocks with an alignment larger than the redzone (e.g. pages)
+/// only get a trailing redzone so that they stay aligned.
+fn head_size(layout: Layout) -> usize {
+    if layout.align() <= REDZONE { REDZONE } else { 0 }
+}
+
+/// The layout actually requested from the buddy system for `layout`.
+pub fn outer_layout(layout: Layout) -> Layout {
+    let size = head_size(layout) + layout.size() + REDZONE;
+    let align = core::cmp::max(layout.align(), REDZONE);
+    Layout::from_size_align(size, align).unwrap()
+}
+
+/// Collect the return addresses of our callers by following
+/// the frame pointer chain (ra at fp - 8, previous fp at fp - 16).
+unsafe fn callers() -> [usize; CALLER_DEPTH] {
+    let mut ret = [0; CALLER_DEPTH];
+    let mut frame = fp::read();
+    for slot in ret.iter_mut() {
+        if frame == 0 || frame % 8 != 0 {
+            break;
+        }
+        *slot = ptr::read((frame - 8) as *const usize);
+        let prev = ptr::read((frame - 16) as *const usize);
+        if prev <= frame || prev - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = prev;
+    }
+    ret
+}
+
+/// Fill the redzones of a fresh block allocated at `raw` and record it.
+/// Returns the pointer handed to the caller.
+pub unsafe fn track_alloc(raw: *mut u8, layout: Layout) -> *mut u8 {
+    if raw.is_null() {
+        return raw
+    }
+    let head = head_size(layout);
+    let block = raw.add(head);
+    ptr::write_bytes(raw, REDZONE_BYTE, head);
+    ptr::write_bytes(block.add(layout.size()), REDZONE_BYTE, REDZONE);
+
+    ALLOC_TABLE.acquire().insert(block as usize, layout.size(), callers());
+    block
+}
+
+/// Check a block which is about to be freed and poison it.
+/// Returns the pointer which must be given back to the buddy system.
+pub unsafe fn track_dealloc(block: *mut u8, layout: Layout) -> *mut u8 {
+    let record = ALLOC_TABLE.acquire().remove(block as usize);
+    let record = match record {
+        Ok(record) => Some(record),
+        Err(FreeError::Untracked) => None,
+        Err(FreeError::DoubleFree(record)) => {
+            println!("kmem: double free of {:#x}, allocated at:", block as usize);
+            record.print();
+            panic!("kmem: double free");
+        },
+        Err(FreeError::Invalid) => {
+            panic!("kmem: free of unknown pointer {:#x}", block as usize);
+        }
+    };
+
+    if let Some(record) = record {
+        if record.size != layout.size() {
+            println!("kmem: {:#x} freed with size {}, allocated with:", block as usize, layout.size());
+            record.print();
+        }
+    }
+
+    let head = head_size(layout);
+    let raw = block.sub(head);
+    let front = core::slice::from_raw_parts(raw, head);
+    let back = core::slice::from_raw_parts(block.add(layout.size()), REDZONE);
+    if front.iter().chain(back.iter()).any(|&b| b != REDZONE_BYTE) {
+        println!("kmem: redzone of {:#x} (size {}) is corrupted", block as usize, layout.size());
+        if let Some(record) = record {
+            record.print();
+        }
+        panic!("kmem: heap overflow");
+    }
+
+    ptr::write_bytes(raw, POISON_BYTE, outer_layout(layout).size());
+    raw
+}
+
+/// Print every allocation that is still live.
+pub fn leak_report() {
+    let table = ALLOC_TABLE.acquire();
+    println!("kmem: {} live allocations, {} bytes", table.live, table.live_bytes);
+    for record in table.records.iter().filter(|r| r.state == RecordState::Live) {
+        record.print();
+    }
+    if table.dropped > 0 {
+        println!("kmem: {} allocations were not tracked (table full)", table.dropped);
+    }
+}
@@ -1,10 +1,10 @@
-use super::{ page_table::PageTable, page_table_entry::PteFlags};
+use super::{ page_table::PageTable, page_table_entry::{ PteFlags, PTE_V, PTE_R, PTE_W, PTE_X, PTE_A, PTE_D } };
 use crate::memory::address::{VirtualAddress, PhysicalAddress, Addr};
 use crate::memory::{PageAllocator, RawPage};
 use crate::arch::riscv::qemu::layout::{ 
-    PGSIZE, MAXVA, UART0, VIRTIO0,
-    PLIC_BASE, KERNEL_BASE, PHYSTOP, TRAMPOLINE,
-    E1000_REGS, ECAM, VIRT_TEST, CLINT, TRAPFRAME
+    PGSIZE, PGSHIFT, UART0, VIRTIO0, VIRTIO1, RTC0,
+    PLIC_BASE, KERNEL_BASE, PHYSTOP, trampoline_va,
+    E1000_REGS, ECAM, set_page_levels, page_levels
 };
 use crate::arch::riscv::{ satp, sfence_vma };
 use crate::process::*;
@@ -13,6 +13,8 @@ use core::mem::{ size_of, align_of };
 
 
 pub static mut KERNEL_PAGETABLE:PageTable = PageTable::empty();
+/// Only used by probe_page_levels().
+static mut PROBE_PAGETABLE:PageTable = PageTable::empty();
 extern "C" {
     fn etext();
     fn trampoline();
@@ -27,28 +29,37 @@ pub unsafe fn kvm_init(){
     assert_eq!(size_of::<RawPage>(), size_of::<PageTable>());
     assert_eq!(align_of::<RawPage>(), align_of::<PageTable>());
 
+    // 必须在建立任何页表之前决定页表级数
+    set_page_levels(probe_page_levels());
     kernel_map();
 }
 
 /// Switch h/w page table register to the kernel's page table,
 /// and enable paging.
 pub unsafe fn kvm_init_hart() {
-    satp::write(KERNEL_PAGETABLE.as_satp());
+    // the kernel always uses ASID 0.
+    satp::write(KERNEL_PAGETABLE.as_satp(0));
     sfence_vma();
 }
 
 
+/// Find out whether the hart implements Sv48.
+/// Writing an unsupported MODE to satp has no effect, so try Sv48
+/// with a root whose first entry maps the low 512GiB identically
+/// (a level-3 leaf), and read the register back.
+/// Must be called while paging is still off.
+unsafe fn probe_page_levels() -> usize {
+    PROBE_PAGETABLE.entries[0].write(PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D);
+    satp::write(satp::SATP_SV48 | (PROBE_PAGETABLE.as_addr() >> PGSHIFT));
+    let levels = if satp::read() >> 60 == satp::SATP_SV48 >> 60 { 4 } else { 3 };
+    satp::write(0);
+    sfence_vma();
+    levels
+}
+
 /// Make a direct-map page table for the kernel.
 unsafe fn kernel_map() {
-    println!("kernel page map");
-    // map VIRT_TEST for shutdown or reboot
-    KERNEL_PAGETABLE.kernel_map(
-        VirtualAddress::new(VIRT_TEST),
-        PhysicalAddress::new(VIRT_TEST),
-        PGSIZE,
-        PteFlags::R | PteFlags::W
-    );
-
+    println!("kernel page map: Sv{}", 39 + 9 * (page_levels() - 3));
     // uart registers
     KERNEL_PAGETABLE.kernel_map(
         VirtualAddress::new(UART0), 
@@ -56,6 +67,13 @@ unsafe fn kernel_map() {
         PGSIZE, 
         PteFlags::R | PteFlags::W,
     );
+    // real-time clock, for rtc.rs
+    KERNEL_PAGETABLE.kernel_map(
+        VirtualAddress::new(RTC0), 
+        PhysicalAddress::new(RTC0), 
+        PGSIZE, 
+        PteFlags::R | PteFlags::W
+    );
     // virtio mmio disk interface
     KERNEL_PAGETABLE.kernel_map(
         VirtualAddress::new(VIRTIO0), 
@@ -63,6 +81,12 @@ unsafe fn kernel_map() {
         PGSIZE, 
         PteFlags::R | PteFlags::W
     );
+    KERNEL_PAGETABLE.kernel_map(
+        VirtualAddress::new(VIRTIO1), 
+        PhysicalAddress::new(VIRTIO1), 
+        PGSIZE, 
+        PteFlags::R | PteFlags::W
+    );
 
     // PCI-E ECAM (configuration space), for pci.rs
     KERNEL_PAGETABLE.kernel_map(
@@ -80,14 +104,6 @@ unsafe fn kernel_map() {
         PteFlags::R | PteFlags::W
     );
 
-    // CLINT
-    KERNEL_PAGETABLE.kernel_map(
-        VirtualAddress::new(CLINT),
-        PhysicalAddress::new(CLINT),
-        0x10000,
-        PteFlags::R | PteFlags::W
-    );
-
     // PLIC
     KERNEL_PAGETABLE.kernel_map(
         VirtualAddress::new(PLIC_BASE), 
@@ -115,7 +131,7 @@ unsafe fn kernel_map() {
     // map the trampoline for trap entry/exit
     // the highest virtual address in the kernel
     KERNEL_PAGETABLE.kernel_map(
-        VirtualAddress::new(TRAMPOLINE), 
+        VirtualAddress::new(trampoline_va()), 
         PhysicalAddress::new(trampoline as usize), 
         PGSIZE, 
         PteFlags::R | PteFlags::X
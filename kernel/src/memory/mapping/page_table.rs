@@ -5,10 +5,11 @@ use core::ptr::copy;
 use crate::trap::kernel_trap;
 use crate::arch::riscv::{ sfence_vma, satp };
 use crate::memory::mapping::page_table_entry::{ PageTableEntry, PteFlags};
-use crate::arch::riscv::qemu::layout::{ PGSIZE, MAXVA, PGSHIFT, TRAMPOLINE, TRAPFRAME };
+use crate::arch::riscv::qemu::layout::{ PGSIZE, max_va, PGSHIFT, trampoline_va, trapframe_va, KERNEL_BASE, user_top, page_levels };
 use crate::memory::{
     address::{ VirtualAddress, PhysicalAddress, Addr }, 
     kalloc::KERNEL_HEAP,
+    usercopy::{ user_copy, UserCopy },
     RawPage,
     PageAllocator
 };
@@ -16,6 +17,7 @@ use crate::misc::mem_copy;
 
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use super::*;
 
 #[derive(Debug, Clone )]
@@ -24,9 +26,6 @@ pub struct PageTable{
     pub entries: [PageTableEntry; PGSIZE/8],
 }
 
-static mut KERNEL_PAGETABLE:PageTable = PageTable::empty();
-
-
 impl PageTable{
     pub fn as_addr(&self) -> usize{
         self.entries.as_ptr() as usize
@@ -39,9 +38,10 @@ impl PageTable{
     }
 
     /// Convert the page table to be the usize
-    /// that can be written in satp register
-    pub fn as_satp(&self) -> usize {
-        satp::SATP_SV39 | ((self.entries.as_ptr() as usize) >> PGSHIFT)
+    /// that can be written in satp register,
+    /// tagged with the address space identifier asid.
+    pub fn as_satp(&self, asid: usize) -> usize {
+        satp::mode(page_levels()) | (asid << satp::ASID_SHIFT) | ((self.entries.as_ptr() as usize) >> PGSHIFT)
     }
 
     #[inline]
@@ -60,6 +60,7 @@ impl PageTable{
 
     /// Recursively free page-table pages.
     /// All leaf mappings must already have been removed.
+    /// The page of this table itself belongs to its owner. 
     pub fn free(&mut self) {
         // there are 2^9 = 512 PTEs in a pagetable
         for i in 0..self.entries.len() {
@@ -69,13 +70,13 @@ impl PageTable{
                 unsafe {
                     let child_pgt = &mut *(pte.as_pagetable());
                     child_pgt.free();
+                    RawPage::free(child_pgt.as_addr());
                 }
                 self.entries[i] = PageTableEntry::new(0);
             } else if pte.is_valid() {
                 panic!("pagetable free(): leaf not be removed");
             }
         }
-        drop(self);
     }
 
     /// Return the address of the PTE in page table pagetable
@@ -83,13 +84,15 @@ impl PageTable{
     /// create any required page-table pages.
     ///
     /// The risc-v Sv39 scheme has three levels of page-table
-    /// pages. A page-table page contains 512 64-bit PTEs.
-    /// A 64-bit virtual address is split into five fields:
+    /// pages, Sv48 adds a fourth one (see page_levels()).
+    /// A page-table page contains 512 64-bit PTEs.
+    /// A 64-bit virtual address is split into five fields (Sv39):
     ///   39..63 -- must be zero.
     ///   30..38 -- 9 bits of level-2 index.
     ///   21..29 -- 9 bits of level-1 index.
     ///   12..20 -- 9 bits of level-0 index.
     ///    0..11 -- 12 bits of byte offset within the page.
+    /// With Sv48 bits 39..47 hold the level-3 index.
     /// 
     /// Look up a virtual address, return the physical address,
     /// or 0 if not mapped.
@@ -99,11 +102,11 @@ impl PageTable{
         &mut self,
         va: VirtualAddress
     ) -> Option<&mut PageTableEntry> {
-        if va.as_usize() > MAXVA {
+        if va.as_usize() >= max_va() {
             return None
         }
         let mut page_table = self as *mut PageTable;
-        for level in (1..=2).rev() {
+        for level in (1..page_levels()).rev() {
             let pte = unsafe{ &mut (*page_table).entries[va.page_num(level)] };
             if pte.is_valid() {
                 page_table = pte.as_pagetable();
@@ -117,31 +120,99 @@ impl PageTable{
     }
 
     /// 将虚拟地址翻译成物理地址或者直接映射
+    /// Return None if a page-table page could not be allocated. 
     fn translate_or_alloc(
         &mut self,
         va: VirtualAddress
     ) -> Option<&mut PageTableEntry> {
         let mut pagetable = self as *mut PageTable;
         let real_addr:usize = va.as_usize();
-        if real_addr > MAXVA {
+        if real_addr >= max_va() {
             panic!("walk");
         }
-        for level in (1..=2).rev() {
+        for level in (1..page_levels()).rev() {
             let pte = unsafe{ &mut (*pagetable).entries[va.page_num(level)] };
             if pte.is_valid() {
                 pagetable = pte.as_pagetable();
     
             }else {
-                let zeroed_pgt: Box<PageTable> = unsafe{ 
-                    Box::new_zeroed().assume_init()
-                };
-                pagetable = Box::into_raw(zeroed_pgt);
+                pagetable = unsafe{ RawPage::try_new_zeroed()? } as *mut PageTable;
                 pte.0 = (((pagetable as usize) >> 12) << 10) | (PteFlags::V.bits());
             }
         }
         Some(unsafe{&mut (*pagetable).entries[va.page_num(0)]})
     }
 
+    /// Like pgt_translate, but for kernel pages too. 
+    pub fn kvm_pa(
+        &mut self,
+        va: VirtualAddress
+    ) -> Option<PhysicalAddress> {
+        match self.translate(va) {
+            Some(pte) if pte.is_valid() => Some(PhysicalAddress::new(pte.as_pagetable() as usize)),
+            _ => None
+        }
+    }
+
+    /// Make [KERNEL_BASE, KERNEL_BASE + 1GiB) of this user page table
+    /// translate like the kernel page table, by pointing at the kernel's
+    /// own lower level table, so that the kernel can run on it while
+    /// copying user memory (see usercopy.rs). 
+    /// Return false if a page-table page could not be allocated.
+    pub unsafe fn share_kernel(&mut self) -> bool {
+        let va = VirtualAddress::new(KERNEL_BASE);
+        let mut pagetable = self as *mut PageTable;
+        let mut kernel_pagetable = &mut kernel_map::KERNEL_PAGETABLE as *mut PageTable;
+        // level 2 entries cover 1GiB each.
+        for level in (3..page_levels()).rev() {
+            kernel_pagetable = (*kernel_pagetable).entries[va.page_num(level)].as_pagetable();
+            let pte = &mut (*pagetable).entries[va.page_num(level)];
+            if !pte.is_valid() {
+                let page = match RawPage::try_new_zeroed() {
+                    Some(page) => page,
+                    None => return false
+                };
+                pte.0 = ((page >> 12) << 10) | (PteFlags::V.bits());
+            }
+            pagetable = pte.as_pagetable();
+        }
+        (*pagetable).entries[va.page_num(2)] = (*kernel_pagetable).entries[va.page_num(2)];
+        true
+    }
+
+    /// Undo share_kernel(), before the table is freed or
+    /// user memory grows into [KERNEL_BASE, KERNEL_BASE + 1GiB).
+    pub fn unshare_kernel(&mut self) {
+        if self.shares_kernel() {
+            self.kernel_window().unwrap().write_zero();
+        }
+    }
+
+    /// Whether share_kernel() is in effect. Once user memory took
+    /// its place, the kernel can not run on this table anymore.
+    pub fn shares_kernel(&mut self) -> bool {
+        let kernel = unsafe{ kernel_map::KERNEL_PAGETABLE.kernel_window().map(|pte| pte.0) };
+        match self.kernel_window() {
+            Some(pte) => pte.is_valid() && Some(pte.0) == kernel,
+            None => false
+        }
+    }
+
+    /// The entry mapping [KERNEL_BASE, KERNEL_BASE + 1GiB),
+    /// None if a higher level table is missing.
+    fn kernel_window(&mut self) -> Option<&mut PageTableEntry> {
+        let va = VirtualAddress::new(KERNEL_BASE);
+        let mut pagetable = self as *mut PageTable;
+        for level in (3..page_levels()).rev() {
+            let pte = unsafe{ &mut (*pagetable).entries[va.page_num(level)] };
+            if !pte.is_valid() {
+                return None
+            }
+            pagetable = pte.as_pagetable();
+        }
+        Some(unsafe{ &mut (*pagetable).entries[va.page_num(2)] })
+    }
+
     /// Look up a virtual address, return the physical address,
     /// or 0 if not mapped.
     /// Can only be used to look up user pages.
@@ -151,7 +222,7 @@ impl PageTable{
         va: VirtualAddress
     ) -> Option<PhysicalAddress> {
         let addr = va.as_usize();
-        if addr > MAXVA {
+        if addr >= max_va() {
             return None
         }
         match self.translate(va){
@@ -172,6 +243,24 @@ impl PageTable{
     }
 
 
+    /// Whether every page of [start, start + len) is mapped for the user,
+    /// as pgt_translate() wants it; the stack guard page is not.
+    pub fn user_mapped(&mut self, start: usize, len: usize) -> bool {
+        let end = match start.checked_add(len) {
+            Some(end) => end,
+            None => return false
+        };
+        let mut va = VirtualAddress::new(start);
+        va.pg_round_down();
+        while va.as_usize() < end {
+            if self.pgt_translate(va).is_none() {
+                return false
+            }
+            va.add_page();
+        }
+        true
+    }
+
     /// Create PTEs for virtual addresses starting at va that refer to
     /// physical addresses starting at pa. va and size might not
     /// be page-aligned. Returns 0 on success, -1 if walk() couldn't
@@ -235,8 +324,9 @@ impl PageTable{
 
     /// Create an empty user page table.
     /// return None if out of memory
-    pub unsafe fn uvmcreate() -> Box<PageTable>{
-        Box::new_zeroed().assume_init()
+    pub unsafe fn uvmcreate() -> Option<Box<PageTable>>{
+        let addr = RawPage::try_new_zeroed()?;
+        Some(Box::from_raw(addr as *mut PageTable))
     }
 
     /// Load the user initcode into address 0 of pagetable
@@ -248,7 +338,6 @@ impl PageTable{
         }
 
         let mem = RawPage::new_zeroed() as *mut u8;
-        write_bytes(mem, 0, PGSIZE);
 
         self.map(
             VirtualAddress::new(0), 
@@ -271,12 +360,24 @@ impl PageTable{
         if new_size < old_size {
             return Some(old_size)
         }
+        if new_size > user_top() {
+            return None
+        }
+        // user pages there would go into the kernel's own table
+        if new_size > KERNEL_BASE {
+            self.unshare_kernel();
+        }
 
         old_size = page_round_up(old_size);
 
         for cur_size in (old_size..new_size).step_by(PGSIZE) {
-            let memory = RawPage::new_zeroed();
-            write_bytes(memory as *mut u8, 0, PGSIZE);
+            let memory = match RawPage::try_new_zeroed() {
+                Some(memory) => memory,
+                None => {
+                    self.uvm_dealloc(cur_size, old_size);
+                    return None
+                }
+            };
 
             if !self.map(
                 VirtualAddress::new(cur_size), 
@@ -284,7 +385,7 @@ impl PageTable{
                 PGSIZE, 
                 PteFlags::W | PteFlags::R | PteFlags::X | PteFlags::U
             ){
-                drop_in_place(memory as *mut RawPage);
+                RawPage::free(memory);
                 self.uvm_dealloc(cur_size, old_size);
                 return None
             }
@@ -306,7 +407,7 @@ impl PageTable{
                 true
             );
         }
-        drop(self);
+        self.free();
     }
 
 
@@ -360,9 +461,10 @@ impl PageTable{
                         panic!("uvm_unmap: not a leaf");
                     }
                     if free {
-                        let pa = pte.as_pagetable();
-                        unsafe{ drop_in_place(pa) };
+                        let pa = pte.as_pagetable() as usize;
+                        unsafe{ RawPage::free(pa) };
                     }
+                    pte.write_zero();
                 },
 
                 None => {
@@ -374,6 +476,30 @@ impl PageTable{
     }
 
 
+    /// Remove npages of mappings starting from va like uvm_unmap,
+    /// but hand the physical pages back instead of freeing them:
+    /// other cpus may still reach them through their TLBs.
+    pub fn uvm_take(
+        &mut self,
+        mut va: VirtualAddress,
+        npages: usize
+    ) -> Vec<usize> {
+        let mut pages = Vec::with_capacity(npages);
+        for _ in 0..npages {
+            let pte = self.translate(va).expect("uvm_take");
+            if !pte.is_valid() {
+                panic!("uvm_take: not mapped");
+            }
+            if pte.as_flags() == PteFlags::V.bits() {
+                panic!("uvm_take: not a leaf");
+            }
+            pages.push(pte.as_pagetable() as usize);
+            pte.write_zero();
+            va.add_page();
+        }
+        pages
+    }
+
     /// Given a parent process's page table, copy
     /// its memory into a child's page table.
     /// Copies both the page table and the
@@ -385,6 +511,9 @@ impl PageTable{
         child_pgt: &mut Self, 
         size: usize
     ) -> Result<(), &'static str> {
+        if size > KERNEL_BASE {
+            child_pgt.unshare_kernel();
+        }
         let mut va = VirtualAddress::new(0);
         while va.as_usize() != size {
             match self.translate(va) {
@@ -397,7 +526,17 @@ impl PageTable{
                     let flags = pte.as_flags();
                     let flags = PteFlags::new(flags);
 
-                    let allocated_pgt = &mut *(RawPage::new_zeroed() as *mut PageTable);
+                    let allocated_pgt = match RawPage::try_new_zeroed() {
+                        Some(page) => &mut *(page as *mut PageTable),
+                        None => {
+                            child_pgt.uvm_unmap(
+                                VirtualAddress::new(0), 
+                                va.as_usize() / PGSIZE, 
+                                true
+                            );
+                            return Err("uvmcopy: out of memory.")
+                        }
+                    };
                     allocated_pgt.write(& *page_table);
 
                     // println!("uvm_copy: va: 0x{:x}", va.as_usize());
@@ -407,7 +546,7 @@ impl PageTable{
                         PGSIZE,
                         flags
                     ) {
-                        drop(allocated_pgt);
+                        RawPage::free(allocated_pgt.as_addr());
                         child_pgt.uvm_unmap(
                             VirtualAddress::new(0), 
                             va.as_usize() / PGSIZE, 
@@ -437,6 +576,24 @@ impl PageTable{
         }
     }
 
+    /// The mapped pages of user memory [0, size), as runs of
+    /// pages with the same permissions: (start, end, flags).
+    /// The stack guard page shows up without PteFlags::U.
+    pub fn uvm_regions(&mut self, size: usize) -> Vec<(usize, usize, PteFlags)> {
+        let mut regions: Vec<(usize, usize, PteFlags)> = Vec::new();
+        for va in (0..size).step_by(PGSIZE) {
+            let flags = match self.translate(VirtualAddress::new(va)) {
+                Some(pte) if pte.is_valid() => PteFlags::from_bits_truncate(pte.as_flags()) - PteFlags::V,
+                _ => continue
+            };
+            match regions.last_mut() {
+                Some(last) if last.1 == va && last.2 == flags => last.1 = va + PGSIZE,
+                _ => regions.push((va, va + PGSIZE, flags))
+            }
+        }
+        regions
+    }
+
     /// Copy from kernel to user.
     /// Copy len bytes from src to virtual address dstva in a given page table.
     /// Return Result<(), Err>. 
@@ -446,6 +603,9 @@ impl PageTable{
         src: *const u8,
         mut len: usize 
     ) -> Result<(), &'static str> {
+        if let Some(ret) = user_copy(self, UserCopy::Out{ dst, src: src as usize, len }) {
+            return ret
+        }
         // 从内核空间向用户空间拷贝数据
         // 拷贝的起始地址为 dst, 拷贝的结束地址为 dst + len
         // 首先将目标地址转成虚拟地址并进行页对齐
@@ -458,7 +618,7 @@ impl PageTable{
         // 拷贝地址的偏移量，即已经拷贝了多少字节
         let mut offset = 0;
         // 将目标地址的虚拟地址翻译成物理地址
-        let mut pa = self.pgt_translate(va).unwrap();
+        let mut pa = self.pgt_translate(va).ok_or("copy_out: address not mapped")?;
         // 计算需要拷贝的虚拟地址的位置
         let mut dst_ptr = unsafe{
             pa.as_mut_ptr().offset((dst - va.as_usize()) as isize)
@@ -493,7 +653,7 @@ impl PageTable{
                 len -= count;
                 offset += count;
                 va.add_page();
-                pa = self.pgt_translate(va).unwrap();
+                pa = self.pgt_translate(va).ok_or("copy_out: address not mapped")?;
                 count = PGSIZE;
                 dst_ptr = pa.as_mut_ptr();
             }
@@ -510,14 +670,18 @@ impl PageTable{
         src: usize, 
         mut len: usize
     ) -> Result<(), &'static str> {
+        if let Some(ret) = user_copy(self, UserCopy::In{ dst: dst as usize, src, len }) {
+            return ret
+        }
+        let mut src = src;
         let mut va = VirtualAddress::new(src);
         va.pg_round_down();
         loop {
             // Get physical address by virtual address
-            let pa = self.pgt_translate(va).unwrap();
+            let pa = self.pgt_translate(va).ok_or("copy_in: address not mapped")?;
             // Get copy bytes of current page.
             let count = PGSIZE - (src - va.as_usize());
-            if len < count {
+            if len <= count {
                 mem_copy(
                     dst as usize, 
                     pa.as_usize() + ( src - va.as_usize() ), 
@@ -532,6 +696,7 @@ impl PageTable{
             );
 
             len -= count;
+            src += count;
             dst = unsafe{ dst.offset(count as isize) };
             va.add_page();
         }
@@ -547,13 +712,18 @@ impl PageTable{
         src: usize,
         mut max: usize
     ) -> Result<(),&'static str> {
+        if let Some(ret) = user_copy(self, UserCopy::InStr{ dst: dst as usize, src, max }) {
+            return ret
+        }
+        let mut dst = dst;
+        let mut src = src;
         // 将 src 作为虚拟地址
         let mut va = VirtualAddress::new(src as usize);
         // 将虚拟地址进行页对齐
         va.pg_round_down();
         loop {
             // 将用户态的虚拟地址转成物理地址
-            let pa = self.pgt_translate(va).unwrap();
+            let pa = self.pgt_translate(va).ok_or("copy_in_str: address not mapped")?;
             // 计算该页所要读取的字节数
             let count = PGSIZE - (src - va.as_usize());
             let s = (pa.as_usize() + (src - va.as_usize())) as *const u8;
@@ -583,6 +753,8 @@ impl PageTable{
                 }
             }
             max -= count;
+            src += count;
+            dst = unsafe{ dst.add(count) };
             va.add_page();
         }
     }
@@ -590,15 +762,21 @@ impl PageTable{
 
     /// Free a process's page table, and free the
     /// physical memory it refers to.
-    pub fn proc_free_pagetable(&mut self, size: usize) {
+    pub fn proc_free_pagetable(&mut self, size: usize, kstack: usize) {
+        self.unshare_kernel();
+        self.uvm_unmap(
+            VirtualAddress::new(kstack),
+            4,
+            false
+        );
         self.uvm_unmap(
-            VirtualAddress::new(TRAMPOLINE ), 
+            VirtualAddress::new(trampoline_va()), 
             1, 
             false
         );
 
         self.uvm_unmap(
-            VirtualAddress::new(TRAPFRAME),
+            VirtualAddress::new(trapframe_va()),
             1,
             false
         );
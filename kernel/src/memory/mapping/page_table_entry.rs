@@ -9,6 +9,8 @@ pub const PTE_R:usize = 1 << 1;
 pub const PTE_W:usize = 1 << 2;
 pub const PTE_X:usize = 1 << 3;
 pub const PTE_U:usize = 1 << 4; // 1 -> user can access
+pub const PTE_A:usize = 1 << 6; // accessed
+pub const PTE_D:usize = 1 << 7; // dirty
 
 #[derive(Debug, Clone, Copy)]
 pub struct PageTableEntry(pub usize);
@@ -58,7 +60,7 @@ impl PageTableEntry{
 
     #[inline]
     pub fn is_user(&self) -> bool {
-        (self.0 & (PteFlags::V.bits())) > 0
+        (self.0 & (PteFlags::U.bits())) > 0
     }
 
     #[inline] 
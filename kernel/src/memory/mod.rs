@@ -1,6 +1,10 @@
 pub mod kalloc;
 pub mod mapping;
 pub mod address;
+pub mod asid;
+pub mod usercopy;
+#[cfg(feature = "kmem_debug")]
+pub mod kdebug;
 
 use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut, self};
 
@@ -12,6 +16,8 @@ use crate::{arch::riscv::qemu::layout::PGSIZE, process::{ CPU_MANAGER }};
 use crate::misc::mem_copy;
 
 use alloc::{boxed::Box, vec};
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
 
 pub trait PageAllocator: Sized {
     unsafe fn new_zeroed() -> usize {
@@ -19,6 +25,22 @@ pub trait PageAllocator: Sized {
         let ptr = Box::into_raw(boxed_page) as usize;
         ptr
     }
+
+    /// Like `new_zeroed`, but return None instead of
+    /// panicking when the kernel heap runs out of memory. 
+    unsafe fn try_new_zeroed() -> Option<usize> {
+        let ptr = alloc_zeroed(Layout::new::<Self>());
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ptr as usize)
+        }
+    }
+
+    /// Give back memory got from `new_zeroed` or `try_new_zeroed`. 
+    unsafe fn free(addr: usize) {
+        drop(Box::from_raw(addr as *mut Self));
+    }
 }
 
 #[repr(C, align(4096))]
@@ -0,0 +1,128 @@
+//! Direct access to user memory.
+//!
+//! Instead of translating every user page in software, the kernel loads
+//! the page table of the current process, sets sstatus.SUM and copies with
+//! ordinary loads and stores (asm/usercopy.S). User page tables share the
+//! kernel's mapping of RAM and map the process's kernel stack, so the kernel
+//! keeps running while the user table is loaded; the process's ASID makes
+//! the switch free of TLB flushes, so the fast path is only taken when
+//! ASIDs are available. A fault on a bad user address is resumed through
+//! the exception table and the copy fails with EFAULT.
+
+use core::mem::size_of;
+
+use crate::arch::riscv::{ satp, sstatus, sfence_vma };
+use crate::process::{ CPU_MANAGER, cpuid, push_off, pop_off };
+use super::mapping::{ page_table::PageTable, kernel_map::KERNEL_PAGETABLE };
+use super::asid::{ asid_enabled, asid_activate };
+
+extern "C" {
+    fn __user_copy(dst: usize, src: usize, len: usize) -> isize;
+    fn __user_copy_str(dst: usize, src: usize, max: usize) -> isize;
+    fn __start_ex_table();
+    fn __stop_ex_table();
+}
+
+#[repr(C)]
+struct ExTableEntry {
+    insn: usize,
+    fixup: usize
+}
+
+/// Where to resume a faulting access to user memory at pc, if it is one.
+pub fn search_exception_table(pc: usize) -> Option<usize> {
+    let start = __start_ex_table as usize as *const ExTableEntry;
+    let count = (__stop_ex_table as usize - __start_ex_table as usize) / size_of::<ExTableEntry>();
+    let table = unsafe{ core::slice::from_raw_parts(start, count) };
+    table.iter().find(|entry| entry.insn == pc).map(|entry| entry.fixup)
+}
+
+/// Called at the top of kernel_trap(): a fault which is not resumed must be
+/// handled with the kernel page table, which maps the devices.
+pub unsafe fn leave_user_space() {
+    let kernel_satp = KERNEL_PAGETABLE.as_satp(0);
+    if satp::read() != kernel_satp {
+        sstatus::sum_off();
+        satp::write(kernel_satp);
+    }
+}
+
+pub enum UserCopy {
+    /// [dst, dst + len) in user space is copied from src in the kernel
+    Out { dst: usize, src: usize, len: usize },
+    /// [src, src + len) in user space is copied to dst in the kernel
+    In { dst: usize, src: usize, len: usize },
+    /// a string of at most max bytes in user space is copied to dst
+    InStr { dst: usize, src: usize, max: usize }
+}
+
+/// Try to copy with the page table of the current process loaded.
+/// Return None if page_table does not belong to the running process
+/// or the fast path can not be used, the caller then walks the table.
+pub fn user_copy(page_table: &PageTable, copy: UserCopy) -> Option<Result<(), &'static str>> {
+    if !asid_enabled() {
+        return None
+    }
+    push_off();
+    let ret = unsafe{ user_copy_locked(page_table, copy) };
+    pop_off();
+    ret
+}
+
+/// Interrupts must be off: nothing but this copy may run
+/// while the user page table is loaded.
+unsafe fn user_copy_locked(page_table: &PageTable, copy: UserCopy) -> Option<Result<(), &'static str>> {
+    let my_cpu = CPU_MANAGER.mycpu();
+    let pdata = &mut *my_cpu.process?.as_ref().data.get();
+    let table = match pdata.pagetable.as_mut() {
+        Some(table) if table.as_addr() == page_table.as_addr() => table,
+        _ => return None
+    };
+
+    let (start, len) = match copy {
+        UserCopy::Out{ dst, len, .. } => (dst, len),
+        UserCopy::In{ src, len, .. } => (src, len),
+        UserCopy::InStr{ src, max, .. } => (src, core::cmp::min(max, pdata.size.saturating_sub(src)))
+    };
+    // the user table also maps the kernel, keep to user memory,
+    // which must not have taken the place of the kernel's RAM.
+    match start.checked_add(len) {
+        Some(end) if end <= pdata.size => {},
+        _ => return Some(Err("user_copy: bad address"))
+    }
+    // the stack guard page is mapped without PTE_U, which SUM ignores:
+    // let the page walk refuse such ranges.
+    if !table.shares_kernel() || !table.user_mapped(start, len) {
+        return None
+    }
+
+    let flush = asid_activate(&mut pdata.asid, &mut my_cpu.asid_generation);
+    pdata.tlb_harts |= 1 << cpuid();
+    satp::write(page_table.as_satp(pdata.asid.as_usize()));
+    if flush {
+        sfence_vma();
+    }
+    sstatus::sum_on();
+
+    let ret = match copy {
+        UserCopy::Out{ dst, src, len } => match __user_copy(dst, src, len) {
+            0 => Ok(()),
+            _ => Err("copy_out: bad address")
+        },
+        UserCopy::In{ dst, src, len } => match __user_copy(dst, src, len) {
+            0 => Ok(()),
+            _ => Err("copy_in: bad address")
+        },
+        UserCopy::InStr{ dst, src, max } => match __user_copy_str(dst, src, len) {
+            n if n > 0 => Ok(()),
+            // no NUL within max: truncate, as the page walk did.
+            0 if len == max => Ok(()),
+            0 => Err("copy_in_str: bad address"),
+            _ => Err("copy_in_str: bad address")
+        }
+    };
+
+    sstatus::sum_off();
+    satp::write(KERNEL_PAGETABLE.as_satp(0));
+    Some(ret)
+}
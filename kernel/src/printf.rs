@@ -24,8 +24,8 @@ pub fn console_ptr(c: u8) {
 /// use [`core::fmt::Write`] trait's [`console::Stdout`]
 #[macro_export]
 macro_rules! print {
-    (fmt:literal$(, $($arg: tt)+)?) => {
-        $crate::printf::console_putchar(format_args!($fmt(, $($arg)+)?));
+    ($fmt:literal$(, $($arg: tt)+)?) => {
+        $crate::printf::_print(format_args!($fmt $(,$($arg)+)?));
     }
 }
 
@@ -39,8 +39,8 @@ macro_rules! println {
 #[panic_handler]
 fn panic(info: &PanicInfo<'_>) -> ! {
     println!("\x1b[1;31mpanic: '{}'\x1b[0m", info);
-    shutdown();
-    loop {}
+    crate::backtrace::print_backtrace();
+    shutdown()
 }
 
 #[no_mangle]
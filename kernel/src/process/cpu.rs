@@ -11,7 +11,88 @@ pub struct CPU {
     pub process: Option<NonNull<Process>>, // The process running on this cpu, or null.
     pub context: Context, // swtch() here to enter scheduler().
     pub noff: usize, // Depth of push_off() nesting.
-    pub intena: usize // Were interrupts enabled before push_off()?
+    pub intena: usize, // Were interrupts enabled before push_off()?
+    pub asid_generation: usize, // Last ASID generation this cpu flushed its TLB for.
+    pub switch_stats: SwitchStats,
+    pub held_locks: HeldLocks // Spinlocks this cpu holds, for the watchdog.
+}
+
+const NHELD: usize = 16;
+
+/// Spinlocks held by one cpu, innermost last.
+pub struct HeldLocks {
+    locks: [(usize, &'static str); NHELD],
+    depth: usize
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            locks: [(0, ""); NHELD],
+            depth: 0
+        }
+    }
+
+    pub fn push(&mut self, lock: usize, name: &'static str) {
+        if self.depth < NHELD {
+            self.locks[self.depth] = (lock, name);
+        }
+        self.depth += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(usize, &'static str)> {
+        self.locks[..core::cmp::min(self.depth, NHELD)].iter()
+    }
+
+    /// Locks need not be released in order.
+    pub fn pop(&mut self, lock: usize) {
+        let depth = core::cmp::min(self.depth, NHELD);
+        if let Some(i) = self.locks[..depth].iter().rposition(|&(l, _)| l == lock) {
+            self.locks.copy_within(i + 1..depth, i);
+        }
+        self.depth = self.depth.saturating_sub(1);
+    }
+}
+
+/// How much leaving and re-entering user space costs on one cpu.
+#[derive(Clone, Copy)]
+pub struct SwitchStats {
+    pub user_returns: usize, // Returns to user space.
+    pub satp_switches: usize, // Returns to another address space than the last one.
+    pub full_flushes: usize, // Returns which flushed the whole TLB.
+    pub traps: usize, // Traps from user space measured in trap_cycles.
+    pub trap_cycles: usize, // Cycles from user_trap() to user_trap_ret().
+    last_satp: usize
+}
+
+impl SwitchStats {
+    pub const fn new() -> Self {
+        Self {
+            user_returns: 0,
+            satp_switches: 0,
+            full_flushes: 0,
+            traps: 0,
+            trap_cycles: 0,
+            last_satp: 0
+        }
+    }
+
+    /// Account one return to user space with satp.
+    /// cycles is the time spent in the kernel since the trap, if known.
+    pub fn record(&mut self, satp: usize, flush: bool, cycles: Option<usize>) {
+        self.user_returns += 1;
+        if satp != self.last_satp {
+            self.satp_switches += 1;
+            self.last_satp = satp;
+        }
+        if flush {
+            self.full_flushes += 1;
+        }
+        if let Some(cycles) = cycles {
+            self.traps += 1;
+            self.trap_cycles += cycles;
+        }
+    }
 }
 
 pub struct CPUManager{
@@ -99,7 +180,30 @@ impl CPUManager{
         }
     }
 
-    pub fn alloc_fd(&mut self, file:&VFile) -> Result<usize, &'static str> {
+    /// Print the context switch counters of every cpu.
+    pub fn switch_dump(&self) {
+        for (id, cpu) in self.cpus.iter().enumerate() {
+            let stats = &cpu.switch_stats;
+            if stats.user_returns == 0 { continue; }
+            println!(
+                "hart {}: {} user returns, {} address space switches, {} full tlb flushes, {} cycles per trap",
+                id, stats.user_returns, stats.satp_switches, stats.full_flushes,
+                stats.trap_cycles / core::cmp::max(stats.traps, 1)
+            );
+        }
+    }
+
+    /// Print the spinlocks held by a cpu. The cpu may be running,
+    /// so this is only a snapshot.
+    pub fn held_locks_dump(&self, id: usize) {
+        let held = &self.cpus[id].held_locks;
+        println!("hart {} holds {} spinlocks:", id, held.depth);
+        for &(lock, name) in held.locks[..core::cmp::min(held.depth, NHELD)].iter() {
+            println!("  {} ({:#x})", name, lock);
+        }
+    }
+
+    pub fn alloc_fd(&mut self, file: VFile) -> Result<usize, &'static str> {
         let proc = unsafe{ self.myproc().ok_or("Fail to find current process")? };
         proc.fd_alloc(file)
     }
@@ -119,7 +223,10 @@ impl CPU{
             process:None,
             context:Context::new(),
             noff:0,
-            intena:0
+            intena:0,
+            asid_generation: 0,
+            switch_stats: SwitchStats::new(),
+            held_locks: HeldLocks::new()
         }
     }
 
@@ -170,6 +277,12 @@ impl CPU{
             panic!("sched: interruptible");
         }
 
+        // a trap that gives up the cpu goes untimed, it would count
+        // the time off it and end on the cycle counter of another hart
+        if let Some(process) = self.process {
+            (*(*process.as_ptr()).data.get()).trap_enter = 0;
+        }
+
         let intena = self.intena;
         // println!("[Kernel] switch");
         // println!("[Kernel] old_context: 0x{:x}, new_context: 0x{:x}", ctx as usize, &mut self.context as *mut Context as usize);
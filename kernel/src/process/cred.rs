@@ -0,0 +1,121 @@
+//! Credentials of a process.
+//!
+//! As in Unix, the real ids say who started the process, the effective
+//! ids are checked against inodes and the saved ids keep what a setuid
+//! program was started as, so it can switch back after dropping them.
+//! uid 0 is root and may do anything.
+
+use crate::arch::riscv::qemu::param::NGROUPS;
+use crate::syscall::Errno;
+
+use super::CPU_MANAGER;
+
+#[derive(Clone, Copy)]
+pub struct Cred {
+    pub uid: u32,  // real user id
+    pub euid: u32, // effective user id
+    pub suid: u32, // saved user id
+    pub gid: u32,
+    pub egid: u32,
+    pub sgid: u32,
+    groups: [u32; NGROUPS], // supplementary groups
+    ngroups: usize
+}
+
+impl Cred {
+    /// Credentials of init, root with no supplementary groups. 
+    pub const fn root() -> Self {
+        Self {
+            uid: 0,
+            euid: 0,
+            suid: 0,
+            gid: 0,
+            egid: 0,
+            sgid: 0,
+            groups: [0; NGROUPS],
+            ngroups: 0
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// The supplementary groups. 
+    pub fn groups(&self) -> &[u32] {
+        &self.groups[..self.ngroups]
+    }
+
+    /// Is gid the effective or a supplementary group? 
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.egid == gid || self.groups[..self.ngroups].contains(&gid)
+    }
+
+    /// Root sets all three user ids, others may only set the
+    /// effective one back to their real or saved id. 
+    pub fn set_uid(&mut self, uid: u32) -> Result<(), Errno> {
+        if self.is_root() {
+            self.uid = uid;
+            self.euid = uid;
+            self.suid = uid;
+        } else if uid == self.uid || uid == self.suid {
+            self.euid = uid;
+        } else {
+            return Err(Errno::EPERM)
+        }
+        Ok(())
+    }
+
+    /// Same as `set_uid`, for the group ids. 
+    pub fn set_gid(&mut self, gid: u32) -> Result<(), Errno> {
+        if self.is_root() {
+            self.gid = gid;
+            self.egid = gid;
+            self.sgid = gid;
+        } else if gid == self.gid || gid == self.sgid {
+            self.egid = gid;
+        } else {
+            return Err(Errno::EPERM)
+        }
+        Ok(())
+    }
+
+    /// Replace the supplementary groups, root only. 
+    pub fn set_groups(&mut self, groups: &[u32]) -> Result<(), Errno> {
+        if !self.is_root() {
+            return Err(Errno::EPERM)
+        }
+        if groups.len() > NGROUPS {
+            return Err(Errno::EINVAL)
+        }
+        self.groups[..groups.len()].copy_from_slice(groups);
+        self.ngroups = groups.len();
+        Ok(())
+    }
+
+    /// Take on the owner of a setuid/setgid program being executed. 
+    /// The saved ids follow the effective ones either way. 
+    pub fn exec(&mut self, setuid: Option<u32>, setgid: Option<u32>) {
+        if let Some(uid) = setuid {
+            self.euid = uid;
+        }
+        if let Some(gid) = setgid {
+            self.egid = gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+
+    /// May a process with these credentials signal one with target? 
+    pub fn may_kill(&self, target: &Cred) -> bool {
+        self.is_root() ||
+        self.uid == target.uid || self.uid == target.suid ||
+        self.euid == target.uid || self.euid == target.suid
+    }
+}
+
+/// Credentials of the current process. 
+pub fn current_cred() -> Cred {
+    let p = unsafe{ CPU_MANAGER.myproc().unwrap() };
+    unsafe{ (*p.data.get()).cred }
+}
@@ -1,20 +1,20 @@
-use crate::lock::sleeplock::SleepLockGuard;
 use crate::memory::{Addr, PageTable, VirtualAddress, page_round_up};
-use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::arch::riscv::qemu::layout::{ PGSIZE, user_top };
 use crate::arch::riscv::qemu::param::MAXARG;
-use crate::fs::{ICACHE, Inode};
-use crate::fs::LOG;
-use crate::fs::InodeData;
+use crate::arch::riscv::qemu::fs::{ S_ISUID, S_ISGID };
+use crate::fs::{InodeType, VNode, Stat, MAY_EXEC, namei, permission};
 use crate::misc::str_len;
 
 use core::mem::size_of;
 use core::ops::IndexMut;
 
-use super::CPU_MANAGER;
+use super::{CPU_MANAGER, PROC_MANAGER};
+use crate::syscall::Errno;
 use super::Process;
 
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 const ELF_MAGIC: u32 = 0x464C457F; // elf magic number
 
@@ -69,7 +69,7 @@ pub struct ProgHeader {
 fn load_seg(
     page_table: &mut Box<PageTable>, 
     va: usize, 
-    inode_data: &mut SleepLockGuard<InodeData>,
+    node: &dyn VNode,
     offset: usize, 
     size: usize
 ) -> Result<(), &'static str> {
@@ -92,11 +92,11 @@ fn load_seg(
                     count = PGSIZE;
                 }
 
-                if inode_data.read(
+                if node.read(
                     false, 
                     pa.as_usize(), 
-                    (offset + copy_size) as u32, 
-                    count as u32
+                    offset + copy_size, 
+                    count
                 ).is_err() {
                     return Err("load_seg: Fail to read inode")
                 }
@@ -118,7 +118,7 @@ fn load_seg(
 pub unsafe fn exec(
     path: &str, 
     argv: &[*const u8]
-) -> Result<usize, &'static str> {
+) -> Result<usize, Errno> {
     let elf = Box::<ElfHeader>::new_zeroed().assume_init();
     let ph = Box::<ProgHeader>::new_zeroed().assume_init();
     let mut page_table: Box<PageTable>;
@@ -127,65 +127,65 @@ pub unsafe fn exec(
     let mut sp: usize;
     let stack_base: usize;
     let mut user_stack: [usize; MAXARG] = [0;MAXARG];
-    let inode: Inode;
 
-    LOG.begin_op();
-
-    // Get current inode by path
-    inode = ICACHE.namei(path.as_bytes()).ok_or("Fail to find executable file")?;
-
-    // Get inode data by sleeplock
-    let mut inode_guard = inode.lock();
+    // Get current node by path
+    let node = namei::lookup(path.as_bytes(), true)?;
+    let mut stat = Stat::new();
+    node.node.stat(&mut stat);
+    if stat.itype != InodeType::File {
+        return Err(Errno::EACCES)
+    }
+    permission(&*node.node, MAY_EXEC)?;
+    // ids of the owner, taken on if the program is setuid/setgid
+    let setuid = if stat.mode & S_ISUID != 0 { Some(stat.uid) } else { None };
+    let setgid = if stat.mode & S_ISGID != 0 { Some(stat.gid) } else { None };
            
     // Check ELF header
-    if inode_guard.read(
+    if node.node.read(
         false, 
         &*elf as *const ElfHeader as usize, 
         0, 
-        size_of::<ElfHeader>() as u32
+        size_of::<ElfHeader>()
     ).is_err() {
-        drop(inode_guard);
-        LOG.end_op();
-        return Err("exec: Fail to read elf header.")
+        return Err(Errno::ENOEXEC)
     }
 
     // println!("[Debug] 检查魔数");
     if elf.magic != ELF_MAGIC {
         // println!("[Debug] 魔数错误, 为0x{:x}, 应为0x{:x}", elf.magic, ELF_MAGIC);
-        drop(inode_guard);
-        LOG.end_op();
-        return Err("exec: Elf magic number is wrong.")
+        return Err(Errno::ENOEXEC)
     }
 
     let my_proc = CPU_MANAGER.myproc().unwrap();
-        page_table = my_proc
-            .proc_pagetable()
-            .expect("Fail to alloc pagetable for current process.");
+    let kstack = (*my_proc.data.get()).kstack;
+        page_table = match my_proc.proc_pagetable() {
+            Some(page_table) => page_table,
+            None => {
+                PROC_MANAGER.oom_kill();
+                return Err(Errno::ENOMEM)
+            }
+        };
         
-        let ph_size = size_of::<ProgHeader>() as u32;
+        let ph_size = size_of::<ProgHeader>();
         // Load program into memeory. 
         let mut off = elf.phoff;
         for _ in 0..elf.phnum {
-            if inode_guard.read(
+            if node.node.read(
                 false, 
                 &*ph as *const ProgHeader as usize, 
-                off as u32, 
+                off, 
                 ph_size
             ).is_ok() {
                 if ph.prog_type != ELF_PROG_LOAD { continue; }
                 // Check program header size
                 if ph.mem_size < ph.file_size {
-                    page_table.proc_free_pagetable(size);
-                    drop(inode_guard);
-                    LOG.end_op();
-                    return Err("exec: memory size is less than file size.")
+                    page_table.proc_free_pagetable(size, kstack);
+                    return Err(Errno::ENOEXEC)
                 }
 
-                if ph.vaddr + ph.mem_size < ph.vaddr {
-                    page_table.proc_free_pagetable(size);
-                    drop(inode_guard);
-                    LOG.end_op();
-                    return Err("exec: vaddr + mem_size < vaddr")
+                if ph.vaddr + ph.mem_size < ph.vaddr || ph.vaddr + ph.mem_size > user_top() {
+                    page_table.proc_free_pagetable(size, kstack);
+                    return Err(Errno::ENOEXEC)
                 }
                 
                 // alloc memory for load program
@@ -193,10 +193,9 @@ pub unsafe fn exec(
                 .uvm_alloc(size, ph.vaddr + ph.mem_size)
                 .take() {
                     None => {
-                        page_table.proc_free_pagetable(size);
-                        drop(inode_guard);
-                        LOG.end_op();
-                        return Err("exec: Fail to uvmalloc.")
+                        page_table.proc_free_pagetable(size, kstack);
+                        PROC_MANAGER.oom_kill();
+                        return Err(Errno::ENOMEM)
                     }
 
                     Some(new_size) => {
@@ -205,9 +204,8 @@ pub unsafe fn exec(
                 }
 
                 if ph.vaddr % PGSIZE != 0 {
-                    page_table.proc_free_pagetable(size);
-                    LOG.end_op();
-                    return Err("exec: Programe Header must be integer multiple of PGSIZE. ")
+                    page_table.proc_free_pagetable(size, kstack);
+                    return Err(Errno::ENOEXEC)
                 }
 
                 // load segement information
@@ -215,29 +213,24 @@ pub unsafe fn exec(
                 if load_seg(
                     &mut page_table, 
                     ph.vaddr, 
-                    &mut inode_guard, 
+                    &*node.node, 
                     ph.off, 
                     ph.file_size
                 ).is_err() {
-                    page_table.proc_free_pagetable(size);
-                    drop(inode_guard);
-                    LOG.end_op();
-                    return Err("exec: Fail to load segment. ")
+                    page_table.proc_free_pagetable(size, kstack);
+                    return Err(Errno::EIO)
                 }
                 
 
             } else {
-                drop(page_table);
-                drop(inode_guard);
-                LOG.end_op();
-                return Err("exec: Fail to read from inode")
+                page_table.proc_free_pagetable(size, kstack);
+                return Err(Errno::EIO)
             }
             off += size_of::<ProgHeader>();
         }
         // println!("[Debug] 完成加载程序");
+        drop(node);
 
-        drop(inode_guard);
-        LOG.end_op();
 
         p = CPU_MANAGER.myproc().unwrap();
         let old_size = (&*p.data.get()).size;
@@ -248,8 +241,9 @@ pub unsafe fn exec(
         match page_table
                 .uvm_alloc(size, size + 2 * PGSIZE) {
             None => {
-                page_table.proc_free_pagetable(size);
-                return Err("exec: Fail to uvmalloc")
+                page_table.proc_free_pagetable(size, kstack);
+                PROC_MANAGER.oom_kill();
+                return Err(Errno::ENOMEM)
             }
 
             Some(new_size) => {
@@ -265,18 +259,19 @@ pub unsafe fn exec(
 
         // Push argument strings, prepare rest of stack in ustack. 
         let mut argc = 0;
+        let mut cmdline: Vec<u8> = Vec::new();
         loop {
             if argv[argc] as usize == 0x0 { break; }
             if argc >= MAXARG {
-                page_table.proc_free_pagetable(size);
-                return Err("exec: argc is more than MAXARG. ")
+                page_table.proc_free_pagetable(size, kstack);
+                return Err(Errno::E2BIG)
             }
             sp -= str_len(argv[argc]) + 1;
             // riscv sp must be 16-byte aligned. 
             sp = align_sp(sp);
             if sp < stack_base {
-                drop(page_table);
-                return Err("User Stack Bomb!")
+                page_table.proc_free_pagetable(size, kstack);
+                return Err(Errno::E2BIG)
             }
             
             // Copy arguments into stack top
@@ -289,10 +284,11 @@ pub unsafe fn exec(
                     ).as_ptr(),
                     str_len(argv[argc]) + 1,
                 ).is_err() {
-                    page_table.proc_free_pagetable(size);
-                    return Err("exec: Fail to copy out.") 
+                    page_table.proc_free_pagetable(size, kstack);
+                    return Err(Errno::EFAULT)
                 }
             user_stack[argc] = sp;
+            cmdline.extend_from_slice(core::slice::from_raw_parts(argv[argc], str_len(argv[argc]) + 1));
             argc += 1;
         }
     user_stack[argc] = 0;
@@ -301,8 +297,8 @@ pub unsafe fn exec(
     sp -= (argc + 1) * size_of::<usize>();
     sp = align_sp(sp);
     if sp < stack_base {
-        LOG.end_op();
-        page_table.proc_free_pagetable(size);
+        page_table.proc_free_pagetable(size, kstack);
+        return Err(Errno::E2BIG)
     }
 
     if page_table
@@ -314,8 +310,8 @@ pub unsafe fn exec(
         ).as_ptr(),
             (argc + 1)*size_of::<usize>()
     ).is_err() {
-        page_table.proc_free_pagetable(size);
-        return Err("exec: Fail to copy out.")
+        page_table.proc_free_pagetable(size, kstack);
+        return Err(Errno::EFAULT)
     }
 
     // arguments to user main(argc, argv)
@@ -336,11 +332,14 @@ pub unsafe fn exec(
     core::ptr::copy(exec_name.as_ptr(), &mut pdata.name as *mut u8, 16);
 
     // Commit to user image.
-    let old_pgt = pdata.pagetable.as_mut().take().unwrap();
-    old_pgt.proc_free_pagetable(old_size);
+    let old_pgt = pdata.pagetable.as_mut().unwrap();
+    old_pgt.proc_free_pagetable(old_size, kstack);
 
-    pdata.pagetable = Some(page_table);
+    pdata.set_pagetable(Some(page_table));
     pdata.size = size;
+    pdata.cmdline = cmdline;
+    pdata.cred.exec(setuid, setgid);
+    p.publish();
     // initial program counter = main
     trapframe.epc = elf.entry;
     // initial stack pointer
@@ -1,18 +1,22 @@
 use array_macro::array;
 use core::cell::RefCell;
 use core::str::{from_utf8, from_utf8_unchecked};
-use core::{mem::size_of_val, ptr::NonNull};
+use core::{mem::{ size_of, size_of_val }, ptr::NonNull};
 use core::ops::{ DerefMut };
 use super::*;
-use crate::arch::riscv::qemu::fs::ROOTIPATH;
 use crate::arch::riscv::qemu::{
     param::NPROC,
-    layout::{ PGSIZE, TRAMPOLINE }
+    layout::{ PGSIZE, trampoline_va, KSTACK_PAGES }
 };
-use crate::fs::VFile;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::fs::{ PinnedNode, VFile };
 use crate::lock::spinlock::{ Spinlock, SpinlockGuard };
+use crate::lock::rwlock::RwSpinlock;
 use crate::arch::riscv::register::sstatus::intr_on;
 use crate::memory::*;
+use crate::syscall::Errno;
+use crate::watchdog;
 
 pub struct ProcManager {
     proc: [Process; NPROC],
@@ -23,10 +27,38 @@ pub struct ProcManager {
     /// memory model when using p->parent.
     /// must be acquired before any p->lock.
     pub wait_lock: Spinlock<()>,
+    /// pid of the process in each slot, 0 when unused, so that
+    /// finding a process by pid does not lock every process. 
+    pids: RwSpinlock<[usize; NPROC]>,
 }
 
 pub static mut PROC_MANAGER:ProcManager = ProcManager::new();
 
+/// How often `proc_info` waits for a process running on
+/// another cpu to stop before giving up. 
+const PROC_INFO_TRIES: usize = 10;
+
+/// A look at a process, taken under its p->lock, see `proc_info`. 
+pub struct ProcInfo {
+    pub pid: usize,
+    pub ppid: usize,
+    pub state: ProcState,
+    pub killed: bool,
+    pub name: [u8; 16],
+    pub cred: Cred,
+    pub size: usize,
+    pub detail: Option<ProcDetail>
+}
+
+/// What only the process itself changes, without a lock, so it is
+/// only looked at while the process is not running. 
+pub struct ProcDetail {
+    pub cmdline: Vec<u8>,
+    pub files: Vec<(usize, Arc<VFile>)>,
+    pub cwd: Option<PinnedNode>,
+    pub regions: Vec<(usize, usize, PteFlags)>
+}
+
 
 impl ProcManager{
     pub const fn new() -> Self {
@@ -35,6 +67,7 @@ impl ProcManager{
             init_proc: 0 as *mut Process,
             pid_lock: Spinlock::new(0, "pid_lock"),
             wait_lock: Spinlock::new((), "wait_lock"),
+            pids: RwSpinlock::new([0; NPROC], "pids"),
         }
     }
     
@@ -101,8 +134,10 @@ impl ProcManager{
 
         let init_name = b"initname\0";
         pdata.set_name(init_name);
-        // Set init process's directory
-        pdata.cwd = Some(ICACHE.namei(&ROOTIPATH).expect("cannot find root inode"));
+        p.publish();
+        // Set init process's directory, None is the root, 
+        // which is only mounted in fork_ret
+        pdata.cwd = None;
         
         let mut guard = p.meta.acquire();
         guard.set_state(ProcState::RUNNABLE);
@@ -115,53 +150,51 @@ impl ProcManager{
 
     /// Look in the process table for an UNUSED proc.
     /// If found, initialize state required to run in the kernel,
-    /// and return p.acquire() held.
-    /// If there are a free procs, or a memory allocation fails, return 0. 
-
-    /// WARNING: possible error occurs here.
-    pub fn alloc_proc(&mut self) -> Option<&mut Process> {
+    /// and return it with p.acquire() not held. 
+    /// Fails with EAGAIN if there are no free procs, 
+    /// or ENOMEM if a memory allocation fails. 
+    pub fn alloc_proc(&mut self) -> Result<&mut Process, Errno> {
         let alloc_pid = self.alloc_pid();
         // self.proc_dump();
-        for proc in self.proc.iter_mut() {
+        for (slot, proc) in self.proc.iter_mut().enumerate() {
             let mut pmeta = proc.meta.acquire();
             match pmeta.state {
                 ProcState::UNUSED => {
                     pmeta.pid = alloc_pid;
                     pmeta.set_state(ProcState::ALLOCATED);
-                    let pdata = proc.data.get_mut();
+                    // The slot is ours now, don't hold the lock 
+                    // while allocating memory. 
+                    drop(pmeta);
+                    self.pids.write()[slot] = alloc_pid;
+                    let pdata = unsafe{ &mut *proc.data.get() };
                     // Allocate a trapframe page.
-                    let trapframe = unsafe{ RawPage::new_zeroed() as *mut u8 };
-                    pdata.set_trapframe(trapframe as *mut Trapframe);
+                    match unsafe{ RawPage::try_new_zeroed() } {
+                        Some(trapframe) => pdata.set_trapframe(trapframe as *mut Trapframe),
+                        None => {
+                            proc.free_proc();
+                            return Err(Errno::ENOMEM)
+                        }
+                    }
                     // An empty user page table
-                    unsafe{
-                        pdata.proc_pagetable();
+                    match proc.proc_pagetable() {
+                        Some(page_table) => pdata.set_pagetable(Some(page_table)),
+                        None => {
+                            proc.free_proc();
+                            return Err(Errno::ENOMEM)
+                        }
                     }
                     // Set up new context to start executing at forkret, 
                     // which returns to user space. 
                     pdata.init_context();
-                    drop(pmeta);
-                    return Some(proc)
+                    return Ok(proc)
                 }
                 _ => {}
             }
         }
-        None
+        Err(Errno::EAGAIN)
     }
 
 
-    /// Wake up all processes sleeping on chan.
-    /// Must be called without any p->lock.
-    pub fn wake_up(&self, channel: usize) {
-        for p in self.proc.iter() {
-            let mut guard = p.meta.acquire();
-            if guard.state == ProcState::SLEEPING && guard.channel == channel {
-                // println!("[Debug] Wake up process {}", guard.pid);
-                guard.state = ProcState::RUNNABLE;
-            }
-            drop(guard);
-        }
-    }
-
     /// Find a runnable and set status to allocated
     pub fn seek_runnable(&mut self) -> Option<&mut Process> {
         for p in self.proc.iter_mut() {
@@ -189,7 +222,7 @@ impl ProcManager{
                 if let Some(parent) = pdata.parent {
                     if parent as *const _ == proc as *const _ {
                         pdata.parent = Some(self.init_proc);
-                        self.wake_up(self.init_proc as usize);
+                        unsafe{ (*self.init_proc).child_exit.wake_all(); }
                     }
                 }
         }
@@ -212,10 +245,6 @@ impl ProcManager{
             }
         }
 
-        LOG.begin_op();
-        let cwd = pdata.cwd.as_mut().expect("Fail to get inode");
-        drop(cwd);
-        LOG.end_op();
         pdata.cwd = None;
 
         let wait_guard = self.wait_lock.acquire();
@@ -223,7 +252,8 @@ impl ProcManager{
         self.reparent(my_proc);
         // Parent might be sleeping in wait. 
         // 唤醒父进程
-        self.wake_up(pdata.parent.expect("Fail to find parent process") as usize);
+        let parent = pdata.parent.expect("Fail to find parent process");
+        unsafe{ (*parent).child_exit.wake_all(); }
 
         let mut proc_data = my_proc.meta.acquire();
         // 设置退出状态
@@ -299,10 +329,7 @@ impl ProcManager{
             // 释放锁，否则会死锁
             drop(my_proc_data);
             // Wait for a child to exit.
-            my_proc.sleep(
-                my_proc as *const _ as usize, 
-                wait_guard
-            );
+            my_proc.child_exit.wait(wait_guard);
             wait_guard = self.wait_lock.acquire();
         }
     }
@@ -310,34 +337,196 @@ impl ProcManager{
     /// Kill the process with the given pid. 
     /// The victim won't exit until it tries to return. 
     /// to user space (user_trap)
-    pub fn kill(&mut self, pid: usize) -> Result<usize, ()> {
-        for proc in self.proc.iter_mut() {
-            if proc.pid() == pid {
-                proc.set_killed(true);
-                if proc.state() == ProcState::SLEEPING {
-                    // Wake process from sleep. 
-                    proc.set_state(ProcState::RUNNABLE);
-                    return Ok(0)
+    pub fn kill(&mut self, pid: usize, cred: &Cred) -> Result<usize, Errno> {
+        let proc = self.find_pid(pid).ok_or(Errno::ESRCH)?;
+        let mut pmeta = proc.meta.acquire();
+        // the slot may have been reused since we looked. 
+        if pmeta.pid == pid && pmeta.state != ProcState::UNUSED {
+            if !cred.may_kill(&pmeta.cred) {
+                return Err(Errno::EPERM)
+            }
+            pmeta.killed = true;
+            if pmeta.state == ProcState::SLEEPING {
+                // Wake process from sleep. 
+                pmeta.state = ProcState::RUNNABLE;
+            }
+            return Ok(0)
+        }
+        Err(Errno::ESRCH)
+    }
+
+    /// The process with pid, if any. Its p->lock is not held, 
+    /// so the caller must check the pid again under it. 
+    pub fn find_pid(&self, pid: usize) -> Option<&Process> {
+        if pid == 0 {
+            return None
+        }
+        let slot = self.pids.read().iter().position(|&p| p == pid)?;
+        Some(&self.proc[slot])
+    }
+
+    /// Pids of all processes. 
+    pub fn pids(&self) -> Vec<usize> {
+        self.pids.read().iter().cloned().filter(|&pid| pid != 0).collect()
+    }
+
+    /// Look at the process with pid, and at its files, working
+    /// directory and memory if detail is set. Those are only looked
+    /// at while the process does not run, we wait for a process
+    /// running on another cpu to stop for a while, then give up
+    /// with EAGAIN. Must be called without any p->lock. 
+    pub fn proc_info(&self, pid: usize, detail: bool) -> Result<ProcInfo, Errno> {
+        let proc = self.find_pid(pid).ok_or(Errno::ESRCH)?;
+        let me = unsafe{ CPU_MANAGER.myproc() }.map_or(0, |p| p.as_ptr_addr());
+        for _ in 0..PROC_INFO_TRIES {
+            let wait_guard = self.wait_lock.acquire();
+            let pdata = unsafe{ &mut *proc.data.get() };
+            let ppid = pdata.parent.map_or(0, |parent| unsafe{ (*parent).pid() });
+            let pmeta = proc.meta.acquire();
+            if pmeta.pid != pid || pmeta.state == ProcState::UNUSED {
+                return Err(Errno::ESRCH)
+            }
+            if detail && pmeta.state == ProcState::RUNNING && proc.as_ptr_addr() != me {
+                drop(pmeta);
+                drop(wait_guard);
+                unsafe{ CPU_MANAGER.yield_proc(); }
+                continue;
+            }
+            let detail = match detail {
+                false => None,
+                true => Some(ProcDetail {
+                    cmdline: pdata.cmdline.clone(),
+                    files: pdata.open_files.iter().enumerate()
+                        .filter_map(|(fd, file)| Some((fd, file.as_ref()?.clone())))
+                        .collect(),
+                    cwd: pdata.cwd.clone(),
+                    regions: match pdata.pagetable.as_mut() {
+                        Some(page_table) => page_table.uvm_regions(pdata.size),
+                        None => Vec::new()
+                    }
+                })
+            };
+            let info = ProcInfo {
+                pid,
+                ppid,
+                state: pmeta.state,
+                killed: pmeta.killed,
+                name: pmeta.name,
+                cred: pmeta.cred,
+                size: pmeta.size,
+                detail
+            };
+            drop(pmeta);
+            drop(wait_guard);
+            return Ok(info)
+        }
+        Err(Errno::EAGAIN)
+    }
+
+    /// Forget the pid of a process being freed. 
+    pub fn release_pid(&self, proc: &Process) {
+        let slot = (proc as *const Process as usize - self.proc.as_ptr() as usize) / size_of::<Process>();
+        self.pids.write()[slot] = 0;
+    }
+
+    /// Called when memory for a user process could not be allocated. 
+    /// Kill the process holding the most resident memory so that its
+    /// pages come back once it exits. Init is never chosen, and nothing
+    /// is killed while an earlier victim is still on its way out. 
+    /// Must be called without any p->lock.
+    pub fn oom_kill(&mut self) {
+        let mut victim: Option<(usize, usize)> = None;
+        for proc in self.proc.iter() {
+            if proc as *const Process == self.init_proc as *const Process {
+                continue;
+            }
+            let pmeta = proc.meta.acquire();
+            match pmeta.state {
+                ProcState::UNUSED | ProcState::ZOMBIE => {},
+                _ if pmeta.killed => {
+                    println!("oom-killer: pid {} is already exiting, not killing another process", pmeta.pid);
+                    return
+                },
+                _ => {
+                    // every mapped user page is resident
+                    let pages = page_round_up(pmeta.size) / PGSIZE;
+                    if victim.map_or(true, |(_, most)| pages > most) {
+                        victim = Some((pmeta.pid, pages));
+                    }
                 }
             }
+            drop(pmeta);
+        }
+
+        let (pid, pages) = match victim {
+            Some(victim) => victim,
+            None => {
+                println!("oom-killer: out of memory, but no process can be killed");
+                return
+            }
+        };
+        for proc in self.proc.iter() {
+            let mut pmeta = proc.meta.acquire();
+            if pmeta.pid == pid && pmeta.state != ProcState::UNUSED {
+                println!(
+                    "oom-killer: out of memory, killed pid {} ({}) holding {} pages",
+                    pid, 
+                    from_utf8(&pmeta.name).unwrap_or("?").trim_end_matches('\0'),
+                    pages
+                );
+                pmeta.killed = true;
+                if pmeta.state == ProcState::SLEEPING {
+                    pmeta.state = ProcState::RUNNABLE;
+                }
+                return
+            }
         }
-        Err(())
     }
 
     /// Print a process listing to console. For debugging. 
     /// Runs when user type ^P on console. 
     /// No lock to avoid wedging a stuck machine further
+    /// The watchdog dumps the processes too, while some cpu
+    /// may hold a process lock forever; don't wait for it.
     pub fn proc_dump(&self) {
         for proc in self.proc.iter() {
-            if proc.state() == ProcState::UNUSED { continue; }
-            else {
-                println!("pid: {} state: {:?} name: {}", proc.pid(), proc.state(), proc.name());
+            match proc.meta.try_acquire() {
+                Some(pmeta) => {
+                    if pmeta.state == ProcState::UNUSED { continue; }
+                    println!("pid: {} state: {:?} name: {}", pmeta.pid, pmeta.state, proc.name());
+                },
+                None => println!("(locked) name: {}", proc.name())
             }
         }
+        unsafe{ CPU_MANAGER.switch_dump(); }
+    }
+
+    /// Report processes which have been in an uninterruptible
+    /// sleep for longer than timeout, once per sleep.
+    pub fn check_hung(&self, now: usize, timeout: usize) {
+        let mut hung = false;
+        for proc in self.proc.iter() {
+            let mut pmeta = match proc.meta.try_acquire() {
+                Some(pmeta) => pmeta,
+                None => continue
+            };
+            if pmeta.state == ProcState::SLEEPING && !pmeta.interruptible &&
+                !pmeta.hung_reported && now.saturating_sub(pmeta.sleep_since) > timeout {
+                pmeta.hung_reported = true;
+                println!(
+                    "watchdog: pid {} ({}) blocked for {}s on wait queue {:#x}",
+                    pmeta.pid, proc.name(), watchdog::secs(now.saturating_sub(pmeta.sleep_since)), pmeta.channel
+                );
+                hung = true;
+            }
+        }
+        if hung {
+            self.proc_dump();
+        }
     }
 }
 
 #[inline]
 fn kernel_stack(pos: usize) -> usize {
-    TRAMPOLINE - (pos + 1) * 5 * PGSIZE
+    trampoline_va() - (pos + 1) * KSTACK_PAGES * PGSIZE
 }
\ No newline at end of file
@@ -6,7 +6,7 @@ use array_macro::array;
 
 use crate::arch::riscv::qemu::fs::{NFILE, ROOTDEV};
 use crate::trap::user_trap_ret;
-use crate::fs::{ LOG, ICACHE, init };
+use crate::fs::init;
 use crate::syscall::SysResult;
 
 
@@ -16,12 +16,14 @@ mod trapframe;
 mod manager;
 mod elf;
 mod process;
+mod cred;
 pub use context::*;
 pub use trapframe::*;
 pub use cpu::*;
 pub use process::*;
 pub use manager::*;
 pub use elf::*;
+pub use cred::*;
 
 static INITCODE: [u8; 51] = [
     0x17, 0x05, 0x00, 0x00, 0x13, 0x05, 0x05, 0x02, 0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0x05, 0x02,
@@ -47,18 +49,15 @@ pub unsafe fn exit(status: i32) {
     }
     pdata.open_files = array![_ => None; NFILE];
 
-    LOG.begin_op();
     // extern_data.cwd.as_ref().unwrap().put();
     // ICACHE.put(extern_data.cwd.as_ref());
-    drop(pdata.cwd.as_mut());
-    LOG.end_op();
     pdata.cwd = None;
 
     let wait_guard = PROC_MANAGER.wait_lock.acquire();
     // TODO: Give any children to init
     
     // Parent might be sleeping in wait(). 
-    PROC_MANAGER.wake_up(pdata.parent.unwrap() as usize);
+    (*pdata.parent.unwrap()).child_exit.wake_all();
 
     let mut guard = my_proc.meta.acquire();
 
@@ -9,16 +9,20 @@ use array_macro::array;
 
 use crate::arch::riscv::qemu::fs::{NFILE, NOFILE};
 use crate::lock::spinlock::{ Spinlock, SpinlockGuard };
+use crate::lock::waitqueue::WaitQueue;
 use crate::memory::{
     kalloc::*,
     address::{ PhysicalAddress, VirtualAddress, Addr },
-    mapping::{ page_table::PageTable, page_table_entry::PteFlags},
+    mapping::{ page_table::PageTable, page_table_entry::PteFlags, page_round_up, kernel_map::KERNEL_PAGETABLE },
+    asid::{ Asid, asid_enabled },
     RawPage
 };
-use crate::arch::riscv::qemu::layout::{ PGSIZE, TRAMPOLINE, TRAPFRAME };
+use crate::arch::riscv::qemu::layout::{ PGSIZE, trampoline_va, trapframe_va, KERNEL_BASE, user_top };
 use crate::arch::riscv::register::satp;
 use super::*;
-use crate::fs::{FileType, Inode, VFile};
+use crate::fs::{PinnedNode, VFile};
+use crate::syscall::Errno;
+use crate::ipi::tlb_shootdown;
 
 
 use alloc::boxed::Box;
@@ -38,15 +42,23 @@ pub enum ProcState{
 pub struct Process {
     pub meta: Spinlock<ProcMeta>,
     pub data: UnsafeCell<ProcData>,
+    pub child_exit: WaitQueue, // wait() sleeps here for a child to exit
 }
 
 pub struct ProcMeta {
     // p->lock must be held when using these
     pub state: ProcState,
-    pub channel: usize, // If non-zero, sleeping on chan
+    pub channel: usize, // If non-zero, address of the wait queue we sleep on
     pub killed: bool, // If non-zero, have been killed
     pub xstate: usize, // Exit status to be returned to parent's wait
     pub pid: usize,   // Process ID
+    pub sleep_since: usize, // Time the process went to sleep
+    pub interruptible: bool, // Sleeping for an event which may never come
+    pub hung_reported: bool, // The watchdog reported this sleep
+    // copies of those in ProcData for other processes, see publish
+    pub name: [u8; 16],
+    pub cred: Cred,
+    pub size: usize,
 }
 
 impl ProcMeta {
@@ -57,7 +69,12 @@ impl ProcMeta {
             killed: false,
             xstate: 0,
             pid: 0,
-
+            sleep_since: 0,
+            interruptible: false,
+            hung_reported: false,
+            name: [0; 16],
+            cred: Cred::root(),
+            size: 0
         }
     }
 
@@ -74,10 +91,19 @@ pub struct ProcData {
     pub trapframe: *mut Trapframe, // data page for trampoline.S
     pub context: Context, // switch() here to run processs
     pub name: [u8; 16],   // Process name (debugging)
+    pub cmdline: Vec<u8>, // Arguments of the last exec, each ending with 0
     // proc_tree_lock must be held when using this:
     pub parent: Option<*mut Process>,   
     pub open_files: [Option<Arc<VFile>>; NFILE],
-    pub cwd: Option<Inode>
+    pub cwd: Option<PinnedNode>,
+    pub cred: Cred, // User and group ids, see cred.rs
+    pub asid: Asid, // Address space identifier of pagetable
+    pub tlb_harts: usize, // Mask of cpus which may cache translations of asid
+    pub trap_enter: usize, // Cycle counter at the last trap from user space, 0 once it gave up the cpu
+    pub wait_next: *mut Process, // Next waiter on our wait queue, under its lock
+    pub wait_expired: bool, // Our timer took us off the wait queue, under its lock
+    #[cfg(feature = "lockdep")]
+    pub held_sleeplocks: HeldLocks, // Sleep locks held, for lockdep
 
 }
 
@@ -90,9 +116,18 @@ impl ProcData {
             trapframe: null_mut(),
             context: Context::new(),
             name: [0u8; 16],
+            cmdline: Vec::new(),
             parent: None,
             open_files: array![_ => None; NFILE],
-            cwd: None
+            cwd: None,
+            cred: Cred::root(),
+            asid: Asid::NONE,
+            tlb_harts: 0,
+            trap_enter: 0,
+            wait_next: null_mut(),
+            wait_expired: false,
+            #[cfg(feature = "lockdep")]
+            held_sleeplocks: HeldLocks::new()
         }
     }
 
@@ -122,8 +157,25 @@ impl ProcData {
         self.trapframe = trapframe;
     }
 
+    /// Install a new user page table. The old ASID may still be cached
+    /// on any cpu, so the process gets a fresh one on its next return
+    /// to user space.
     pub fn set_pagetable(&mut self, pagetable: Option<Box<PageTable>>) {
-        self.pagetable = pagetable
+        self.pagetable = pagetable;
+        self.asid = Asid::NONE;
+        self.tlb_harts = 0;
+    }
+
+    /// Drop stale translations of [start, end) after the user page table
+    /// changed, on every cpu which may cache them. Returns once they are
+    /// all gone, so frames unmapped from the range may be freed.
+    pub fn flush_tlb(&self, start: usize, end: usize) {
+        if !asid_enabled() || !self.asid.is_current() {
+            // either satp is written with sfence.vma on every return to
+            // user space, or no cpu will switch to this ASID again.
+            return
+        }
+        tlb_shootdown(self.tlb_harts, self.asid, start, end);
     }
 
     pub fn set_context(&mut self, ctx: Context) {
@@ -152,42 +204,6 @@ impl ProcData {
         Err("Fail to find unallocted fd")
     }
 
-    // Create a user page table for a given process,
-    // with no user memory, but with trampoline pages
-    pub unsafe fn proc_pagetable(&mut self) {
-
-        extern "C" {
-            fn trampoline();
-        }
-
-        // An empty page table
-        let mut page_table = PageTable::uvmcreate();
-        // map the trampoline code (for system call return )
-        // at the highest user virtual address.
-        // only the supervisor uses it, on the way
-        // to/from user space, so not PTE_U. 
-        if !page_table.map(
-            VirtualAddress::new(TRAMPOLINE),
-            PhysicalAddress::new(trampoline as usize),
-            PGSIZE,
-            PteFlags::R | PteFlags::X
-        ) {
-            page_table.uvm_free(0);
-        }
-
-        // map the trapframe just below TRAMPOLINE, for trampoline.S 
-        if !page_table.map(
-            VirtualAddress::new(TRAPFRAME), 
-            PhysicalAddress::new(self.trapframe as usize),
-            PGSIZE,
-            PteFlags::R | PteFlags::W
-        ) {
-            page_table.uvm_free(0);
-        }
-
-        self.pagetable = Some(page_table);
-    }
-
     /// Initialize first user process
     pub fn user_init(&mut self) {
         extern "C" {
@@ -204,6 +220,8 @@ impl ProcData {
         tf.kernel_hartid = unsafe {
             cpu::cpuid()
         };
+        // without ASIDs user entries look like kernel ones.
+        tf.kernel_flush = !asid_enabled() as usize;
     }
 }
 
@@ -214,6 +232,7 @@ impl Process{
         Self{    
             meta: Spinlock::new(ProcMeta::new(), "process"),
             data: UnsafeCell::new(ProcData::new()),
+            child_exit: WaitQueue::new_interruptible("child_exit"),
         }
     }
 
@@ -281,6 +300,16 @@ impl Process{
         from_utf8(&pdata.name).unwrap()
     }
 
+    /// Copy the name, credentials and size to p->lock for other
+    /// processes to look at. Called by the process after it changed them.
+    pub fn publish(&self) {
+        let pdata = unsafe{ &*self.data.get() };
+        let mut pmeta = self.meta.acquire();
+        pmeta.name = pdata.name;
+        pmeta.cred = pdata.cred;
+        pmeta.size = pdata.size;
+    }
+
     pub fn modify_kill(&self, killed: bool) {
         let mut proc_data = self.meta.acquire();
         proc_data.killed = killed;
@@ -297,7 +326,7 @@ impl Process{
     /// with no user memory, but with trampoline pages. 
     pub fn proc_pagetable(&self) -> Option<Box<PageTable>> {
         // An empty page table
-        let mut page_table = unsafe{ PageTable::uvmcreate() };
+        let mut page_table = unsafe{ PageTable::uvmcreate()? };
          
         // map the trampoline code(for system call return)
         // at the highest user virtual address. 
@@ -305,7 +334,7 @@ impl Process{
         // to/from user space, so not PTE_U. 
         unsafe{
             if !page_table.map(
-            VirtualAddress::new(TRAMPOLINE), 
+            VirtualAddress::new(trampoline_va()), 
             PhysicalAddress::new(trampoline as usize),
              PGSIZE, 
              PteFlags::R | PteFlags::X
@@ -314,54 +343,76 @@ impl Process{
                 return None
             }
 
-            // map the trapframe just below TRAMPOLINE, for trampoline.S 
+            // map the trapframe just below trampoline_va, for trampoline.S 
             if !page_table.map(
-                VirtualAddress::new(TRAPFRAME), 
+                VirtualAddress::new(trapframe_va()), 
                 PhysicalAddress::new((&*self.data.get()).get_trapframe() as usize), 
                 PGSIZE, 
                 PteFlags::R | PteFlags::W
             ) {
                 page_table.uvm_unmap(
-                    VirtualAddress::new(TRAPFRAME), 
+                    VirtualAddress::new(trampoline_va()), 
                     1, 
                     false
                 );
                 page_table.uvm_free(0);
                 return None
             }
+
+            // map our kernel stack and share the kernel's mapping of RAM,
+            // so that the kernel can keep running on this page table
+            // while it copies user memory (see usercopy.rs). 
+            let kstack = (&*self.data.get()).kstack;
+            let kstack_pa = KERNEL_PAGETABLE
+                .kvm_pa(VirtualAddress::new(kstack))
+                .expect("proc_pagetable: kernel stack not mapped");
+            if !page_table.map(
+                VirtualAddress::new(kstack),
+                kstack_pa,
+                PGSIZE * 4,
+                PteFlags::R | PteFlags::W
+            ) {
+                page_table.uvm_unmap(VirtualAddress::new(trampoline_va()), 1, false);
+                page_table.uvm_unmap(VirtualAddress::new(trapframe_va()), 1, false);
+                page_table.uvm_free(0);
+                return None
+            }
+            if !page_table.share_kernel() {
+                page_table.proc_free_pagetable(0, kstack);
+                return None
+            }
         }
         Some(page_table)
     }
 
     /// free a proc structure and the data hanging from it,
     /// including user pages.
-    /// p.acquire() must be held.
+    /// p.acquire() must not be held.
     pub fn free_proc(&mut self) {
-        let mut pdata = self.data.get_mut();
+        let pdata = self.data.get_mut();
         if !pdata.trapframe.is_null() {
-            drop(pdata.trapframe as *mut RawPage);
+            unsafe{ RawPage::free(pdata.trapframe as usize) };
             pdata.set_trapframe(0 as *mut Trapframe);
+        }
 
-            if let Some(page_table) = pdata.pagetable.as_mut() {
-                page_table.proc_free_pagetable(pdata.size);
-            }
-
-
-            let mut guard = self.meta.acquire();
-
-            pdata.set_pagetable(None);
-            pdata.set_parent(None);
-            pdata.size = 0;
+        if let Some(page_table) = pdata.pagetable.as_mut() {
+            page_table.proc_free_pagetable(pdata.size, pdata.kstack);
+        }
 
-            guard.pid = 0;
-            guard.channel = 0;
-            guard.killed = false;
-            guard.xstate = 0;
-            guard.set_state(ProcState::UNUSED);
+        pdata.set_pagetable(None);
+        pdata.set_parent(None);
+        pdata.size = 0;
+        pdata.cmdline = Vec::new();
 
-            drop(guard);
-            
-        }
+        unsafe{ PROC_MANAGER.release_pid(self); }
+        let mut guard = self.meta.acquire();
+        guard.pid = 0;
+        guard.channel = 0;
+        guard.killed = false;
+        guard.xstate = 0;
+        guard.size = 0;
+        guard.set_state(ProcState::UNUSED);
+        drop(guard);
     }
 
     
@@ -372,21 +423,43 @@ impl Process{
         let mut size = pdata.size; 
         let page_table = pdata.pagetable.as_mut().unwrap();
         if count > 0 {
+            if size + count as usize > user_top() {
+                return Err("Fail to grow user memory past user_top")
+            }
             match unsafe { page_table.uvm_alloc(size, size + count as usize) } {
                 Some(new_size) => {
+                    // the kernel's RAM gave way to user memory, drop
+                    // what the TLBs still hold of it for this ASID.
+                    if size <= KERNEL_BASE && new_size > KERNEL_BASE {
+                        pdata.flush_tlb(KERNEL_BASE, KERNEL_BASE + (1 << 30));
+                    }
                     size = new_size;
                 },
 
                 None => {
+                    // Let the OOM killer make room before reporting ENOMEM. 
+                    unsafe{ PROC_MANAGER.oom_kill(); }
                     return Err("Fail to allocate virtual memory for user")
                 }
             }
         } else if count < 0 {
+            if (-count) as usize > size {
+                return Err("Fail to shrink user memory below zero")
+            }
             let new_size = (size as isize + count) as usize;
-            size = page_table.uvm_dealloc(size, new_size);
+            let (start, end) = (page_round_up(new_size), page_round_up(size));
+            // Other cpus may still reach the frames through their TLBs,
+            // so free them only after the shootdown.
+            let pages = page_table.uvm_take(VirtualAddress::new(start), (end - start) / PGSIZE);
+            pdata.flush_tlb(start, end);
+            for pa in pages {
+                unsafe{ RawPage::free(pa) };
+            }
+            size = new_size;
         }
 
         pdata.size = size;
+        self.publish();
 
         Ok(())
     }
@@ -410,82 +483,59 @@ impl Process{
         drop(pmeta)
     }
 
-    /// Atomically release lock and sleep on chan
-    /// Reacquires lock when awakened.
-    pub fn sleep<T>(&self, channel: usize, lock: SpinlockGuard<'_, T>) {
-        // Must acquire p->lock in order to 
-        // change p->state and then call sched.
-        // Once we hold p->lock, we can be
-        // guaranteed that we won't miss any wakeup
-        // (wakeup locks p->lock)
-        // so it's okay to release lk;
-        let mut guard = self.meta.acquire();
-        drop(lock);
-        // Go to sleep.
-        guard.channel = channel;
-        guard.set_state(ProcState::SLEEPING);
-        unsafe {
-            let my_cpu = CPU_MANAGER.mycpu();
-            let ctx = (&mut (*self.data.get())).get_context_mut();      
-            // get schedule process
-            guard = my_cpu.sched(
-                guard, 
-                ctx
-            );
-            // Tide up
-            guard.channel = 0;
-            drop(guard);
-        }
-    }
-
     /// Find a unallocated fd
-    pub fn fd_alloc(&mut self, file: &VFile) -> Result<usize, &'static str>{
+    pub fn fd_alloc(&mut self, file: VFile) -> Result<usize, &'static str>{
         let pdata = unsafe {
             &mut *self.data.get()
         };
         let fd = pdata.find_unallocated_fd()?;
-        pdata.open_files[fd].replace(Arc::new(file.clone()));
+        pdata.open_files[fd].replace(Arc::new(file));
         Ok(fd)       
     } 
 
-    pub fn fork(&mut self) -> Option<&mut Self> {
+    /// Create a new process, copying the parent. 
+    /// Fails with EAGAIN when the process table is full
+    /// and with ENOMEM when memory runs out. 
+    pub fn fork(&mut self) -> Result<&mut Self, Errno> {
         // 从表中获取未被分配的子进程
-        if let Some(child_proc) = unsafe{ PROC_MANAGER.alloc_proc() } {
-            // 从当前进程的页表拷贝到子进程中
-            let pdata = unsafe{ &mut *self.data.get() };
-            let child_data = unsafe{ &mut *child_proc.data.get() };
-            if unsafe{ pdata.pagetable.as_mut().unwrap().uvm_copy(
-                child_data.pagetable.as_mut().unwrap(), 
-                pdata.size
-            ).is_err() } {
-                panic!("fork: Fail to copy data from parent process.")
-            }
-            // 将当前进程的 trapframe 拷贝到子进程
-            let ptf = pdata.trapframe as *const Trapframe;
-            let child_tf = unsafe{ &mut *child_data.trapframe };
-            unsafe{ copy_nonoverlapping(ptf, child_tf, 1); }
-            // fork 后子进程应当返回0
-            child_tf.a0 = 0;
-
-            // 子进程拷贝父进程的文件和工作目录
-            child_data.open_files.clone_from(&pdata.open_files);
-            child_data.cwd.clone_from(&pdata.cwd);
-
-            child_data.name = pdata.name;
-            child_data.size = pdata.size;
-
-            let mut child_meta = child_proc.meta.acquire();
-            child_meta.state = ProcState::RUNNABLE;
-            drop(child_meta);
-
-            let wait = unsafe{ PROC_MANAGER.wait_lock.acquire() };
-            child_data.parent = Some(self as *mut Process);
-            drop(wait);
-            Some(child_proc)
-        }else {
-            println!("[Kernel] fork: None");
-            None
+        let child_proc = unsafe{ PROC_MANAGER.alloc_proc()? };
+        // 从当前进程的页表拷贝到子进程中
+        let pdata = unsafe{ &mut *self.data.get() };
+        let child_data = unsafe{ &mut *child_proc.data.get() };
+        if unsafe{ pdata.pagetable.as_mut().unwrap().uvm_copy(
+            child_data.pagetable.as_mut().unwrap(), 
+            pdata.size
+        ).is_err() } {
+            println!("[Kernel] fork: Fail to copy data from parent process.");
+            child_proc.free_proc();
+            unsafe{ PROC_MANAGER.oom_kill(); }
+            return Err(Errno::ENOMEM)
         }
+        // 将当前进程的 trapframe 拷贝到子进程
+        let ptf = pdata.trapframe as *const Trapframe;
+        let child_tf = unsafe{ &mut *child_data.trapframe };
+        unsafe{ copy_nonoverlapping(ptf, child_tf, 1); }
+        // fork 后子进程应当返回0
+        child_tf.a0 = 0;
+
+        // 子进程拷贝父进程的文件和工作目录
+        child_data.open_files.clone_from(&pdata.open_files);
+        child_data.cwd.clone_from(&pdata.cwd);
+
+        child_data.name = pdata.name;
+        child_data.cmdline = pdata.cmdline.clone();
+        child_data.cred = pdata.cred;
+        child_data.size = pdata.size;
+
+        child_proc.publish();
+        let mut child_meta = child_proc.meta.acquire();
+        child_meta.state = ProcState::RUNNABLE;
+        drop(child_meta);
+
+        let wait = unsafe{ PROC_MANAGER.wait_lock.acquire() };
+        child_data.parent = Some(self as *mut Process);
+        drop(wait);
+        Ok(child_proc)
     }
 }
 
@@ -50,7 +50,8 @@ pub struct Trapframe {
     /*256 */    pub t3:usize,
     /*264 */    pub t4:usize,
     /*272 */    pub t5:usize,
-    /*280 */    pub t6:usize
+    /*280 */    pub t6:usize,
+    /*288 */    pub kernel_flush:usize // flush the TLB in uservec, address spaces are not tagged
 }
 
 
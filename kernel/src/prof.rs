@@ -0,0 +1,175 @@
+//! Sampling profiler driven by the timer interrupt.
+//!
+//! While enabled, every timer interrupt records the interrupted pc, the
+//! mode and the pid into a ring buffer of its hart; kernel samples also
+//! keep the call chain. /dev/prof controls it: writing "start", "stop" or
+//! "reset" does what it says, reading gives the samples as folded stacks,
+//! one `pid-N;mode;outer;...;inner 1` line each, which flamegraph.pl reads
+//! as is. Code running with interrupts off is never sampled.
+
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use alloc::string::String;
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::arch::riscv::qemu::devices::PROF;
+use crate::backtrace::{ lookup, kernel_callers };
+use crate::fs::{ DEVICE_LIST, read_text };
+use crate::lock::spinlock::Spinlock;
+use crate::memory::copy_to_kernel;
+use crate::process::cpuid;
+
+/// Samples kept per hart, the oldest are overwritten.
+const NSAMPLE: usize = 512;
+/// Deepest kernel call chain kept in a sample.
+const NFRAME: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RINGS: [Spinlock<Ring>; NCPU] = array![_ => Spinlock::new(Ring::new(), "prof"); NCPU];
+/// Text of the last read from offset 0, so that a reader
+/// going through it in pieces sees one consistent report.
+static REPORT: Spinlock<String> = Spinlock::new(String::new(), "prof_report");
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    User,
+    Kernel
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    pc: usize,
+    mode: Mode,
+    pid: usize,
+    depth: usize,
+    callers: [usize; NFRAME]
+}
+
+impl Sample {
+    const fn new() -> Self {
+        Self {
+            pc: 0,
+            mode: Mode::Kernel,
+            pid: 0,
+            depth: 0,
+            callers: [0; NFRAME]
+        }
+    }
+}
+
+struct Ring {
+    samples: [Sample; NSAMPLE],
+    next: usize, // Total samples taken, next % NSAMPLE is the next slot.
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            samples: [Sample::new(); NSAMPLE],
+            next: 0
+        }
+    }
+
+    fn samples(&self) -> &[Sample] {
+        &self.samples[..core::cmp::min(self.next, NSAMPLE)]
+    }
+
+    fn dropped(&self) -> usize {
+        self.next.saturating_sub(NSAMPLE)
+    }
+}
+
+pub fn prof_init() {
+    unsafe {
+        DEVICE_LIST.table[PROF].read = prof_read as *const u8;
+        DEVICE_LIST.table[PROF].write = prof_write as *const u8;
+    }
+}
+
+/// Record one sample, called from the timer interrupt.
+/// frame is the interrupted frame pointer for kernel samples.
+pub fn sample(mode: Mode, pc: usize, pid: usize, frame: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return
+    }
+    let mut sample = Sample {
+        pc,
+        mode,
+        pid,
+        depth: 0,
+        callers: [0; NFRAME]
+    };
+    if mode == Mode::Kernel {
+        sample.depth = kernel_callers(frame, &mut sample.callers);
+    }
+    let mut ring = RINGS[unsafe{ cpuid() }].acquire();
+    let slot = ring.next % NSAMPLE;
+    ring.samples[slot] = sample;
+    ring.next += 1;
+}
+
+fn reset() {
+    for ring in RINGS.iter() {
+        ring.acquire().next = 0;
+    }
+}
+
+fn write_frame(text: &mut String, pc: usize) {
+    match lookup(pc) {
+        Some((name, _)) => { let _ = write!(text, ";{}", name); },
+        None => { let _ = write!(text, ";{:#x}", pc); }
+    }
+}
+
+fn report() -> String {
+    let mut text = String::new();
+    let mut dropped = 0;
+    for ring in RINGS.iter() {
+        let ring = ring.acquire();
+        dropped += ring.dropped();
+        for sample in ring.samples() {
+            let _ = write!(text, "pid-{}", sample.pid);
+            match sample.mode {
+                Mode::User => {
+                    let _ = write!(text, ";user;{:#x}", sample.pc);
+                },
+                Mode::Kernel => {
+                    text.push_str(";kernel");
+                    for &ra in sample.callers[..sample.depth].iter().rev() {
+                        write_frame(&mut text, ra);
+                    }
+                    write_frame(&mut text, sample.pc);
+                }
+            }
+            text.push_str(" 1\n");
+        }
+    }
+    if dropped != 0 {
+        println!("prof: {} samples overwritten", dropped);
+    }
+    text
+}
+
+/// Read of /dev/prof: the samples as folded stacks.
+fn prof_read(is_user: bool, dst: usize, len: usize, offset: usize) -> Option<usize> {
+    let mut text = REPORT.acquire();
+    if offset == 0 {
+        *text = report();
+    }
+    read_text(is_user, dst, len, offset, &text)
+}
+
+/// Write of /dev/prof: "start", "stop" or "reset".
+fn prof_write(is_user: bool, src: usize, len: usize) -> Option<usize> {
+    let mut buf = [0u8; 8];
+    let count = core::cmp::min(len, buf.len());
+    copy_to_kernel(buf.as_mut_ptr(), is_user, src, count).ok()?;
+    match core::str::from_utf8(&buf[..count]).ok()?.trim() {
+        "start" => ENABLED.store(true, Ordering::SeqCst),
+        "stop" => ENABLED.store(false, Ordering::SeqCst),
+        "reset" => reset(),
+        _ => return None
+    }
+    Some(len)
+}
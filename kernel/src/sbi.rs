@@ -0,0 +1,156 @@
+//! RISC-V Supervisor Binary Interface.
+//!
+//! The kernel runs in supervisor mode on top of an SBI implementation
+//! (OpenSBI under QEMU), which owns machine mode. Timers, IPIs, remote
+//! fences, starting harts and resetting the system all go through it.
+
+/// Error codes returned in a0.
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+// Extension ids
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x5449_4D45;
+const EID_IPI: usize = 0x0073_5049;
+const EID_RFENCE: usize = 0x5246_4E43;
+const EID_HSM: usize = 0x0048_534D;
+const EID_SRST: usize = 0x5352_5354;
+
+/// hart states of the HSM extension.
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+pub const HART_START_PENDING: usize = 2;
+pub const HART_STOP_PENDING: usize = 3;
+
+/// reset types and reasons of the SRST extension.
+pub const RESET_TYPE_SHUTDOWN: usize = 0x0000_0000;
+pub const RESET_TYPE_COLD_REBOOT: usize = 0x0000_0001;
+pub const RESET_TYPE_WARM_REBOOT: usize = 0x0000_0002;
+
+pub const RESET_REASON_NO_REASON: usize = 0x0000_0000;
+pub const RESET_REASON_SYSTEM_FAILURE: usize = 0x0000_0001;
+
+pub type SbiResult = Result<usize, isize>;
+
+#[inline]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> SbiResult {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        core::arch::asm!("ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2, in("x13") arg3, in("x14") arg4,
+            in("x16") fid, in("x17") eid,
+        );
+    }
+    match error {
+        SBI_SUCCESS => Ok(value),
+        err => Err(err)
+    }
+}
+
+// Base extension
+
+pub fn spec_version() -> usize {
+    sbi_call(EID_BASE, 0, 0, 0, 0, 0, 0).unwrap_or(0)
+}
+
+pub fn impl_id() -> usize {
+    sbi_call(EID_BASE, 1, 0, 0, 0, 0, 0).unwrap_or(0)
+}
+
+pub fn impl_version() -> usize {
+    sbi_call(EID_BASE, 2, 0, 0, 0, 0, 0).unwrap_or(0)
+}
+
+/// Whether the SBI implementation provides extension eid.
+pub fn probe_extension(eid: usize) -> bool {
+    match sbi_call(EID_BASE, 3, eid, 0, 0, 0, 0) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => true
+    }
+}
+
+/// Make sure every extension the kernel depends on is there.
+pub fn sbi_init() {
+    let version = spec_version();
+    println!("sbi: spec v{}.{}, impl {} v0x{:x}",
+        version >> 24 & 0x7f, version & 0xff_ffff, impl_id(), impl_version());
+    for &(eid, name) in [
+        (EID_TIME, "TIME"), (EID_IPI, "IPI"), (EID_RFENCE, "RFENCE"),
+        (EID_HSM, "HSM"), (EID_SRST, "SRST")
+    ].iter() {
+        if !probe_extension(eid) {
+            panic!("sbi: missing {} extension", name);
+        }
+    }
+}
+
+// Timer extension
+
+/// Program the next timer interrupt of this hart at absolute time
+/// stime_value, this also clears the pending supervisor timer interrupt.
+pub fn set_timer(stime_value: u64) {
+    let _ = sbi_call(EID_TIME, 0, stime_value as usize, 0, 0, 0, 0);
+}
+
+// IPI extension
+
+/// Raise a supervisor software interrupt on the harts in hart_mask,
+/// bit i stands for hart hart_mask_base + i.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult {
+    sbi_call(EID_IPI, 0, hart_mask, hart_mask_base, 0, 0, 0)
+}
+
+// RFENCE extension
+
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiResult {
+    sbi_call(EID_RFENCE, 0, hart_mask, hart_mask_base, 0, 0, 0)
+}
+
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize) -> SbiResult {
+    sbi_call(EID_RFENCE, 1, hart_mask, hart_mask_base, start, size, 0)
+}
+
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize
+) -> SbiResult {
+    sbi_call(EID_RFENCE, 2, hart_mask, hart_mask_base, start, size, asid)
+}
+
+// Hart state management extension
+
+/// Start hart in supervisor mode at start_addr, with
+/// a0 = hartid and a1 = opaque.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiResult {
+    sbi_call(EID_HSM, 0, hartid, start_addr, opaque, 0, 0)
+}
+
+/// Stop the calling hart, does not return on success.
+pub fn hart_stop() -> SbiResult {
+    sbi_call(EID_HSM, 1, 0, 0, 0, 0, 0)
+}
+
+pub fn hart_status(hartid: usize) -> SbiResult {
+    sbi_call(EID_HSM, 2, hartid, 0, 0, 0, 0)
+}
+
+// System reset extension
+
+pub fn system_reset(reset_type: usize, reset_reason: usize) -> ! {
+    let _ = sbi_call(EID_SRST, 0, reset_type, reset_reason, 0, 0, 0);
+    // the panic handler resets too, so just park the hart.
+    loop {
+        unsafe{ core::arch::asm!("wfi"); }
+    }
+}
@@ -11,8 +11,12 @@ use crate::arch::riscv::qemu::layout::PGSIZE;
 use crate::arch::riscv::qemu::param::MAXARG;
 use crate::memory::{ RawPage, PageAllocator };
 use crate::misc::str_cmp;
-use crate::{arch::riscv::qemu::{fs::OpenMode, param::MAXPATH}, fs::{FileType, ICACHE, Inode, InodeData, InodeType, LOG, VFile}, lock::sleeplock::{SleepLock, SleepLockGuard}};
-use crate::fs::{Pipe, DirEntry};
+use crate::arch::riscv::qemu::{fs::OpenMode, param::MAXPATH};
+use crate::fs::{InodeType, VFile, VNode, Attr, namei, permission, owned, mount, umount, unlink};
+use crate::fs::{Pipe, DirEntry, Stat, name_len, MAY_READ, MAY_WRITE, MAY_EXEC, NO_SPACE};
+use crate::arch::riscv::qemu::fs::{ S_IALL, S_ISUID, S_ISGID };
+use crate::driver::rtc;
+use crate::process::current_cred;
 use super::*;
 
 use alloc::string::String;
@@ -22,13 +26,11 @@ use bit_field::BitField;
 
 impl Syscall<'_> {
     pub fn sys_dup(&self) -> SysResult {
-        let old_fd = self.arg(0);
+        let (_, file) = self.arg_fd(0)?;
         let pdata = unsafe{ &mut *self.process.data.get() };
-        let file = pdata.open_files[old_fd].as_ref().unwrap();
         // 使用 Arc 来代替 refs
-        let new_fd = unsafe{ CPU_MANAGER.alloc_fd(&file) }.unwrap();
-        let new_file = Arc::clone(&file);
-        pdata.open_files[new_fd].replace(new_file);
+        let new_fd = pdata.find_unallocated_fd().map_err(|_| Errno::EMFILE)?;
+        pdata.open_files[new_fd].replace(file);
         Ok(new_fd)
     }
 
@@ -36,9 +38,7 @@ impl Syscall<'_> {
     pub fn sys_read(&self) -> SysResult {
         let size: usize;
         // Get file
-        let fd = self.arg(0);
-        let pdata = unsafe{ &mut *self.process.data.get() };
-        let file = pdata.open_files[fd].as_ref().unwrap();
+        let (_, file) = self.arg_fd(0)?;
         // 两个参数分别是读取存储的地址和读取的最大字节数
         // Get user read address
         let ptr = self.arg(1);
@@ -53,7 +53,7 @@ impl Syscall<'_> {
             Err(err) => {
                 // #[cfg(feature = "kernel_warning")]
                 println!("[kernel] sys_read: err: {}", err);
-                return Err(())
+                return Err(Errno::EIO)
             }
         }
         Ok(size)
@@ -62,18 +62,17 @@ impl Syscall<'_> {
     /// Write into file.
     pub fn sys_write(&self) -> SysResult {
         let size;
-        let fd = self.arg(0);
-        let pdata = unsafe{ &mut *self.process.data.get() };
-        let file = pdata.open_files[fd].as_ref().unwrap();
+        let (_, file) = self.arg_fd(0)?;
         let ptr = self.arg(1);
         let len = self.arg(2);
         match file.write(ptr, len) {
             Ok(cur_size) => {
                 size = cur_size;
             },
+            Err(err) if err == NO_SPACE => return Err(Errno::ENOSPC),
             Err(err) => {
                 println!("[Kernel] sys_write: err: {}", err);
-                return Err(())
+                return Err(Errno::EIO)
             }
         }
         Ok(size)
@@ -81,89 +80,65 @@ impl Syscall<'_> {
 
     pub fn sys_open(&self) -> SysResult {
         let mut path = [0;MAXPATH];
-        let inode: Inode;
-        let mut file: VFile;
-        let mut inode_guard: SleepLockGuard<InodeData>;
         // Get file path
         let addr = self.arg(0);
-        self.copy_from_str(addr, &mut path, MAXPATH).unwrap();
+        self.copy_from_str(addr, &mut path, MAXPATH)?;
         // Get open mode
         let open_mode = self.arg(1);
-        // Start write log
-        LOG.begin_op();
-        match OpenMode::mode(open_mode) {
+        let node = match OpenMode::mode(open_mode) {
             OpenMode::CREATE => {
-                match ICACHE.create(&path, crate::fs::InodeType::File, 0, 0) {
-                    Ok(cur_inode) => {
-                        inode = cur_inode;
-                        inode_guard = inode.lock();
-                    },
+                match namei::create(&path, InodeType::File, 0, 0) {
+                    Ok(node) => node,
                     Err(err) => {
-                        LOG.end_op();
-                        println!("[Kernel] syscall: sys_open: {}", err);
-                        return Err(())
+                        println!("[Kernel] syscall: sys_open: {:?}", err);
+                        return Err(err)
                     }
                 }
             },
     
             _ => {
-                match ICACHE.namei(&path) {
-                    Some(cur_inode) => {
-                        inode = cur_inode;
-                        inode_guard = inode.lock();
-                        if inode_guard.dinode.itype == InodeType::Directory && open_mode != OpenMode::RDONLY as usize {
-                            // println!("[Kernel] itype: {:?}, open_mode: {}", inode_guard.dinode.itype, open_mode);
-                            drop(inode_guard);
-                            LOG.end_op();
-                            return Err(());
-                        }
+                // O_NOFOLLOW: a symbolic link in the last component is an error
+                let follow = !open_mode.get_bit(12);
+                let node = namei::lookup(&path, follow)?;
+                match node.node.itype() {
+                    InodeType::Directory if open_mode & !(OpenMode::NOFOLLOW as usize) != OpenMode::RDONLY as usize => {
+                        return Err(Errno::EISDIR)
                     },
-                    None => {
-                        LOG.end_op();
-                        return Err(())
-                    }
+                    InodeType::Symlink => return Err(Errno::ELOOP),
+                    _ => node
                 }
             }
+        };
+        let mut mask = 0;
+        if !open_mode.get_bit(0) || open_mode.get_bit(1) {
+            mask |= MAY_READ;
         }
-        file = VFile::init();
-        match inode_guard.dinode.itype {
-            InodeType::Device => {
-                file.ftype = FileType::Device;
-                file.major = inode_guard.dinode.major;
-                file.readable = true;
-                file.writeable = true;
-            },
-            _ => {
-                file.ftype = FileType::Inode;
-                file.offset = 0;
-                file.readable = true;
-                file.writeable = true;
-            }
+        if open_mode.get_bit(0) || open_mode.get_bit(1) || open_mode.get_bit(11) {
+            mask |= MAY_WRITE;
         }
-    
-        if open_mode.get_bit(11) && inode_guard.dinode.itype == InodeType::File {
-            inode_guard.truncate(&inode);
+        permission(&*node.node, mask)?;
+
+        if open_mode.get_bit(11) && node.node.itype() == InodeType::File {
+            node.node.truncate()?;
         }
     
-        // Drop guard for immutable borrow
-        drop(inode_guard);
-        LOG.end_op();
-    
-        file.inode = Some(inode);
         // 0x0 -> read only
         // 0x1 -> write only
         // 0x2 -> read & write
-        file.writeable = open_mode.get_bit(0) | open_mode.get_bit(1);
-        file.readable = !open_mode.get_bit(0) | open_mode.get_bit(1);
+        let file = VFile::open(
+            node, 
+            !open_mode.get_bit(0) | open_mode.get_bit(1), 
+            open_mode.get_bit(0) | open_mode.get_bit(1)
+        );
         let fd;
-        match unsafe { CPU_MANAGER.alloc_fd(&file) } {
+        match unsafe { CPU_MANAGER.alloc_fd(file) } {
             Ok(new_fd) => {
                 fd = new_fd;
                 // println!("[Kernel] fd: {}", fd);
             }
             Err(err) => {
                 println!("[Kernel] sys_open: err: {}", err);
-                return Err(())
+                return Err(Errno::EMFILE)
             }
         }
         Ok(fd)
@@ -175,95 +150,100 @@ impl Syscall<'_> {
         let mut argv = [0 as *mut u8; MAXARG];
         let mut user_arg: usize;
         let addr = self.arg(0);
-        self.copy_from_str(addr, &mut path, MAXPATH).unwrap();
+        self.copy_from_str(addr, &mut path, MAXPATH)?;
         let user_argv = self.arg(1);
-        let path = from_utf8(&path).unwrap();
+        let path = from_utf8(&path).map_err(|_| Errno::EINVAL)?;
     
+        // 将用户参数逐个拷贝到内核页中
+        let mut res = Ok(());
         let mut count = 0;
         loop {
             if count >= argv.len() {
-                for i in 0..MAXARG {
-                    if argv[i] != 0 as *mut u8 {
-                        unsafe{ drop_in_place(argv[i] as *mut RawPage) };
-                    }
-                }
-                return Err(());
+                res = Err(Errno::E2BIG);
+                break;
             }
             let mut buf = [0u8;8];
-            self.copy_form_addr(
+            if let Err(err) = self.copy_form_addr(
                 user_argv + count * size_of::<usize>(), 
                 &mut buf, 
                 8
-            )?;
+            ) {
+                res = Err(err);
+                break;
+            }
 
             user_arg = usize::from_le_bytes(buf);
             if user_arg == 0 {
                 argv[count] = 0 as *mut u8;
                 break;
             }
-            let mem = unsafe{ RawPage::new_zeroed() as *mut u8 };
+            let mem = match unsafe{ RawPage::try_new_zeroed() } {
+                Some(mem) => mem as *mut u8,
+                None => {
+                    res = Err(Errno::ENOMEM);
+                    break;
+                }
+            };
             argv[count] = mem;
+            count += 1;
             let buf = unsafe { from_raw_parts_mut(mem, PGSIZE) };
-            self.copy_from_str(
+            if let Err(err) = self.copy_from_str(
                 user_arg, 
                 buf, 
                 PGSIZE
-            )?;   
-            count += 1;
+            ) {
+                res = Err(err);
+                break;
+            }
         }
     
-        let argv = unsafe{ 
-            from_raw_parts(
-                argv.as_ptr() as *const *const u8, 
-                MAXARG
-            ) 
-        };
-        let ret = unsafe {
-            exec(path, &argv).map_err(
-                |_|(())
-            )?
+        let ret = match res {
+            Ok(()) => unsafe {
+                let argv = from_raw_parts(
+                    argv.as_ptr() as *const *const u8, 
+                    MAXARG
+                );
+                exec(path, &argv)
+            },
+            Err(err) => Err(err)
         };
     
         for i in 0..MAXARG {
             if argv[i] != 0 as *mut u8 {
-                unsafe{ drop_in_place(argv[i] as *mut RawPage) };
+                unsafe{ RawPage::free(argv[i] as usize) };
             }
         }
         // println!("[Debug] sys_exec return {}", ret);
-        Ok(ret)
+        ret
     }
 
     pub fn sys_mknod(&self) -> SysResult {
         let mut path: [u8; MAXPATH] = [0;MAXPATH];
         let major = self.arg(1);
         let minor = self.arg(2);
-        LOG.begin_op();
         // Get file path
         let addr = self.arg(0);
         self.copy_from_str(addr, &mut path, MAXPATH)?;
-        match ICACHE.create(
+        if !current_cred().is_root() {
+            return Err(Errno::EPERM)
+        }
+        match namei::create(
             &path, 
             InodeType::Device, 
             major as i16, 
             minor as i16
         ) {
-            Ok(inode) => {
-                LOG.end_op();
-                drop(inode);
-                Ok(0)
-            },
+            Ok(_) => Ok(0),
     
             Err(err) => {
-                println!("[Kernel] sys_mknod: err: {}", err);
-                LOG.end_op();
-                Err(())
+                println!("[Kernel] sys_mknod: err: {:?}", err);
+                Err(err)
             }
         }
     
     }
-
     pub fn sys_close(&self) -> SysResult {
-        let fd = self.arg(0);
+        let (fd, _) = self.arg_fd(0)?;
         let pdata = unsafe{ &mut *self.process.data.get() };
         // 使用 take() 夺取所有权来将引用数减 1
         pdata.open_files[fd].take();
@@ -271,71 +251,41 @@ impl Syscall<'_> {
     }
 
     pub fn sys_fstat(&self) -> SysResult {
-        let fd = self.arg(0);
+        let (fd, file) = self.arg_fd(0)?;
         let stat = self.arg(1);
 
         #[cfg(feature = "kernel_debug")]
         println!("[Kernel] sys_fstat: fd: {}, stat:0x{:x}", fd, stat);
 
-        let pdata = unsafe{ &mut *self.process.data.get() };
-        let file = pdata.open_files[fd].as_ref().unwrap();
-
-        #[cfg(feature = "kernel_debug")]
-        println!("[Kernel] sys_fstat: File Type: {:?}", file.ftype);
-
-        match file.stat(stat) {
-            Ok(()) => {
-                return Ok(0)
-            },
-
-            Err(err) => {
-                println!("[Kernel] sys_stat: err: {}", err);
-                return Err(())
-            }
+        let mut st = Stat::new();
+        if let Err(err) = file.stat(&mut st) {
+            println!("[Kernel] sys_stat: err: {}", err);
+            return Err(Errno::EFAULT)
         }
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let page_table = pdata.pagetable.as_mut().unwrap();
+        page_table.copy_out(stat, (&st) as *const Stat as *const u8, size_of::<Stat>())
+            .map_err(|_| Errno::EFAULT)?;
+        Ok(0)
     }
 
     pub fn sys_chdir(&self) -> SysResult {
         let mut path = [0u8; MAXPATH];
-        LOG.begin_op();
         let addr = self.arg(0);
         self.copy_from_str(addr, &mut path, MAXPATH)?;
-        match ICACHE.namei(&path) {
-            Some(inode) => {
-                let inode_guard = inode.lock();
-                match inode_guard.dinode.itype {
-                    InodeType::Directory => {
-                        drop(inode_guard);
-                        let old_cwd = unsafe{ (&mut *self.process.data.get()).cwd.replace(inode) };
-                        drop(old_cwd);
-                        LOG.end_op();
-                        return Ok(0)
-                    },
-
-                    _ => {
-                        LOG.end_op();
-                        drop(inode_guard);
-                        return Err(())
-                    }
-                }
-            },
-
-            None => {
-                LOG.end_op();
-                return Err(())
-            }
+        let node = namei::lookup(&path, true)?;
+        if node.node.itype() != InodeType::Directory {
+            return Err(Errno::ENOTDIR)
         }
-
+        permission(&*node.node, MAY_EXEC)?;
+        let old_cwd = unsafe{ (&mut *self.process.data.get()).cwd.replace(node) };
+        drop(old_cwd);
+        Ok(0)
     }
-
     pub fn sys_pipe(&self) -> SysResult {
-        // User use an array to represent two file. 
-        // let mut fd_array: usize = 0;
-        let mut rf: &mut VFile = &mut VFile::init();
-        let mut wf: &mut VFile = &mut VFile::init();
-        // arg_addr(0, &mut &mut fd_array)?;
+        // User use an array of two int to represent two file. 
         let fd_array = self.arg(0);
-        Pipe::alloc(&mut rf, &mut wf);
+        let (rf, wf) = Pipe::alloc();
 
         let p = unsafe {
             CPU_MANAGER.myproc().expect("Fail to get my process.")
@@ -350,9 +300,8 @@ impl Syscall<'_> {
             },
 
             Err(err) => {
-                // rf.close();
                 println!("[Kernel] sys_pipe: err: {}", err);
-                return Err(())
+                return Err(Errno::EMFILE)
             }
         }
         
@@ -362,34 +311,21 @@ impl Syscall<'_> {
             },
 
             Err(err) => {
-                // rf.close();
-                // wf.close();
                 println!("[Kernel] sys_pipe: err: {}", err);
-                return Err(())
+                p.data.get_mut().open_files[rfd].take();
+                return Err(Errno::EMFILE)
             }
         }
 
+        let fds = [rfd as i32, wfd as i32];
         let pgt = p.page_table();
         let pdata = unsafe{ &mut *self.process.data.get() };
         let open_files = &mut pdata.open_files;
-        if pgt.copy_out(fd_array, rf as *const _ as *const u8, size_of::<usize>()).is_err() {
-            open_files[rfd].take();
-            open_files[wfd].take();
-            // rf.close();
-            // wf.close();
-            return Err(())
-        }
-
-        if pgt.copy_out(
-            fd_array + size_of::<usize>(), 
-            wf as *const _ as *const u8, 
-            size_of::<usize>()
-        ).is_err() {
+        if pgt.copy_out(fd_array, fds.as_ptr() as *const u8, size_of::<[i32; 2]>()).is_err() {
+            // dropping the files closes the pipe
             open_files[rfd].take();
             open_files[wfd].take();
-            // rf.close();
-            // wf.close();
-            return Err(())
+            return Err(Errno::EFAULT)
         }
         Ok(0)
     }
@@ -397,64 +333,18 @@ impl Syscall<'_> {
     pub fn sys_unlink(&self) -> SysResult {
         let mut path = [0u8; MAXPATH];
         let mut name = [0u8; DIRSIZ];
-        let parent: Inode;
-        let inode: Inode;
 
         let addr = self.arg(0);
         self.copy_from_str(addr, &mut path, MAXPATH)?;
 
-        LOG.begin_op();
-        match ICACHE.namei_parent(&path, &mut name) {
-            Some(cur) => {
-                parent = cur;
-            },
-            None => {
-                LOG.end_op();
-                return Err(())
-            }
-        }
-        let mut parent_guard = parent.lock();
-        if str_cmp(&name, ".".as_bytes(), DIRSIZ) &&
-            str_cmp(&name, "..".as_bytes(), DIRSIZ) {
-                drop(parent_guard);
-                LOG.end_op();
-                return Err(())
-        }
-        match parent_guard.dir_lookup(&name) {
-            Some(cur) => {
-                inode = cur;
-            },
-            _ => {
-                drop(parent_guard);
-                LOG.end_op();
-                return Err(())
-            }
-        }
-
-        let mut inode_guard = inode.lock();
-        if inode_guard.dinode.nlink < 1 {
-            panic!("sys_unlink: inods's nlink must be larger than 1.");
+        let dir = namei::lookup_parent(&path, &mut name)?;
+        permission(&*dir.node, MAY_WRITE | MAY_EXEC)?;
+        let len = name_len(&name);
+        if &name[..len] == b"." || &name[..len] == b".." {
+            return Err(Errno::EINVAL)
         }
-
-        if inode_guard.dinode.itype == InodeType::Directory && 
-            !inode_guard.is_dir_empty() {
-                drop(inode_guard);
-                drop(parent_guard);
-                LOG.end_op();
-                return Err(())
-            }
-
-        if inode_guard.dinode.itype == InodeType::Directory {
-            parent_guard.dinode.nlink -= 1;
-            parent_guard.update();
-        }
-        drop(parent_guard);
-
-        inode_guard.dinode.nlink -= 1;
-        inode_guard.update();
-        drop(inode_guard);
-
-        LOG.end_op();
+        // a mount point stays until it is unmounted
+        unlink(&dir, &name)?;
         Ok(0)
     }
 
@@ -463,85 +353,207 @@ impl Syscall<'_> {
         let mut new_path = [0u8; MAXPATH];
         let mut old_path = [0u8; MAXPATH];
         let mut name = [0u8; DIRSIZ];
-        let inode: Inode;
-        let parent: Inode;
 
         let old_path_addr = self.arg(0);
         let new_path_addr = self.arg(1);
         self.copy_from_str(old_path_addr, &mut old_path, MAXPATH)?;
         self.copy_from_str(new_path_addr, &mut new_path, MAXPATH)?;
 
-        LOG.begin_op();
-        match ICACHE.namei(&old_path) {
-            Some(cur) => {
-                inode = cur;
-            },
-
-            None => {
-                LOG.end_op();
-                return Err(())
-            }
+        let node = namei::lookup(&old_path, true)?;
+        if node.node.itype() == InodeType::Directory {
+            return Err(Errno::EPERM)
         }
-        let mut inode_guard = inode.lock();
-        if inode_guard.dinode.itype == InodeType::Directory {
-            drop(inode_guard);
-            LOG.end_op();
-            return Err(())
+        let dir = namei::lookup_parent(&new_path, &mut name)?;
+        permission(&*dir.node, MAY_WRITE | MAY_EXEC)?;
+        dir.node.link(&name, &*node.node)?;
+        Ok(0)
+    }
+
+    /// Create the path linkpath as a symbolic link to target. 
+    pub fn sys_symlink(&self) -> SysResult {
+        let mut target = [0u8; MAXPATH];
+        let mut path = [0u8; MAXPATH];
+        let mut name = [0u8; DIRSIZ];
+        self.copy_from_str(self.arg(0), &mut target, MAXPATH)?;
+        self.copy_from_str(self.arg(1), &mut path, MAXPATH)?;
+        let len = name_len(&target);
+        if len == 0 {
+            return Err(Errno::ENOENT)
         }
 
-        inode_guard.dinode.nlink += 1;
+        let dir = namei::lookup_parent(&path, &mut name)?;
+        permission(&*dir.node, MAY_WRITE | MAY_EXEC)?;
+        dir.node.symlink(&name, &target[..len])?;
+        Ok(0)
+    }
 
-        match ICACHE.namei_parent(&new_path, &mut name) {
-            Some(cur) => {
-                parent = cur;
-            },
+    /// Copy the target of the symbolic link path into buf, 
+    /// at most size bytes and without a trailing 0. 
+    pub fn sys_readlink(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        let buf = self.arg(1);
+        let size = self.arg(2);
+
+        let node = namei::lookup(&path, false)?;
+        let mut target = [0u8; MAXPATH];
+        let len = node.node.read_link(&mut target)?;
+        drop(node);
+        let count = core::cmp::min(size, len);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let page_table = pdata.pagetable.as_mut().unwrap();
+        page_table.copy_out(buf, target.as_ptr(), count)
+            .map_err(|_| Errno::EFAULT)?;
+        Ok(count)
+    }
 
-            _ => {
-                inode_guard.dinode.nlink -= 1;
-                drop(inode_guard);
-                LOG.end_op();
-                return Err(())
+    /// Like fstat on an open path, but a symbolic link in the
+    /// last component is described itself. 
+    pub fn sys_lstat(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        let addr = self.arg(1);
+
+        let node = namei::lookup(&path, false)?;
+        let mut stat = Stat::new();
+        node.node.stat(&mut stat);
+        drop(node);
+
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let page_table = pdata.pagetable.as_mut().unwrap();
+        page_table.copy_out(addr, (&stat) as *const Stat as *const u8, size_of::<Stat>())
+            .map_err(|_| Errno::EFAULT)?;
+        Ok(0)
+    }
+
+    /// Look up path and run f on its node. 
+    fn with_node<F>(&self, path: &[u8], f: F) -> SysResult 
+        where F: FnOnce(&dyn VNode) -> SysResult
+    {
+        let node = namei::lookup(path, true)?;
+        f(&*node.node)
+    }
+
+    /// Set the permission bits of path, for its owner and root. 
+    pub fn sys_chmod(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        let mode = self.arg(1) as u16 & S_IALL;
+        self.with_node(&path, |node| {
+            if !owned(node) {
+                return Err(Errno::EPERM)
             }
-        }
-        let mut parent_guard = parent.lock();
-        if parent_guard.dinode.itype != InodeType::Directory || 
-            parent_guard.dir_link(&name, inode.inum).is_ok() {
-                drop(parent_guard);
-                inode_guard.dinode.nlink -= 1;
-                drop(inode_guard);
-                LOG.end_op();
-                return Err(())
+            node.set_attr(&Attr { mode: Some(mode), ..Attr::default() })?;
+            Ok(0)
+        })
+    }
+
+    /// Give path to another owner and group, root only. 
+    /// An id of -1 is left as it is. 
+    pub fn sys_chown(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        let uid = self.arg(1) as u32;
+        let gid = self.arg(2) as u32;
+        self.with_node(&path, |node| {
+            if !current_cred().is_root() {
+                return Err(Errno::EPERM)
             }
-        
-        inode_guard.update();
-        drop(inode_guard);
-        LOG.end_op();
-        return Ok(0)
+            let mut stat = Stat::new();
+            node.stat(&mut stat);
+            let mut attr = Attr::default();
+            if uid != u32::MAX {
+                attr.uid = Some(uid);
+            }
+            if gid != u32::MAX {
+                attr.gid = Some(gid);
+            }
+            // a new owner does not get the old one's setuid program
+            if stat.itype != InodeType::Directory {
+                attr.mode = Some(stat.mode & !(S_ISUID | S_ISGID));
+            }
+            node.set_attr(&attr)?;
+            Ok(0)
+        })
+    }
+
+    /// Set the access and modification times of path from the two
+    /// u64 seconds at times, or to now when times is 0. 
+    pub fn sys_utimes(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        let addr = self.arg(1);
+        let times = if addr == 0 {
+            None
+        } else {
+            let mut times = [0u64; 2];
+            let pdata = unsafe{ &mut *self.process.data.get() };
+            pdata.pagetable.as_mut().unwrap()
+                .copy_in(times.as_mut_ptr() as *mut u8, addr, size_of::<[u64; 2]>())
+                .map_err(|_| Errno::EFAULT)?;
+            Some(times)
+        };
+        self.with_node(&path, |node| {
+            let [atime, mtime] = match times {
+                Some(times) => {
+                    if !owned(node) {
+                        return Err(Errno::EPERM)
+                    }
+                    times
+                },
+                None => {
+                    if !owned(node) {
+                        permission(node, MAY_WRITE)?;
+                    }
+                    let now = rtc::now();
+                    [now, now]
+                }
+            };
+            node.set_attr(&Attr { atime: Some(atime), mtime: Some(mtime), ..Attr::default() })?;
+            Ok(0)
+        })
     }
 
     pub fn sys_mkdir(&self) -> SysResult {
         let mut path = [0u8; MAXPATH];
-        LOG.begin_op();
         let addr = self.arg(0);
         self.copy_from_str(addr, &mut path, MAXPATH)?;
-        match ICACHE.create(&path, InodeType::Directory, 0, 0) {
-            Ok(inode) => {
-                drop(inode);
-                LOG.end_op();
-                Ok(0)
-            },
+        match namei::create(&path, InodeType::Directory, 0, 0) {
+            Ok(_) => Ok(0),
 
             Err(err) => {
-                println!("[Kernel] sys_mkdir: err: {}", err);
-                Err(())
+                println!("[Kernel] sys_mkdir: err: {:?}", err);
+                Err(err)
             }
         }
     }
 
-}
-
-
-
-
+    /// Mount the file system of type fstype on device dev
+    /// at the directory path, root only. 
+    pub fn sys_mount(&self) -> SysResult {
+        let dev = self.arg(0) as u32;
+        let mut path = [0u8; MAXPATH];
+        let mut fstype = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(1), &mut path, MAXPATH)?;
+        self.copy_from_str(self.arg(2), &mut fstype, MAXPATH)?;
+        if !current_cred().is_root() {
+            return Err(Errno::EPERM)
+        }
+        let target = namei::lookup(&path, true)?;
+        mount(&fstype[..name_len(&fstype)], dev, target)?;
+        Ok(0)
+    }
 
+    /// Unmount the file system mounted at path, root only. 
+    pub fn sys_umount(&self) -> SysResult {
+        let mut path = [0u8; MAXPATH];
+        self.copy_from_str(self.arg(0), &mut path, MAXPATH)?;
+        if !current_cred().is_root() {
+            return Err(Errno::EPERM)
+        }
+        let target = namei::lookup(&path, true)?;
+        umount(target)?;
+        Ok(0)
+    }
 
+}
@@ -1,7 +1,9 @@
 mod proc;
 mod file;
+mod errno;
 pub use proc::*;
 pub use file::*;
+pub use errno::*;
 
 use crate::arch::riscv::qemu::fs::NOFILE;
 use crate::{println, process::*};
@@ -14,26 +16,23 @@ use core::str::from_utf8;
 use alloc::sync::Arc;
 
 type SyscallFn = fn() -> SysResult;
-pub type SysResult = Result<usize, ()>;
+pub type SysResult = Result<usize, Errno>;
 
-pub const SYSCALL_NUM:usize = 21;
-pub const SHUTDOWN: usize = 8;
-pub const REBOOT: usize = 9;
+pub const SYSCALL_NUM:usize = 37;
 
 #[no_mangle]
 pub unsafe fn handle_syscall() {
     let proc = CPU_MANAGER.myproc().unwrap();
     let mut syscall = Syscall{ process: proc };
-    if let Ok(res) = syscall.syscall() {
-        let pdata = &mut *proc.data.get();
-        let tf = &mut *pdata.trapframe;
-        tf.a0 = res;
-    }else{
-        let pdata = &mut *proc.data.get();
-        let tf = &mut *pdata.trapframe;
-        tf.a0 = -1 as isize as usize
+    let res = syscall.syscall();
+    let pdata = &mut *proc.data.get();
+    let tf = &mut *pdata.trapframe;
+    match res {
+        Ok(res) => tf.a0 = res,
+        // 失败时返回负的错误号
+        Err(errno) => tf.a0 = errno.as_return()
     }
-    
+
 }
 
 
@@ -61,6 +60,22 @@ pub enum SysCallID {
     SysLink = 19,
     SysMkdir = 20,
     SysClose = 21,
+    SysSymlink = 22,
+    SysReadlink = 23,
+    SysLstat = 24,
+    SysChmod = 25,
+    SysChown = 26,
+    SysUtimes = 27,
+    SysGetUid = 28,
+    SysSetUid = 29,
+    SysGetGid = 30,
+    SysSetGid = 31,
+    SysSetGroups = 32,
+    SysShutdown = 33,
+    SysMount = 34,
+    SysUmount = 35,
+    SysGetEUid = 36,
+    SysGetEGid = 37,
     Unknown
 }
 
@@ -87,7 +102,23 @@ impl SysCallID {
             18 => { Self::SysUnlink },
             19 => { Self::SysLink },
             20 => { Self::SysMkdir },
-            21 => { Self::SysClose }
+            21 => { Self::SysClose },
+            22 => { Self::SysSymlink },
+            23 => { Self::SysReadlink },
+            24 => { Self::SysLstat },
+            25 => { Self::SysChmod },
+            26 => { Self::SysChown },
+            27 => { Self::SysUtimes },
+            28 => { Self::SysGetUid },
+            29 => { Self::SysSetUid },
+            30 => { Self::SysGetGid },
+            31 => { Self::SysSetGid },
+            32 => { Self::SysSetGroups },
+            33 => { Self::SysShutdown },
+            34 => { Self::SysMount },
+            35 => { Self::SysUmount },
+            36 => { Self::SysGetEUid },
+            37 => { Self::SysGetEGid },
             _ => { Self::Unknown }
         }
     }
@@ -118,13 +149,35 @@ impl Syscall<'_> {
             SysCallID::SysDup => { self.sys_dup() },
             SysCallID::SysUptime => { Ok(0) },
             SysCallID::SysSbrk => { self.sys_sbrk() },
+            SysCallID::SysGetPid => { self.sys_getpid() },
+            SysCallID::SysSleep => { self.sys_sleep() },
+            SysCallID::SysKill => { self.sys_kill() },
             SysCallID::SysFstat => { self.sys_fstat() },
             SysCallID::SysChdir => { self.sys_chdir()},
             SysCallID::SysPipe => { self.sys_pipe() },
             SysCallID::SysUnlink => { self.sys_unlink() },
             SysCallID::SysLink => { self.sys_link() },
             SysCallID::SysMkdir => { self.sys_mkdir() },
-            _ => { panic!("Invalid syscall id: {:?}", sys_id) }
+            SysCallID::SysSymlink => { self.sys_symlink() },
+            SysCallID::SysReadlink => { self.sys_readlink() },
+            SysCallID::SysLstat => { self.sys_lstat() },
+            SysCallID::SysChmod => { self.sys_chmod() },
+            SysCallID::SysChown => { self.sys_chown() },
+            SysCallID::SysUtimes => { self.sys_utimes() },
+            SysCallID::SysGetUid => { self.sys_getuid() },
+            SysCallID::SysSetUid => { self.sys_setuid() },
+            SysCallID::SysGetGid => { self.sys_getgid() },
+            SysCallID::SysSetGid => { self.sys_setgid() },
+            SysCallID::SysSetGroups => { self.sys_setgroups() },
+            SysCallID::SysShutdown => { self.sys_shutdown() },
+            SysCallID::SysMount => { self.sys_mount() },
+            SysCallID::SysUmount => { self.sys_umount() },
+            SysCallID::SysGetEUid => { self.sys_geteuid() },
+            SysCallID::SysGetEGid => { self.sys_getegid() },
+            _ => {
+                println!("[Kernel] Invalid syscall id: {}", tf.a7);
+                Err(Errno::ENOSYS)
+            }
         }
     }
 
@@ -143,50 +196,41 @@ impl Syscall<'_> {
         }
     }
 
+    /// 获取第n个参数对应的文件描述符及打开的文件
+    pub fn arg_fd(&self, id: usize) -> Result<(usize, Arc<VFile>), Errno> {
+        let fd = self.arg(id);
+        let pdata = unsafe{ &*self.process.data.get() };
+        match pdata.open_files.get(fd) {
+            Some(Some(file)) => Ok((fd, Arc::clone(file))),
+            _ => Err(Errno::EBADF)
+        }
+    }
+
     /// 通过地址获取str并将其填入到缓冲区中
-    pub fn copy_from_str(&self, addr: usize, buf: &mut [u8], max_len: usize) -> Result<(), ()> {
+    pub fn copy_from_str(&self, addr: usize, buf: &mut [u8], max_len: usize) -> Result<(), Errno> {
         let pdata = unsafe{ &mut *self.process.data.get() };
         let pgt = pdata.pagetable.as_mut().unwrap();
         if pgt.copy_in_str(buf.as_mut_ptr(), addr, max_len).is_err() {
             println!("Fail to copy in str");
-            return Err(())
+            return Err(Errno::EFAULT)
         }
         Ok(())
     }
 
-    pub fn copy_form_addr(&self, addr: usize, buf: &mut [u8], len: usize) -> Result<(), ()> {
+    pub fn copy_form_addr(&self, addr: usize, buf: &mut [u8], len: usize) -> Result<(), Errno> {
         let pdata = unsafe{ &mut *self.process.data.get() };
     
-        if addr > pdata.size || addr + size_of::<usize>() > pdata.size {
-            println!("[Debug] addr: 0x{:x}", addr);
-            println!("[Debug] pdata size: 0x{:x}", pdata.size);
-            panic!("拷贝的地址值超出了进程")
+        if addr >= pdata.size || addr + len > pdata.size {
+            return Err(Errno::EFAULT)
         }
     
         let pgt = pdata.pagetable.as_mut().unwrap();
         if pgt.copy_in(buf.as_mut_ptr(), addr, len).is_err() {
             println!("Fail copy data from pagetable!");
-            return Err(())
+            return Err(Errno::EFAULT)
         }
         
         
         Ok(())
     }
 }
-
-#[inline]
-pub fn kernel_env_call(
-    which: usize,
-    arg0: usize,
-    arg1: usize,
-    arg2: usize,   
-) -> usize {
-    let mut ret;
-    unsafe {
-        core::arch::asm!("ecall"
-            , inlateout("x10") arg0 => ret,
-             in("x11") arg1, in("x12") arg2, in("x17") which,
-        );
-    }
-    ret
-}
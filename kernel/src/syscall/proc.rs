@@ -1,11 +1,16 @@
-use crate::trap::TICKS_LOCK;
+use crate::trap::ticks;
+use crate::lock::waitqueue::WaitQueue;
+use crate::arch::riscv::qemu::param::NGROUPS;
+use crate::shutdown::shutdown;
 use super::*;
 
+static SLEEP_QUEUE: WaitQueue = WaitQueue::new_interruptible("sleep");
+
 impl Syscall<'_> {
     pub fn sys_fork(&mut self) -> SysResult {
         let proc_meta = self.process.meta.acquire();
         drop(proc_meta);
-        let child_proc = self.process.fork().ok_or(())?;
+        let child_proc = self.process.fork()?;
         let pmeta = child_proc.meta.acquire();
         let pid = pmeta.pid;
         drop(pmeta);
@@ -29,7 +34,7 @@ impl Syscall<'_> {
             },
     
             None => {
-                Err(())
+                Err(Errno::ECHILD)
             }
         }
     }
@@ -53,7 +58,8 @@ impl Syscall<'_> {
             }
     
             Err(err) => {
-                panic!("err: {:?}", err);
+                println!("[Kernel] sys_sbrk: err: {}", err);
+                Err(Errno::ENOMEM)
             }
         }
     }
@@ -62,37 +68,88 @@ impl Syscall<'_> {
     
     pub fn sys_sleep(&self) -> SysResult {
         let time_span = self.arg(0);
-
-        let mut ticks_guard = unsafe {
-            TICKS_LOCK.acquire()
-        };
-        let now_time = *ticks_guard;
-        let mut cur_time = *ticks_guard;
-        while cur_time - now_time < time_span {
+        let now_time = ticks();
+        loop {
+            let slept = ticks() - now_time;
+            if slept >= time_span {
+                return Ok(0)
+            }
             let my_proc = unsafe {
                 CPU_MANAGER.myproc().expect("Fail to get my procsss")
             };
             if my_proc.killed() {
-                drop(ticks_guard);           
-                return Err(())
-            } else {
-                my_proc.sleep(0, ticks_guard);
-                ticks_guard = unsafe {
-                    TICKS_LOCK.acquire()
-                }
+                return Err(Errno::EINTR)
             }
-            cur_time = *ticks_guard;
+            // Nobody wakes this queue, only the timer does.
+            SLEEP_QUEUE.wait_timeout((), time_span - slept);
         }
-        drop(ticks_guard);
-        Ok(0)
     }
     
     
     pub fn sys_kill(&self) -> SysResult {
         let pid = self.arg(0);
+        let cred = current_cred();
         unsafe {
-            PROC_MANAGER.kill(pid)
+            PROC_MANAGER.kill(pid, &cred)
+        }
+    }
+
+    pub fn sys_getuid(&self) -> SysResult {
+        Ok(current_cred().uid as usize)
+    }
+
+    pub fn sys_geteuid(&self) -> SysResult {
+        Ok(current_cred().euid as usize)
+    }
+
+    pub fn sys_setuid(&self) -> SysResult {
+        let uid = self.arg(0) as u32;
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        pdata.cred.set_uid(uid)?;
+        self.process.publish();
+        Ok(0)
+    }
+
+    pub fn sys_getgid(&self) -> SysResult {
+        Ok(current_cred().gid as usize)
+    }
+
+    pub fn sys_getegid(&self) -> SysResult {
+        Ok(current_cred().egid as usize)
+    }
+
+    pub fn sys_setgid(&self) -> SysResult {
+        let gid = self.arg(0) as u32;
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        pdata.cred.set_gid(gid)?;
+        self.process.publish();
+        Ok(0)
+    }
+
+    /// setgroups(n, groups): replace the supplementary groups 
+    /// with the n u32 group ids at groups. 
+    pub fn sys_setgroups(&self) -> SysResult {
+        let n = self.arg(0);
+        let addr = self.arg(1);
+        if n > NGROUPS {
+            return Err(Errno::EINVAL)
+        }
+        let mut groups = [0u32; NGROUPS];
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        pdata.pagetable.as_mut().unwrap()
+            .copy_in(groups.as_mut_ptr() as *mut u8, addr, n * size_of::<u32>())
+            .map_err(|_| Errno::EFAULT)?;
+        pdata.cred.set_groups(&groups[..n])?;
+        self.process.publish();
+        Ok(0)
+    }
+
+    /// Power the machine off, root only. 
+    pub fn sys_shutdown(&self) -> SysResult {
+        if !current_cred().is_root() {
+            return Err(Errno::EPERM)
         }
+        shutdown();
     }
     
 }
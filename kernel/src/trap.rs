@@ -1,19 +1,30 @@
 use core::panic;
+use core::sync::atomic::{ AtomicUsize, Ordering };
 
 use crate::syscall::handle_syscall;
-use crate::driver::plic::{plic_claim, plic_complete};
-use crate::driver::virtio_disk::DISK;
+use crate::driver::plic::handle_irq;
 use crate::arch::riscv::qemu::fs::DIRSIZ;
-use crate::arch::riscv::{sepc, sstatus, scause, stval, stvec, sip, scause::{Scause, Exception, Trap, Interrupt}};
-use crate::lock::spinlock::Spinlock;
+use crate::arch::riscv::{sepc, sstatus, scause, stval, stvec, sip, cycle, time, scause::{Scause, Exception, Trap, Interrupt}};
+use crate::memory::asid::asid_activate;
+use crate::memory::usercopy::{ search_exception_table, leave_user_space };
+use crate::ipi::handle_ipi;
+use crate::sbi;
+use crate::watchdog;
+use crate::prof;
+use crate::backtrace::{ print_pc, print_user_backtrace };
+use crate::lock::waitqueue::expire_timers;
 use crate::process::cpu;
 use crate::arch::riscv::qemu::layout::*;
 use crate::process::*;
 use crate::driver::console::*;
-use crate::shutdown::*;
 use super::*;
 
-pub static mut TICKS_LOCK:Spinlock<usize> = Spinlock::new(0, "time");
+/// Clock ticks since boot, counted by hart 0.
+pub static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
 
 /// Set up to take exceptions and traps while in the kernel.
 pub unsafe fn trap_init_hart() {
@@ -43,6 +54,7 @@ pub unsafe fn user_trap() {
 
     let my_proc = CPU_MANAGER.myproc().unwrap();
     let pdata = my_proc.data.get_mut();
+    pdata.trap_enter = cycle::read();
 
     let tf = &mut *pdata.trapframe;
     tf.epc = sepc;
@@ -66,36 +78,24 @@ pub unsafe fn user_trap() {
         // Device interrupt
         Trap::Interrupt(Interrupt::SupervisorExternal) => {
             // this is a supervisor external interrupt, via PLIC.
-            // irq indicates which device interrupted.
-            if let Some(interrupt) = plic_claim() {
-                match interrupt {
-                    VIRTIO0_IRQ => {
-                        DISK.acquire().intr();
-                    },
-
-                    UART0_IRQ => {
-                        UART.intr();
-                    },
-
-                    _ => {
-                        panic!("Unresolved interrupt");
-                    }
-                }
-                plic_complete(interrupt);
-            }
+            // the handler registered for the irq serves it.
+            handle_irq();
             
         },
 
-        // Clock Interrupt
+        // IPI
         Trap::Interrupt(Interrupt::SupervisorSoft) => {
-            // software interrupt from a machine-mode timer interrupt,
-            // forwarded by timervec in kernelvec.S.
-            if cpu::cpuid() == 0{
-                clock_intr();
-            }
+            // software interrupt raised by another hart through the SBI.
             // acknowledge the software interrupt by clearing
             // the SSIP bit in sip.
             sip::clear_ssip();
+            handle_ipi();
+        },
+
+        // Clock Interrupt
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            timer_intr(tf.epc, tf.ra);
+            prof::sample(prof::Mode::User, tf.epc, my_proc.pid(), 0);
             if my_proc.killed() {
                 exit(-1);
             }
@@ -106,6 +106,7 @@ pub unsafe fn user_trap() {
         _ => {
             println!("usertrap: unexpected scacuse: {:?}\n pid: {}", scause.cause(), my_proc.pid());
             println!("sepc: 0x{:x}, stval: 0x{:x}", sepc, stval::read());
+            print_user_backtrace(pdata);
             my_proc.modify_kill(true);
         }
 
@@ -137,7 +138,7 @@ pub unsafe fn user_trap_ret() -> ! {
     sstatus::intr_off();
 
     // send syscalls, interrupts, and exceptions to trampoline.S
-    stvec::write(TRAMPOLINE + (uservec as usize - trampoline as usize));
+    stvec::write(trampoline_va() + (uservec as usize - trampoline as usize));
 
     // set up trapframe values that uservec will need when
     // the process next re-enters the kernel.
@@ -155,25 +156,34 @@ pub unsafe fn user_trap_ret() -> ! {
     // set S Exception Program Counter to the saved user pc. 
     sepc::write((*pdata.trapframe).epc);
     
-    // tell trampoline.S the user page table to switch to
-    let satp = pdata.pagetable.as_ref().unwrap().as_satp();
+    // tell trampoline.S the user page table to switch to,
+    // and whether the TLB must be flushed before using it.
+    let my_cpu = CPU_MANAGER.mycpu();
+    let flush = asid_activate(&mut pdata.asid, &mut my_cpu.asid_generation);
+    pdata.tlb_harts |= 1 << cpu::cpuid();
+    let satp = pdata.pagetable.as_ref().unwrap().as_satp(pdata.asid.as_usize());
+
+    let cycles = match pdata.trap_enter {
+        0 => None,
+        enter => Some(cycle::read().saturating_sub(enter))
+    };
+    pdata.trap_enter = 0;
+    my_cpu.switch_stats.record(satp, flush, cycles);
 
     // jump to trampoline.S at the top of memory, which
     // switches to the user page table, restores user registers,
     // and switches to user mode with sret. 
-    let userret_virt = TRAMPOLINE + (userret as usize - trampoline as usize);
-    let userret_virt: extern "C" fn(usize, usize) -> ! = 
+    let userret_virt = trampoline_va() + (userret as usize - trampoline as usize);
+    let userret_virt: extern "C" fn(usize, usize, usize) -> ! = 
     core::mem::transmute(userret_virt as usize);
-    userret_virt(TRAPFRAME, satp);
+    userret_virt(trapframe_va(), satp, flush as usize);
 }
 
 /// interrupts and exceptions from kernel code go here via kernelvec,
 /// on whatever the current kernel stack is.
 #[no_mangle]
-pub unsafe fn kernel_trap(
-   _: usize, _: usize, _: usize, _: usize,
-   _: usize, _: usize, _: usize, which: usize
-) {
+/// regs points to the registers saved by kernelvec, ra first.
+pub unsafe fn kernel_trap(regs: *const usize) {
     let sepc = sepc::read();
     let sstatus = sstatus::read();
     let scause = scause::read();
@@ -187,99 +197,90 @@ pub unsafe fn kernel_trap(
         panic!("kerneltrap(): interrupts enabled");
     }
 
+    // a fault while copying user memory resumes at the fixup of
+    // the faulting instruction, which makes the copy fail.
+    let scause = Scause::new(scause);
+    match scause.cause() {
+        Trap::Exception(Exception::LoadFault) |
+        Trap::Exception(Exception::LoadPageFault) |
+        Trap::Exception(Exception::StoreFault) |
+        Trap::Exception(Exception::StorePageFault) => {
+            if let Some(fixup) = search_exception_table(sepc) {
+                sepc::write(fixup);
+                sstatus::write(sstatus);
+                return
+            }
+            leave_user_space();
+        },
+        _ => {}
+    }
+
     let mut local_spec = sepc;
     // Update progrma counter
-    let scause = Scause::new(scause);
     match scause.cause() {
         Trap::Exception(Exception::Breakpoint) => {
             local_spec += 2;
             println!("BreakPoint!");
         },
 
-        Trap::Exception(Exception::LoadFault) => panic!("Load Fault!"),
+        Trap::Exception(Exception::LoadFault) => {
+            print_pc(0, sepc);
+            panic!("Load Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
+        },
 
         Trap::Exception(Exception::LoadPageFault) => {
+            print_pc(0, sepc);
             panic!("[Panic] Load Page Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
         },
 
         Trap::Exception(Exception::StorePageFault) => {
+            print_pc(0, sepc);
             panic!("[Panic] Store Page Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
         },
 
-        Trap::Exception(Exception::KernelEnvCall) => {
-            match which  {
-                SHUTDOWN => {
-                    println!("\x1b[1;31mShutdown!\x1b[0m");
-                    system_reset(
-                        RESET_TYPE_SHUTDOWN,
-                        RESET_REASON_NO_REASON
-                    );
-                },
-        
-                REBOOT => {
-                    println!("\x1b[1;31mReboot!\x1b[0m");
-                    system_reset(
-                        RESET_TYPE_COLD_REBOOT,
-                        RESET_REASON_NO_REASON
-                    );
-                },
-        
-                _ => {
-                    panic!("Unresolved Kernel Syscall!");
-                }
-            }
+        Trap::Exception(Exception::InstructionFault) => {
+            print_pc(0, sepc);
+            panic!("Instruction Fault, sepc: 0x{:x}", sepc)
         },
 
-        Trap::Exception(Exception::InstructionFault) => panic!("Instruction Fault, sepc: 0x{:x}", sepc),
-
         Trap::Exception(Exception::InstructionPageFault) => {
-            println!("sepc: 0x{:x}", sepc);
-            println!("stval: 0x{:x}", stval);
-            panic!();
+            print_pc(0, sepc);
+            panic!("Instruction Page Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
         },
 
         // Device Interruput
         Trap::Interrupt(Interrupt::SupervisorExternal) => {
             // this is a supervisor external interrupt, via PLIC.
-            // interrupt indicates which device interrupted.
-            if let Some(interrupt) = plic_claim() {
-                match interrupt {
-                    VIRTIO0_IRQ => {
-                        DISK.acquire().intr();
-                    },
-
-                    UART0_IRQ => {
-                        UART.intr();
-                        // uart_intr();
-                    },
-
-                    _ => {
-                        panic!("Unresolved interrupt");
-                    }
-                }
-                plic_complete(interrupt);
-            }
+            // the handler registered for the irq serves it.
+            handle_irq();
             
         },
 
-        // Clock Interrupt
+        // IPI
         Trap::Interrupt(Interrupt::SupervisorSoft) => {
-            // software interrupt from a machine-mode timer interrupt,
-            // forwarded by timervec in kernelvec.S.
-
-            if cpu::cpuid() == 0{
-                clock_intr();
-            }
+            // software interrupt raised by another hart through the SBI.
             // acknowledge the software interrupt by clearing
             // the SSIP bit in sip.
             sip::clear_ssip();
+            handle_ipi();
+        },
 
+        // Clock Interrupt
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            timer_intr(sepc, *regs);
+            let pid = match CPU_MANAGER.mycpu().process {
+                Some(proc) => proc.as_ref().pid(),
+                None => 0
+            };
+            // s0, the frame pointer, is saved at 56(sp) by kernelvec
+            prof::sample(prof::Mode::Kernel, sepc, pid, *regs.add(7));
             // give up the cpu. 
             CPU_MANAGER.mycpu().try_yield_proc();
         }
 
         _ => {       
-            panic!("Unresolved Trap!");
+            print_pc(0, sepc);
+            panic!("Unresolved Trap!\n scause: {:?}\n stval: 0x{:x}\n sepc: 0x{:x}\n", scause.cause(), stval, sepc);
         }
     }
     // store context
@@ -289,8 +290,27 @@ pub unsafe fn kernel_trap(
 }
 
 
+/// cycles; about 1/10th second in qemu.
+const TIMER_INTERVAL: usize = 1000000;
+
+/// Ask the SBI for the next timer interrupt of this hart,
+/// which also clears the pending one.
+pub fn set_next_timer() {
+    let now = unsafe{ time::read() };
+    sbi::set_timer((now + TIMER_INTERVAL) as u64);
+}
+
+/// supervisor timer interrupt, hart 0 keeps the ticks.
+/// sepc and ra tell the watchdog where the hart was.
+unsafe fn timer_intr(sepc: usize, ra: usize) {
+    set_next_timer();
+    watchdog::touch(sepc, ra);
+    if cpu::cpuid() == 0 {
+        clock_intr();
+    }
+}
+
 pub unsafe fn clock_intr(){
-    let mut ticks = TICKS_LOCK.acquire();
-    *ticks = *ticks + 1;
-    drop(ticks);
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    expire_timers(now);
 }
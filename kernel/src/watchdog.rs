@@ -0,0 +1,156 @@
+//! Soft-lockup and hung-task watchdog.
+//!
+//! Every timer interrupt is a heartbeat of its hart. A hart which stops
+//! beating is spinning with interrupts off, so the others notice it and
+//! report where it was last seen. A hart which spins on one spinlock for
+//! too long reports itself. Processes in an uninterruptible sleep (disk,
+//! log, sleep locks) are reported when they stay asleep for too long.
+
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use array_macro::array;
+
+use crate::arch::riscv::time;
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::backtrace::{ print_pc, print_backtrace };
+use crate::process::{ CPU_MANAGER, PROC_MANAGER, cpuid };
+
+/// Frequency of the time CSR on qemu virt.
+const TIMEBASE_FREQ: usize = 10_000_000;
+/// A hart without timer interrupts for this long is locked up.
+const SOFTLOCKUP_SECS: usize = 20;
+/// A hart spinning on one lock for this long is locked up.
+const SPIN_TIMEOUT_SECS: usize = 10;
+/// A process in an uninterruptible sleep for this long is hung.
+const HUNG_TASK_SECS: usize = 120;
+
+struct HartWatch {
+    heartbeat: AtomicUsize, // Time of the last timer interrupt, 0 if never.
+    sepc: AtomicUsize, // Where that interrupt came from.
+    ra: AtomicUsize,
+    reported: AtomicBool, // Lockup reported since the last heartbeat.
+    reporting: AtomicBool // Reporting, don't report spins of the report itself.
+}
+
+impl HartWatch {
+    const fn new() -> Self {
+        Self {
+            heartbeat: AtomicUsize::new(0),
+            sepc: AtomicUsize::new(0),
+            ra: AtomicUsize::new(0),
+            reported: AtomicBool::new(false),
+            reporting: AtomicBool::new(false)
+        }
+    }
+}
+
+static WATCH: [HartWatch; NCPU] = array![_ => HartWatch::new(); NCPU];
+static LAST_CHECK: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub fn now() -> usize {
+    unsafe{ time::read() }
+}
+
+/// Time as seconds since boot.
+#[inline]
+pub fn secs(time: usize) -> usize {
+    time / TIMEBASE_FREQ
+}
+
+/// Time as hundredths of a second since boot.
+#[inline]
+pub fn centisecs(time: usize) -> usize {
+    time / (TIMEBASE_FREQ / 100)
+}
+
+/// Called from every timer interrupt with the interrupted sepc and ra.
+/// Once a second some hart checks the others and the processes.
+pub fn touch(sepc: usize, ra: usize) {
+    let me = unsafe{ cpuid() };
+    let now = now();
+    let watch = &WATCH[me];
+    watch.sepc.store(sepc, Ordering::Relaxed);
+    watch.ra.store(ra, Ordering::Relaxed);
+    watch.heartbeat.store(now, Ordering::Release);
+    watch.reported.store(false, Ordering::Relaxed);
+
+    let last = LAST_CHECK.load(Ordering::Relaxed);
+    if now.saturating_sub(last) < TIMEBASE_FREQ ||
+        LAST_CHECK.compare_exchange(last, now, Ordering::SeqCst, Ordering::Relaxed).is_err() {
+        return
+    }
+    check_harts(me, now);
+    unsafe{ PROC_MANAGER.check_hung(now, HUNG_TASK_SECS * TIMEBASE_FREQ); }
+}
+
+fn check_harts(me: usize, now: usize) {
+    for (hart, watch) in WATCH.iter().enumerate() {
+        let heartbeat = watch.heartbeat.load(Ordering::Acquire);
+        if hart == me || heartbeat == 0 || now.saturating_sub(heartbeat) < SOFTLOCKUP_SECS * TIMEBASE_FREQ {
+            continue;
+        }
+        if watch.reported.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        report(|| {
+            println!("watchdog: soft lockup on hart {}, stuck for {}s", hart, secs(now.saturating_sub(heartbeat)));
+            println!("last seen at:");
+            print_pc(0, watch.sepc.load(Ordering::Relaxed));
+            print_pc(1, watch.ra.load(Ordering::Relaxed));
+            unsafe{ CPU_MANAGER.held_locks_dump(hart); }
+        });
+    }
+}
+
+/// Run f with the reports of this hart's own spins suppressed,
+/// then dump the processes.
+fn report(f: impl FnOnce()) {
+    let me = unsafe{ cpuid() };
+    if WATCH[me].reporting.swap(true, Ordering::SeqCst) {
+        return
+    }
+    f();
+    unsafe{ PROC_MANAGER.proc_dump(); }
+    WATCH[me].reporting.store(false, Ordering::SeqCst);
+}
+
+/// Times one spinlock acquisition.
+pub struct SpinWatch {
+    spins: usize,
+    start: usize,
+    reported: bool
+}
+
+impl SpinWatch {
+    pub const fn new() -> Self {
+        Self { spins: 0, start: 0, reported: false }
+    }
+
+    /// Called from each round of the spin on lock name,
+    /// holder is the hart which held it when we looked.
+    #[inline]
+    pub fn check(&mut self, name: &'static str, holder: isize) {
+        self.spins += 1;
+        if self.spins & 0xfff != 0 || self.reported {
+            return
+        }
+        let now = now();
+        if self.start == 0 {
+            self.start = now;
+            return
+        }
+        if now.saturating_sub(self.start) < SPIN_TIMEOUT_SECS * TIMEBASE_FREQ {
+            return
+        }
+        self.reported = true;
+        let me = unsafe{ cpuid() };
+        report(|| {
+            println!(
+                "watchdog: soft lockup on hart {}, spinning on lock {} held by hart {} for {}s",
+                me, name, holder, secs(now.saturating_sub(self.start))
+            );
+            print_backtrace();
+            unsafe{ CPU_MANAGER.held_locks_dump(me); }
+        });
+    }
+}
@@ -0,0 +1,483 @@
+// Make a file system image for the kernel:
+//     mkfs fs.img files...
+// Every file goes into the root directory, named after the last
+// component of its path without a leading '_'.
+//
+// The layout must match the kernel: kernel/src/arch/riscv/qemu/fs.rs
+// and kernel/src/fs/{superblock,dinode,inode}.rs.
+//
+// Disk layout:
+// [ boot block | super block | log | inode blocks | free bit map | data blocks ]
+
+#include <stdio.h>
+#include <unistd.h>
+#include <stdlib.h>
+#include <string.h>
+#include <fcntl.h>
+#include <assert.h>
+#include <stdint.h>
+#include <time.h>
+#include <sys/stat.h>
+
+#define FSMAGIC     0x10203040
+#define BSIZE       1024        // block size
+#define FSSIZE      20000       // size of file system in blocks, reaches the doubly indirect blocks
+#define MAXOPBLOCKS 14          // max # of blocks any FS op writes
+#define LOGSIZE     (MAXOPBLOCKS*3)
+#define NINODES     200
+#define ROOTINO     1           // root i-number
+
+// Block numbers kept in the inode itself, followed by one singly,
+// one doubly and one triply indirect block.
+#define NDIRECT     10
+#define NINDIRECT   (BSIZE / 8)
+#define NDINDIRECT  (NINDIRECT * NINDIRECT)
+#define NTINDIRECT  (NDINDIRECT * NINDIRECT)
+#define MAXFILE     (NDIRECT + NINDIRECT + NDINDIRECT + NTINDIRECT)
+
+// Longest file name, in bytes
+#define NAME_MAX    255
+
+// superblock features
+#define FEATURE_LONG_NAMES  (1 << 0)    // directories hold variable-length records
+#define FEATURE_INODE_ATTRS (1 << 1)    // 128-byte inodes with mode, owner and times
+
+#define T_DIR       1   // Directory
+#define T_FILE      2   // File
+#define T_DEVICE    3   // Device
+
+#ifndef static_assert
+#define static_assert(a, b) do { switch (0) case 0: case (a): ; } while (0)
+#endif
+
+typedef uint32_t uint;
+typedef uint16_t ushort;
+typedef uint8_t  uchar;
+
+struct superblock {
+  uint magic;        // Must be FSMAGIC
+  uint size;         // Size of file system image (blocks)
+  uint nblocks;      // Number of data blocks
+  uint ninodes;      // Number of inodes
+  uint nlog;         // Number of log blocks
+  uint logstart;     // Block number of first log block
+  uint inodestart;   // Block number of first inode block
+  uint bmapstart;    // Block number of first free map block
+  uint features;     // FEATURE_* flags
+};
+
+// On-disk inode structure, 128 bytes
+struct dinode {
+  short type;               // File type
+  short major;              // Major device number (T_DEVICE only)
+  short minor;              // Minor device number (T_DEVICE only)
+  short nlink;              // Number of links to inode in file system
+  uint size;                // Size of file (bytes)
+  ushort mode;              // Permission bits
+  ushort pad;
+  uint uid;                 // Owner
+  uint gid;                 // Group
+  uint64_t atime;           // Last access, seconds since the epoch
+  uint64_t mtime;           // Last change of the data
+  uint64_t ctime;           // Last change of the inode
+  uint addrs[NDIRECT+3];    // Data block addresses
+  uint reserved[7];
+};
+
+// On-disk directory record: this header followed by name_len bytes
+// of name, without a trailing 0. rec_len covers the header, the name
+// and the free space up to the next record; records never cross a
+// block, so a directory is a whole number of blocks.
+struct dirent {
+  uint inum;
+  ushort rec_len;
+  uchar name_len;
+  uchar file_type;  // T_* of the entry
+};
+
+// Bytes a record with a name of n bytes takes, records start 4-byte aligned.
+#define REC_SIZE(n)   ((sizeof(struct dirent) + (n) + 3) & ~3)
+
+// Inodes per block.
+#define IPB           (BSIZE / sizeof(struct dinode))
+// Block containing inode i
+#define IBLOCK(i, sb) ((i) / IPB + sb.inodestart)
+// Bitmap bits per block
+#define BPB           (BSIZE*8)
+
+int nbitmap = FSSIZE/BPB + 1;
+int ninodeblocks = NINODES / IPB + 1;
+int nlog = LOGSIZE;
+int nmeta;    // Number of meta blocks (boot, sb, nlog, inode, bitmap)
+int nblocks;  // Number of data blocks
+
+int fsfd;
+struct superblock sb;
+char zeroes[BSIZE];
+uint freeinode = 1;
+uint freeblock;
+uint64_t now;   // times of every inode
+
+// The block of the root directory being filled.
+char dirbuf[BSIZE];
+uint dirused;   // bytes of dirbuf holding records
+uint dirlast;   // offset of the last record in dirbuf
+
+void balloc(int);
+void wsect(uint, void*);
+void winode(uint, struct dinode*);
+void rinode(uint inum, struct dinode *ip);
+void rsect(uint sec, void *buf);
+uint ialloc(ushort type, ushort mode);
+void iappend(uint inum, void *p, int n);
+void dirlink(uint dirino, char *name, uint inum, ushort type);
+void dirflush(uint dirino);
+void die(const char *);
+
+// convert to riscv byte order
+ushort
+xshort(ushort x)
+{
+  ushort y;
+  uchar *a = (uchar*)&y;
+  a[0] = x;
+  a[1] = x >> 8;
+  return y;
+}
+
+uint
+xint(uint x)
+{
+  uint y;
+  uchar *a = (uchar*)&y;
+  a[0] = x;
+  a[1] = x >> 8;
+  a[2] = x >> 16;
+  a[3] = x >> 24;
+  return y;
+}
+
+uint64_t
+xlong(uint64_t x)
+{
+  uint64_t y;
+  uchar *a = (uchar*)&y;
+  int i;
+  for(i = 0; i < 8; i++)
+    a[i] = x >> (8*i);
+  return y;
+}
+
+int
+main(int argc, char *argv[])
+{
+  int i, cc, fd;
+  uint rootino, inum;
+  char buf[BSIZE];
+  struct stat st;
+
+  static_assert(sizeof(int) == 4, "Integers must be 4 bytes!");
+  static_assert(sizeof(struct dinode) == 128, "dinode must be 128 bytes!");
+
+  if(argc < 2){
+    fprintf(stderr, "Usage: mkfs fs.img files...\n");
+    exit(1);
+  }
+
+  assert((BSIZE % sizeof(struct dinode)) == 0);
+
+  fsfd = open(argv[1], O_RDWR|O_CREAT|O_TRUNC, 0666);
+  if(fsfd < 0)
+    die(argv[1]);
+
+  // 1 fs block = 1 disk sector
+  nmeta = 2 + nlog + ninodeblocks + nbitmap;
+  nblocks = FSSIZE - nmeta;
+
+  sb.magic = FSMAGIC;
+  sb.size = xint(FSSIZE);
+  sb.nblocks = xint(nblocks);
+  sb.ninodes = xint(NINODES);
+  sb.nlog = xint(nlog);
+  sb.logstart = xint(2);
+  sb.inodestart = xint(2+nlog);
+  sb.bmapstart = xint(2+nlog+ninodeblocks);
+  sb.features = xint(FEATURE_LONG_NAMES | FEATURE_INODE_ATTRS);
+  now = time(NULL);
+
+  printf("nmeta %d (boot, super, log blocks %u inode blocks %u, bitmap blocks %u) blocks %d total %d\n",
+         nmeta, nlog, ninodeblocks, nbitmap, nblocks, FSSIZE);
+
+  freeblock = nmeta;     // the first free block that we can allocate
+
+  for(i = 0; i < FSSIZE; i++)
+    wsect(i, zeroes);
+
+  memset(buf, 0, sizeof(buf));
+  memmove(buf, &sb, sizeof(sb));
+  wsect(1, buf);
+
+  rootino = ialloc(T_DIR, 0755);
+  assert(rootino == ROOTINO);
+
+  dirlink(rootino, ".", rootino, T_DIR);
+  dirlink(rootino, "..", rootino, T_DIR);
+
+  for(i = 2; i < argc; i++){
+    // get rid of the directories, and of the leading _ of programs
+    char *shortname = strrchr(argv[i], '/');
+    shortname = shortname ? shortname + 1 : argv[i];
+    if(*shortname == '_')
+      ++shortname;
+    if(strlen(shortname) > NAME_MAX){
+      fprintf(stderr, "mkfs: name too long: %s\n", shortname);
+      exit(1);
+    }
+
+    if((fd = open(argv[i], 0)) < 0 || fstat(fd, &st) < 0)
+      die(argv[i]);
+
+    // owned by root, with the permissions it has on the host
+    inum = ialloc(T_FILE, st.st_mode & 0777);
+
+    dirlink(rootino, shortname, inum, T_FILE);
+
+    while((cc = read(fd, buf, sizeof(buf))) > 0)
+      iappend(inum, buf, cc);
+
+    close(fd);
+  }
+
+  dirflush(rootino);
+
+  balloc(freeblock);
+
+  exit(0);
+}
+
+void
+wsect(uint sec, void *buf)
+{
+  if(lseek(fsfd, sec * BSIZE, 0) != sec * BSIZE)
+    die("lseek");
+  if(write(fsfd, buf, BSIZE) != BSIZE)
+    die("write");
+}
+
+void
+winode(uint inum, struct dinode *ip)
+{
+  char buf[BSIZE];
+  uint bn;
+  struct dinode *dip;
+
+  bn = IBLOCK(inum, sb);
+  rsect(bn, buf);
+  dip = ((struct dinode*)buf) + (inum % IPB);
+  *dip = *ip;
+  wsect(bn, buf);
+}
+
+void
+rinode(uint inum, struct dinode *ip)
+{
+  char buf[BSIZE];
+  uint bn;
+  struct dinode *dip;
+
+  bn = IBLOCK(inum, sb);
+  rsect(bn, buf);
+  dip = ((struct dinode*)buf) + (inum % IPB);
+  *ip = *dip;
+}
+
+void
+rsect(uint sec, void *buf)
+{
+  if(lseek(fsfd, sec * BSIZE, 0) != sec * BSIZE)
+    die("lseek");
+  if(read(fsfd, buf, BSIZE) != BSIZE)
+    die("read");
+}
+
+uint
+ialloc(ushort type, ushort mode)
+{
+  uint inum = freeinode++;
+  struct dinode din;
+
+  if(inum >= NINODES){
+    fprintf(stderr, "mkfs: out of inodes\n");
+    exit(1);
+  }
+  bzero(&din, sizeof(din));
+  din.type = xshort(type);
+  din.nlink = xshort(1);
+  din.size = xint(0);
+  din.mode = xshort(mode);
+  din.atime = xlong(now);
+  din.mtime = xlong(now);
+  din.ctime = xlong(now);
+  winode(inum, &din);
+  return inum;
+}
+
+// Mark the blocks below used as in use, over as many bitmap blocks as it takes.
+void
+balloc(int used)
+{
+  uchar buf[BSIZE];
+  int i, b;
+
+  printf("balloc: first %d blocks have been allocated\n", used);
+  assert(used <= FSSIZE);
+  for(b = 0; b < nbitmap; b++){
+    bzero(buf, BSIZE);
+    for(i = 0; i < BPB && b*BPB + i < used; i++)
+      buf[i/8] = buf[i/8] | (0x1 << (i%8));
+    printf("balloc: write bitmap block at sector %d\n", xint(sb.bmapstart) + b);
+    wsect(xint(sb.bmapstart) + b, buf);
+  }
+}
+
+// A new zeroed block.
+uint
+newblock(void)
+{
+  if(freeblock >= FSSIZE){
+    fprintf(stderr, "mkfs: out of blocks\n");
+    exit(1);
+  }
+  wsect(freeblock, zeroes);
+  return freeblock++;
+}
+
+// Entry index of the indirect block ind, allocating the block it points to.
+uint
+indirect(uint ind, int index)
+{
+  uint entries[BSIZE / sizeof(uint)];
+  uint x;
+
+  rsect(ind, (char*)entries);
+  if(entries[index] == 0){
+    entries[index] = xint(newblock());
+    wsect(ind, (char*)entries);
+  }
+  x = xint(entries[index]);
+  return x;
+}
+
+// Disk block of block fbn of the inode, allocating it and
+// the indirect blocks on the way, like bmap() in the kernel.
+uint
+bmap(struct dinode *din, uint fbn)
+{
+  int slot, depth, i;
+  uint index[3];
+  uint x;
+
+  if(fbn < NDIRECT){
+    slot = fbn;
+    depth = 0;
+  } else if((fbn -= NDIRECT) < NINDIRECT){
+    slot = NDIRECT;
+    depth = 1;
+    index[0] = fbn;
+  } else if((fbn -= NINDIRECT) < NDINDIRECT){
+    slot = NDIRECT + 1;
+    depth = 2;
+    index[0] = fbn / NINDIRECT;
+    index[1] = fbn % NINDIRECT;
+  } else {
+    fbn -= NDINDIRECT;
+    assert(fbn < NTINDIRECT);
+    slot = NDIRECT + 2;
+    depth = 3;
+    index[0] = fbn / NDINDIRECT;
+    index[1] = fbn / NINDIRECT % NINDIRECT;
+    index[2] = fbn % NINDIRECT;
+  }
+
+  if(din->addrs[slot] == 0)
+    din->addrs[slot] = xint(newblock());
+  x = xint(din->addrs[slot]);
+  for(i = 0; i < depth; i++)
+    x = indirect(x, index[i]);
+  return x;
+}
+
+void
+iappend(uint inum, void *xp, int n)
+{
+  char *p = (char*)xp;
+  uint fbn, off, n1;
+  struct dinode din;
+  char buf[BSIZE];
+  uint x;
+
+  rinode(inum, &din);
+  off = xint(din.size);
+  // printf("append inum %d at off %d sz %d\n", inum, off, n);
+  while(n > 0){
+    fbn = off / BSIZE;
+    assert(fbn < MAXFILE);
+    x = bmap(&din, fbn);
+    n1 = BSIZE - (off - fbn * BSIZE);
+    if(n1 > n)
+      n1 = n;
+    rsect(x, buf);
+    bcopy(p, buf + off - (fbn * BSIZE), n1);
+    wsect(x, buf);
+    n -= n1;
+    off += n1;
+    p += n1;
+  }
+  din.size = xint(off);
+  winode(inum, &din);
+}
+
+// Add a record for inum as name to the directory, through dirbuf:
+// the directory is written a block at a time by dirflush().
+void
+dirlink(uint dirino, char *name, uint inum, ushort type)
+{
+  int len = strlen(name);
+  uint need = REC_SIZE(len);
+  struct dirent *de;
+
+  assert(len <= NAME_MAX);
+  if(dirused + need > BSIZE)
+    dirflush(dirino);
+  de = (struct dirent*)(dirbuf + dirused);
+  de->inum = xint(inum);
+  de->rec_len = xshort(need);
+  de->name_len = len;
+  de->file_type = type;
+  memmove(de + 1, name, len);
+  dirlast = dirused;
+  dirused += need;
+}
+
+// Append the block in dirbuf to the directory, its last
+// record taking the free space up to the end of the block.
+void
+dirflush(uint dirino)
+{
+  struct dirent *de;
+
+  if(dirused == 0)
+    return;
+  de = (struct dirent*)(dirbuf + dirlast);
+  de->rec_len = xshort(BSIZE - dirlast);
+  iappend(dirino, dirbuf, BSIZE);
+  bzero(dirbuf, BSIZE);
+  dirused = 0;
+}
+
+void
+die(const char *s)
+{
+  perror(s);
+  exit(1);
+}
@@ -0,0 +1,78 @@
+// ls for directories of variable-length records with long names.
+#include "sys.h"
+
+char*
+basename(char *path)
+{
+  char *p;
+
+  for(p = path + strlen(path); p > path && *(p - 1) != '/'; p--)
+    ;
+  return p;
+}
+
+void
+ls(char *path)
+{
+  char buf[BSIZE], name[512], *p;
+  struct dirent *de;
+  struct stat st;
+  int fd, n, off;
+
+  if((fd = open(path, O_RDONLY)) < 0){
+    fprintf(2, "ls: cannot open %s\n", path);
+    return;
+  }
+  if(fstat(fd, &st) < 0){
+    fprintf(2, "ls: cannot stat %s\n", path);
+    close(fd);
+    return;
+  }
+
+  if(st.type != T_DIR){
+    printf("%s %d %d %d\n", basename(path), st.type, st.ino, (int)st.size);
+    close(fd);
+    return;
+  }
+
+  if(strlen(path) + 1 + NAME_MAX + 1 > sizeof name){
+    printf("ls: path too long\n");
+    close(fd);
+    return;
+  }
+  strcpy(name, path);
+  p = name + strlen(name);
+  *p++ = '/';
+  // whole blocks, so that no record is cut in two
+  while((n = read(fd, buf, sizeof buf)) > 0){
+    for(off = 0; off + sizeof(*de) <= n; off += de->rec_len){
+      de = (struct dirent*)(buf + off);
+      if(de->rec_len == 0)
+        break;
+      if(de->inum == 0 || de->name_len == 0)
+        continue;
+      memmove(p, buf + off + sizeof(*de), de->name_len);
+      p[de->name_len] = 0;
+      if(stat(name, &st) < 0){
+        printf("ls: cannot stat %s\n", name);
+        continue;
+      }
+      printf("%s %d %d %d\n", p, st.type, st.ino, (int)st.size);
+    }
+  }
+  close(fd);
+}
+
+int
+main(int argc, char *argv[])
+{
+  int i;
+
+  if(argc < 2){
+    ls(".");
+    exit(0);
+  }
+  for(i = 1; i < argc; i++)
+    ls(argv[i]);
+  exit(0);
+}
@@ -0,0 +1,19 @@
+// Mount a file system.
+//   mount dev dir type
+// type is xv6fs, vfat, tmpfs or proc; dev is ignored but for the
+// file systems on a disk.
+#include "sys.h"
+
+int
+main(int argc, char *argv[])
+{
+  if(argc != 4){
+    fprintf(2, "usage: mount dev dir type\n");
+    exit(1);
+  }
+  if(mount(atoi(argv[1]), argv[2], argv[3]) < 0){
+    fprintf(2, "mount: cannot mount %s on %s\n", argv[3], argv[2]);
+    exit(1);
+  }
+  exit(0);
+}
@@ -0,0 +1,85 @@
+// Check the link counts of directories across mkdir and rmdir:
+// each subdirectory links its parent through "..".
+//   nlinktest [dir]
+#include "sys.h"
+
+char path[3][512];
+int failed;
+
+int
+nlink(char *path)
+{
+  struct stat st;
+
+  if(stat(path, &st) < 0){
+    printf("nlinktest: cannot stat %s\n", path);
+    exit(1);
+  }
+  return st.nlink;
+}
+
+void
+expect(char *path, int n)
+{
+  int got = nlink(path);
+
+  if(got != n){
+    printf("nlinktest: %s has %d links, not %d\n", path, got, n);
+    failed = 1;
+  }
+}
+
+void
+join(char *dst, char *dir, char *name)
+{
+  uint len = strlen(dir);
+
+  strcpy(dst, dir);
+  if(len > 0 && dir[len - 1] != '/')
+    dst[len++] = '/';
+  strcpy(dst + len, name);
+}
+
+int
+main(int argc, char *argv[])
+{
+  char *parent = argc > 1 ? argv[1] : "/";
+  char *dir = path[0], *a = path[1], *b = path[2];
+  int base, own;
+
+  join(dir, parent, "nlinktest.d");
+  join(a, dir, "a");
+  join(b, dir, "b");
+
+  base = nlink(parent);
+  if(mkdir(dir) < 0){
+    printf("nlinktest: mkdir %s failed\n", dir);
+    exit(1);
+  }
+  expect(parent, base + 1);
+  // what an empty directory has, "." counts on some file systems
+  own = nlink(dir);
+  if(mkdir(a) < 0 || mkdir(b) < 0){
+    printf("nlinktest: mkdir in %s failed\n", dir);
+    exit(1);
+  }
+  expect(dir, own + 2);
+  expect(a, own);
+  expect(b, own);
+
+  if(unlink(a) < 0 || unlink(b) < 0){
+    printf("nlinktest: rmdir in %s failed\n", dir);
+    exit(1);
+  }
+  expect(dir, own);
+  if(unlink(dir) < 0){
+    printf("nlinktest: rmdir %s failed\n", dir);
+    exit(1);
+  }
+  expect(parent, base);
+
+  if(failed)
+    exit(1);
+  printf("nlinktest: ok\n");
+  exit(0);
+}
@@ -0,0 +1,66 @@
+// What the programs in user/ need of the kernel and of the xv6-user
+// library (ULIB), for the parts this tree has changed: struct stat is
+// kernel/src/fs/stat.rs and struct dirent the DirEntry records of
+// kernel/src/fs/dinode.rs.
+
+typedef unsigned int   uint;
+typedef unsigned short ushort;
+typedef unsigned char  uchar;
+typedef unsigned long  uint64;
+
+#define T_DIR     1   // Directory
+#define T_FILE    2   // File
+#define T_DEVICE  3   // Device
+#define T_SYMLINK 4   // Symbolic link
+
+#define O_RDONLY  0x000
+
+#define BSIZE     1024  // directories are read a block at a time
+#define NAME_MAX  255
+
+struct stat {
+  uint dev;     // File system's disk device
+  uint ino;     // Inode number
+  short type;   // Type of file
+  short nlink;  // Number of links to file
+  ushort mode;  // Permission bits
+  uint uid;     // Owner
+  uint gid;     // Group
+  uint64 size;  // Size of file in bytes
+  uint64 atime; // Last access, seconds since the epoch
+  uint64 mtime; // Last change of the data
+  uint64 ctime; // Last change of the inode
+};
+
+// A directory record, the name follows it. Records are 4-byte
+// aligned, never cross a block and are free when inum is 0.
+struct dirent {
+  uint inum;
+  ushort rec_len;   // up to the next record
+  uchar name_len;
+  uchar type;       // T_* of the entry
+};
+
+// system calls
+int open(const char*, int);
+int read(int, void*, int);
+int close(int);
+int fstat(int, struct stat*);
+int mkdir(const char*);
+int unlink(const char*);
+void exit(int) __attribute__((noreturn));
+
+// usys.S, those xv6-user does not have
+int mount(int, const char*, const char*);
+int umount(const char*);
+int geteuid(void);
+int getegid(void);
+
+// ulib.c, printf.c
+int stat(const char*, struct stat*);
+char* strcpy(char*, const char*);
+uint strlen(const char*);
+void* memmove(void*, const void*, int);
+int atoi(const char*);
+void printf(const char*, ...);
+void fprintf(int, const char*, ...);
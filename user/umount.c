@@ -0,0 +1,17 @@
+// Unmount the file system mounted on dir.
+//   umount dir
+#include "sys.h"
+
+int
+main(int argc, char *argv[])
+{
+  if(argc != 2){
+    fprintf(2, "usage: umount dir\n");
+    exit(1);
+  }
+  if(umount(argv[1]) < 0){
+    fprintf(2, "umount: cannot unmount %s\n", argv[1]);
+    exit(1);
+  }
+  exit(0);
+}